@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(reader) = pcan_basic::trc::Reader::new(data) {
+        for record in reader {
+            let _ = record;
+        }
+    }
+});
@@ -0,0 +1,670 @@
+//! Vector ASC log files, the text format of CANoe and CANalyzer.
+//!
+//! Classic CAN, CAN FD and error frame lines are mapped to [`Record`]s, all
+//! other events are skipped. Error frames in ASC carry no details, they are
+//! read as [`ErrorKind::Other`] without error counters.
+//!
+//! The date in the header has no time zone, it is read and written as UTC.
+
+use std::{
+    convert::TryFrom,
+    fmt::{self, Write as _},
+    io::{self, BufRead, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use embedded_can::Frame as _;
+
+use crate::{
+    calendar::{DateTime, MONTHS, WEEKDAYS},
+    dlc_to_len, Capture, Direction, ErrorFrame, ErrorKind, ExtendedId, FdFrame, Frame, Id, Message,
+    Record, StandardId,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A line of the file could not be parsed.
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Parse { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+// Flags column of CAN FD lines.
+const FD_FLAG_EDL: u32 = 0x1000;
+const FD_FLAG_BRS: u32 = 0x2000;
+const FD_FLAG_ESI: u32 = 0x4000;
+
+/// Parses `Thu Sep 17 10:21:52.123 am 2020`, the weekday is ignored.
+fn parse_date(s: &str) -> Option<SystemTime> {
+    let tokens: Vec<&str> = s.split_whitespace().collect();
+    let (month, day, time, rest) = match tokens.as_slice() {
+        [_, month, day, time, rest @ ..] => (month, day, time, rest),
+        _ => return None,
+    };
+    let (meridiem, year) = match rest {
+        [meridiem, year] => (Some(meridiem.to_ascii_lowercase()), year),
+        [year] => (None, year),
+        _ => return None,
+    };
+
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as u8 + 1;
+    let (hms, fraction) = time.split_once('.').unwrap_or((time, "0"));
+    let mut hms = hms.split(':').map(|t| t.parse::<u8>().ok());
+    let (mut hour, minute, second) = (hms.next()??, hms.next()??, hms.next()??);
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    match meridiem.as_deref() {
+        Some("am") if hour == 12 => hour = 0,
+        Some("pm") if hour < 12 => hour += 12,
+        _ => {}
+    }
+
+    DateTime {
+        year: year.parse().ok()?,
+        month,
+        day: day.parse().ok()?,
+        weekday: 0,
+        hour,
+        minute,
+        second,
+        nanos: format!("{:0<9}", fraction).parse().ok()?,
+    }
+    .to_system_time()
+}
+
+fn format_date(time: SystemTime) -> String {
+    let date = DateTime::from_system_time(time);
+    let (hour, meridiem) = match date.hour {
+        0 => (12, "am"),
+        h @ 1..=11 => (h, "am"),
+        12 => (12, "pm"),
+        h => (h - 12, "pm"),
+    };
+    format!(
+        "{} {} {:02} {:02}:{:02}:{:02}.{:03} {} {}",
+        WEEKDAYS[date.weekday as usize],
+        MONTHS[date.month as usize - 1],
+        date.day,
+        hour,
+        date.minute,
+        date.second,
+        date.nanos / 1_000_000,
+        meridiem,
+        date.year
+    )
+}
+
+/// Streaming reader for ASC files.
+///
+/// Header lines are parsed by [`Reader::new()`]. Record timestamps are
+/// relative to the start of the measurement, also for files with relative
+/// timestamps between lines.
+pub struct Reader<R> {
+    reader: R,
+    start_time: Option<SystemTime>,
+    hex: bool,
+    relative: bool,
+    last: Duration,
+    line: String,
+    line_number: usize,
+    // The first record line is read together with the header.
+    pending: bool,
+    done: bool,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(reader: R) -> Result<Self, Error> {
+        let mut this = Self {
+            reader,
+            start_time: None,
+            hex: true,
+            relative: false,
+            last: Duration::default(),
+            line: String::new(),
+            line_number: 0,
+            pending: false,
+            done: false,
+        };
+
+        while this.read_line()? {
+            let line = this.line.trim();
+            if let Some(date) = line.strip_prefix("date ") {
+                this.start_time = parse_date(date);
+            } else if line.starts_with("base ") {
+                let tokens: Vec<&str> = line.split_whitespace().collect();
+                this.hex = tokens.get(1) != Some(&"dec");
+                this.relative = tokens.get(3) == Some(&"relative");
+            } else if line.starts_with(|c: char| c.is_ascii_digit()) {
+                this.pending = true;
+                break;
+            }
+        }
+        Ok(this)
+    }
+
+    /// Absolute time of the start of the measurement, from the header.
+    pub fn start_time(&self) -> Option<SystemTime> {
+        self.start_time
+    }
+
+    fn read_line(&mut self) -> Result<bool, Error> {
+        self.line.clear();
+        let n = self.reader.read_line(&mut self.line)?;
+        self.line_number += 1;
+        Ok(n > 0)
+    }
+
+    fn parse_record(&mut self) -> Result<Option<Record>, String> {
+        let tokens: Vec<&str> = self.line.split_whitespace().collect();
+        let offset = match tokens.first().and_then(|t| parse_seconds(t)) {
+            Some(offset) => offset,
+            // `Begin Triggerblock`, comments and other non-event lines.
+            None => return Ok(None),
+        };
+        let timestamp = if self.relative {
+            self.last
+                .checked_add(offset)
+                .ok_or_else(|| "timestamp overflow".to_string())?
+        } else {
+            offset
+        };
+        self.last = timestamp;
+
+        let parsed = match tokens.get(1) {
+            Some(&"CANFD") => self.parse_fd(&tokens[2..])?,
+            Some(channel) => match channel.parse() {
+                Ok(channel) => self
+                    .parse_classic(&tokens[2..])?
+                    .map(|(direction, message)| (channel, direction, message)),
+                Err(_) => None,
+            },
+            None => None,
+        };
+        Ok(parsed.map(|(channel, direction, message)| Record {
+            timestamp,
+            channel,
+            direction,
+            message,
+        }))
+    }
+
+    /// `123x Rx d 8 00 01 02 03 04 05 06 07` or `ErrorFrame`, after the channel.
+    fn parse_classic(&self, tokens: &[&str]) -> Result<Option<(Direction, Message)>, String> {
+        if tokens.first() == Some(&"ErrorFrame") {
+            return Ok(Some((Direction::Rx, Message::Error(unknown_error()))));
+        }
+        let id = match tokens.first().and_then(|t| self.parse_id(t)) {
+            Some(id) => id,
+            // Statistics, chip state and other events.
+            None => return Ok(None),
+        };
+        let direction = match tokens.get(1) {
+            Some(&"Rx") => Direction::Rx,
+            Some(&"Tx") => Direction::Tx,
+            // Transmit requests were not on the bus yet.
+            _ => return Ok(None),
+        };
+
+        let frame = match tokens.get(2) {
+            Some(&"r") => {
+                let dlc = match tokens.get(3).and_then(|t| u8::from_str_radix(t, 16).ok()) {
+                    Some(dlc) if tokens[3].len() == 1 => dlc as usize,
+                    _ => 0,
+                };
+                Frame::new_remote(id, dlc).map_err(|_| format!("invalid DLC {}", dlc))?
+            }
+            Some(&"d") => {
+                let dlc = tokens
+                    .get(3)
+                    .and_then(|t| u8::from_str_radix(t, 16).ok())
+                    .ok_or("missing DLC")?;
+                let len = dlc_to_len(dlc).min(8);
+                let data = self.parse_data(&tokens[4..], len)?;
+                Frame::new(id, &data).unwrap()
+            }
+            _ => return Err("expected d or r after the direction".to_string()),
+        };
+        Ok(Some((direction, Message::Can(frame))))
+    }
+
+    /// `1 Rx 123 1 0 9 12 00 01 ...`, optionally with a symbolic name after the ID.
+    fn parse_fd(&self, tokens: &[&str]) -> Result<Option<(u8, Direction, Message)>, String> {
+        let channel = tokens
+            .first()
+            .and_then(|t| t.parse().ok())
+            .ok_or("invalid channel")?;
+        let direction = match tokens.get(1) {
+            Some(&"Rx") => Direction::Rx,
+            Some(&"Tx") => Direction::Tx,
+            _ => return Ok(None),
+        };
+        if tokens.get(2) == Some(&"ErrorFrame") {
+            return Ok(Some((channel, direction, Message::Error(unknown_error()))));
+        }
+        let id = match tokens.get(2).and_then(|t| self.parse_id(t)) {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        let mut rest = &tokens[3..];
+        if rest.first().is_some_and(|t| *t != "0" && *t != "1") {
+            rest = &rest[1..];
+        }
+        let field = |i: usize, name: &str| rest.get(i).copied().ok_or(format!("missing {}", name));
+        let brs = field(0, "BRS")? == "1";
+        let esi = field(1, "ESI")? == "1";
+        let len: usize = field(3, "data length")?
+            .parse()
+            .map_err(|_| "invalid data length")?;
+        let data = self.parse_data(&rest[4..], len)?;
+
+        // Flags after the data tell whether this is a classic frame on a CAN FD channel.
+        let flags = rest
+            .get(4 + len + 2)
+            .and_then(|t| u32::from_str_radix(t, 16).ok());
+        let message = if flags.is_some_and(|flags| flags & FD_FLAG_EDL == 0) {
+            Message::Can(Frame::new(id, &data).map_err(|_| "invalid data length")?)
+        } else {
+            let mut frame = FdFrame::new(id, &data).ok_or("invalid data length")?;
+            frame.set_bit_rate_switch(brs);
+            frame.set_error_state_indicator(esi);
+            Message::Fd(frame)
+        };
+        Ok(Some((channel, direction, message)))
+    }
+
+    fn parse_id(&self, token: &str) -> Option<Id> {
+        let (digits, extended) = match token.strip_suffix('x') {
+            Some(digits) => (digits, true),
+            None => (token, false),
+        };
+        let raw = u32::from_str_radix(digits, if self.hex { 16 } else { 10 }).ok()?;
+        if extended {
+            ExtendedId::new(raw).map(Id::from)
+        } else {
+            StandardId::new(u16::try_from(raw).ok()?).map(Id::from)
+        }
+    }
+
+    fn parse_data(&self, tokens: &[&str], len: usize) -> Result<Vec<u8>, String> {
+        if tokens.len() < len {
+            return Err(format!("expected {} data bytes", len));
+        }
+        tokens[..len]
+            .iter()
+            .map(|t| {
+                u8::from_str_radix(t, if self.hex { 16 } else { 10 })
+                    .map_err(|_| format!("invalid data byte {}", t))
+            })
+            .collect()
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if !self.pending {
+                match self.read_line() {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        self.done = true;
+                        break;
+                    }
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err));
+                    }
+                }
+            }
+            self.pending = false;
+
+            match self.parse_record() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => continue,
+                Err(message) => {
+                    return Some(Err(Error::Parse {
+                        line: self.line_number,
+                        message,
+                    }))
+                }
+            }
+        }
+        None
+    }
+}
+
+fn unknown_error() -> ErrorFrame {
+    ErrorFrame {
+        kind: ErrorKind::Other,
+        direction: Direction::Rx,
+        ecc: 0,
+        rx_error_counter: 0,
+        tx_error_counter: 0,
+    }
+}
+
+/// Parses seconds with up to 9 fractional digits without rounding errors.
+fn parse_seconds(s: &str) -> Option<Duration> {
+    let (secs, fraction) = s.split_once('.')?;
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = format!("{:0<9}", fraction).parse().ok()?;
+    Some(Duration::new(secs.parse().ok()?, nanos))
+}
+
+/// Writes records as ASC file with absolute timestamps and hexadecimal numbers.
+///
+/// The header is written together with the first record, the footer by
+/// [`Writer::finish()`].
+pub struct Writer<W: Write> {
+    writer: W,
+    start_time: Option<SystemTime>,
+    // Timestamp of the first record, written as start of the measurement.
+    base: Option<Duration>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            start_time: None,
+            base: None,
+        }
+    }
+
+    /// Absolute time that corresponds to a record timestamp of zero.
+    ///
+    /// If not set, the first record is assumed to be written right when it
+    /// was captured.
+    pub fn with_start_time(&mut self, start_time: SystemTime) -> &mut Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let base = match self.base {
+            Some(base) => base,
+            None => {
+                let start_time = self
+                    .start_time
+                    .or_else(|| SystemTime::now().checked_sub(record.timestamp))
+                    .unwrap_or(UNIX_EPOCH);
+                self.write_header(start_time + record.timestamp)?;
+                self.base = Some(record.timestamp);
+                record.timestamp
+            }
+        };
+
+        let offset = record.timestamp.checked_sub(base).unwrap_or_default();
+        let time = format!("{}.{:06}", offset.as_secs(), offset.subsec_micros());
+        let direction = match record.direction {
+            Direction::Rx => "Rx",
+            Direction::Tx => "Tx",
+        };
+        let line = match &record.message {
+            Message::Can(frame) => {
+                let (kind, data) = if frame.is_remote_frame() {
+                    ("r", String::new())
+                } else {
+                    ("d", format_data(frame.data()))
+                };
+                format!(
+                    "{:>11} {}  {:<15} {:<4} {} {:X} {}",
+                    time,
+                    record.channel,
+                    format_id(frame.id()),
+                    direction,
+                    kind,
+                    frame.dlc(),
+                    data
+                )
+            }
+            Message::Fd(frame) => {
+                let mut flags = FD_FLAG_EDL;
+                if frame.bit_rate_switch() {
+                    flags |= FD_FLAG_BRS;
+                }
+                if frame.error_state_indicator() {
+                    flags |= FD_FLAG_ESI;
+                }
+                format!(
+                    "{:>11} CANFD {:>3} {:<4} {:>8} {} {} {:X} {:>2} {} {:>8} {:>4} {:>8X} {:>8} {:>8} {:>8} {:>8} {:>8}",
+                    time,
+                    record.channel,
+                    direction,
+                    format_id(frame.id()),
+                    frame.bit_rate_switch() as u8,
+                    frame.error_state_indicator() as u8,
+                    frame.dlc(),
+                    frame.data().len(),
+                    format_data(frame.data()),
+                    0,
+                    0,
+                    flags,
+                    0,
+                    0,
+                    0,
+                    0,
+                    0
+                )
+            }
+            Message::Error(_) => format!("{:>11} {}  ErrorFrame", time, record.channel),
+        };
+        writeln!(self.writer, "{}", line.trim_end())
+    }
+
+    /// Writes the footer and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.base.is_none() {
+            self.write_header(self.start_time.unwrap_or_else(SystemTime::now))?;
+        }
+        writeln!(self.writer, "End TriggerBlock")?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_header(&mut self, start_time: SystemTime) -> io::Result<()> {
+        let date = format_date(start_time);
+        writeln!(self.writer, "date {}", date)?;
+        writeln!(self.writer, "base hex  timestamps absolute")?;
+        writeln!(self.writer, "internal events logged")?;
+        writeln!(self.writer, "// version 9.0.0")?;
+        writeln!(self.writer, "Begin Triggerblock {}", date)?;
+        writeln!(self.writer, "   0.000000 Start of measurement")
+    }
+}
+
+impl<W: Write> Capture for Writer<W> {
+    fn capture(&mut self, record: &Record) -> io::Result<()> {
+        self.write(record)
+    }
+}
+
+fn format_id(id: Id) -> String {
+    match id {
+        Id::Standard(id) => format!("{:X}", id.as_raw()),
+        Id::Extended(id) => format!("{:X}x", id.as_raw()),
+    }
+}
+
+fn format_data(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 3);
+    for (i, byte) in data.iter().enumerate() {
+        if i > 0 {
+            s.push(' ');
+        }
+        write!(s, "{:02X}", byte).unwrap();
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn std_id(id: u16) -> Id {
+        StandardId::new(id).unwrap().into()
+    }
+
+    #[test]
+    fn read() {
+        let asc = "date Thu Sep 17 10:21:52.123 pm 2020
+base hex  timestamps absolute
+internal events logged
+// version 9.0.0
+Begin Triggerblock Thu Sep 17 10:21:52.123 pm 2020
+   0.000000 Start of measurement
+   0.015991 1  123             Rx   d 8 00 01 02 03 04 05 06 07  Length = 228000 BitCount = 118 ID = 291
+   0.020000 2  1F334455x       Tx   d 2 AA BB
+   0.030000 1  7FF             Rx   r 4
+   0.035000 1  Statistic: D 0 R 0 XD 0 XR 0 E 0 O 0 B 0.00%
+   0.040000 1  ErrorFrame
+   0.050000 CANFD   1 Tx        100  Engine_Data                      1 0 9 12 00 01 02 03 04 05 06 07 08 09 0A 0B   130000  130     3000 1234 0 0 0 0
+   0.060000 CANFD   1 Rx        101                                   0 0 2  2 AA BB   130000  130        0 1234 0 0 0 0
+End TriggerBlock
+";
+        let reader = Reader::new(asc.as_bytes()).unwrap();
+        assert_eq!(
+            reader.start_time(),
+            Some(UNIX_EPOCH + Duration::new(1_600_381_312, 123_000_000))
+        );
+        let records: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(records.len(), 6);
+
+        assert_eq!(records[0].timestamp, Duration::from_micros(15991));
+        assert_eq!(
+            records[0].message,
+            Message::Can(Frame::new(std_id(0x123), &[0, 1, 2, 3, 4, 5, 6, 7]).unwrap())
+        );
+        assert_eq!(records[1].channel, 2);
+        assert_eq!(records[1].direction, Direction::Tx);
+        match records[2].message {
+            Message::Can(frame) => assert!(frame.is_remote_frame() && frame.dlc() == 4),
+            _ => panic!(),
+        }
+        assert_eq!(records[3].message, Message::Error(unknown_error()));
+        match records[4].message {
+            Message::Fd(frame) => {
+                assert_eq!(frame.data().len(), 12);
+                assert!(frame.bit_rate_switch());
+            }
+            _ => panic!(),
+        }
+        assert_eq!(
+            records[5].message,
+            Message::Can(Frame::new(std_id(0x101), &[0xAA, 0xBB]).unwrap())
+        );
+    }
+
+    #[test]
+    fn relative_decimal() {
+        let asc = "base dec  timestamps relative
+   0.100000 1  291             Rx   d 1 255
+   0.050000 1  291             Rx   d 1 16
+";
+        let records: Vec<_> = Reader::new(asc.as_bytes())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records[1].timestamp, Duration::from_millis(150));
+        assert_eq!(
+            records[0].message,
+            Message::Can(Frame::new(std_id(0x123), &[0xFF]).unwrap())
+        );
+    }
+
+    #[test]
+    fn relative_overflow() {
+        let asc = "base dec  timestamps relative
+   18446744073709551615.000000 1  291             Rx   d 1 255
+   1.000000 1  291             Rx   d 1 16
+";
+        let records: Vec<_> = Reader::new(asc.as_bytes()).unwrap().collect();
+        assert!(records[0].is_ok());
+        assert!(matches!(records[1], Err(Error::Parse { line: 3, .. })));
+    }
+
+    #[test]
+    fn round_trip() {
+        let start = UNIX_EPOCH + Duration::new(1_600_338_112, 0);
+        let mut fd = FdFrame::new(std_id(0x200), &[7; 24]).unwrap();
+        fd.set_bit_rate_switch(false);
+        let records = vec![
+            Record {
+                timestamp: Duration::from_millis(1),
+                channel: 1,
+                direction: Direction::Rx,
+                message: Message::Can(
+                    Frame::new(ExtendedId::new(0x1234_5678).unwrap(), &[1, 2, 3]).unwrap(),
+                ),
+            },
+            Record {
+                timestamp: Duration::from_millis(2),
+                channel: 2,
+                direction: Direction::Tx,
+                message: Message::Fd(fd),
+            },
+            Record {
+                timestamp: Duration::from_millis(3),
+                channel: 1,
+                direction: Direction::Tx,
+                message: Message::Can(Frame::new_remote(std_id(0x7FF), 2).unwrap()),
+            },
+            Record {
+                timestamp: Duration::from_millis(4),
+                channel: 1,
+                direction: Direction::Rx,
+                message: Message::Error(unknown_error()),
+            },
+        ];
+
+        let mut writer = Writer::new(Vec::new());
+        writer.with_start_time(start);
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let asc = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert!(asc.starts_with("date Thu Sep 17 10:21:52.001 am 2020\n"));
+
+        let reader = Reader::new(asc.as_bytes()).unwrap();
+        assert_eq!(reader.start_time(), Some(start + Duration::from_millis(1)));
+        let read: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(read.len(), records.len());
+        for (read, written) in read.iter().zip(&records) {
+            assert_eq!(read.timestamp, written.timestamp - records[0].timestamp);
+            assert_eq!(read.channel, written.channel);
+            assert_eq!(read.direction, written.direction);
+            assert_eq!(read.message, written.message);
+        }
+    }
+}
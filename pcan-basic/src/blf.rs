@@ -0,0 +1,842 @@
+//! Vector BLF binary log files.
+//!
+//! A BLF file is a header followed by objects, usually packed into
+//! zlib-compressed containers. CAN, CAN FD and CAN error objects are mapped to
+//! [`Record`]s, other objects are skipped.
+//!
+//! Error frames are stored with the error code capture register (ECC) of the
+//! controller, error counters are lost. The start time in the header has
+//! no time zone and only millisecond resolution, it is read and written as UTC.
+
+use std::{
+    convert::TryInto,
+    fmt,
+    io::{self, Read, Seek, SeekFrom, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use embedded_can::Frame as _;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+
+use crate::{
+    calendar::DateTime, dlc_to_len, Capture, Direction, ErrorFrame, ErrorKind, ExtendedId, FdFrame,
+    Frame, Id, Message, Record, StandardId,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The file is not a BLF file or is corrupted.
+    Format(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Format(message) => write!(f, "invalid BLF file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Format(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+const FILE_SIGNATURE: &[u8; 4] = b"LOGG";
+const FILE_HEADER_SIZE: usize = 144;
+const OBJECT_SIGNATURE: &[u8; 4] = b"LOBJ";
+const OBJECT_HEADER_BASE_SIZE: usize = 16;
+const OBJECT_HEADER_V1_SIZE: usize = 32;
+const CONTAINER_HEADER_SIZE: usize = 32;
+// Uncompressed size at which a container is written, like CANoe does.
+const CONTAINER_SIZE: usize = 128 * 1024;
+// Larger objects are rejected instead of trusting the size of a corrupt header.
+const MAX_OBJECT_SIZE: usize = 16 * 1024 * 1024;
+
+const NO_COMPRESSION: u16 = 0;
+const ZLIB_DEFLATE: u16 = 2;
+
+// Object types.
+const CAN_MESSAGE: u32 = 1;
+const CAN_ERROR: u32 = 2;
+const LOG_CONTAINER: u32 = 10;
+const CAN_ERROR_EXT: u32 = 73;
+const CAN_MESSAGE2: u32 = 86;
+const CAN_FD_MESSAGE: u32 = 100;
+const CAN_FD_MESSAGE_64: u32 = 101;
+const CAN_FD_ERROR_64: u32 = 104;
+
+// Timestamp units in the object header flags.
+const TIME_TEN_MICS: u32 = 1;
+const TIME_ONE_NANS: u32 = 2;
+
+const CAN_MSG_EXT: u32 = 0x8000_0000;
+const CAN_MSG_TX: u8 = 0x01;
+const CAN_MSG_RTR: u8 = 0x80;
+const CAN_FD_EDL: u8 = 0x01;
+const CAN_FD_BRS: u8 = 0x02;
+const CAN_FD_ESI: u8 = 0x04;
+const CAN_FD_64_RTR: u32 = 0x0010;
+const CAN_FD_64_EDL: u32 = 0x1000;
+const CAN_FD_64_BRS: u32 = 0x2000;
+const CAN_FD_64_ESI: u32 = 0x4000;
+
+// Error code capture register in SJA1000 layout.
+const ECC_TYPE_SHIFT: u8 = 6;
+const ECC_RX: u8 = 0x20;
+
+fn u16_at(b: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(b[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(b[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(b: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(b[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_systemtime(b: &[u8]) -> Option<SystemTime> {
+    let field = |i: usize| u16_at(b, i * 2);
+    DateTime {
+        year: field(0),
+        month: field(1) as u8,
+        weekday: field(2) as u8,
+        day: field(3) as u8,
+        hour: field(4) as u8,
+        minute: field(5) as u8,
+        second: field(6) as u8,
+        nanos: field(7).min(999) as u32 * 1_000_000,
+    }
+    .to_system_time()
+}
+
+fn write_systemtime(b: &mut Vec<u8>, time: SystemTime) {
+    let date = DateTime::from_system_time(time);
+    let fields = [
+        date.year,
+        date.month as u16,
+        date.weekday as u16,
+        date.day as u16,
+        date.hour as u16,
+        date.minute as u16,
+        date.second as u16,
+        (date.nanos / 1_000_000) as u16,
+    ];
+    for field in &fields {
+        b.extend_from_slice(&field.to_le_bytes());
+    }
+}
+
+fn make_id(raw: u32) -> Option<Id> {
+    if raw & CAN_MSG_EXT != 0 {
+        ExtendedId::new(raw & !CAN_MSG_EXT).map(Id::from)
+    } else {
+        StandardId::new(raw as u16).map(Id::from)
+    }
+}
+
+fn raw_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => id.as_raw() | CAN_MSG_EXT,
+    }
+}
+
+fn error_from_ecc(ecc: u8) -> ErrorFrame {
+    let kind = match ecc >> ECC_TYPE_SHIFT {
+        0 => ErrorKind::Bit,
+        1 => ErrorKind::Form,
+        2 => ErrorKind::Stuff,
+        _ => ErrorKind::Other,
+    };
+    ErrorFrame {
+        kind,
+        direction: if ecc & ECC_RX != 0 {
+            Direction::Rx
+        } else {
+            Direction::Tx
+        },
+        ecc,
+        rx_error_counter: 0,
+        tx_error_counter: 0,
+    }
+}
+
+/// Uses the recorded ECC if there is one, otherwise derives it from the error type.
+fn ecc_from_error(error: &ErrorFrame) -> u8 {
+    if error.ecc != 0 {
+        return error.ecc;
+    }
+    let kind = match error.kind {
+        ErrorKind::Bit => 0,
+        ErrorKind::Form => 1,
+        ErrorKind::Stuff => 2,
+        ErrorKind::Other => 3,
+    };
+    let direction = match error.direction {
+        Direction::Rx => ECC_RX,
+        Direction::Tx => 0,
+    };
+    kind << ECC_TYPE_SHIFT | direction
+}
+
+/// Streaming reader for BLF files.
+///
+/// The header is parsed by [`Reader::new()`]. Reading stops at the first
+/// corrupted object.
+pub struct Reader<R> {
+    reader: R,
+    start_time: Option<SystemTime>,
+    // Uncompressed objects, may end with the start of an object continued in the next container.
+    buffer: Vec<u8>,
+    position: usize,
+    eof: bool,
+    done: bool,
+}
+
+impl<R: Read> Reader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut header = [0; FILE_HEADER_SIZE];
+        reader.read_exact(&mut header[..8])?;
+        if &header[..4] != FILE_SIGNATURE {
+            return Err(Error::Format("missing LOGG signature".to_string()));
+        }
+        let header_size = u32_at(&header, 4) as usize;
+        if header_size < 72 {
+            return Err(Error::Format(format!("header size {}", header_size)));
+        }
+        reader.read_exact(&mut header[8..header_size.min(FILE_HEADER_SIZE)])?;
+        if header_size > FILE_HEADER_SIZE {
+            io::copy(
+                &mut (&mut reader).take((header_size - FILE_HEADER_SIZE) as u64),
+                &mut io::sink(),
+            )?;
+        }
+
+        Ok(Self {
+            reader,
+            start_time: read_systemtime(&header[40..56]),
+            buffer: Vec::new(),
+            position: 0,
+            eof: false,
+            done: false,
+        })
+    }
+
+    /// Absolute time of a record timestamp of zero, from the header.
+    pub fn start_time(&self) -> Option<SystemTime> {
+        self.start_time
+    }
+
+    /// Returns the next complete object from the buffer with its padding removed.
+    fn buffered_object(&mut self) -> Result<Option<(usize, usize)>, Error> {
+        let available = &self.buffer[self.position..];
+        if available.len() < OBJECT_HEADER_BASE_SIZE {
+            return Ok(None);
+        }
+        if &available[..4] != OBJECT_SIGNATURE {
+            return Err(Error::Format("missing LOBJ signature".to_string()));
+        }
+        let size = u32_at(available, 8) as usize;
+        if !(OBJECT_HEADER_BASE_SIZE..=MAX_OBJECT_SIZE).contains(&size) {
+            return Err(Error::Format(format!("object size {}", size)));
+        }
+        // Objects are padded by their size modulo 4, which is what Vector tools do.
+        let padded = size + size % 4;
+        if available.len() < padded && !(self.eof && available.len() >= size) {
+            return Ok(None);
+        }
+        let start = self.position;
+        self.position += padded.min(available.len());
+        Ok(Some((start, start + size)))
+    }
+
+    /// Reads the next object of the file into the buffer. Returns `false` at the end.
+    fn fill(&mut self) -> Result<bool, Error> {
+        if self.position > 0 {
+            self.buffer.drain(..self.position);
+            self.position = 0;
+        }
+
+        let mut header = [0; OBJECT_HEADER_BASE_SIZE];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+            Err(err) => return Err(err.into()),
+        }
+        if &header[..4] != OBJECT_SIGNATURE {
+            return Err(Error::Format("missing LOBJ signature".to_string()));
+        }
+        let size = u32_at(&header, 8) as usize;
+        if !(OBJECT_HEADER_BASE_SIZE..=MAX_OBJECT_SIZE).contains(&size) {
+            return Err(Error::Format(format!("object size {}", size)));
+        }
+        let mut body = vec![0; size - OBJECT_HEADER_BASE_SIZE];
+        self.reader.read_exact(&mut body)?;
+        let mut padding = [0; 4];
+        match self.reader.read_exact(&mut padding[..size % 4]) {
+            // The last object of a file may lack its padding.
+            Err(err) if err.kind() != io::ErrorKind::UnexpectedEof => return Err(err.into()),
+            _ => {}
+        }
+
+        if u32_at(&header, 12) != LOG_CONTAINER {
+            self.buffer.extend_from_slice(&header);
+            self.buffer.extend_from_slice(&body);
+            self.buffer.resize(self.buffer.len() + size % 4, 0);
+            return Ok(true);
+        }
+
+        if body.len() < CONTAINER_HEADER_SIZE - OBJECT_HEADER_BASE_SIZE {
+            return Err(Error::Format("truncated container".to_string()));
+        }
+        let data = &body[CONTAINER_HEADER_SIZE - OBJECT_HEADER_BASE_SIZE..];
+        match u16_at(&body, 0) {
+            NO_COMPRESSION => self.buffer.extend_from_slice(data),
+            ZLIB_DEFLATE => {
+                let expected = u32_at(&body, 8) as usize;
+                if expected > MAX_OBJECT_SIZE {
+                    return Err(Error::Format(format!("container size {}", expected)));
+                }
+                // One byte more than declared tells a mismatch from a complete container.
+                let inflated = ZlibDecoder::new(data)
+                    .take(expected as u64 + 1)
+                    .read_to_end(&mut self.buffer)?;
+                if inflated != expected {
+                    return Err(Error::Format(format!(
+                        "container of {} bytes inflates to {}",
+                        expected, inflated
+                    )));
+                }
+            }
+            method => return Err(Error::Format(format!("compression method {}", method))),
+        }
+        Ok(true)
+    }
+
+    fn next_record(&mut self) -> Result<Option<Record>, Error> {
+        loop {
+            let (start, end) = match self.buffered_object()? {
+                Some(range) => range,
+                // A header without the rest of its object.
+                None if self.eof
+                    && self.buffer.len() - self.position >= OBJECT_HEADER_BASE_SIZE =>
+                {
+                    return Err(Error::Format("truncated object".to_string()))
+                }
+                None if self.eof => return Ok(None),
+                None => {
+                    self.eof = !self.fill()?;
+                    continue;
+                }
+            };
+            if let Some(record) = parse_object(&self.buffer[start..end])? {
+                return Ok(Some(record));
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for Reader<R> {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.next_record().transpose();
+        if !matches!(result, Some(Ok(_))) {
+            self.done = true;
+        }
+        result
+    }
+}
+
+fn parse_object(object: &[u8]) -> Result<Option<Record>, Error> {
+    let header_size = u16_at(object, 4) as usize;
+    let object_type = u32_at(object, 12);
+    if header_size < OBJECT_HEADER_V1_SIZE || object.len() < header_size {
+        return Err(Error::Format(format!("object header size {}", header_size)));
+    }
+    // Version 1 and 2 headers both start with the flags and have the timestamp at offset 24.
+    let flags = u32_at(object, 16);
+    let ticks = u64_at(object, 24);
+    let timestamp = match flags {
+        TIME_TEN_MICS => match ticks.checked_mul(10) {
+            Some(micros) => Duration::from_micros(micros),
+            None => return Err(Error::Format(format!("timestamp {}", ticks))),
+        },
+        TIME_ONE_NANS => Duration::from_nanos(ticks),
+        _ => return Err(Error::Format(format!("timestamp flags {:#x}", flags))),
+    };
+
+    let p = &object[header_size..];
+    let truncated = |size: usize| {
+        if p.len() < size {
+            Err(Error::Format(format!(
+                "truncated object of type {}",
+                object_type
+            )))
+        } else {
+            Ok(())
+        }
+    };
+    let invalid = || Error::Format(format!("invalid object of type {}", object_type));
+
+    let (channel, direction, message) = match object_type {
+        CAN_MESSAGE | CAN_MESSAGE2 => {
+            truncated(16)?;
+            let flags = p[2];
+            let id = make_id(u32_at(p, 4)).ok_or_else(invalid)?;
+            let dlc = p[3].min(8) as usize;
+            let frame = if flags & CAN_MSG_RTR != 0 {
+                Frame::new_remote(id, dlc)
+            } else {
+                Frame::new(id, &p[8..8 + dlc])
+            }
+            .map_err(|_| invalid())?;
+            (
+                u16_at(p, 0),
+                tx_flag(flags & CAN_MSG_TX != 0),
+                Message::Can(frame),
+            )
+        }
+        CAN_FD_MESSAGE => {
+            truncated(84)?;
+            let flags = p[2];
+            let fd_flags = p[13];
+            let id = make_id(u32_at(p, 4)).ok_or_else(invalid)?;
+            let message = if fd_flags & CAN_FD_EDL != 0 {
+                let len = dlc_to_len(p[3]);
+                let mut frame = FdFrame::new(id, &p[20..20 + len]).ok_or_else(invalid)?;
+                frame.set_bit_rate_switch(fd_flags & CAN_FD_BRS != 0);
+                frame.set_error_state_indicator(fd_flags & CAN_FD_ESI != 0);
+                Message::Fd(frame)
+            } else if flags & CAN_MSG_RTR != 0 {
+                Message::Can(Frame::new_remote(id, p[3].min(8) as usize).map_err(|_| invalid())?)
+            } else {
+                let len = p[3].min(8) as usize;
+                Message::Can(Frame::new(id, &p[20..20 + len]).map_err(|_| invalid())?)
+            };
+            (u16_at(p, 0), tx_flag(flags & CAN_MSG_TX != 0), message)
+        }
+        CAN_FD_MESSAGE_64 => {
+            truncated(40)?;
+            let id = make_id(u32_at(p, 4)).ok_or_else(invalid)?;
+            let flags = u32_at(p, 12);
+            let len = p[2] as usize;
+            truncated(40 + len)?;
+            let data = &p[40..40 + len];
+            let message = if flags & CAN_FD_64_EDL != 0 {
+                let mut frame = FdFrame::new(id, data).ok_or_else(invalid)?;
+                frame.set_bit_rate_switch(flags & CAN_FD_64_BRS != 0);
+                frame.set_error_state_indicator(flags & CAN_FD_64_ESI != 0);
+                Message::Fd(frame)
+            } else if flags & CAN_FD_64_RTR != 0 {
+                Message::Can(Frame::new_remote(id, p[1].min(8) as usize).map_err(|_| invalid())?)
+            } else {
+                Message::Can(Frame::new(id, data).map_err(|_| invalid())?)
+            };
+            (p[0] as u16, tx_flag(p[34] != 0), message)
+        }
+        CAN_ERROR_EXT => {
+            truncated(32)?;
+            let error = error_from_ecc(p[8]);
+            (u16_at(p, 0), error.direction, Message::Error(error))
+        }
+        CAN_ERROR | CAN_FD_ERROR_64 => {
+            truncated(2)?;
+            let channel = if object_type == CAN_ERROR {
+                u16_at(p, 0)
+            } else {
+                p[0] as u16
+            };
+            let error = ErrorFrame {
+                kind: ErrorKind::Other,
+                direction: Direction::Rx,
+                ecc: 0,
+                rx_error_counter: 0,
+                tx_error_counter: 0,
+            };
+            (channel, Direction::Rx, Message::Error(error))
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Record {
+        timestamp,
+        channel: channel.min(u8::MAX as u16) as u8,
+        direction,
+        message,
+    }))
+}
+
+fn tx_flag(tx: bool) -> Direction {
+    if tx {
+        Direction::Tx
+    } else {
+        Direction::Rx
+    }
+}
+
+/// Writes records as BLF file with zlib-compressed containers.
+///
+/// The file header is updated with the object count and the time span of the
+/// records by [`Writer::finish()`], which has to be called for a valid file.
+pub struct Writer<W: Write + Seek> {
+    writer: W,
+    start_time: Option<SystemTime>,
+    last_timestamp: Duration,
+    buffer: Vec<u8>,
+    count: u32,
+    file_size: u64,
+    uncompressed_size: u64,
+}
+
+impl<W: Write + Seek> Writer<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        // Placeholder, the header is written by `finish()`.
+        writer.write_all(&[0; FILE_HEADER_SIZE])?;
+        Ok(Self {
+            writer,
+            start_time: None,
+            last_timestamp: Duration::default(),
+            buffer: Vec::new(),
+            count: 0,
+            file_size: FILE_HEADER_SIZE as u64,
+            uncompressed_size: FILE_HEADER_SIZE as u64,
+        })
+    }
+
+    /// Absolute time that corresponds to a record timestamp of zero.
+    ///
+    /// If not set, the first record is assumed to be written right when it
+    /// was captured.
+    pub fn with_start_time(&mut self, start_time: SystemTime) -> &mut Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        if self.start_time.is_none() {
+            self.start_time = Some(
+                SystemTime::now()
+                    .checked_sub(record.timestamp)
+                    .unwrap_or(UNIX_EPOCH),
+            );
+        }
+        self.last_timestamp = self.last_timestamp.max(record.timestamp);
+
+        let channel = record.channel as u16;
+        let mut payload = Vec::with_capacity(84);
+        let object_type = match &record.message {
+            Message::Can(frame) => {
+                let mut flags = 0;
+                if record.direction == Direction::Tx {
+                    flags |= CAN_MSG_TX;
+                }
+                if frame.is_remote_frame() {
+                    flags |= CAN_MSG_RTR;
+                }
+                let mut data = [0; 8];
+                data[..frame.data().len()].copy_from_slice(frame.data());
+                payload.extend_from_slice(&channel.to_le_bytes());
+                payload.extend_from_slice(&[flags, frame.dlc() as u8]);
+                payload.extend_from_slice(&raw_id(frame.id()).to_le_bytes());
+                payload.extend_from_slice(&data);
+                CAN_MESSAGE
+            }
+            Message::Fd(frame) => {
+                let flags = if record.direction == Direction::Tx {
+                    CAN_MSG_TX
+                } else {
+                    0
+                };
+                let mut fd_flags = CAN_FD_EDL;
+                if frame.bit_rate_switch() {
+                    fd_flags |= CAN_FD_BRS;
+                }
+                if frame.error_state_indicator() {
+                    fd_flags |= CAN_FD_ESI;
+                }
+                let mut data = [0; 64];
+                data[..frame.data().len()].copy_from_slice(frame.data());
+                payload.extend_from_slice(&channel.to_le_bytes());
+                payload.extend_from_slice(&[flags, frame.dlc() as u8]);
+                payload.extend_from_slice(&raw_id(frame.id()).to_le_bytes());
+                // Frame length in ns and bit count are unknown.
+                payload.extend_from_slice(&[0; 5]);
+                payload.extend_from_slice(&[fd_flags, frame.data().len() as u8]);
+                payload.extend_from_slice(&[0; 5]);
+                payload.extend_from_slice(&data);
+                CAN_FD_MESSAGE
+            }
+            Message::Error(error) => {
+                payload.extend_from_slice(&channel.to_le_bytes());
+                // Length, flags, ECC, position, DLC, reserved, frame length, ID,
+                // extended flags, reserved and data.
+                payload.extend_from_slice(&0u16.to_le_bytes());
+                payload.extend_from_slice(&0u32.to_le_bytes());
+                payload.extend_from_slice(&[ecc_from_error(error), 0, 0, 0]);
+                payload.extend_from_slice(&[0; 20]);
+                CAN_ERROR_EXT
+            }
+        };
+
+        let size = (OBJECT_HEADER_V1_SIZE + payload.len()) as u32;
+        let timestamp = record.timestamp.as_nanos().min(u64::MAX as u128) as u64;
+        self.buffer.extend_from_slice(OBJECT_SIGNATURE);
+        self.buffer
+            .extend_from_slice(&(OBJECT_HEADER_V1_SIZE as u16).to_le_bytes());
+        self.buffer.extend_from_slice(&1u16.to_le_bytes());
+        self.buffer.extend_from_slice(&size.to_le_bytes());
+        self.buffer.extend_from_slice(&object_type.to_le_bytes());
+        self.buffer.extend_from_slice(&TIME_ONE_NANS.to_le_bytes());
+        // Client index and object version.
+        self.buffer.extend_from_slice(&[0; 4]);
+        self.buffer.extend_from_slice(&timestamp.to_le_bytes());
+        self.buffer.extend_from_slice(&payload);
+        self.buffer.resize(self.buffer.len() + size as usize % 4, 0);
+        self.count += 1;
+
+        if self.buffer.len() >= CONTAINER_SIZE {
+            self.write_container()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining records and the file header and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_container()?;
+
+        let start_time = self.start_time.unwrap_or_else(SystemTime::now);
+        let mut header = Vec::with_capacity(FILE_HEADER_SIZE);
+        header.extend_from_slice(FILE_SIGNATURE);
+        header.extend_from_slice(&(FILE_HEADER_SIZE as u32).to_le_bytes());
+        // Application ID and version, BL library version.
+        header.extend_from_slice(&[0, 0, 0, 0, 2, 6, 8, 1]);
+        header.extend_from_slice(&self.file_size.to_le_bytes());
+        header.extend_from_slice(&self.uncompressed_size.to_le_bytes());
+        header.extend_from_slice(&self.count.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        write_systemtime(&mut header, start_time);
+        write_systemtime(&mut header, start_time + self.last_timestamp);
+        header.resize(FILE_HEADER_SIZE, 0);
+
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_container(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&self.buffer)?;
+        let data = encoder.finish()?;
+
+        let size = (CONTAINER_HEADER_SIZE + data.len()) as u32;
+        let mut header = Vec::with_capacity(CONTAINER_HEADER_SIZE);
+        header.extend_from_slice(OBJECT_SIGNATURE);
+        header.extend_from_slice(&(OBJECT_HEADER_BASE_SIZE as u16).to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&size.to_le_bytes());
+        header.extend_from_slice(&LOG_CONTAINER.to_le_bytes());
+        header.extend_from_slice(&ZLIB_DEFLATE.to_le_bytes());
+        header.extend_from_slice(&[0; 6]);
+        header.extend_from_slice(&(self.buffer.len() as u32).to_le_bytes());
+        header.extend_from_slice(&[0; 4]);
+
+        self.writer.write_all(&header)?;
+        self.writer.write_all(&data)?;
+        let padding = size as usize % 4;
+        self.writer.write_all(&[0; 4][..padding])?;
+
+        self.file_size += (size as usize + padding) as u64;
+        self.uncompressed_size += (CONTAINER_HEADER_SIZE + self.buffer.len()) as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Capture for Writer<W> {
+    fn capture(&mut self, record: &Record) -> io::Result<()> {
+        self.write(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn std_id(id: u16) -> Id {
+        StandardId::new(id).unwrap().into()
+    }
+
+    fn records(count: usize) -> Vec<Record> {
+        let mut fd = FdFrame::new(ExtendedId::new(0x1ABC_DEF0).unwrap(), &[0x55; 48]).unwrap();
+        fd.set_error_state_indicator(true);
+        let messages = [
+            Message::Can(Frame::new(std_id(0x123), &[1, 2, 3]).unwrap()),
+            Message::Can(Frame::new_remote(std_id(0x7FF), 8).unwrap()),
+            Message::Fd(fd),
+            Message::Error(error_from_ecc(0x80 | ECC_RX | 0x08)),
+        ];
+        (0..count)
+            .map(|i| Record {
+                timestamp: Duration::from_micros(i as u64 * 1234),
+                channel: (i % 2) as u8 + 1,
+                direction: if i % 3 == 0 {
+                    Direction::Tx
+                } else {
+                    Direction::Rx
+                },
+                message: messages[i % messages.len()],
+            })
+            .map(|mut record| {
+                if let Message::Error(error) = record.message {
+                    record.direction = error.direction;
+                }
+                record
+            })
+            .collect()
+    }
+
+    fn round_trip(records: &[Record]) {
+        let start = UNIX_EPOCH + Duration::new(1_600_338_112, 123_000_000);
+        let mut writer = Writer::new(Cursor::new(Vec::new())).unwrap();
+        writer.with_start_time(start);
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        let blf = writer.finish().unwrap().into_inner();
+        assert_eq!(u32_at(&blf, 32), records.len() as u32);
+        assert_eq!(u64_at(&blf, 16), blf.len() as u64);
+
+        let reader = Reader::new(blf.as_slice()).unwrap();
+        assert_eq!(reader.start_time(), Some(start));
+        let read: Vec<_> = reader.map(Result::unwrap).collect();
+        assert_eq!(read, records);
+    }
+
+    #[test]
+    fn single_container() {
+        round_trip(&records(8));
+    }
+
+    #[test]
+    fn objects_spanning_containers() {
+        // The FD objects are larger than 100 bytes, some end up split.
+        round_trip(&records(5000));
+    }
+
+    #[test]
+    fn uncompressed_objects() {
+        let mut blf = vec![0; FILE_HEADER_SIZE];
+        blf[..4].copy_from_slice(FILE_SIGNATURE);
+        blf[4..8].copy_from_slice(&(FILE_HEADER_SIZE as u32).to_le_bytes());
+        // A CAN_MESSAGE2 object with a version 2 header and timestamps in 10us.
+        let mut object = Vec::new();
+        object.extend_from_slice(OBJECT_SIGNATURE);
+        object.extend_from_slice(&40u16.to_le_bytes());
+        object.extend_from_slice(&2u16.to_le_bytes());
+        object.extend_from_slice(&(40u32 + 24).to_le_bytes());
+        object.extend_from_slice(&CAN_MESSAGE2.to_le_bytes());
+        object.extend_from_slice(&TIME_TEN_MICS.to_le_bytes());
+        object.extend_from_slice(&[0; 4]);
+        object.extend_from_slice(&100u64.to_le_bytes());
+        object.extend_from_slice(&[0; 8]);
+        object.extend_from_slice(&3u16.to_le_bytes());
+        object.extend_from_slice(&[CAN_MSG_TX, 2]);
+        object.extend_from_slice(&(0x100 | CAN_MSG_EXT).to_le_bytes());
+        object.extend_from_slice(&[0xAA, 0xBB, 0, 0, 0, 0, 0, 0]);
+        object.extend_from_slice(&[0; 8]);
+        blf.extend_from_slice(&object);
+
+        let read: Vec<_> = Reader::new(blf.as_slice()).unwrap().collect();
+        let record = read[0].as_ref().unwrap();
+        assert_eq!(record.timestamp, Duration::from_millis(1));
+        assert_eq!(record.channel, 3);
+        assert_eq!(record.direction, Direction::Tx);
+        assert_eq!(
+            record.message,
+            Message::Can(Frame::new(ExtendedId::new(0x100).unwrap(), &[0xAA, 0xBB]).unwrap())
+        );
+        assert_eq!(read.len(), 1);
+
+        let mut overflow = blf.clone();
+        overflow[FILE_HEADER_SIZE + 24..][..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let read: Vec<_> = Reader::new(overflow.as_slice()).unwrap().collect();
+        assert!(matches!(read[..], [Err(Error::Format(_))]));
+
+        let mut oversized = blf;
+        oversized[FILE_HEADER_SIZE + 8..][..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let read: Vec<_> = Reader::new(oversized.as_slice()).unwrap().collect();
+        assert!(matches!(read[..], [Err(Error::Format(_))]));
+    }
+
+    #[test]
+    fn corrupt_containers() {
+        let mut writer = Writer::new(Cursor::new(Vec::new())).unwrap();
+        for record in records(8) {
+            writer.write(&record).unwrap();
+        }
+        let blf = writer.finish().unwrap().into_inner();
+        // The uncompressed size in the container header.
+        let size = FILE_HEADER_SIZE + 24;
+        for declared in [0, u32_at(&blf, size) - 1, u32_at(&blf, size) + 1, u32::MAX] {
+            let mut corrupt = blf.clone();
+            corrupt[size..size + 4].copy_from_slice(&declared.to_le_bytes());
+            let read: Vec<_> = Reader::new(corrupt.as_slice()).unwrap().collect();
+            assert!(matches!(read[..], [Err(Error::Format(_))]));
+        }
+
+        // Objects in an uncompressed container that are too large or cut off.
+        for object_size in [u32::MAX, 100] {
+            let mut blf = blf[..FILE_HEADER_SIZE].to_vec();
+            blf.extend_from_slice(OBJECT_SIGNATURE);
+            blf.extend_from_slice(&(OBJECT_HEADER_BASE_SIZE as u16).to_le_bytes());
+            blf.extend_from_slice(&1u16.to_le_bytes());
+            blf.extend_from_slice(&(CONTAINER_HEADER_SIZE as u32 + 16).to_le_bytes());
+            blf.extend_from_slice(&LOG_CONTAINER.to_le_bytes());
+            blf.extend_from_slice(&NO_COMPRESSION.to_le_bytes());
+            blf.extend_from_slice(&[0; 6]);
+            blf.extend_from_slice(&16u32.to_le_bytes());
+            blf.extend_from_slice(&[0; 4]);
+            blf.extend_from_slice(OBJECT_SIGNATURE);
+            blf.extend_from_slice(&32u16.to_le_bytes());
+            blf.extend_from_slice(&1u16.to_le_bytes());
+            blf.extend_from_slice(&object_size.to_le_bytes());
+            blf.extend_from_slice(&CAN_MESSAGE.to_le_bytes());
+            let read: Vec<_> = Reader::new(blf.as_slice()).unwrap().collect();
+            assert!(matches!(read[..], [Err(Error::Format(_))]));
+        }
+    }
+
+    #[test]
+    fn not_a_blf_file() {
+        assert!(matches!(
+            Reader::new(&b"LOGX\x90\0\0\0"[..]),
+            Err(Error::Format(_))
+        ));
+    }
+}
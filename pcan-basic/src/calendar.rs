@@ -0,0 +1,120 @@
+//! Conversion between `SystemTime` and calendar dates for log file headers.
+//!
+//! Log files store wall clock dates without a time zone. They are treated as UTC.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+pub(crate) const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    pub day: u8,
+    /// 0 is Sunday.
+    pub weekday: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanos: u32,
+}
+
+impl DateTime {
+    pub fn from_system_time(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let days = (secs / 86400) as i64;
+        let (year, month, day) = civil_from_days(days);
+        let seconds = secs % 86400;
+
+        Self {
+            year: year as u16,
+            month,
+            day,
+            // 1970-01-01 was a Thursday.
+            weekday: ((days + 4) % 7) as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+            nanos: since_epoch.subsec_nanos(),
+        }
+    }
+
+    /// Returns `None` for invalid dates or dates before 1970.
+    pub fn to_system_time(self) -> Option<SystemTime> {
+        if !(1..=12).contains(&self.month)
+            || !(1..=31).contains(&self.day)
+            || self.hour > 23
+            || self.minute > 59
+            || self.second > 60
+            || self.nanos >= 1_000_000_000
+        {
+            return None;
+        }
+
+        let days = days_from_civil(self.year as i64, self.month, self.day);
+        let secs =
+            days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64;
+        if secs < 0 {
+            return None;
+        }
+        Some(UNIX_EPOCH + Duration::new(secs as u64, self.nanos))
+    }
+}
+
+// Algorithms from http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u8, day: u8) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let time = UNIX_EPOCH + Duration::new(1_600_338_112, 123_000_000);
+        let date = DateTime::from_system_time(time);
+        assert_eq!(
+            date,
+            DateTime {
+                year: 2020,
+                month: 9,
+                day: 17,
+                weekday: 4,
+                hour: 10,
+                minute: 21,
+                second: 52,
+                nanos: 123_000_000,
+            }
+        );
+        assert_eq!(date.to_system_time(), Some(time));
+
+        let leap = DateTime::from_system_time(UNIX_EPOCH + Duration::from_secs(951_782_400));
+        assert_eq!((leap.year, leap.month, leap.day), (2000, 2, 29));
+    }
+}
@@ -0,0 +1,690 @@
+//! Log files of the SocketCAN `candump` tool, as written by `candump -l`.
+//!
+//! Every line holds one frame in the compact `ID#DATA` syntax of `cansend`,
+//! preceded by the absolute time and the interface name:
+//!
+//! ```text
+//! (1600000000.123456) can0 123#DEADBEEF
+//! (1600000000.124001) can0 1F334455#R
+//! (1600000000.130500) can1 123##1112233
+//! ```
+//!
+//! The same syntax is available for single frames through the [`FromStr`] and
+//! [`Display`](fmt::Display) implementations of [`Frame`] and [`FdFrame`].
+//! Interfaces are numbered as channels in order of their first appearance.
+
+use std::{
+    fmt::{self, Write as _},
+    io::{self, BufRead, Write},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use embedded_can::Frame as _;
+
+use crate::{
+    Capture, Direction, ErrorFrame, ErrorKind, ExtendedId, FdFrame, Frame, Id, Message, Record,
+    StandardId,
+};
+
+/// Error returned when parsing a frame from the `ID#DATA` syntax fails.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseFrameError(String);
+
+impl fmt::Display for ParseFrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ParseFrameError {}
+
+// The SocketCAN error frame layout from `linux/can/error.h`.
+const CAN_ERR_FLAG: u32 = 0x2000_0000;
+const CAN_ERR_PROT: u32 = 0x0000_0008;
+const CAN_ERR_CNT: u32 = 0x0000_0200;
+const CAN_ERR_PROT_BIT: u8 = 0x01;
+const CAN_ERR_PROT_FORM: u8 = 0x02;
+const CAN_ERR_PROT_STUFF: u8 = 0x04;
+const CAN_ERR_PROT_BIT0: u8 = 0x08;
+const CAN_ERR_PROT_BIT1: u8 = 0x10;
+const CAN_ERR_PROT_TX: u8 = 0x80;
+
+// Flags nibble of CAN FD frames after `##`.
+const CANFD_BRS: u8 = 0x01;
+const CANFD_ESI: u8 = 0x02;
+
+/// Frame of any kind as written after the interface name.
+enum Parsed {
+    Can(Frame),
+    Fd(FdFrame),
+    Error(ErrorFrame),
+}
+
+fn parse_id(s: &str) -> Result<(u32, bool), ParseFrameError> {
+    let raw = u32::from_str_radix(s, 16)
+        .map_err(|_| ParseFrameError(format!("invalid identifier {}", s)))?;
+    match s.len() {
+        3 => Ok((raw, false)),
+        8 => Ok((raw, true)),
+        _ => Err(ParseFrameError(format!(
+            "identifier {} must have 3 or 8 hex digits",
+            s
+        ))),
+    }
+}
+
+fn make_id(raw: u32, extended: bool) -> Result<Id, ParseFrameError> {
+    let id = if extended {
+        ExtendedId::new(raw).map(Id::from)
+    } else {
+        StandardId::new(raw as u16).map(Id::from)
+    };
+    id.ok_or_else(|| ParseFrameError(format!("identifier {:X} out of range", raw)))
+}
+
+fn parse_data(s: &str, max: usize) -> Result<Vec<u8>, ParseFrameError> {
+    let digits: Vec<u8> = s.bytes().filter(|&b| b != b'.').collect();
+    if !digits.len().is_multiple_of(2) || digits.len() / 2 > max {
+        return Err(ParseFrameError(format!("invalid data {}", s)));
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                .ok_or_else(|| ParseFrameError(format!("invalid data {}", s)))
+        })
+        .collect()
+}
+
+fn parse(s: &str) -> Result<Parsed, ParseFrameError> {
+    let (id, rest) = s
+        .split_once('#')
+        .ok_or_else(|| ParseFrameError(format!("missing # in {}", s)))?;
+    let (raw, extended) = parse_id(id)?;
+
+    if extended && raw & CAN_ERR_FLAG != 0 {
+        let data = parse_data(rest, 8)?;
+        return Ok(Parsed::Error(parse_error_frame(raw, &data)));
+    }
+    let id = make_id(raw, extended)?;
+
+    if let Some(rest) = rest.strip_prefix('#') {
+        let flags = rest
+            .get(..1)
+            .and_then(|flags| u8::from_str_radix(flags, 16).ok())
+            .ok_or_else(|| ParseFrameError(format!("missing CAN FD flags in {}", s)))?;
+        let data = parse_data(&rest[1..], 64)?;
+        let mut frame = FdFrame::new(id, &data)
+            .ok_or_else(|| ParseFrameError(format!("invalid CAN FD data length {}", data.len())))?;
+        frame.set_bit_rate_switch(flags & CANFD_BRS != 0);
+        frame.set_error_state_indicator(flags & CANFD_ESI != 0);
+        return Ok(Parsed::Fd(frame));
+    }
+
+    if let Some(dlc) = rest.strip_prefix('R') {
+        let dlc = match dlc {
+            "" => 0,
+            dlc => dlc
+                .parse()
+                .map_err(|_| ParseFrameError(format!("invalid remote frame length {}", dlc)))?,
+        };
+        return Frame::new_remote(id, dlc)
+            .map(Parsed::Can)
+            .map_err(|_| ParseFrameError(format!("invalid remote frame length {}", dlc)));
+    }
+
+    let data = parse_data(rest, 8)?;
+    Ok(Parsed::Can(Frame::new(id, &data).unwrap()))
+}
+
+fn parse_error_frame(raw: u32, data: &[u8]) -> ErrorFrame {
+    let byte = |i: usize| data.get(i).copied().unwrap_or_default();
+    let prot = if raw & CAN_ERR_PROT != 0 { byte(2) } else { 0 };
+    let kind = if prot & (CAN_ERR_PROT_BIT | CAN_ERR_PROT_BIT0 | CAN_ERR_PROT_BIT1) != 0 {
+        ErrorKind::Bit
+    } else if prot & CAN_ERR_PROT_FORM != 0 {
+        ErrorKind::Form
+    } else if prot & CAN_ERR_PROT_STUFF != 0 {
+        ErrorKind::Stuff
+    } else {
+        ErrorKind::Other
+    };
+    let (tx_error_counter, rx_error_counter) = if raw & CAN_ERR_CNT != 0 {
+        (byte(6), byte(7))
+    } else {
+        (0, 0)
+    };
+
+    ErrorFrame {
+        kind,
+        direction: if prot & CAN_ERR_PROT_TX != 0 {
+            Direction::Tx
+        } else {
+            Direction::Rx
+        },
+        ecc: 0,
+        rx_error_counter,
+        tx_error_counter,
+    }
+}
+
+/// SocketCAN ID with error flags and data of an error frame.
+pub(crate) fn socketcan_error_frame(error: &ErrorFrame) -> (u32, [u8; 8]) {
+    let mut prot = match error.kind {
+        ErrorKind::Bit => CAN_ERR_PROT_BIT,
+        ErrorKind::Form => CAN_ERR_PROT_FORM,
+        ErrorKind::Stuff => CAN_ERR_PROT_STUFF,
+        ErrorKind::Other => 0,
+    };
+    if error.direction == Direction::Tx {
+        prot |= CAN_ERR_PROT_TX;
+    }
+    let data = [
+        0,
+        0,
+        prot,
+        0,
+        0,
+        0,
+        error.tx_error_counter,
+        error.rx_error_counter,
+    ];
+    (CAN_ERR_FLAG | CAN_ERR_PROT | CAN_ERR_CNT, data)
+}
+
+fn format_error_frame(error: &ErrorFrame) -> String {
+    let (id, data) = socketcan_error_frame(error);
+    format!("{:08X}#{}", id, format_data(&data))
+}
+
+fn format_id(id: Id) -> String {
+    match id {
+        Id::Standard(id) => format!("{:03X}", id.as_raw()),
+        Id::Extended(id) => format!("{:08X}", id.as_raw()),
+    }
+}
+
+fn format_data(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 2);
+    for byte in data {
+        write!(s, "{:02X}", byte).unwrap();
+    }
+    s
+}
+
+/// Parses `123#DEADBEEF`, `12345678#11.22` or `123#R` and `123#R4` for remote frames.
+impl FromStr for Frame {
+    type Err = ParseFrameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse(s.trim())? {
+            Parsed::Can(frame) => Ok(frame),
+            Parsed::Fd(_) => Err(ParseFrameError(format!("{} is a CAN FD frame", s))),
+            Parsed::Error(_) => Err(ParseFrameError(format!("{} is an error frame", s))),
+        }
+    }
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#", format_id(self.id()))?;
+        if self.is_remote_frame() {
+            f.write_str("R")?;
+            if self.dlc() > 0 {
+                write!(f, "{}", self.dlc())?;
+            }
+            Ok(())
+        } else {
+            f.write_str(&format_data(self.data()))
+        }
+    }
+}
+
+/// Parses `123##1DEADBEEF`, the digit after `##` holds the flags
+/// (1: bit rate switch, 2: error state indicator).
+impl FromStr for FdFrame {
+    type Err = ParseFrameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse(s.trim())? {
+            Parsed::Fd(frame) => Ok(frame),
+            _ => Err(ParseFrameError(format!("{} is not a CAN FD frame", s))),
+        }
+    }
+}
+
+impl fmt::Display for FdFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut flags = 0;
+        if self.bit_rate_switch() {
+            flags |= CANFD_BRS;
+        }
+        if self.error_state_indicator() {
+            flags |= CANFD_ESI;
+        }
+        write!(
+            f,
+            "{}##{:X}{}",
+            format_id(self.id()),
+            flags,
+            format_data(self.data())
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A line of the file could not be parsed.
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Parse { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Streaming reader for `candump` log files.
+///
+/// Record timestamps are relative to the first line of the file, its absolute
+/// time is available from [`Reader::start_time()`]. Lines may end with `R` or
+/// `T` for the direction, without it frames are assumed to be received.
+pub struct Reader<R> {
+    reader: R,
+    line: String,
+    line_number: usize,
+    start: Option<Duration>,
+    interfaces: Vec<String>,
+    // The first line is parsed by `new()` to learn the start time.
+    pending: Option<Result<Record, Error>>,
+    done: bool,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(reader: R) -> Result<Self, Error> {
+        let mut this = Self {
+            reader,
+            line: String::new(),
+            line_number: 0,
+            start: None,
+            interfaces: Vec::new(),
+            pending: None,
+            done: false,
+        };
+        this.pending = this.read_record();
+        if let Some(Err(Error::Io(_))) = this.pending {
+            return Err(this.pending.take().unwrap().unwrap_err());
+        }
+        Ok(this)
+    }
+
+    /// Absolute time of the first record.
+    pub fn start_time(&self) -> Option<SystemTime> {
+        self.start.map(|start| UNIX_EPOCH + start)
+    }
+
+    /// Names of the interfaces seen so far, the channel of a record is the
+    /// index into this list plus one.
+    pub fn interfaces(&self) -> &[String] {
+        &self.interfaces
+    }
+
+    fn read_record(&mut self) -> Option<Result<Record, Error>> {
+        while !self.done {
+            self.line.clear();
+            match self.reader.read_line(&mut self.line) {
+                Ok(0) => self.done = true,
+                Ok(_) => {}
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err.into()));
+                }
+            }
+            self.line_number += 1;
+
+            if self.done || self.line.trim().is_empty() {
+                continue;
+            }
+            return Some(self.parse_line().map_err(|message| Error::Parse {
+                line: self.line_number,
+                message,
+            }));
+        }
+        None
+    }
+
+    fn parse_line(&mut self) -> Result<Record, String> {
+        let mut tokens = self.line.split_whitespace();
+        let time = tokens.next().unwrap_or_default();
+        let absolute = time
+            .strip_prefix('(')
+            .and_then(|t| t.strip_suffix(')'))
+            .and_then(parse_time)
+            .ok_or_else(|| format!("invalid timestamp {}", time))?;
+        let interface = tokens.next().ok_or("missing interface name")?;
+        let frame = tokens.next().ok_or("missing frame")?;
+        let direction = match tokens.next() {
+            None | Some("R") => Direction::Rx,
+            Some("T") => Direction::Tx,
+            Some(token) => return Err(format!("unexpected {}", token)),
+        };
+
+        let message = match parse(frame).map_err(|err| err.0)? {
+            Parsed::Can(frame) => Message::Can(frame),
+            Parsed::Fd(frame) => Message::Fd(frame),
+            Parsed::Error(error) => Message::Error(error),
+        };
+        let channel = match self.interfaces.iter().position(|i| i == interface) {
+            Some(index) => index + 1,
+            None => {
+                self.interfaces.push(interface.to_string());
+                self.interfaces.len()
+            }
+        };
+        let start = *self.start.get_or_insert(absolute);
+
+        Ok(Record {
+            timestamp: absolute.checked_sub(start).unwrap_or_default(),
+            channel: channel.min(u8::MAX as usize) as u8,
+            direction,
+            message,
+        })
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pending.take().or_else(|| self.read_record())
+    }
+}
+
+/// Parses seconds with up to 9 fractional digits without rounding errors.
+fn parse_time(s: &str) -> Option<Duration> {
+    let (secs, fraction) = s.split_once('.').unwrap_or((s, ""));
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos = format!("{:0<9}", fraction).parse().ok()?;
+    Some(Duration::new(secs.parse().ok()?, nanos))
+}
+
+/// Writes records as `candump` log file.
+///
+/// Channels are written as interfaces `can0`, `can1`, ... unless named with
+/// [`Writer::with_interface()`].
+pub struct Writer<W: Write> {
+    writer: W,
+    start_time: Option<SystemTime>,
+    interfaces: Vec<(u8, String)>,
+    directions: bool,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            start_time: None,
+            interfaces: Vec::new(),
+            directions: false,
+        }
+    }
+
+    /// Absolute time that corresponds to a record timestamp of zero.
+    ///
+    /// If not set, the first record is assumed to be written right when it
+    /// was captured.
+    pub fn with_start_time(&mut self, start_time: SystemTime) -> &mut Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    pub fn with_interface(&mut self, channel: u8, name: impl Into<String>) -> &mut Self {
+        self.interfaces.retain(|(c, _)| *c != channel);
+        self.interfaces.push((channel, name.into()));
+        self
+    }
+
+    /// Appends `R` or `T` to every line like `candump -x`.
+    /// Older tools do not understand this.
+    pub fn with_directions(&mut self, directions: bool) -> &mut Self {
+        self.directions = directions;
+        self
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let start_time = *self.start_time.get_or_insert_with(|| {
+            SystemTime::now()
+                .checked_sub(record.timestamp)
+                .unwrap_or(UNIX_EPOCH)
+        });
+        let time = (start_time + record.timestamp)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let frame = match &record.message {
+            Message::Can(frame) => frame.to_string(),
+            Message::Fd(frame) => frame.to_string(),
+            Message::Error(error) => format_error_frame(error),
+        };
+        write!(
+            self.writer,
+            "({}.{:06}) ",
+            time.as_secs(),
+            time.subsec_micros()
+        )?;
+        match self.interfaces.iter().find(|(c, _)| *c == record.channel) {
+            Some((_, name)) => write!(self.writer, "{} {}", name, frame)?,
+            None => write!(
+                self.writer,
+                "can{} {}",
+                record.channel.saturating_sub(1),
+                frame
+            )?,
+        }
+        if self.directions {
+            let direction = match record.direction {
+                Direction::Rx => "R",
+                Direction::Tx => "T",
+            };
+            write!(self.writer, " {}", direction)?;
+        }
+        writeln!(self.writer)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Capture for Writer<W> {
+    fn capture(&mut self, record: &Record) -> io::Result<()> {
+        self.write(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn std_id(id: u16) -> Id {
+        StandardId::new(id).unwrap().into()
+    }
+
+    #[test]
+    fn frame_syntax() {
+        let frame: Frame = "123#DEADBEEF".parse().unwrap();
+        assert_eq!(
+            frame,
+            Frame::new(std_id(0x123), &[0xDE, 0xAD, 0xBE, 0xEF]).unwrap()
+        );
+        assert_eq!(frame.to_string(), "123#DEADBEEF");
+
+        let frame: Frame = "1F334455#11.22.33".parse().unwrap();
+        assert!(frame.is_extended());
+        assert_eq!(frame.to_string(), "1F334455#112233");
+
+        let frame: Frame = "00000123#".parse().unwrap();
+        assert!(frame.is_extended());
+        assert!(frame.data().is_empty());
+
+        let frame: Frame = "7FF#R".parse().unwrap();
+        assert!(frame.is_remote_frame());
+        assert_eq!(frame.to_string(), "7FF#R");
+        assert_eq!("7FF#R3".parse::<Frame>().unwrap().dlc(), 3);
+
+        let frame: FdFrame = "123##3".parse().unwrap();
+        assert!(frame.bit_rate_switch() && frame.error_state_indicator());
+        let frame: FdFrame = "123##0000102030405060708090A0B".parse().unwrap();
+        assert!(!frame.bit_rate_switch());
+        assert_eq!(frame.data().len(), 12);
+        assert_eq!(frame.to_string(), "123##0000102030405060708090A0B");
+
+        for invalid in &[
+            "",
+            "123",
+            "12#00",
+            "800#",
+            "123#0",
+            "123#000102030405060708",
+            "123#XY",
+            "123#R9",
+            "123##",
+            "123##1AABBCCDDEEFF001122",
+            "20000080#0000000000000000",
+        ] {
+            assert!(invalid.parse::<Frame>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn read_log() {
+        let log = "(1600000000.123456) can0 123#DEADBEEF
+(1600000000.124001) vcan1 1F334455#R
+(1600000000.130500) can0 123##1112233
+(1600000000.200000) can0 2000020C#0000040000000A80 T
+
+(1600000001.000000) vcan1 7FF#01 T
+";
+        let reader = Reader::new(log.as_bytes()).unwrap();
+        assert_eq!(
+            reader.start_time(),
+            Some(UNIX_EPOCH + Duration::new(1_600_000_000, 123_456_000))
+        );
+        let records: Vec<_> = reader.map(Result::unwrap).collect();
+
+        assert_eq!(records.len(), 5);
+        assert_eq!(records[0].timestamp, Duration::default());
+        assert_eq!(records[1].timestamp, Duration::from_micros(545));
+        assert_eq!(records[1].channel, 2);
+        assert_eq!(
+            records[2].message,
+            Message::Fd(FdFrame::new(std_id(0x123), &[0x11, 0x22, 0x33]).unwrap())
+        );
+        assert_eq!(
+            records[3].message,
+            Message::Error(ErrorFrame {
+                kind: ErrorKind::Stuff,
+                direction: Direction::Rx,
+                ecc: 0,
+                rx_error_counter: 128,
+                tx_error_counter: 10,
+            })
+        );
+        assert_eq!(records[3].direction, Direction::Tx);
+        assert_eq!(records[4].timestamp, Duration::from_micros(876_544));
+        assert_eq!(records[4].channel, 2);
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let log = "(1600000000.1) can0 123#00\nbogus\n(1.0) can0 123#0\n(2.0) can0 123#00 X\n";
+        let results: Vec<_> = Reader::new(log.as_bytes()).unwrap().collect();
+        assert!(results[0].is_ok());
+        for (result, line) in results[1..].iter().zip(2..) {
+            match result {
+                Err(Error::Parse { line: l, .. }) => assert_eq!(*l, line),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let start = UNIX_EPOCH + Duration::new(1_600_000_000, 0);
+        let records = vec![
+            Record {
+                timestamp: Duration::from_micros(10),
+                channel: 1,
+                direction: Direction::Rx,
+                message: Message::Can(Frame::new(std_id(0x100), &[1, 2, 3]).unwrap()),
+            },
+            Record {
+                timestamp: Duration::from_micros(2_000_010),
+                channel: 2,
+                direction: Direction::Tx,
+                message: Message::Fd(FdFrame::new(std_id(0x200), &[0; 16]).unwrap()),
+            },
+            Record {
+                timestamp: Duration::from_micros(2_500_010),
+                channel: 1,
+                direction: Direction::Tx,
+                message: Message::Error(ErrorFrame {
+                    kind: ErrorKind::Bit,
+                    direction: Direction::Tx,
+                    ecc: 0,
+                    rx_error_counter: 0,
+                    tx_error_counter: 96,
+                }),
+            },
+        ];
+
+        let mut writer = Writer::new(Vec::new());
+        writer
+            .with_start_time(start)
+            .with_interface(2, "vcan7")
+            .with_directions(true);
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let log = String::from_utf8(writer.finish().unwrap()).unwrap();
+        assert!(log.starts_with("(1600000000.000010) can0 100#010203 R\n"));
+
+        let reader = Reader::new(log.as_bytes()).unwrap();
+        assert_eq!(reader.start_time(), Some(start + Duration::from_micros(10)));
+        let read: Vec<_> = reader.map(Result::unwrap).collect();
+        for (read, written) in read.iter().zip(&records) {
+            assert_eq!(read.timestamp, written.timestamp - records[0].timestamp);
+            assert_eq!(read.channel, written.channel);
+            assert_eq!(read.direction, written.direction);
+            assert_eq!(read.message, written.message);
+        }
+    }
+}
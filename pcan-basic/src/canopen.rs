@@ -0,0 +1,44 @@
+//! CANopen (CiA 301) protocols.
+//!
+//! Node IDs are in the range 1 to 127. COB-IDs follow the predefined
+//! connection set, a function code from [`cob`] plus the node ID.
+
+pub mod device;
+pub mod lss;
+pub mod nmt;
+pub mod od;
+pub mod pdo;
+pub mod sdo;
+
+use crate::StandardId;
+
+/// Function codes of the predefined connection set.
+pub mod cob {
+    pub const NMT: u16 = 0x000;
+    pub const SYNC: u16 = 0x080;
+    pub const EMCY: u16 = 0x080;
+    pub const TIME: u16 = 0x100;
+    pub const TPDO1: u16 = 0x180;
+    pub const RPDO1: u16 = 0x200;
+    pub const TPDO2: u16 = 0x280;
+    pub const RPDO2: u16 = 0x300;
+    pub const TPDO3: u16 = 0x380;
+    pub const RPDO3: u16 = 0x400;
+    pub const TPDO4: u16 = 0x480;
+    pub const RPDO4: u16 = 0x500;
+    /// SDO responses from server to client.
+    pub const SDO_TX: u16 = 0x580;
+    /// SDO requests from client to server.
+    pub const SDO_RX: u16 = 0x600;
+    pub const HEARTBEAT: u16 = 0x700;
+}
+
+/// COB-ID of a function for a node.
+///
+/// # Panics
+///
+/// If `node` is not in the range 1 to 127.
+pub fn cob_id(function: u16, node: u8) -> StandardId {
+    assert!((1..=127).contains(&node), "invalid node ID {}", node);
+    StandardId::new(function + node as u16).unwrap()
+}
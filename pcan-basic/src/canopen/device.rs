@@ -0,0 +1,1201 @@
+//! CANopen device with an object dictionary, for rest-bus simulation.
+//!
+//! [`Device`] implements the slave side of CiA 301 without doing any I/O: an
+//! SDO server with expedited and segmented transfers, the NMT state machine
+//! with heartbeat producer and node guarding, TPDOs triggered by events, timers
+//! and SYNC, RPDO reception and emergency messages. Communication parameters
+//! are taken from the object dictionary and can be changed by SDO.
+//!
+//! [`Runner`] drives a device over a CAN interface. A device is also a
+//! [`Node`] of a simulated [`Bus`](crate::sim::Bus), it boots when it receives
+//! the first frame.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use pcan_basic::{
+//!     canopen::{device::{Device, Runner}, od::ObjectDictionary},
+//!     Interface, SystemClock,
+//! };
+//!
+//! let od = ObjectDictionary::load("drive.eds")?;
+//! let mut runner = Runner::new(Interface::init()?, SystemClock::new(), Device::new(od, 5));
+//! let mut position = 0i32;
+//! loop {
+//!     runner.run_for(Duration::from_millis(10))?;
+//!     let device = runner.device_mut();
+//!     while let Some(event) = device.poll_event() {
+//!         println!("{:?}", event);
+//!     }
+//!     position += 1;
+//!     device.set_value(0x6064, 0, position.into()).unwrap();
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{collections::VecDeque, convert::TryInto, time::Duration};
+
+use embedded_can::{Frame, Id};
+
+use super::{
+    cob, cob_id,
+    nmt::{Command, State},
+    od::{DataType, ObjectDictionary, Value},
+    pdo::{Kind, Pdo},
+    sdo::abort,
+};
+use crate::{
+    sim::{Context, Node},
+    Clock, ReadTimeout, StandardId,
+};
+
+/// Notifications for the application.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The NMT state changed by a command or after a reset.
+    StateChanged(State),
+    /// All values have been restored to the defaults.
+    ResetNode,
+    /// The communication parameters have been restored to the defaults.
+    ResetCommunication,
+    /// An entry was written by SDO or RPDO.
+    Written { index: u16, subindex: u8 },
+}
+
+enum Transfer {
+    Upload {
+        index: u16,
+        subindex: u8,
+        data: Vec<u8>,
+        offset: usize,
+        toggle: u8,
+    },
+    Download {
+        index: u16,
+        subindex: u8,
+        data: Vec<u8>,
+        toggle: u8,
+    },
+}
+
+struct Tpdo {
+    pdo: Pdo,
+    /// Waiting for the inhibit time, or the next SYNC for acyclic PDOs.
+    pending: bool,
+    syncs: u8,
+    next_event: Option<Duration>,
+    inhibited_until: Duration,
+}
+
+impl Tpdo {
+    fn is_event_driven(&self) -> bool {
+        self.pdo.transmission_type >= 0xFE
+    }
+}
+
+struct Rpdo {
+    pdo: Pdo,
+    /// Data of synchronous PDOs, applied with the next SYNC.
+    buffered: Option<Vec<u8>>,
+}
+
+/// Sans-IO CANopen slave.
+pub struct Device {
+    od: ObjectDictionary,
+    defaults: ObjectDictionary,
+    node: u8,
+    state: State,
+    booted: bool,
+    transmit: VecDeque<(Id, Vec<u8>)>,
+    events: VecDeque<Event>,
+    transfer: Option<Transfer>,
+    tpdos: Vec<Tpdo>,
+    rpdos: Vec<Rpdo>,
+    next_heartbeat: Option<Duration>,
+    toggle: u8,
+    /// Earliest timer of the simulated bus.
+    wake: Option<Duration>,
+}
+
+impl Device {
+    /// Device with node ID `node`, values relative to the node ID in `od`
+    /// are updated.
+    ///
+    /// # Panics
+    ///
+    /// If `node` is not in the range 1 to 127.
+    pub fn new(mut od: ObjectDictionary, node: u8) -> Self {
+        assert!((1..=127).contains(&node), "invalid node ID {}", node);
+        od.set_node_id(node);
+        Self {
+            defaults: od.clone(),
+            od,
+            node,
+            state: State::BootUp,
+            booted: false,
+            transmit: VecDeque::new(),
+            events: VecDeque::new(),
+            transfer: None,
+            tpdos: Vec::new(),
+            rpdos: Vec::new(),
+            next_heartbeat: None,
+            toggle: 0,
+            wake: None,
+        }
+    }
+
+    pub fn node(&self) -> u8 {
+        self.node
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn od(&self) -> &ObjectDictionary {
+        &self.od
+    }
+
+    pub fn value(&self, index: u16, subindex: u8) -> Option<&Value> {
+        self.od.value(index, subindex)
+    }
+
+    /// Sets a value from the application, regardless of the access type.
+    ///
+    /// Event driven and acyclic synchronous TPDOs mapping the entry are sent
+    /// if the value changed. Fails with an SDO abort code if the entry does not
+    /// exist or has a different type.
+    pub fn set_value(&mut self, index: u16, subindex: u8, value: Value) -> Result<(), u32> {
+        let variable = self.entry_mut(index, subindex)?;
+        if variable.data_type != value.data_type() {
+            return Err(abort::LENGTH);
+        }
+        if variable.current() == Some(&value) {
+            return Ok(());
+        }
+        variable.set(value);
+        for tpdo in &mut self.tpdos {
+            let mapped = tpdo
+                .pdo
+                .signals
+                .iter()
+                .any(|s| s.index == index && s.subindex == subindex);
+            if mapped && (tpdo.is_event_driven() || tpdo.pdo.transmission_type == 0) {
+                tpdo.pending = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends TPDO `number` as soon as the inhibit time allows, or with the
+    /// next SYNC for synchronous PDOs.
+    pub fn trigger(&mut self, number: u16) {
+        let cob_id = Pdo::from_dictionary(&self.od, Kind::Transmit, number).map(|p| p.cob_id);
+        for tpdo in &mut self.tpdos {
+            if Some(tpdo.pdo.cob_id) == cob_id {
+                tpdo.pending = true;
+            }
+        }
+    }
+
+    /// Sends an emergency message and records the error.
+    ///
+    /// Updates the error register (0x1001) and the pre-defined error field
+    /// (0x1003) if present. The error code 0 resets the error register.
+    pub fn emergency(&mut self, code: u16, manufacturer: [u8; 5]) {
+        let register = if code == 0 {
+            0
+        } else {
+            let class = match code >> 12 {
+                0x2 => 0x02,
+                0x3 => 0x04,
+                0x4 => 0x08,
+                0x8 => 0x10,
+                0x6 if code >> 8 == 0x63 => 0x20,
+                0xF if code >> 8 == 0xFF => 0x80,
+                _ => 0x00,
+            };
+            let register = match self.od.value(0x1001, 0) {
+                Some(Value::Unsigned8(register)) => *register,
+                _ => 0,
+            };
+            register | 0x01 | class
+        };
+        self.store(0x1001, 0, Value::Unsigned8(register));
+
+        if code != 0 {
+            if let Some(field) = self.od.object(0x1003) {
+                // Newest error at subindex 1, subindex 0 counts the errors.
+                let len = field.entries.keys().copied().max().unwrap_or(0);
+                for subindex in (2..=len).rev() {
+                    if let Some(older) = self.od.value(0x1003, subindex - 1).cloned() {
+                        self.store(0x1003, subindex, older);
+                    }
+                }
+                let count = match self.od.value(0x1003, 0) {
+                    Some(Value::Unsigned8(count)) => *count,
+                    _ => 0,
+                };
+                self.store(0x1003, 1, Value::Unsigned32(code as u32));
+                self.store(0x1003, 0, Value::Unsigned8((count + 1).min(len)));
+            }
+        }
+
+        if self.state != State::Stopped {
+            let id = self.cob_id(0x1014, cob::EMCY + self.node as u16);
+            let id = Id::Standard(StandardId::new(id).unwrap());
+            let mut data = vec![code as u8, (code >> 8) as u8, register];
+            data.extend_from_slice(&manufacturer);
+            self.transmit.push_back((id, data));
+        }
+    }
+
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+
+    pub fn handle_frame<F: Frame>(&mut self, now: Duration, frame: &F) {
+        let id = match frame.id() {
+            Id::Standard(id) => id.as_raw(),
+            Id::Extended(_) => return,
+        };
+        let data = frame.data();
+
+        if id == cob::NMT && !frame.is_remote_frame() {
+            if let [command, node] = *data {
+                if node == 0 || node == self.node {
+                    self.nmt(now, command);
+                }
+                return;
+            }
+        }
+        self.boot_once(now);
+
+        if frame.is_remote_frame() {
+            if id == cob::HEARTBEAT + self.node as u16 {
+                let value = self.toggle << 7 | self.state as u8;
+                self.toggle ^= 1;
+                self.transmit.push_back((frame.id(), vec![value]));
+            } else if self.state == State::Operational {
+                for tpdo in &mut self.tpdos {
+                    if tpdo.pdo.transmission_type == 0xFD && tpdo.pdo.id() == Some(frame.id()) {
+                        tpdo.pending = true;
+                    }
+                }
+            }
+            return;
+        }
+        if self.state == State::Stopped {
+            return;
+        }
+
+        if id == cob::SDO_RX + self.node as u16 {
+            if let Ok(request) = data.try_into() {
+                self.sdo(now, request);
+            }
+            return;
+        }
+        if self.state != State::Operational {
+            return;
+        }
+
+        if id == self.cob_id(0x1005, cob::SYNC) {
+            self.sync();
+            return;
+        }
+        let position = self
+            .rpdos
+            .iter()
+            .position(|r| r.pdo.id() == Some(frame.id()));
+        if let Some(position) = position {
+            let rpdo = &mut self.rpdos[position];
+            if data.len() < rpdo.pdo.len() {
+                return;
+            }
+            if rpdo.pdo.transmission_type <= 240 {
+                rpdo.buffered = Some(data.to_vec());
+            } else {
+                self.receive_pdo(position, data);
+            }
+        }
+    }
+
+    /// Returns the next frame to send, sending due heartbeats and TPDOs.
+    pub fn poll_transmit<F: Frame>(&mut self, now: Duration) -> Option<F> {
+        self.boot_once(now);
+
+        if self.next_heartbeat.is_some_and(|next| now >= next) {
+            let id = cob_id(cob::HEARTBEAT, self.node);
+            self.transmit
+                .push_back((Id::Standard(id), vec![self.state as u8]));
+            self.next_heartbeat = self.heartbeat_time().map(|period| now + period);
+        }
+
+        if self.state == State::Operational {
+            for position in 0..self.tpdos.len() {
+                let tpdo = &mut self.tpdos[position];
+                if !tpdo.is_event_driven() {
+                    continue;
+                }
+                if tpdo.next_event.is_some_and(|next| now >= next) {
+                    tpdo.pending = true;
+                }
+                if tpdo.pending && now >= tpdo.inhibited_until {
+                    tpdo.inhibited_until = now + inhibit_time(&tpdo.pdo);
+                    tpdo.next_event = event_timer(&tpdo.pdo).map(|timer| now + timer);
+                    self.send_pdo(position);
+                }
+            }
+        }
+
+        let (id, data) = self.transmit.pop_front()?;
+        F::new(id, &data).ok()
+    }
+
+    /// When [`Device::poll_transmit()`] has to be called next.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let tpdos = self
+            .tpdos
+            .iter()
+            .filter(|_| self.state == State::Operational)
+            .filter(|t| t.is_event_driven())
+            .flat_map(|t| {
+                let inhibit = Some(t.inhibited_until).filter(|_| t.pending);
+                t.next_event.into_iter().chain(inhibit)
+            });
+        self.next_heartbeat.into_iter().chain(tpdos).min()
+    }
+
+    fn boot_once(&mut self, now: Duration) {
+        if !self.booted {
+            self.boot(now);
+        }
+    }
+
+    /// Sends the boot-up message and enters the pre-operational state.
+    fn boot(&mut self, now: Duration) {
+        self.booted = true;
+        self.transfer = None;
+        self.toggle = 0;
+        self.transmit
+            .push_back((Id::Standard(cob_id(cob::HEARTBEAT, self.node)), vec![0]));
+        self.next_heartbeat = self.heartbeat_time().map(|period| now + period);
+        self.enter(State::PreOperational);
+    }
+
+    fn enter(&mut self, state: State) {
+        if state == self.state {
+            return;
+        }
+        self.state = state;
+        if state == State::Operational {
+            self.configure_pdos();
+        }
+        self.events.push_back(Event::StateChanged(state));
+    }
+
+    fn nmt(&mut self, now: Duration, command: u8) {
+        match command {
+            c if c == Command::ResetNode as u8 => {
+                self.od = self.defaults.clone();
+                self.events.push_back(Event::ResetNode);
+                self.state = State::BootUp;
+                self.boot(now);
+            }
+            c if c == Command::ResetCommunication as u8 => {
+                for index in 0x1000..=0x1FFF {
+                    if let Some(object) = self.defaults.object(index) {
+                        self.od.insert(index, object.clone());
+                    }
+                }
+                self.events.push_back(Event::ResetCommunication);
+                self.state = State::BootUp;
+                self.boot(now);
+            }
+            c => {
+                self.boot_once(now);
+                match c {
+                    c if c == Command::Start as u8 => self.enter(State::Operational),
+                    c if c == Command::Stop as u8 => self.enter(State::Stopped),
+                    c if c == Command::EnterPreOperational as u8 => {
+                        self.enter(State::PreOperational)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn configure_pdos(&mut self) {
+        let od = &self.od;
+        // Only the communication parameters in the dictionary are looked at,
+        // not all 512 possible PDOs.
+        let pdos = |kind: Kind| {
+            let first = kind.communication_index(1);
+            od.objects()
+                .filter(move |(index, _)| (first..first + 512).contains(index))
+                .filter_map(move |(index, _)| Pdo::from_dictionary(od, kind, index - first + 1))
+                .filter(|pdo| pdo.id().is_some())
+        };
+        let tpdos = pdos(Kind::Transmit)
+            .map(|pdo| Tpdo {
+                // Cyclic PDOs start with the first transmission.
+                pending: pdo.transmission_type >= 0xFE && pdo.event_timer != 0,
+                syncs: 0,
+                next_event: None,
+                inhibited_until: Duration::default(),
+                pdo,
+            })
+            .collect();
+        let rpdos = pdos(Kind::Receive)
+            .map(|pdo| Rpdo {
+                pdo,
+                buffered: None,
+            })
+            .collect();
+        self.tpdos = tpdos;
+        self.rpdos = rpdos;
+    }
+
+    fn sync(&mut self) {
+        for position in 0..self.rpdos.len() {
+            if let Some(data) = self.rpdos[position].buffered.take() {
+                self.receive_pdo(position, &data);
+            }
+        }
+        for position in 0..self.tpdos.len() {
+            let tpdo = &mut self.tpdos[position];
+            let send = match tpdo.pdo.transmission_type {
+                0 => std::mem::take(&mut tpdo.pending),
+                n @ 1..=240 => {
+                    tpdo.syncs += 1;
+                    tpdo.syncs >= n
+                }
+                _ => false,
+            };
+            if send {
+                tpdo.syncs = 0;
+                self.send_pdo(position);
+            }
+        }
+    }
+
+    fn send_pdo(&mut self, position: usize) {
+        let tpdo = &mut self.tpdos[position];
+        tpdo.pending = false;
+        let od = &self.od;
+        let data = tpdo
+            .pdo
+            .encode_with(|signal| od.value(signal.index, signal.subindex).cloned());
+        if let Some(id) = tpdo.pdo.id() {
+            self.transmit.push_back((id, data));
+        }
+    }
+
+    fn receive_pdo(&mut self, position: usize, data: &[u8]) {
+        let values: Vec<_> = match self.rpdos[position].pdo.decode(data) {
+            Some(values) => values
+                .into_iter()
+                .map(|(s, value)| (s.index, s.subindex, value))
+                .collect(),
+            None => return,
+        };
+        for (index, subindex, value) in values {
+            if let Ok(variable) = self.entry_mut(index, subindex) {
+                variable.set(value);
+                self.events.push_back(Event::Written { index, subindex });
+            }
+        }
+    }
+
+    fn sdo(&mut self, now: Duration, request: [u8; 8]) {
+        if request[0] >> 5 == 4 {
+            self.transfer = None;
+            return;
+        }
+        match self.sdo_response(now, request) {
+            Ok(response) => self.send_sdo(response),
+            Err((index, subindex, code)) => self.abort(index, subindex, code),
+        }
+    }
+
+    /// Response to an SDO request, or the multiplexer and code to abort with.
+    fn sdo_response(&mut self, now: Duration, request: [u8; 8]) -> Result<[u8; 8], (u16, u8, u32)> {
+        let index = u16::from_le_bytes([request[1], request[2]]);
+        let subindex = request[3];
+        let multiplexer = |code| (index, subindex, code);
+        match request[0] >> 5 {
+            // Initiate download
+            1 => {
+                self.transfer = None;
+                if request[0] & 0x02 != 0 {
+                    let len = if request[0] & 0x01 != 0 {
+                        4 - (request[0] >> 2 & 0x03) as usize
+                    } else {
+                        4
+                    };
+                    self.write(now, index, subindex, &request[4..4 + len])
+                        .map_err(multiplexer)?;
+                } else {
+                    self.entry_mut(index, subindex).map_err(multiplexer)?;
+                    self.transfer = Some(Transfer::Download {
+                        index,
+                        subindex,
+                        data: Vec::new(),
+                        toggle: 0,
+                    });
+                }
+                Ok([0x60, request[1], request[2], subindex, 0, 0, 0, 0])
+            }
+            0 => self.download_segment(now, request),
+            // Initiate upload
+            2 => {
+                self.transfer = None;
+                let data = self.read(index, subindex).map_err(multiplexer)?;
+                let mut response = [0, request[1], request[2], subindex, 0, 0, 0, 0];
+                if !data.is_empty() && data.len() <= 4 {
+                    response[0] = 0x43 | ((4 - data.len()) as u8) << 2;
+                    response[4..4 + data.len()].copy_from_slice(&data);
+                } else {
+                    response[0] = 0x41;
+                    response[4..].copy_from_slice(&(data.len() as u32).to_le_bytes());
+                    self.transfer = Some(Transfer::Upload {
+                        index,
+                        subindex,
+                        data,
+                        offset: 0,
+                        toggle: 0,
+                    });
+                }
+                Ok(response)
+            }
+            3 => self.upload_segment(request),
+            _ => Err(multiplexer(abort::COMMAND)),
+        }
+    }
+
+    fn download_segment(
+        &mut self,
+        now: Duration,
+        request: [u8; 8],
+    ) -> Result<[u8; 8], (u16, u8, u32)> {
+        let (index, subindex, data, toggle) = match &mut self.transfer {
+            Some(Transfer::Download {
+                index,
+                subindex,
+                data,
+                toggle,
+            }) => (*index, *subindex, data, toggle),
+            _ => return Err((0, 0, abort::COMMAND)),
+        };
+        if request[0] >> 4 & 0x01 != *toggle {
+            return Err((index, subindex, abort::TOGGLE_BIT));
+        }
+        let unused = (request[0] >> 1 & 0x07) as usize;
+        data.extend_from_slice(&request[1..8 - unused]);
+        let response = [0x20 | *toggle << 4, 0, 0, 0, 0, 0, 0, 0];
+        *toggle ^= 1;
+        if request[0] & 0x01 != 0 {
+            let data = std::mem::take(data);
+            self.transfer = None;
+            self.write(now, index, subindex, &data)
+                .map_err(|code| (index, subindex, code))?;
+        }
+        Ok(response)
+    }
+
+    fn upload_segment(&mut self, request: [u8; 8]) -> Result<[u8; 8], (u16, u8, u32)> {
+        let (index, subindex, data, offset, toggle) = match &mut self.transfer {
+            Some(Transfer::Upload {
+                index,
+                subindex,
+                data,
+                offset,
+                toggle,
+            }) => (*index, *subindex, data, offset, toggle),
+            _ => return Err((0, 0, abort::COMMAND)),
+        };
+        if request[0] >> 4 & 0x01 != *toggle {
+            return Err((index, subindex, abort::TOGGLE_BIT));
+        }
+        let segment = &data[*offset..data.len().min(*offset + 7)];
+        let mut response = [0; 8];
+        response[0] = *toggle << 4 | ((7 - segment.len()) as u8) << 1;
+        response[1..1 + segment.len()].copy_from_slice(segment);
+        *offset += segment.len();
+        *toggle ^= 1;
+        if *offset == data.len() {
+            response[0] |= 0x01;
+            self.transfer = None;
+        }
+        Ok(response)
+    }
+
+    fn abort(&mut self, index: u16, subindex: u8, code: u32) {
+        self.transfer = None;
+        let [i0, i1] = index.to_le_bytes();
+        let [c0, c1, c2, c3] = code.to_le_bytes();
+        self.send_sdo([0x80, i0, i1, subindex, c0, c1, c2, c3]);
+    }
+
+    fn send_sdo(&mut self, response: [u8; 8]) {
+        let id = Id::Standard(cob_id(cob::SDO_TX, self.node));
+        self.transmit.push_back((id, response.to_vec()));
+    }
+
+    fn read(&self, index: u16, subindex: u8) -> Result<Vec<u8>, u32> {
+        let object = self.od.object(index).ok_or(abort::NO_OBJECT)?;
+        let variable = object.entries.get(&subindex).ok_or(abort::NO_SUBINDEX)?;
+        if !variable.access.is_readable() {
+            return Err(abort::WRITE_ONLY);
+        }
+        Ok(variable
+            .current()
+            .cloned()
+            .unwrap_or_else(|| Value::zero(variable.data_type))
+            .encode())
+    }
+
+    fn write(&mut self, now: Duration, index: u16, subindex: u8, data: &[u8]) -> Result<(), u32> {
+        let variable = self.entry_mut(index, subindex)?;
+        if !variable.access.is_writable() {
+            return Err(abort::READ_ONLY);
+        }
+        let data_type = variable.data_type;
+        let value = match data_type.bits() {
+            Some(_) if data.len() > size(data_type) => return Err(abort::LENGTH_TOO_HIGH),
+            Some(_) if data.len() < size(data_type) => return Err(abort::LENGTH_TOO_LOW),
+            _ => Value::decode(data_type, data).ok_or(abort::INVALID_VALUE)?,
+        };
+        variable.set(value);
+        self.events.push_back(Event::Written { index, subindex });
+
+        if index == 0x1017 {
+            self.next_heartbeat = self.heartbeat_time().map(|period| now + period);
+        }
+        if (0x1400..=0x1BFF).contains(&index) && self.state == State::Operational {
+            self.configure_pdos();
+        }
+        Ok(())
+    }
+
+    fn entry_mut(&mut self, index: u16, subindex: u8) -> Result<&mut super::od::Variable, u32> {
+        if self.od.object(index).is_none() {
+            return Err(abort::NO_OBJECT);
+        }
+        self.od.get_mut(index, subindex).ok_or(abort::NO_SUBINDEX)
+    }
+
+    fn store(&mut self, index: u16, subindex: u8, value: Value) {
+        if let Some(variable) = self.od.get_mut(index, subindex) {
+            variable.set(value);
+        }
+    }
+
+    /// Identifier from a COB-ID entry, or `default` if not present.
+    fn cob_id(&self, index: u16, default: u16) -> u16 {
+        match self.od.value(index, 0) {
+            Some(Value::Unsigned32(cob_id)) => (*cob_id & 0x7FF) as u16,
+            _ => default,
+        }
+    }
+
+    fn heartbeat_time(&self) -> Option<Duration> {
+        match self.od.value(0x1017, 0) {
+            Some(Value::Unsigned16(ms)) if *ms != 0 => Some(Duration::from_millis(*ms as u64)),
+            _ => None,
+        }
+    }
+}
+
+/// Size in bytes of types with a fixed length.
+fn size(data_type: DataType) -> usize {
+    data_type
+        .bits()
+        .map_or(0, |bits| (bits as usize).div_ceil(8))
+}
+
+fn inhibit_time(pdo: &Pdo) -> Duration {
+    Duration::from_micros(pdo.inhibit_time as u64 * 100)
+}
+
+fn event_timer(pdo: &Pdo) -> Option<Duration> {
+    Some(Duration::from_millis(pdo.event_timer as u64)).filter(|_| pdo.event_timer != 0)
+}
+
+impl Device {
+    fn drive(&mut self, cx: &mut Context<'_>) {
+        let now = cx.now();
+        while let Some(frame) = self.poll_transmit(now) {
+            cx.send(frame);
+        }
+        if self.wake.is_some_and(|wake| wake <= now) {
+            self.wake = None;
+        }
+        if let Some(deadline) = self.next_deadline() {
+            if self.wake.is_none_or(|wake| deadline < wake) {
+                self.wake = Some(deadline);
+                cx.wake_after(deadline.saturating_sub(now));
+            }
+        }
+    }
+}
+
+impl Node for Device {
+    fn on_frame(&mut self, frame: &crate::Frame, cx: &mut Context<'_>) {
+        self.handle_frame(cx.now(), frame);
+        self.drive(cx);
+    }
+
+    fn on_timer(&mut self, cx: &mut Context<'_>) {
+        self.drive(cx);
+    }
+}
+
+/// Runs a [`Device`] over a CAN interface.
+pub struct Runner<C, K> {
+    can: C,
+    clock: K,
+    device: Device,
+}
+
+impl<C, K> Runner<C, K>
+where
+    C: ReadTimeout,
+    C::Frame: Frame,
+    K: Clock,
+{
+    pub fn new(can: C, clock: K, device: Device) -> Self {
+        Self { can, clock, device }
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn device_mut(&mut self) -> &mut Device {
+        &mut self.device
+    }
+
+    pub fn can(&mut self) -> &mut C {
+        &mut self.can
+    }
+
+    pub fn into_inner(self) -> (C, K, Device) {
+        (self.can, self.clock, self.device)
+    }
+
+    /// Processes received frames and sends due messages for `duration`.
+    ///
+    /// Boots the device on the first call.
+    pub fn run_for(&mut self, duration: Duration) -> Result<(), C::Error> {
+        let end = self.clock.now() + duration;
+        loop {
+            let now = self.clock.now();
+            while let Some(frame) = self.device.poll_transmit::<C::Frame>(now) {
+                self.can.try_write(&frame)?;
+            }
+            if now >= end {
+                return Ok(());
+            }
+
+            let wake = self.device.next_deadline().map_or(end, |d| d.min(end));
+            if let Some(frame) = self.can.try_read_timeout(wake.saturating_sub(now))? {
+                let now = self.clock.now();
+                self.device.handle_frame(now, &frame);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        canopen::{
+            od::AccessType,
+            sdo::{Client, Error},
+        },
+        sim::{self, Bus},
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    const EDS: &str = "
+[1000]
+ParameterName=Device type
+DataType=0x0007
+AccessType=ro
+DefaultValue=0x00020192
+
+[1001]
+ParameterName=Error register
+DataType=0x0005
+AccessType=ro
+DefaultValue=0
+
+[1003]
+ParameterName=Pre-defined error field
+ObjectType=0x8
+DataType=0x0007
+AccessType=ro
+CompactSubObj=2
+
+[1008]
+ParameterName=Manufacturer device name
+DataType=0x0009
+AccessType=const
+DefaultValue=Rest-bus drive
+
+[1017]
+ParameterName=Producer heartbeat time
+DataType=0x0006
+AccessType=rw
+DefaultValue=100
+
+[1400]
+ParameterName=RPDO1 communication parameter
+ObjectType=0x9
+
+[1400sub1]
+ParameterName=COB-ID
+DataType=0x0007
+AccessType=rw
+DefaultValue=$NODEID+0x200
+
+[1400sub2]
+ParameterName=Transmission type
+DataType=0x0005
+AccessType=rw
+DefaultValue=0xFF
+
+[1600]
+ParameterName=RPDO1 mapping parameter
+ObjectType=0x9
+
+[1600sub0]
+ParameterName=Number of mapped objects
+DataType=0x0005
+AccessType=rw
+DefaultValue=1
+
+[1600sub1]
+ParameterName=Object 1
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x60400010
+
+[1800]
+ParameterName=TPDO1 communication parameter
+ObjectType=0x9
+
+[1800sub1]
+ParameterName=COB-ID
+DataType=0x0007
+AccessType=rw
+DefaultValue=$NODEID+0x180
+
+[1800sub2]
+ParameterName=Transmission type
+DataType=0x0005
+AccessType=rw
+DefaultValue=0xFF
+
+[1801]
+ParameterName=TPDO2 communication parameter
+ObjectType=0x9
+
+[1801sub1]
+ParameterName=COB-ID
+DataType=0x0007
+AccessType=rw
+DefaultValue=$NODEID+0x280
+
+[1801sub2]
+ParameterName=Transmission type
+DataType=0x0005
+AccessType=rw
+DefaultValue=2
+
+[1802]
+ParameterName=TPDO3 communication parameter
+ObjectType=0x9
+
+[1802sub1]
+ParameterName=COB-ID
+DataType=0x0007
+AccessType=rw
+DefaultValue=$NODEID+0x380
+
+[1802sub2]
+ParameterName=Transmission type
+DataType=0x0005
+AccessType=rw
+DefaultValue=0xFE
+
+[1802sub5]
+ParameterName=Event timer
+DataType=0x0006
+AccessType=rw
+DefaultValue=30
+
+[1A00]
+ParameterName=TPDO1 mapping parameter
+ObjectType=0x9
+
+[1A00sub0]
+ParameterName=Number of mapped objects
+DataType=0x0005
+AccessType=rw
+DefaultValue=2
+
+[1A00sub1]
+ParameterName=Object 1
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x60410010
+
+[1A00sub2]
+ParameterName=Object 2
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x60640020
+
+[1A01]
+ParameterName=TPDO2 mapping parameter
+ObjectType=0x9
+
+[1A01sub0]
+ParameterName=Number of mapped objects
+DataType=0x0005
+AccessType=rw
+DefaultValue=1
+
+[1A01sub1]
+ParameterName=Object 1
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x60640020
+
+[1A02]
+ParameterName=TPDO3 mapping parameter
+ObjectType=0x9
+
+[1A02sub0]
+ParameterName=Number of mapped objects
+DataType=0x0005
+AccessType=rw
+DefaultValue=1
+
+[1A02sub1]
+ParameterName=Object 1
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x60410010
+
+[6040]
+ParameterName=Controlword
+DataType=0x0006
+AccessType=rww
+PDOMapping=1
+
+[6041]
+ParameterName=Statusword
+DataType=0x0006
+AccessType=ro
+PDOMapping=1
+
+[6064]
+ParameterName=Position actual value
+DataType=0x0004
+AccessType=ro
+PDOMapping=1
+";
+
+    fn setup() -> (sim::Endpoint, Rc<RefCell<Device>>) {
+        let od = ObjectDictionary::parse(EDS).unwrap();
+        let bus = Bus::new();
+        let device = bus.add_node(Device::new(od, 5));
+        (bus.endpoint(), device)
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn send(can: &mut sim::Endpoint, id: u16, data: &[u8]) {
+        let frame = crate::Frame::new(StandardId::new(id).unwrap(), data).unwrap();
+        embedded_can::blocking::Can::try_write(can, &frame).unwrap();
+    }
+
+    /// Frames received within `time` except those with `id`.
+    fn receive_without(can: &mut sim::Endpoint, time: Duration, id: u16) -> Vec<(u16, Vec<u8>)> {
+        let mut frames = receive(can, time);
+        frames.retain(|frame| frame.0 != id);
+        frames
+    }
+
+    /// Frames received within `time`.
+    fn receive(can: &mut sim::Endpoint, time: Duration) -> Vec<(u16, Vec<u8>)> {
+        let end = can.clock().now() + time;
+        let mut frames = Vec::new();
+        loop {
+            let remaining = end.saturating_sub(can.clock().now());
+            match can.try_read_timeout(remaining).unwrap() {
+                Some(frame) => match frame.id() {
+                    Id::Standard(id) => frames.push((id.as_raw(), frame.data().to_vec())),
+                    Id::Extended(_) => {}
+                },
+                None => return frames,
+            }
+        }
+    }
+
+    #[test]
+    fn sdo_server() {
+        let (endpoint, device) = setup();
+        let clock = endpoint.clock();
+        let mut sdo = Client::new(endpoint, clock, 5);
+
+        assert_eq!(sdo.read::<u32>(0x1000, 0).unwrap(), 0x0002_0192);
+        assert_eq!(device.borrow().state(), State::PreOperational);
+        assert_eq!(sdo.read::<String>(0x1008, 0).unwrap(), "Rest-bus drive");
+        sdo.download(0x6040, 0, &[0x06, 0x00]).unwrap();
+        assert_eq!(
+            device.borrow().value(0x6040, 0),
+            Some(&Value::Unsigned16(6))
+        );
+
+        let abort = |err| match err {
+            Error::Abort { code, .. } => code,
+            err => panic!("{:?}", err),
+        };
+        assert_eq!(
+            abort(sdo.read::<u8>(0x2000, 0).unwrap_err()),
+            abort::NO_OBJECT
+        );
+        assert_eq!(
+            abort(sdo.read::<u8>(0x1000, 1).unwrap_err()),
+            abort::NO_SUBINDEX
+        );
+        assert_eq!(
+            abort(sdo.write(0x1000, 0, &0u32).unwrap_err()),
+            abort::READ_ONLY
+        );
+        assert_eq!(
+            abort(sdo.write(0x6040, 0, &0u32).unwrap_err()),
+            abort::LENGTH_TOO_HIGH
+        );
+
+        let od = ObjectDictionary::parse(EDS).unwrap();
+        let tpdo1 = Pdo::read(&mut sdo, Kind::Transmit, 1, Some(&od)).unwrap();
+        assert_eq!(tpdo1.cob_id, 0x185);
+        assert_eq!(tpdo1.signals[1].name, "Position actual value");
+        assert_eq!(tpdo1.signals[1].data_type, DataType::Integer32);
+
+        let mut device = device.borrow_mut();
+        assert_eq!(
+            device.poll_event(),
+            Some(Event::StateChanged(State::PreOperational))
+        );
+        assert_eq!(
+            device.poll_event(),
+            Some(Event::Written {
+                index: 0x6040,
+                subindex: 0
+            })
+        );
+        assert_eq!(device.poll_event(), None);
+        assert_eq!(
+            device.od().get(0x6040, 0).unwrap().access,
+            AccessType::ReadWriteWrite
+        );
+    }
+
+    #[test]
+    fn nmt_pdos_and_emergency() {
+        let (mut can, device) = setup();
+        send(&mut can, cob::NMT, &[Command::ResetNode as u8, 0]);
+        assert_eq!(
+            receive(&mut can, ms(250)),
+            [
+                (0x705, vec![0x00]),
+                (0x705, vec![0x7F]),
+                (0x705, vec![0x7F])
+            ]
+        );
+
+        // TPDO3 every 30 ms without any value changes.
+        send(&mut can, cob::NMT, &[Command::Start as u8, 5]);
+        let frames = receive(&mut can, ms(100));
+        let tpdo3 = frames.iter().filter(|(id, _)| *id == 0x385);
+        assert_eq!(tpdo3.count(), 4);
+        assert!(frames.contains(&(0x385, vec![0x00, 0x00])));
+
+        // Event driven TPDO1 when the value changes, TPDO2 with every second SYNC.
+        device
+            .borrow_mut()
+            .set_value(0x6064, 0, Value::Integer32(-2))
+            .unwrap();
+        send(&mut can, cob::SYNC, &[]);
+        send(&mut can, cob::SYNC, &[]);
+        assert_eq!(
+            receive_without(&mut can, ms(10), 0x385),
+            [
+                (0x185, vec![0, 0, 0xFE, 0xFF, 0xFF, 0xFF]),
+                (0x285, vec![0xFE, 0xFF, 0xFF, 0xFF]),
+            ]
+        );
+
+        send(&mut can, 0x205, &[0x0F, 0x00]);
+        receive(&mut can, ms(1));
+        assert_eq!(
+            device.borrow().value(0x6040, 0),
+            Some(&Value::Unsigned16(0x0F))
+        );
+
+        device.borrow_mut().emergency(0x2310, [1, 2, 3, 4, 5]);
+        device.borrow_mut().emergency(0x4210, [0; 5]);
+        let frames = receive_without(&mut can, ms(100), 0x385);
+        assert_eq!(frames[0], (0x85, vec![0x10, 0x23, 0x03, 1, 2, 3, 4, 5]));
+        assert_eq!(frames[1], (0x85, vec![0x10, 0x42, 0x0B, 0, 0, 0, 0, 0]));
+        assert_eq!(frames[2], (0x705, vec![0x05]));
+        let device_ref = device.borrow();
+        assert_eq!(device_ref.value(0x1003, 0), Some(&Value::Unsigned8(2)));
+        assert_eq!(
+            device_ref.value(0x1003, 1),
+            Some(&Value::Unsigned32(0x4210))
+        );
+        assert_eq!(
+            device_ref.value(0x1003, 2),
+            Some(&Value::Unsigned32(0x2310))
+        );
+        drop(device_ref);
+
+        // Stopped nodes only answer NMT and node guarding.
+        send(&mut can, cob::NMT, &[Command::Stop as u8, 5]);
+        send(
+            &mut can,
+            cob::SDO_RX + 5,
+            &[0x40, 0x00, 0x10, 0x00, 0, 0, 0, 0],
+        );
+        let frame = crate::Frame::new_remote(StandardId::new(0x705).unwrap(), 1).unwrap();
+        embedded_can::blocking::Can::try_write(&mut can, &frame).unwrap();
+        embedded_can::blocking::Can::try_write(&mut can, &frame).unwrap();
+        let frames = receive(&mut can, ms(50));
+        assert!(frames.iter().all(|(id, _)| *id == 0x705));
+        assert!(frames.contains(&(0x705, vec![0x84])));
+
+        send(&mut can, cob::NMT, &[Command::ResetCommunication as u8, 5]);
+        receive(&mut can, ms(1));
+        let mut device = device.borrow_mut();
+        assert_eq!(device.state(), State::PreOperational);
+        assert_eq!(device.value(0x6040, 0), Some(&Value::Unsigned16(0x0F)));
+        let events: Vec<_> = std::iter::from_fn(|| device.poll_event()).collect();
+        assert_eq!(
+            events[events.len() - 2..],
+            [
+                Event::ResetCommunication,
+                Event::StateChanged(State::PreOperational)
+            ]
+        );
+    }
+}
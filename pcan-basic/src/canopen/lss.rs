@@ -0,0 +1,541 @@
+//! Layer setting services (CiA 305) to assign node IDs and bit rates.
+//!
+//! LSS slaves are addressed by their identity, the four entries of object
+//! 0x1018. Only one slave may be in the configuration state when its node ID
+//! or bit timing is configured. [`Master::fastscan()`] finds slaves without a
+//! node ID by identity, one at a time:
+//!
+//! ```no_run
+//! use pcan_basic::{canopen::lss::{Master, Mode}, Interface, SystemClock};
+//!
+//! let mut lss = Master::new(Interface::init()?, SystemClock::new());
+//! let mut node = 10;
+//! while let Some(identity) = lss.fastscan()? {
+//!     println!("{:08X?} gets node ID {}", identity, node);
+//!     lss.configure_node_id(node)?;
+//!     lss.store_configuration()?;
+//!     lss.switch_global(Mode::Waiting)?;
+//!     node += 1;
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{convert::TryInto, fmt, time::Duration};
+
+use embedded_can::{Frame, Id};
+
+use crate::{Clock, ReadTimeout, StandardId};
+
+/// Requests from the master.
+pub const MASTER_ID: u16 = 0x7E5;
+/// Responses from the slaves.
+pub const SLAVE_ID: u16 = 0x7E4;
+
+/// Node ID of slaves that have not been configured.
+pub const UNCONFIGURED: u8 = 0xFF;
+
+/// Command specifiers.
+pub mod command {
+    pub const SWITCH_GLOBAL: u8 = 0x04;
+    pub const CONFIGURE_NODE_ID: u8 = 0x11;
+    pub const CONFIGURE_BIT_TIMING: u8 = 0x13;
+    pub const ACTIVATE_BIT_TIMING: u8 = 0x15;
+    pub const STORE_CONFIGURATION: u8 = 0x17;
+    pub const SWITCH_SELECTIVE_VENDOR_ID: u8 = 0x40;
+    pub const SWITCH_SELECTIVE_PRODUCT_CODE: u8 = 0x41;
+    pub const SWITCH_SELECTIVE_REVISION: u8 = 0x42;
+    pub const SWITCH_SELECTIVE_SERIAL: u8 = 0x43;
+    pub const SWITCH_SELECTIVE_RESPONSE: u8 = 0x44;
+    pub const IDENTIFY_NON_CONFIGURED: u8 = 0x4C;
+    pub const IDENTIFY_SLAVE: u8 = 0x4F;
+    pub const IDENTIFY_NON_CONFIGURED_RESPONSE: u8 = 0x50;
+    pub const FASTSCAN: u8 = 0x51;
+    pub const INQUIRE_VENDOR_ID: u8 = 0x5A;
+    pub const INQUIRE_PRODUCT_CODE: u8 = 0x5B;
+    pub const INQUIRE_REVISION: u8 = 0x5C;
+    pub const INQUIRE_SERIAL: u8 = 0x5D;
+    pub const INQUIRE_NODE_ID: u8 = 0x5E;
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Can(E),
+    /// No slave responded in time.
+    Timeout,
+    /// The slave rejected a configuration request.
+    Rejected {
+        command: u8,
+        code: u8,
+        /// Manufacturer specific error code if `code` is 0xFF.
+        specific: u8,
+    },
+    /// A slave stopped responding during Fastscan.
+    Fastscan,
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Can(err) => write!(f, "CAN error: {}", err),
+            Error::Timeout => write!(f, "LSS timeout"),
+            Error::Rejected {
+                command,
+                code,
+                specific,
+            } => write!(
+                f,
+                "LSS command {:#04x} rejected with error {} ({})",
+                command, code, specific
+            ),
+            Error::Fastscan => write!(f, "LSS slave lost during Fastscan"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Error<E> {}
+
+impl<E> From<E> for Error<E> {
+    fn from(err: E) -> Self {
+        Error::Can(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Waiting = 0,
+    Configuration = 1,
+}
+
+/// LSS address of a slave, object 0x1018 sub 1 to 4.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Identity {
+    pub vendor_id: u32,
+    pub product_code: u32,
+    pub revision: u32,
+    pub serial: u32,
+}
+
+impl Identity {
+    fn parts(&self) -> [u32; 4] {
+        [
+            self.vendor_id,
+            self.product_code,
+            self.revision,
+            self.serial,
+        ]
+    }
+}
+
+/// Bit rates of the standard bit timing table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitRate {
+    Kbit1000 = 0,
+    Kbit800 = 1,
+    Kbit500 = 2,
+    Kbit250 = 3,
+    Kbit125 = 4,
+    Kbit50 = 6,
+    Kbit20 = 7,
+    Kbit10 = 8,
+    Auto = 9,
+}
+
+pub struct Master<C, K> {
+    can: C,
+    clock: K,
+    timeout: Duration,
+}
+
+impl<C, K> Master<C, K>
+where
+    C: ReadTimeout,
+    C::Frame: Frame,
+    K: Clock,
+{
+    pub fn new(can: C, clock: K) -> Self {
+        Self {
+            can,
+            clock,
+            timeout: Duration::from_millis(20),
+        }
+    }
+
+    /// Time to wait for responses, 20 ms by default.
+    ///
+    /// Fastscan waits this long for every bit that no slave confirms.
+    pub fn with_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn can(&mut self) -> &mut C {
+        &mut self.can
+    }
+
+    pub fn into_inner(self) -> (C, K) {
+        (self.can, self.clock)
+    }
+
+    /// Switches all slaves to `mode`.
+    pub fn switch_global(&mut self, mode: Mode) -> Result<(), Error<C::Error>> {
+        self.send(command::SWITCH_GLOBAL, &[mode as u8])?;
+        Ok(())
+    }
+
+    /// Switches the slave with `identity` to the configuration mode.
+    pub fn switch_selective(&mut self, identity: &Identity) -> Result<(), Error<C::Error>> {
+        let commands = [
+            command::SWITCH_SELECTIVE_VENDOR_ID,
+            command::SWITCH_SELECTIVE_PRODUCT_CODE,
+            command::SWITCH_SELECTIVE_REVISION,
+            command::SWITCH_SELECTIVE_SERIAL,
+        ];
+        for (&command, part) in commands.iter().zip(identity.parts().iter()) {
+            self.send(command, &part.to_le_bytes())?;
+        }
+        self.receive(command::SWITCH_SELECTIVE_RESPONSE)?;
+        Ok(())
+    }
+
+    /// Sets the pending node ID of the slave in configuration mode, 1 to 127
+    /// or [`UNCONFIGURED`]. It is used after the next NMT reset.
+    pub fn configure_node_id(&mut self, node: u8) -> Result<(), Error<C::Error>> {
+        self.configure(command::CONFIGURE_NODE_ID, &[node])
+    }
+
+    /// Sets the pending bit rate of the slave in configuration mode.
+    pub fn configure_bit_timing(&mut self, bit_rate: BitRate) -> Result<(), Error<C::Error>> {
+        // Table selector 0 is the standard table.
+        self.configure(command::CONFIGURE_BIT_TIMING, &[0, bit_rate as u8])
+    }
+
+    /// Makes all slaves in configuration mode switch to the pending bit rate.
+    ///
+    /// Slaves stop transmitting for `delay`, switch the bit rate and wait for
+    /// `delay` again before they transmit with the new bit rate.
+    pub fn activate_bit_timing(&mut self, delay: Duration) -> Result<(), Error<C::Error>> {
+        let delay = delay.as_millis().min(u16::MAX as u128) as u16;
+        self.send(command::ACTIVATE_BIT_TIMING, &delay.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Stores the pending configuration in non-volatile memory of the slave.
+    pub fn store_configuration(&mut self) -> Result<(), Error<C::Error>> {
+        self.configure(command::STORE_CONFIGURATION, &[])
+    }
+
+    /// Identity of the slave in configuration mode.
+    pub fn inquire_identity(&mut self) -> Result<Identity, Error<C::Error>> {
+        Ok(Identity {
+            vendor_id: self.inquire(command::INQUIRE_VENDOR_ID)?,
+            product_code: self.inquire(command::INQUIRE_PRODUCT_CODE)?,
+            revision: self.inquire(command::INQUIRE_REVISION)?,
+            serial: self.inquire(command::INQUIRE_SERIAL)?,
+        })
+    }
+
+    /// Active node ID of the slave in configuration mode.
+    pub fn inquire_node_id(&mut self) -> Result<u8, Error<C::Error>> {
+        self.send(command::INQUIRE_NODE_ID, &[])?;
+        Ok(self.receive(command::INQUIRE_NODE_ID)?[1])
+    }
+
+    /// Whether there are slaves without a node ID.
+    pub fn identify_non_configured(&mut self) -> Result<bool, Error<C::Error>> {
+        self.send(command::IDENTIFY_NON_CONFIGURED, &[])?;
+        match self.receive(command::IDENTIFY_NON_CONFIGURED_RESPONSE) {
+            Ok(_) => Ok(true),
+            Err(Error::Timeout) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Finds a slave without a node ID and switches it to configuration mode.
+    ///
+    /// Returns `None` if all slaves have a node ID. If there are several, the
+    /// one with the lowest identity is found.
+    pub fn fastscan(&mut self) -> Result<Option<Identity>, Error<C::Error>> {
+        if !self.fastscan_step(0, 0x80, 0, 0)? {
+            return Ok(None);
+        }
+
+        let mut parts = [0u32; 4];
+        for sub in 0..4u8 {
+            // The slaves confirm if their bits above and including `bit` match.
+            for bit in (0..32u8).rev() {
+                if !self.fastscan_step(parts[sub as usize], bit, sub, sub)? {
+                    parts[sub as usize] |= 1 << bit;
+                }
+            }
+            if !self.fastscan_step(parts[sub as usize], 0, sub, (sub + 1) % 4)? {
+                return Err(Error::Fastscan);
+            }
+        }
+
+        Ok(Some(Identity {
+            vendor_id: parts[0],
+            product_code: parts[1],
+            revision: parts[2],
+            serial: parts[3],
+        }))
+    }
+
+    /// Returns whether a slave identified itself.
+    fn fastscan_step(
+        &mut self,
+        id: u32,
+        bit: u8,
+        sub: u8,
+        next: u8,
+    ) -> Result<bool, Error<C::Error>> {
+        let [a, b, c, d] = id.to_le_bytes();
+        self.send(command::FASTSCAN, &[a, b, c, d, bit, sub, next])?;
+        match self.receive(command::IDENTIFY_SLAVE) {
+            Ok(_) => Ok(true),
+            Err(Error::Timeout) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn configure(&mut self, command: u8, args: &[u8]) -> Result<(), Error<C::Error>> {
+        self.send(command, args)?;
+        let response = self.receive(command)?;
+        match response[1] {
+            0 => Ok(()),
+            code => Err(Error::Rejected {
+                command,
+                code,
+                specific: response[2],
+            }),
+        }
+    }
+
+    fn inquire(&mut self, command: u8) -> Result<u32, Error<C::Error>> {
+        self.send(command, &[])?;
+        let response = self.receive(command)?;
+        Ok(u32::from_le_bytes(response[1..5].try_into().unwrap()))
+    }
+
+    /// Sends a request, dropping responses to earlier requests.
+    ///
+    /// Several slaves may respond to the same Fastscan request, on a real
+    /// bus their identical frames are merged.
+    fn send(&mut self, command: u8, args: &[u8]) -> Result<(), C::Error> {
+        while self.can.try_read_timeout(Duration::default())?.is_some() {}
+        let mut request = [0; 8];
+        request[0] = command;
+        request[1..1 + args.len()].copy_from_slice(args);
+        let frame = C::Frame::new(StandardId::new(MASTER_ID).unwrap(), &request).unwrap();
+        self.can.try_write(&frame)
+    }
+
+    /// Waits for a response, other frames are dropped.
+    fn receive(&mut self, command: u8) -> Result<[u8; 8], Error<C::Error>> {
+        let deadline = self.clock.now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_sub(self.clock.now());
+            let frame = match self.can.try_read_timeout(remaining)? {
+                Some(frame) => frame,
+                None => return Err(Error::Timeout),
+            };
+            if frame.is_remote_frame()
+                || frame.id() != Id::Standard(StandardId::new(SLAVE_ID).unwrap())
+            {
+                continue;
+            }
+            if let Ok(response) = frame.data().try_into() {
+                let response: [u8; 8] = response;
+                if response[0] == command {
+                    return Ok(response);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Bus, Context, Node};
+    use std::{cell::RefCell, rc::Rc};
+
+    /// LSS slave with the services used by the master.
+    struct Slave {
+        identity: Identity,
+        node: u8,
+        bit_rate: u8,
+        stored: bool,
+        configuration: bool,
+        selected: usize,
+        position: u8,
+    }
+
+    impl Slave {
+        fn new(serial: u32) -> Self {
+            Self {
+                identity: Identity {
+                    vendor_id: 0x0000_0123,
+                    product_code: 0x4711,
+                    revision: 0x0001_0002,
+                    serial,
+                },
+                node: UNCONFIGURED,
+                bit_rate: 0,
+                stored: false,
+                configuration: false,
+                selected: 0,
+                position: 0,
+            }
+        }
+    }
+
+    impl Node for Slave {
+        fn on_frame(&mut self, frame: &crate::Frame, cx: &mut Context<'_>) {
+            if frame.id() != Id::Standard(StandardId::new(MASTER_ID).unwrap()) {
+                return;
+            }
+            let data = frame.data();
+            let value = u32::from_le_bytes(data[1..5].try_into().unwrap());
+            let parts = self.identity.parts();
+            let mut response = None;
+            match data[0] {
+                command::SWITCH_GLOBAL => self.configuration = data[1] == 1,
+                c @ command::SWITCH_SELECTIVE_VENDOR_ID..=command::SWITCH_SELECTIVE_SERIAL => {
+                    let part = (c - command::SWITCH_SELECTIVE_VENDOR_ID) as usize;
+                    if part == self.selected && parts[part] == value {
+                        self.selected += 1;
+                    } else {
+                        self.selected = 0;
+                    }
+                    if self.selected == 4 {
+                        self.selected = 0;
+                        self.configuration = true;
+                        response = Some([command::SWITCH_SELECTIVE_RESPONSE, 0]);
+                    }
+                }
+                command::IDENTIFY_NON_CONFIGURED if self.node == UNCONFIGURED => {
+                    response = Some([command::IDENTIFY_NON_CONFIGURED_RESPONSE, 0]);
+                }
+                _ if !self.configuration && data[0] != command::FASTSCAN => {}
+                command::CONFIGURE_NODE_ID => {
+                    let valid = (1..=127).contains(&data[1]) || data[1] == UNCONFIGURED;
+                    if valid {
+                        self.node = data[1];
+                    }
+                    response = Some([data[0], !valid as u8]);
+                }
+                command::CONFIGURE_BIT_TIMING => {
+                    let valid = data[1] == 0 && data[2] <= 4;
+                    if valid {
+                        self.bit_rate = data[2];
+                    }
+                    response = Some([data[0], !valid as u8]);
+                }
+                command::STORE_CONFIGURATION => {
+                    self.stored = true;
+                    response = Some([data[0], 0]);
+                }
+                command::FASTSCAN if self.node == UNCONFIGURED && !self.configuration => {
+                    let (bit, sub, next) = (data[5], data[6], data[7]);
+                    if bit == 0x80 {
+                        self.position = 0;
+                        response = Some([command::IDENTIFY_SLAVE, 0]);
+                    } else if sub == self.position && (parts[sub as usize] ^ value) >> bit == 0 {
+                        response = Some([command::IDENTIFY_SLAVE, 0]);
+                        if bit == 0 {
+                            self.position = next;
+                            self.configuration = next < sub;
+                        }
+                    }
+                }
+                c @ command::INQUIRE_VENDOR_ID..=command::INQUIRE_SERIAL => {
+                    let part = parts[(c - command::INQUIRE_VENDOR_ID) as usize];
+                    let mut data = [c, 0, 0, 0, 0, 0, 0, 0];
+                    data[1..5].copy_from_slice(&part.to_le_bytes());
+                    cx.send(crate::Frame::new(StandardId::new(SLAVE_ID).unwrap(), &data).unwrap());
+                }
+                command::INQUIRE_NODE_ID => response = Some([data[0], self.node]),
+                _ => {}
+            }
+            if let Some([command, value]) = response {
+                let data = [command, value, 0, 0, 0, 0, 0, 0];
+                cx.send(crate::Frame::new(StandardId::new(SLAVE_ID).unwrap(), &data).unwrap());
+            }
+        }
+    }
+
+    type TestMaster = Master<sim::Endpoint, sim::VirtualClock>;
+
+    fn setup(serials: &[u32]) -> (TestMaster, Vec<Rc<RefCell<Slave>>>) {
+        let bus = Bus::new();
+        let slaves = serials
+            .iter()
+            .map(|&s| bus.add_node(Slave::new(s)))
+            .collect();
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        (Master::new(endpoint, clock), slaves)
+    }
+
+    #[test]
+    fn fastscan() {
+        let (mut lss, slaves) = setup(&[0x8000_0042, 0x0000_1234]);
+        assert!(lss.identify_non_configured().unwrap());
+
+        let identity = lss.fastscan().unwrap().unwrap();
+        assert_eq!(identity, slaves[1].borrow().identity);
+        assert!(slaves[1].borrow().configuration);
+        assert!(!slaves[0].borrow().configuration);
+        lss.configure_node_id(10).unwrap();
+        lss.store_configuration().unwrap();
+        lss.switch_global(Mode::Waiting).unwrap();
+
+        assert_eq!(lss.fastscan().unwrap(), Some(slaves[0].borrow().identity));
+        lss.configure_node_id(11).unwrap();
+        lss.switch_global(Mode::Waiting).unwrap();
+        assert_eq!(lss.fastscan().unwrap(), None);
+        assert!(!lss.identify_non_configured().unwrap());
+
+        assert_eq!(slaves[1].borrow().node, 10);
+        assert!(slaves[1].borrow().stored);
+        assert_eq!(slaves[0].borrow().node, 11);
+        assert!(!slaves[0].borrow().stored);
+    }
+
+    #[test]
+    fn selective_configuration() {
+        let (mut lss, slaves) = setup(&[1, 2]);
+        let identity = slaves[1].borrow().identity;
+
+        lss.switch_selective(&identity).unwrap();
+        assert_eq!(lss.inquire_identity().unwrap(), identity);
+        assert_eq!(lss.inquire_node_id().unwrap(), UNCONFIGURED);
+        lss.configure_bit_timing(BitRate::Kbit250).unwrap();
+        assert!(matches!(
+            lss.configure_bit_timing(BitRate::Kbit50),
+            Err(Error::Rejected {
+                command: command::CONFIGURE_BIT_TIMING,
+                code: 1,
+                ..
+            })
+        ));
+        assert!(matches!(
+            lss.configure_node_id(200),
+            Err(Error::Rejected { code: 1, .. })
+        ));
+        lss.activate_bit_timing(Duration::from_millis(100)).unwrap();
+        assert_eq!(slaves[1].borrow().bit_rate, BitRate::Kbit250 as u8);
+        assert!(!slaves[0].borrow().configuration);
+
+        lss.switch_global(Mode::Waiting).unwrap();
+        assert!(matches!(lss.inquire_node_id(), Err(Error::Timeout)));
+        let unknown = Identity {
+            serial: 3,
+            ..identity
+        };
+        assert!(matches!(
+            lss.switch_selective(&unknown),
+            Err(Error::Timeout)
+        ));
+    }
+}
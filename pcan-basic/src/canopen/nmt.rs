@@ -0,0 +1,467 @@
+//! NMT master and supervision of node states by heartbeat or node guarding.
+//!
+//! [`Monitor`] tracks the NMT state of nodes from boot-up, heartbeat and node
+//! guarding messages without doing any I/O. [`Master`] drives a monitor over a
+//! CAN interface and sends NMT commands.
+//!
+//! [`Interface`](crate::Interface) cannot be moved between threads. To
+//! supervise nodes in the background, open the interface on a receive thread
+//! and forward the events:
+//!
+//! ```no_run
+//! use std::{sync::mpsc, thread, time::Duration};
+//! use pcan_basic::{canopen::nmt::{Command, Master}, Interface, SystemClock};
+//!
+//! let (events, received) = mpsc::channel();
+//! thread::spawn(move || -> Result<(), pcan_basic::Error> {
+//!     let mut master = Master::new(Interface::init()?, SystemClock::new());
+//!     for node in 1..=12 {
+//!         master.monitor_mut().with_heartbeat(node, Duration::from_millis(300));
+//!     }
+//!     master.command(Command::Start, 0)?;
+//!     loop {
+//!         if let Some(event) = master.next_event(Duration::from_secs(1))? {
+//!             if events.send(event).is_err() {
+//!                 return Ok(());
+//!             }
+//!         }
+//!     }
+//! });
+//! for event in received {
+//!     println!("{:?}", event);
+//! }
+//! ```
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    time::Duration,
+};
+
+use embedded_can::{Frame, Id};
+
+use super::{cob, cob_id};
+use crate::{Clock, ReadTimeout, StandardId};
+
+/// NMT command specifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Start = 0x01,
+    Stop = 0x02,
+    EnterPreOperational = 0x80,
+    ResetNode = 0x81,
+    ResetCommunication = 0x82,
+}
+
+/// NMT state as reported by heartbeat and node guarding messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    BootUp = 0x00,
+    Stopped = 0x04,
+    Operational = 0x05,
+    PreOperational = 0x7F,
+}
+
+impl State {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(State::BootUp),
+            0x04 => Some(State::Stopped),
+            0x05 => Some(State::Operational),
+            0x7F => Some(State::PreOperational),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A node sent its boot-up message, after power on or a reset.
+    BootUp(u8),
+    /// A node reported a different state, or was heard of again after it was lost.
+    StateChanged {
+        node: u8,
+        previous: Option<State>,
+        state: State,
+    },
+    /// A supervised node missed its heartbeat or node guarding lifetime.
+    Lost(u8),
+    /// A node guarding response did not alternate the toggle bit.
+    ToggleError(u8),
+}
+
+#[derive(Debug, Default)]
+struct Watch {
+    heartbeat: Option<Duration>,
+    /// Guard time and life time factor.
+    guarding: Option<(Duration, u32)>,
+    state: Option<State>,
+    last_seen: Option<Duration>,
+    lost: bool,
+    toggle: u8,
+    next_guard: Option<Duration>,
+}
+
+impl Watch {
+    /// When the node is lost unless heard of before.
+    fn expiry(&self) -> Option<Duration> {
+        if self.lost {
+            return None;
+        }
+        let heartbeat = self.heartbeat.and_then(|time| Some(self.last_seen? + time));
+        let guarding = self
+            .guarding
+            .and_then(|(time, factor)| Some(self.last_seen? + time * factor));
+        match (heartbeat, guarding) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+/// Tracks node states and detects missing nodes.
+#[derive(Debug, Default)]
+pub struct Monitor {
+    nodes: BTreeMap<u8, Watch>,
+    events: VecDeque<Event>,
+}
+
+impl Monitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expects heartbeats of `node` at least every `consumer_time`.
+    ///
+    /// Supervision starts with the first heartbeat of the node.
+    pub fn with_heartbeat(&mut self, node: u8, consumer_time: Duration) -> &mut Self {
+        self.nodes.entry(node).or_default().heartbeat = Some(consumer_time);
+        self
+    }
+
+    /// Polls the state of `node` every `guard_time`. The node is lost if it
+    /// does not answer within `guard_time * life_time_factor`.
+    pub fn with_guarding(
+        &mut self,
+        node: u8,
+        guard_time: Duration,
+        life_time_factor: u32,
+    ) -> &mut Self {
+        self.nodes.entry(node).or_default().guarding = Some((guard_time, life_time_factor.max(1)));
+        self
+    }
+
+    /// Stops supervising a node.
+    pub fn remove(&mut self, node: u8) {
+        self.nodes.remove(&node);
+    }
+
+    /// Last known state of a node, `None` if never seen or lost.
+    pub fn state(&self, node: u8) -> Option<State> {
+        self.nodes.get(&node).and_then(|w| w.state)
+    }
+
+    /// Nodes that have been seen or are supervised, with their state.
+    pub fn nodes(&self) -> impl Iterator<Item = (u8, Option<State>)> + '_ {
+        self.nodes.iter().map(|(&node, w)| (node, w.state))
+    }
+
+    pub fn handle_frame<F: Frame>(&mut self, now: Duration, frame: &F) {
+        let id = match frame.id() {
+            Id::Standard(id) => id.as_raw(),
+            Id::Extended(_) => return,
+        };
+        if frame.is_remote_frame() || !(cob::HEARTBEAT + 1..=cob::HEARTBEAT + 127).contains(&id) {
+            return;
+        }
+        let value = match frame.data() {
+            [value] => *value,
+            _ => return,
+        };
+        let state = match State::from_u8(value & 0x7F) {
+            Some(state) => state,
+            None => return,
+        };
+        let node = (id - cob::HEARTBEAT) as u8;
+        let watch = self.nodes.entry(node).or_default();
+
+        if state == State::BootUp {
+            watch.toggle = 0;
+            self.events.push_back(Event::BootUp(node));
+        } else if watch.guarding.is_some() {
+            if value >> 7 != watch.toggle {
+                self.events.push_back(Event::ToggleError(node));
+            }
+            watch.toggle = value >> 7 ^ 1;
+        }
+
+        if watch.state != Some(state) && state != State::BootUp {
+            self.events.push_back(Event::StateChanged {
+                node,
+                previous: watch.state,
+                state,
+            });
+        }
+        watch.state = Some(state);
+        watch.last_seen = Some(now);
+        watch.lost = false;
+    }
+
+    /// Returns the next event, detecting nodes that have been lost by `now`.
+    pub fn poll(&mut self, now: Duration) -> Option<Event> {
+        for (&node, watch) in &mut self.nodes {
+            if watch.expiry().is_some_and(|expiry| now >= expiry) {
+                watch.lost = true;
+                watch.state = None;
+                self.events.push_back(Event::Lost(node));
+            }
+        }
+        self.events.pop_front()
+    }
+
+    /// Returns the next node guarding request to send.
+    pub fn poll_transmit<F: Frame>(&mut self, now: Duration) -> Option<F> {
+        for (&node, watch) in &mut self.nodes {
+            let (guard_time, _) = match watch.guarding {
+                Some(guarding) => guarding,
+                None => continue,
+            };
+            if watch.next_guard.is_none_or(|next| now >= next) {
+                if watch.next_guard.is_none() && watch.last_seen.is_none() {
+                    // The life time counts from the first request.
+                    watch.last_seen = Some(now);
+                }
+                watch.next_guard = Some(now + guard_time);
+                return F::new_remote(cob_id(cob::HEARTBEAT, node), 1).ok();
+            }
+        }
+        None
+    }
+
+    /// When [`Monitor::poll()`] or [`Monitor::poll_transmit()`] have to be called next.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.nodes
+            .values()
+            .flat_map(|w| {
+                let guard = w.guarding.map(|_| w.next_guard.unwrap_or_default());
+                w.expiry().into_iter().chain(guard)
+            })
+            .min()
+    }
+}
+
+/// Sends NMT commands and supervises nodes over a CAN interface.
+///
+/// Frames other than heartbeat and node guarding messages are dropped while
+/// waiting for events.
+pub struct Master<C, K> {
+    can: C,
+    clock: K,
+    monitor: Monitor,
+}
+
+impl<C, K> Master<C, K>
+where
+    C: ReadTimeout,
+    C::Frame: Frame,
+    K: Clock,
+{
+    pub fn new(can: C, clock: K) -> Self {
+        Self {
+            can,
+            clock,
+            monitor: Monitor::new(),
+        }
+    }
+
+    pub fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    pub fn monitor_mut(&mut self) -> &mut Monitor {
+        &mut self.monitor
+    }
+
+    pub fn can(&mut self) -> &mut C {
+        &mut self.can
+    }
+
+    pub fn into_inner(self) -> (C, K) {
+        (self.can, self.clock)
+    }
+
+    /// Sends a command to `node`, or to all nodes if `node` is 0.
+    pub fn command(&mut self, command: Command, node: u8) -> Result<(), C::Error> {
+        let id = StandardId::new(cob::NMT).unwrap();
+        let frame = C::Frame::new(id, &[command as u8, node]).unwrap();
+        self.can.try_write(&frame)
+    }
+
+    /// Waits up to `timeout` for the next event, sending node guarding requests
+    /// while waiting.
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Option<Event>, C::Error> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            let now = self.clock.now();
+            while let Some(frame) = self.monitor.poll_transmit::<C::Frame>(now) {
+                self.can.try_write(&frame)?;
+            }
+            if let Some(event) = self.monitor.poll(now) {
+                return Ok(Some(event));
+            }
+            if now >= deadline {
+                return Ok(None);
+            }
+
+            let wake = self
+                .monitor
+                .next_deadline()
+                .map_or(deadline, |d| d.min(deadline));
+            if let Some(frame) = self.can.try_read_timeout(wake.saturating_sub(now))? {
+                let now = self.clock.now();
+                self.monitor.handle_frame(now, &frame);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Bus, Context, Node};
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Minimal NMT slave with heartbeat producer and node guarding responses.
+    struct Slave {
+        node: u8,
+        state: State,
+        heartbeat: Option<Duration>,
+        toggle: u8,
+        silent: bool,
+    }
+
+    impl Slave {
+        fn new(node: u8, heartbeat: Option<Duration>) -> Self {
+            Self {
+                node,
+                state: State::PreOperational,
+                heartbeat,
+                toggle: 0,
+                silent: false,
+            }
+        }
+
+        fn send_state(&mut self, value: u8, cx: &mut Context<'_>) {
+            if !self.silent {
+                cx.send(crate::Frame::new(cob_id(cob::HEARTBEAT, self.node), &[value]).unwrap());
+            }
+        }
+    }
+
+    impl Node for Slave {
+        fn on_frame(&mut self, frame: &crate::Frame, cx: &mut Context<'_>) {
+            if frame.id() == Id::Standard(StandardId::new(cob::NMT).unwrap()) {
+                let (command, node) = (frame.data()[0], frame.data()[1]);
+                if node != 0 && node != self.node {
+                    return;
+                }
+                match command {
+                    0x01 => self.state = State::Operational,
+                    0x02 => self.state = State::Stopped,
+                    0x80 => self.state = State::PreOperational,
+                    _ => {
+                        self.state = State::PreOperational;
+                        self.toggle = 0;
+                        self.send_state(0x00, cx);
+                        if let Some(period) = self.heartbeat {
+                            cx.wake_after(period);
+                        }
+                    }
+                }
+            } else if frame.is_remote_frame()
+                && frame.id() == Id::Standard(cob_id(cob::HEARTBEAT, self.node))
+            {
+                let value = self.toggle << 7 | self.state as u8;
+                self.toggle ^= 1;
+                self.send_state(value, cx);
+            }
+        }
+
+        fn on_timer(&mut self, cx: &mut Context<'_>) {
+            self.send_state(self.state as u8, cx);
+            cx.wake_after(self.heartbeat.unwrap());
+        }
+    }
+
+    fn setup(slave: Slave) -> (Master<sim::Endpoint, sim::VirtualClock>, Rc<RefCell<Slave>>) {
+        let bus = Bus::new();
+        let slave = bus.add_node(slave);
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        (Master::new(endpoint, clock), slave)
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn heartbeat() {
+        let (mut master, slave) = setup(Slave::new(3, Some(ms(100))));
+        master.monitor_mut().with_heartbeat(3, ms(250));
+
+        master.command(Command::ResetNode, 0).unwrap();
+        assert_eq!(master.next_event(ms(50)).unwrap(), Some(Event::BootUp(3)));
+        assert_eq!(master.next_event(ms(50)).unwrap(), None);
+        assert_eq!(
+            master.next_event(ms(100)).unwrap(),
+            Some(Event::StateChanged {
+                node: 3,
+                previous: Some(State::BootUp),
+                state: State::PreOperational
+            })
+        );
+
+        master.command(Command::Start, 3).unwrap();
+        assert_eq!(
+            master.next_event(ms(200)).unwrap(),
+            Some(Event::StateChanged {
+                node: 3,
+                previous: Some(State::PreOperational),
+                state: State::Operational
+            })
+        );
+        assert_eq!(master.monitor().state(3), Some(State::Operational));
+
+        slave.borrow_mut().silent = true;
+        assert_eq!(master.next_event(ms(1000)).unwrap(), Some(Event::Lost(3)));
+        assert_eq!(master.monitor().state(3), None);
+        assert_eq!(master.next_event(ms(1000)).unwrap(), None);
+    }
+
+    #[test]
+    fn node_guarding() {
+        let (mut master, slave) = setup(Slave::new(4, None));
+        master.monitor_mut().with_guarding(4, ms(100), 3);
+
+        assert_eq!(
+            master.next_event(ms(50)).unwrap(),
+            Some(Event::StateChanged {
+                node: 4,
+                previous: None,
+                state: State::PreOperational
+            })
+        );
+        assert_eq!(master.next_event(ms(500)).unwrap(), None);
+
+        slave.borrow_mut().toggle ^= 1;
+        assert_eq!(
+            master.next_event(ms(500)).unwrap(),
+            Some(Event::ToggleError(4))
+        );
+
+        slave.borrow_mut().silent = true;
+        let start = master.clock.now();
+        assert_eq!(master.next_event(ms(1000)).unwrap(), Some(Event::Lost(4)));
+        assert!(master.clock.now() - start <= ms(300));
+    }
+}
@@ -0,0 +1,948 @@
+//! Object dictionary and EDS/DCF files (CiA 306).
+//!
+//! [`ObjectDictionary::parse()`] builds a dictionary from the contents of an
+//! electronic data sheet. Device configuration files are EDS files with the
+//! parameter values and node ID of a particular node, these are available in
+//! [`Variable::value`] and [`ObjectDictionary::node_id()`].
+//!
+//! ```no_run
+//! use pcan_basic::canopen::od::ObjectDictionary;
+//!
+//! let od = ObjectDictionary::load("drive.eds")?;
+//! for (index, object) in od.objects() {
+//!     println!("{:04X} {}", index, object.name);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+    fmt, fs, io,
+    path::Path,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// An entry of the file is missing or invalid.
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// Static data types with their index in the object dictionary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    Boolean = 0x01,
+    Integer8 = 0x02,
+    Integer16 = 0x03,
+    Integer32 = 0x04,
+    Unsigned8 = 0x05,
+    Unsigned16 = 0x06,
+    Unsigned32 = 0x07,
+    Real32 = 0x08,
+    VisibleString = 0x09,
+    OctetString = 0x0A,
+    UnicodeString = 0x0B,
+    Domain = 0x0F,
+    Real64 = 0x11,
+    Integer64 = 0x15,
+    Unsigned64 = 0x1B,
+}
+
+impl DataType {
+    pub fn from_u16(value: u16) -> Option<Self> {
+        use DataType::*;
+        Some(match value {
+            0x01 => Boolean,
+            0x02 => Integer8,
+            0x03 => Integer16,
+            0x04 => Integer32,
+            0x05 => Unsigned8,
+            0x06 => Unsigned16,
+            0x07 => Unsigned32,
+            0x08 => Real32,
+            0x09 => VisibleString,
+            0x0A => OctetString,
+            0x0B => UnicodeString,
+            0x0F => Domain,
+            0x11 => Real64,
+            0x15 => Integer64,
+            0x1B => Unsigned64,
+            _ => return None,
+        })
+    }
+
+    /// Size in bits when mapped to a PDO, `None` for types of variable length.
+    pub fn bits(self) -> Option<u8> {
+        use DataType::*;
+        match self {
+            Boolean => Some(1),
+            Integer8 | Unsigned8 => Some(8),
+            Integer16 | Unsigned16 => Some(16),
+            Integer32 | Unsigned32 | Real32 => Some(32),
+            Integer64 | Unsigned64 | Real64 => Some(64),
+            VisibleString | OctetString | UnicodeString | Domain => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+    /// Read-write, mapped to a TPDO.
+    ReadWriteRead,
+    /// Read-write, mapped to an RPDO.
+    ReadWriteWrite,
+    Const,
+}
+
+impl AccessType {
+    pub fn is_readable(self) -> bool {
+        self != AccessType::WriteOnly
+    }
+
+    pub fn is_writable(self) -> bool {
+        !matches!(self, AccessType::ReadOnly | AccessType::Const)
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        Some(match text.to_ascii_lowercase().as_str() {
+            "ro" => AccessType::ReadOnly,
+            "wo" => AccessType::WriteOnly,
+            "rw" => AccessType::ReadWrite,
+            "rwr" => AccessType::ReadWriteRead,
+            "rww" => AccessType::ReadWriteWrite,
+            "const" => AccessType::Const,
+            _ => return None,
+        })
+    }
+}
+
+/// Value of an object dictionary entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Boolean(bool),
+    Integer8(i8),
+    Integer16(i16),
+    Integer32(i32),
+    Integer64(i64),
+    Unsigned8(u8),
+    Unsigned16(u16),
+    Unsigned32(u32),
+    Unsigned64(u64),
+    Real32(f32),
+    Real64(f64),
+    VisibleString(String),
+    OctetString(Vec<u8>),
+    UnicodeString(String),
+    Domain(Vec<u8>),
+}
+
+impl Value {
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Value::Boolean(_) => DataType::Boolean,
+            Value::Integer8(_) => DataType::Integer8,
+            Value::Integer16(_) => DataType::Integer16,
+            Value::Integer32(_) => DataType::Integer32,
+            Value::Integer64(_) => DataType::Integer64,
+            Value::Unsigned8(_) => DataType::Unsigned8,
+            Value::Unsigned16(_) => DataType::Unsigned16,
+            Value::Unsigned32(_) => DataType::Unsigned32,
+            Value::Unsigned64(_) => DataType::Unsigned64,
+            Value::Real32(_) => DataType::Real32,
+            Value::Real64(_) => DataType::Real64,
+            Value::VisibleString(_) => DataType::VisibleString,
+            Value::OctetString(_) => DataType::OctetString,
+            Value::UnicodeString(_) => DataType::UnicodeString,
+            Value::Domain(_) => DataType::Domain,
+        }
+    }
+
+    /// Zero or empty value of a type.
+    pub fn zero(data_type: DataType) -> Self {
+        match data_type {
+            DataType::VisibleString => Value::VisibleString(String::new()),
+            DataType::OctetString => Value::OctetString(Vec::new()),
+            DataType::UnicodeString => Value::UnicodeString(String::new()),
+            DataType::Domain => Value::Domain(Vec::new()),
+            _ => Value::from_bits(data_type, 0).unwrap(),
+        }
+    }
+
+    /// Little endian representation as transferred by SDO.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Value::Boolean(v) => vec![*v as u8],
+            Value::Integer8(v) => v.to_le_bytes().to_vec(),
+            Value::Integer16(v) => v.to_le_bytes().to_vec(),
+            Value::Integer32(v) => v.to_le_bytes().to_vec(),
+            Value::Integer64(v) => v.to_le_bytes().to_vec(),
+            Value::Unsigned8(v) => v.to_le_bytes().to_vec(),
+            Value::Unsigned16(v) => v.to_le_bytes().to_vec(),
+            Value::Unsigned32(v) => v.to_le_bytes().to_vec(),
+            Value::Unsigned64(v) => v.to_le_bytes().to_vec(),
+            Value::Real32(v) => v.to_le_bytes().to_vec(),
+            Value::Real64(v) => v.to_le_bytes().to_vec(),
+            Value::VisibleString(v) => v.as_bytes().to_vec(),
+            Value::UnicodeString(v) => v.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            Value::OctetString(v) | Value::Domain(v) => v.clone(),
+        }
+    }
+
+    /// Returns `None` if the length does not match the type.
+    pub fn decode(data_type: DataType, data: &[u8]) -> Option<Self> {
+        match data_type {
+            DataType::VisibleString => String::from_utf8(data.to_vec())
+                .ok()
+                .map(Value::VisibleString),
+            DataType::OctetString => Some(Value::OctetString(data.to_vec())),
+            DataType::UnicodeString => {
+                if !data.len().is_multiple_of(2) {
+                    return None;
+                }
+                let units: Vec<u16> = data
+                    .chunks(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16(&units).ok().map(Value::UnicodeString)
+            }
+            DataType::Domain => Some(Value::Domain(data.to_vec())),
+            DataType::Boolean => match data {
+                [b] => Some(Value::Boolean(*b != 0)),
+                _ => None,
+            },
+            _ => {
+                let bytes = data_type.bits()? as usize / 8;
+                if data.len() != bytes {
+                    return None;
+                }
+                let mut raw = [0; 8];
+                raw[..bytes].copy_from_slice(data);
+                Value::from_bits(data_type, u64::from_le_bytes(raw))
+            }
+        }
+    }
+
+    /// Value of the lowest bits of `bits`, `None` for types of variable length.
+    pub fn from_bits(data_type: DataType, bits: u64) -> Option<Self> {
+        Some(match data_type {
+            DataType::Boolean => Value::Boolean(bits & 1 != 0),
+            DataType::Integer8 => Value::Integer8(bits as i8),
+            DataType::Integer16 => Value::Integer16(bits as i16),
+            DataType::Integer32 => Value::Integer32(bits as i32),
+            DataType::Integer64 => Value::Integer64(bits as i64),
+            DataType::Unsigned8 => Value::Unsigned8(bits as u8),
+            DataType::Unsigned16 => Value::Unsigned16(bits as u16),
+            DataType::Unsigned32 => Value::Unsigned32(bits as u32),
+            DataType::Unsigned64 => Value::Unsigned64(bits),
+            DataType::Real32 => Value::Real32(f32::from_bits(bits as u32)),
+            DataType::Real64 => Value::Real64(f64::from_bits(bits)),
+            _ => return None,
+        })
+    }
+
+    /// Bit pattern of numeric values, `None` for types of variable length.
+    pub fn to_bits(&self) -> Option<u64> {
+        Some(match *self {
+            Value::Boolean(v) => v as u64,
+            Value::Integer8(v) => v as u8 as u64,
+            Value::Integer16(v) => v as u16 as u64,
+            Value::Integer32(v) => v as u32 as u64,
+            Value::Integer64(v) => v as u64,
+            Value::Unsigned8(v) => v as u64,
+            Value::Unsigned16(v) => v as u64,
+            Value::Unsigned32(v) => v as u64,
+            Value::Unsigned64(v) => v,
+            Value::Real32(v) => v.to_bits() as u64,
+            Value::Real64(v) => v.to_bits(),
+            _ => return None,
+        })
+    }
+
+    /// Numeric values as floating point, e.g. for scaling.
+    pub fn as_f64(&self) -> Option<f64> {
+        Some(match *self {
+            Value::Boolean(v) => v as u8 as f64,
+            Value::Integer8(v) => v as f64,
+            Value::Integer16(v) => v as f64,
+            Value::Integer32(v) => v as f64,
+            Value::Integer64(v) => v as f64,
+            Value::Unsigned8(v) => v as f64,
+            Value::Unsigned16(v) => v as f64,
+            Value::Unsigned32(v) => v as f64,
+            Value::Unsigned64(v) => v as f64,
+            Value::Real32(v) => v as f64,
+            Value::Real64(v) => v,
+            _ => return None,
+        })
+    }
+
+    /// Integer of `data_type`, `None` if out of range or not an integer type.
+    fn from_integer(data_type: DataType, n: i128) -> Option<Self> {
+        Some(match data_type {
+            DataType::Boolean => Value::Boolean(n != 0),
+            DataType::Integer8 => Value::Integer8(n.try_into().ok()?),
+            DataType::Integer16 => Value::Integer16(n.try_into().ok()?),
+            DataType::Integer32 => Value::Integer32(n.try_into().ok()?),
+            DataType::Integer64 => Value::Integer64(n.try_into().ok()?),
+            DataType::Unsigned8 => Value::Unsigned8(n.try_into().ok()?),
+            DataType::Unsigned16 => Value::Unsigned16(n.try_into().ok()?),
+            DataType::Unsigned32 => Value::Unsigned32(n.try_into().ok()?),
+            DataType::Unsigned64 => Value::Unsigned64(n.try_into().ok()?),
+            _ => return None,
+        })
+    }
+
+    fn integer(&self) -> Option<i128> {
+        Some(match *self {
+            Value::Integer8(v) => v as i128,
+            Value::Integer16(v) => v as i128,
+            Value::Integer32(v) => v as i128,
+            Value::Integer64(v) => v as i128,
+            Value::Unsigned8(v) => v as i128,
+            Value::Unsigned16(v) => v as i128,
+            Value::Unsigned32(v) => v as i128,
+            Value::Unsigned64(v) => v as i128,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Boolean(v) => write!(f, "{}", v),
+            Value::Integer8(v) => write!(f, "{}", v),
+            Value::Integer16(v) => write!(f, "{}", v),
+            Value::Integer32(v) => write!(f, "{}", v),
+            Value::Integer64(v) => write!(f, "{}", v),
+            Value::Unsigned8(v) => write!(f, "{}", v),
+            Value::Unsigned16(v) => write!(f, "{}", v),
+            Value::Unsigned32(v) => write!(f, "{}", v),
+            Value::Unsigned64(v) => write!(f, "{}", v),
+            Value::Real32(v) => write!(f, "{}", v),
+            Value::Real64(v) => write!(f, "{}", v),
+            Value::VisibleString(v) | Value::UnicodeString(v) => write!(f, "{}", v),
+            Value::OctetString(v) | Value::Domain(v) => {
+                v.iter().try_for_each(|b| write!(f, "{:02X}", b))
+            }
+        }
+    }
+}
+
+macro_rules! impl_from {
+    ($($t:ty => $variant:ident),*) => {
+        $(
+            impl From<$t> for Value {
+                fn from(value: $t) -> Self {
+                    Value::$variant(value)
+                }
+            }
+        )*
+    };
+}
+
+impl_from!(
+    bool => Boolean,
+    i8 => Integer8,
+    i16 => Integer16,
+    i32 => Integer32,
+    i64 => Integer64,
+    u8 => Unsigned8,
+    u16 => Unsigned16,
+    u32 => Unsigned32,
+    u64 => Unsigned64,
+    f32 => Real32,
+    f64 => Real64,
+    String => VisibleString
+);
+
+/// Kind of an object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectType {
+    Domain = 0x02,
+    Var = 0x07,
+    Array = 0x08,
+    Record = 0x09,
+}
+
+/// A single entry of the dictionary, addressed by index and subindex.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub data_type: DataType,
+    pub access: AccessType,
+    /// May be mapped to a PDO.
+    pub pdo_mapping: bool,
+    pub default: Option<Value>,
+    /// Value configured in a DCF file, or set at runtime.
+    pub value: Option<Value>,
+    /// The default value is relative to the node ID (`$NODEID` in EDS files).
+    pub default_relative: bool,
+    /// The value is relative to the node ID.
+    pub value_relative: bool,
+}
+
+impl Variable {
+    pub fn new(name: impl Into<String>, data_type: DataType, access: AccessType) -> Self {
+        Self {
+            name: name.into(),
+            data_type,
+            access,
+            pdo_mapping: false,
+            default: None,
+            value: None,
+            default_relative: false,
+            value_relative: false,
+        }
+    }
+
+    /// The value, or the default value if not set.
+    pub fn current(&self) -> Option<&Value> {
+        self.value.as_ref().or(self.default.as_ref())
+    }
+
+    /// Sets the value, it is no longer relative to the node ID.
+    pub fn set(&mut self, value: Value) {
+        self.value = Some(value);
+        self.value_relative = false;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub name: String,
+    pub object_type: ObjectType,
+    /// Entries by subindex. Variables and domains only have subindex 0.
+    pub entries: BTreeMap<u8, Variable>,
+}
+
+impl Object {
+    /// An object with a single entry.
+    pub fn var(variable: Variable) -> Self {
+        Self {
+            name: variable.name.clone(),
+            object_type: ObjectType::Var,
+            entries: std::iter::once((0, variable)).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ObjectDictionary {
+    objects: BTreeMap<u16, Object>,
+    node_id: Option<u8>,
+}
+
+impl ObjectDictionary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let data = fs::read(path)?;
+        Self::parse(&String::from_utf8_lossy(&data))
+    }
+
+    /// Parses an EDS or DCF file.
+    ///
+    /// Objects below index 0x1000 define data types and are skipped. Values
+    /// relative to the node ID are resolved if the file is a DCF.
+    pub fn parse(text: &str) -> Result<Self, Error> {
+        let sections = ini(text)?;
+        let mut od = Self::new();
+
+        for (name, section) in &sections {
+            let index = match u16::from_str_radix(name, 16) {
+                Ok(index) if name.len() == 4 && index >= 0x1000 => index,
+                _ => continue,
+            };
+            let object_type = match section.get("objecttype") {
+                Some(_) => match section.integer("objecttype")? {
+                    0x02 => ObjectType::Domain,
+                    0x07 => ObjectType::Var,
+                    0x08 => ObjectType::Array,
+                    0x09 => ObjectType::Record,
+                    _ => continue,
+                },
+                None => ObjectType::Var,
+            };
+            let object_name = section.require("parametername")?.to_string();
+
+            let mut entries = BTreeMap::new();
+            if matches!(object_type, ObjectType::Var | ObjectType::Domain) {
+                entries.insert(0, variable(section, object_type)?);
+            } else if section.get("compactsubobj").is_some_and(|v| !v.is_empty()) {
+                let count = u8::try_from(section.integer("compactsubobj")?)
+                    .map_err(|_| section.error("CompactSubObj out of range"))?;
+                let names = sections.get(&format!("{}name", name));
+                let mut template = variable(section, ObjectType::Var)?;
+                let mut highest =
+                    Variable::new("NrOfObjects", DataType::Unsigned8, AccessType::ReadOnly);
+                highest.default = Some(Value::Unsigned8(count));
+                entries.insert(0, highest);
+                for subindex in 1..=count {
+                    template.name = names
+                        .and_then(|n| n.get(&subindex.to_string()))
+                        .map_or_else(|| format!("{}{}", object_name, subindex), str::to_string);
+                    entries.insert(subindex, template.clone());
+                }
+            } else {
+                let prefix = format!("{}sub", name);
+                for (sub_name, sub_section) in sections.range(prefix.clone()..) {
+                    let subindex = match sub_name.strip_prefix(&prefix) {
+                        Some(subindex) => u8::from_str_radix(subindex, 16)
+                            .map_err(|_| sub_section.error("Invalid subindex"))?,
+                        None => break,
+                    };
+                    entries.insert(subindex, variable(sub_section, ObjectType::Var)?);
+                }
+            }
+
+            od.insert(
+                index,
+                Object {
+                    name: object_name,
+                    object_type,
+                    entries,
+                },
+            );
+        }
+
+        if let Some(commissioning) = sections.get("devicecomissioning") {
+            if commissioning.get("nodeid").is_some_and(|v| !v.is_empty()) {
+                let node_id = u8::try_from(commissioning.integer("nodeid")?)
+                    .map_err(|_| commissioning.error("NodeID out of range"))?;
+                od.set_node_id(node_id);
+            }
+        }
+
+        Ok(od)
+    }
+
+    pub fn insert(&mut self, index: u16, object: Object) {
+        self.objects.insert(index, object);
+    }
+
+    pub fn object(&self, index: u16) -> Option<&Object> {
+        self.objects.get(&index)
+    }
+
+    pub fn objects(&self) -> impl Iterator<Item = (u16, &Object)> + '_ {
+        self.objects.iter().map(|(&index, object)| (index, object))
+    }
+
+    pub fn get(&self, index: u16, subindex: u8) -> Option<&Variable> {
+        self.objects.get(&index)?.entries.get(&subindex)
+    }
+
+    pub fn get_mut(&mut self, index: u16, subindex: u8) -> Option<&mut Variable> {
+        self.objects.get_mut(&index)?.entries.get_mut(&subindex)
+    }
+
+    /// Current value of an entry, see [`Variable::current()`].
+    pub fn value(&self, index: u16, subindex: u8) -> Option<&Value> {
+        self.get(index, subindex)?.current()
+    }
+
+    /// Name of an entry, `Object.Entry` for entries of arrays and records.
+    pub fn name(&self, index: u16, subindex: u8) -> Option<String> {
+        let object = self.objects.get(&index)?;
+        let variable = object.entries.get(&subindex)?;
+        Some(match object.object_type {
+            ObjectType::Var | ObjectType::Domain => object.name.clone(),
+            _ => format!("{}.{}", object.name, variable.name),
+        })
+    }
+
+    /// Index and subindex of an entry by its [name](ObjectDictionary::name()).
+    pub fn find(&self, name: &str) -> Option<(u16, u8)> {
+        self.objects.iter().find_map(|(&index, object)| {
+            object
+                .entries
+                .keys()
+                .find(|&&subindex| self.name(index, subindex).as_deref() == Some(name))
+                .map(|&subindex| (index, subindex))
+        })
+    }
+
+    pub fn node_id(&self) -> Option<u8> {
+        self.node_id
+    }
+
+    /// Sets the node ID and updates all values relative to it.
+    pub fn set_node_id(&mut self, node_id: u8) {
+        let offset = node_id as i128 - self.node_id.unwrap_or(0) as i128;
+        for variable in self
+            .objects
+            .values_mut()
+            .flat_map(|o| o.entries.values_mut())
+        {
+            let Variable {
+                default,
+                value,
+                default_relative,
+                value_relative,
+                ..
+            } = variable;
+            let default = default.as_mut().filter(|_| *default_relative);
+            let value = value.as_mut().filter(|_| *value_relative);
+            for value in default.into_iter().chain(value) {
+                if let Some(n) = value.integer() {
+                    if let Some(v) = Value::from_integer(value.data_type(), n + offset) {
+                        *value = v;
+                    }
+                }
+            }
+        }
+        self.node_id = Some(node_id);
+    }
+}
+
+/// Keys of a section in lower case with their values and line numbers.
+struct Section {
+    line: usize,
+    entries: BTreeMap<String, (usize, String)>,
+}
+
+impl Section {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.entries.get(key).map(|(_, value)| value.as_str())
+    }
+
+    fn error(&self, message: &str) -> Error {
+        Error::Parse {
+            line: self.line,
+            message: message.to_string(),
+        }
+    }
+
+    fn require(&self, key: &str) -> Result<&str, Error> {
+        self.get(key)
+            .ok_or_else(|| self.error(&format!("Missing {}", key)))
+    }
+
+    fn integer(&self, key: &str) -> Result<i128, Error> {
+        let (line, value) = &self.entries[key];
+        parse_integer(value).ok_or_else(|| Error::Parse {
+            line: *line,
+            message: format!("Invalid {} {:?}", key, value),
+        })
+    }
+}
+
+/// Sections by lower case name.
+fn ini(text: &str) -> Result<BTreeMap<String, Section>, Error> {
+    let mut sections = BTreeMap::new();
+    let mut current: Option<&mut Section> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name.strip_suffix(']').ok_or_else(|| Error::Parse {
+                line: line_number,
+                message: "Unterminated section name".to_string(),
+            })?;
+            current = Some(
+                sections
+                    .entry(name.trim().to_ascii_lowercase())
+                    .or_insert(Section {
+                        line: line_number,
+                        entries: BTreeMap::new(),
+                    }),
+            );
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| Error::Parse {
+            line: line_number,
+            message: "Expected key=value".to_string(),
+        })?;
+        let section = current.as_mut().ok_or_else(|| Error::Parse {
+            line: line_number,
+            message: "Entry outside of a section".to_string(),
+        })?;
+        section.entries.insert(
+            key.trim().to_ascii_lowercase(),
+            (line_number, value.trim().to_string()),
+        );
+    }
+
+    Ok(sections)
+}
+
+fn variable(section: &Section, object_type: ObjectType) -> Result<Variable, Error> {
+    let data_type = match section.get("datatype") {
+        Some(_) => {
+            let value = section.integer("datatype")?;
+            u16::try_from(value)
+                .ok()
+                .and_then(DataType::from_u16)
+                .ok_or_else(|| section.error(&format!("Unsupported data type {:#06x}", value)))?
+        }
+        None if object_type == ObjectType::Domain => DataType::Domain,
+        None => return Err(section.error("Missing datatype")),
+    };
+    let access = match section.get("accesstype") {
+        Some(access) => AccessType::parse(access)
+            .ok_or_else(|| section.error(&format!("Invalid access type {:?}", access)))?,
+        None => AccessType::ReadWrite,
+    };
+
+    let mut variable = Variable::new(section.require("parametername")?, data_type, access);
+    variable.pdo_mapping = section.get("pdomapping").is_some_and(|v| v == "1");
+    for (key, target, target_relative) in [
+        (
+            "defaultvalue",
+            &mut variable.default,
+            &mut variable.default_relative,
+        ),
+        (
+            "parametervalue",
+            &mut variable.value,
+            &mut variable.value_relative,
+        ),
+    ] {
+        let (line, text) = match section.entries.get(key) {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let (value, relative) = parse_value(data_type, text).ok_or_else(|| Error::Parse {
+            line: *line,
+            message: format!("Invalid {} {:?}", key, text),
+        })?;
+        *target = value;
+        *target_relative = relative;
+    }
+    Ok(variable)
+}
+
+/// Returns the value and whether it is relative to the node ID. Empty values
+/// of numeric types are `None`.
+fn parse_value(data_type: DataType, text: &str) -> Option<(Option<Value>, bool)> {
+    let value = match data_type {
+        DataType::VisibleString => Value::VisibleString(text.to_string()),
+        DataType::UnicodeString => Value::UnicodeString(text.to_string()),
+        _ if text.is_empty() => return Some((None, false)),
+        DataType::OctetString | DataType::Domain => {
+            let hex: Vec<u8> = text.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+            let bytes = hex
+                .chunks(2)
+                .map(|c| u8::from_str_radix(std::str::from_utf8(c).ok()?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            Value::decode(data_type, &bytes)?
+        }
+        DataType::Real32 => Value::Real32(text.parse().ok()?),
+        DataType::Real64 => Value::Real64(text.parse().ok()?),
+        _ => {
+            let mut relative = false;
+            let mut sum = 0;
+            for term in text.split('+') {
+                let term = term.trim();
+                if term.eq_ignore_ascii_case("$nodeid") {
+                    relative = true;
+                } else {
+                    sum = parse_integer(term)?.checked_add(sum)?;
+                }
+            }
+            return Some((Some(Value::from_integer(data_type, sum)?), relative));
+        }
+    };
+    Some((Some(value), false))
+}
+
+/// Decimal, hexadecimal with `0x` prefix, or octal with a leading zero.
+fn parse_integer(text: &str) -> Option<i128> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i128::from_str_radix(hex, 16).ok()?
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        i128::from_str_radix(&digits[1..], 8).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DCF: &str = "
+[DeviceComissioning]
+NodeID=0x05
+
+; Mandatory objects
+[1000]
+ParameterName=Device type
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0x00020192
+PDOMapping=0
+
+[1003]
+ParameterName=Pre-defined error field
+ObjectType=0x8
+DataType=0x0007
+AccessType=ro
+CompactSubObj=2
+
+[1003Name]
+NrOfEntries=1
+2=Older error
+
+[1008]
+ParameterName=Manufacturer device name
+DataType=0x0009
+AccessType=const
+DefaultValue=Servo Drive
+
+[1800]
+ParameterName=TPDO communication parameter
+ObjectType=0x9
+SubNumber=3
+
+[1800sub0]
+ParameterName=Highest sub-index supported
+DataType=0x0005
+AccessType=const
+DefaultValue=5
+
+[1800sub1]
+ParameterName=COB-ID
+DataType=0x0007
+AccessType=rw
+DefaultValue=$NODEID+0x180
+
+[1800sub5]
+ParameterName=Event timer
+DataType=0x0006
+AccessType=rw
+DefaultValue=100
+ParameterValue=010
+
+[6064]
+ParameterName=Position actual value
+DataType=0x0004
+AccessType=ro
+PDOMapping=1
+DefaultValue=-1
+";
+
+    #[test]
+    fn parse_dcf() {
+        let mut od = ObjectDictionary::parse(DCF).unwrap();
+        assert_eq!(od.node_id(), Some(5));
+        assert_eq!(od.value(0x1000, 0), Some(&Value::Unsigned32(0x0002_0192)));
+        assert_eq!(
+            od.value(0x1008, 0),
+            Some(&Value::VisibleString("Servo Drive".to_string()))
+        );
+        assert_eq!(od.get(0x1008, 0).unwrap().access, AccessType::Const);
+
+        let errors = od.object(0x1003).unwrap();
+        assert_eq!(errors.object_type, ObjectType::Array);
+        assert_eq!(errors.entries.len(), 3);
+        assert_eq!(od.value(0x1003, 0), Some(&Value::Unsigned8(2)));
+        assert_eq!(
+            od.name(0x1003, 1).unwrap(),
+            "Pre-defined error field.Pre-defined error field1"
+        );
+        assert_eq!(od.get(0x1003, 2).unwrap().name, "Older error");
+
+        assert_eq!(od.value(0x1800, 1), Some(&Value::Unsigned32(0x185)));
+        assert_eq!(od.value(0x1800, 5), Some(&Value::Unsigned16(8)));
+        assert_eq!(
+            od.get(0x1800, 5).unwrap().default,
+            Some(Value::Unsigned16(100))
+        );
+        assert_eq!(
+            od.find("TPDO communication parameter.COB-ID"),
+            Some((0x1800, 1))
+        );
+        od.set_node_id(10);
+        assert_eq!(od.value(0x1800, 1), Some(&Value::Unsigned32(0x18A)));
+
+        let position = od.get(0x6064, 0).unwrap();
+        assert!(position.pdo_mapping);
+        assert_eq!(position.default, Some(Value::Integer32(-1)));
+        assert_eq!(od.find("Position actual value"), Some((0x6064, 0)));
+
+        assert_eq!(Value::Integer16(-2).encode(), [0xFE, 0xFF]);
+        assert_eq!(
+            Value::decode(DataType::Real32, &1.5f32.to_le_bytes()),
+            Some(Value::Real32(1.5))
+        );
+        assert_eq!(Value::decode(DataType::Unsigned16, &[1]), None);
+
+        let err =
+            ObjectDictionary::parse("[2000]\nParameterName=x\nDataType=0x0020\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: Unsupported data type 0x0020");
+    }
+
+    #[test]
+    fn resolved_parameter_value() {
+        let mut od = ObjectDictionary::parse(
+            "[1800]
+ParameterName=COB-ID
+DataType=0x0007
+DefaultValue=$NODEID+0x180
+ParameterValue=0x185
+",
+        )
+        .unwrap();
+        od.set_node_id(5);
+        assert_eq!(od.value(0x1800, 0), Some(&Value::Unsigned32(0x185)));
+        assert_eq!(
+            od.get(0x1800, 0).unwrap().default,
+            Some(Value::Unsigned32(0x185))
+        );
+
+        let err = ObjectDictionary::parse(&format!(
+            "[2000]\nParameterName=x\nDataType=0x0007\nDefaultValue={}+1\n",
+            i128::MAX
+        ))
+        .unwrap_err();
+        assert!(err.to_string().starts_with("line 4: Invalid defaultvalue"));
+    }
+}
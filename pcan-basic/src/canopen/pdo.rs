@@ -0,0 +1,503 @@
+//! Process data objects.
+//!
+//! A [`Pdo`] describes the COB-ID and the mapping of a PDO. Its signals are
+//! packed little endian in the order of the mapping, without alignment. The
+//! mapping is taken from an object dictionary, or read from a node by SDO.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use pcan_basic::{
+//!     canopen::{od::ObjectDictionary, pdo::{Kind, Pdo}, sdo::Client},
+//!     Interface, ReadTimeout, SystemClock,
+//! };
+//!
+//! let od = ObjectDictionary::load("drive.eds")?;
+//! let mut sdo = Client::new(Interface::init()?, SystemClock::new(), 5);
+//! let tpdo1 = Pdo::read(&mut sdo, Kind::Transmit, 1, Some(&od))?;
+//! let (mut can, _) = sdo.into_inner();
+//! while let Some(frame) = can.try_read_timeout(Duration::from_secs(1))? {
+//!     for (signal, value) in tpdo1.decode_frame(&frame).unwrap_or_default() {
+//!         println!("{}: {}", signal.name, value);
+//!     }
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::fmt;
+
+use embedded_can::{ExtendedId, Frame, Id};
+
+use super::{
+    cob,
+    od::{DataType, ObjectDictionary, Value},
+    sdo::{self, Client},
+};
+use crate::{Clock, ReadTimeout, StandardId};
+
+/// COB-ID bit of PDOs that are not valid.
+pub const INVALID: u32 = 1 << 31;
+/// COB-ID bit of PDOs with an extended identifier.
+pub const EXTENDED: u32 = 1 << 29;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Sdo(sdo::Error<E>),
+    /// A mapping entry is empty or the mapped signals exceed 64 bits.
+    InvalidMapping(u32),
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Sdo(err) => write!(f, "{}", err),
+            Error::InvalidMapping(entry) => write!(f, "Invalid PDO mapping {:#010x}", entry),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Error<E> {}
+
+impl<E> From<sdo::Error<E>> for Error<E> {
+    fn from(err: sdo::Error<E>) -> Self {
+        Error::Sdo(err)
+    }
+}
+
+/// Receive PDOs are consumed by the node, transmit PDOs are produced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Receive,
+    Transmit,
+}
+
+impl Kind {
+    /// Index of the communication parameter of PDO `number`, starting at 1.
+    pub fn communication_index(self, number: u16) -> u16 {
+        match self {
+            Kind::Receive => 0x1400 + number - 1,
+            Kind::Transmit => 0x1800 + number - 1,
+        }
+    }
+
+    /// Index of the mapping parameter of PDO `number`, starting at 1.
+    pub fn mapping_index(self, number: u16) -> u16 {
+        self.communication_index(number) + 0x200
+    }
+
+    /// COB-ID of the predefined connection set, for PDOs 1 to 4.
+    pub fn default_cob_id(self, number: u16, node: u8) -> Option<u32> {
+        let function = match (self, number) {
+            (Kind::Transmit, 1..=4) => cob::TPDO1 + (number - 1) * 0x100,
+            (Kind::Receive, 1..=4) => cob::RPDO1 + (number - 1) * 0x100,
+            _ => return None,
+        };
+        Some(function as u32 + node as u32)
+    }
+}
+
+/// An object mapped to a PDO.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signal {
+    pub name: String,
+    pub index: u16,
+    pub subindex: u8,
+    pub data_type: DataType,
+    /// Position of the first bit in the PDO.
+    pub offset: u16,
+    pub bits: u8,
+}
+
+impl Signal {
+    /// Dummy entries fill unused bits, they map a data type index.
+    pub fn is_dummy(&self) -> bool {
+        self.index < 0x1000
+    }
+
+    /// Mapping entry of the mapping parameter.
+    pub fn entry(&self) -> u32 {
+        (self.index as u32) << 16 | (self.subindex as u32) << 8 | self.bits as u32
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pdo {
+    /// COB-ID with the [`INVALID`] and [`EXTENDED`] flags.
+    pub cob_id: u32,
+    pub transmission_type: u8,
+    /// In multiples of 100 µs.
+    pub inhibit_time: u16,
+    /// In ms, 0 if disabled.
+    pub event_timer: u16,
+    pub signals: Vec<Signal>,
+}
+
+impl Pdo {
+    /// A valid PDO without mapping, transmitted asynchronously on events.
+    pub fn new(id: Id) -> Self {
+        let cob_id = match id {
+            Id::Standard(id) => id.as_raw() as u32,
+            Id::Extended(id) => id.as_raw() | EXTENDED,
+        };
+        Self {
+            cob_id,
+            transmission_type: 0xFF,
+            inhibit_time: 0,
+            event_timer: 0,
+            signals: Vec::new(),
+        }
+    }
+
+    /// Appends an entry to the mapping.
+    ///
+    /// The data type and name are taken from `od` if it has the object,
+    /// otherwise the signal is unsigned and named after the index.
+    pub fn map(&mut self, entry: u32, od: Option<&ObjectDictionary>) -> Result<&mut Self, u32> {
+        let index = (entry >> 16) as u16;
+        let subindex = (entry >> 8) as u8;
+        let bits = entry as u8;
+        let offset = self.bits();
+        if bits == 0 || offset + bits as u16 > 64 {
+            return Err(entry);
+        }
+
+        let variable = od.and_then(|od| od.get(index, subindex));
+        let data_type = match variable {
+            Some(variable) => variable.data_type,
+            None if index < 0x1000 => DataType::from_u16(index).ok_or(entry)?,
+            None => match bits {
+                1 => DataType::Boolean,
+                2..=8 => DataType::Unsigned8,
+                9..=16 => DataType::Unsigned16,
+                17..=32 => DataType::Unsigned32,
+                _ => DataType::Unsigned64,
+            },
+        };
+        let name = od
+            .and_then(|od| od.name(index, subindex))
+            .unwrap_or_else(|| format!("{:04X}sub{}", index, subindex));
+        self.signals.push(Signal {
+            name,
+            index,
+            subindex,
+            data_type,
+            offset,
+            bits,
+        });
+        Ok(self)
+    }
+
+    /// PDO `number` as configured in an object dictionary, `None` if the
+    /// parameters are missing or the mapping is invalid.
+    pub fn from_dictionary(od: &ObjectDictionary, kind: Kind, number: u16) -> Option<Self> {
+        let communication = kind.communication_index(number);
+        let mapping = kind.mapping_index(number);
+        let integer = |index, subindex| od.value(index, subindex)?.to_bits();
+
+        let mut pdo = Pdo::new(Id::Standard(StandardId::ZERO));
+        pdo.cob_id = integer(communication, 1)? as u32;
+        pdo.transmission_type = integer(communication, 2).unwrap_or(0xFF) as u8;
+        pdo.inhibit_time = integer(communication, 3).unwrap_or(0) as u16;
+        pdo.event_timer = integer(communication, 5).unwrap_or(0) as u16;
+        for subindex in 1..=integer(mapping, 0)? as u8 {
+            pdo.map(integer(mapping, subindex)? as u32, Some(od)).ok()?;
+        }
+        Some(pdo)
+    }
+
+    /// Reads the parameters of PDO `number` from a node.
+    ///
+    /// Names and data types of the signals are taken from `od` if given.
+    pub fn read<C, K>(
+        sdo: &mut Client<C, K>,
+        kind: Kind,
+        number: u16,
+        od: Option<&ObjectDictionary>,
+    ) -> Result<Self, Error<C::Error>>
+    where
+        C: ReadTimeout,
+        C::Frame: Frame,
+        K: Clock,
+    {
+        let communication = kind.communication_index(number);
+        let mapping = kind.mapping_index(number);
+
+        let mut pdo = Pdo::new(Id::Standard(StandardId::ZERO));
+        pdo.cob_id = sdo.read(communication, 1)?;
+        pdo.transmission_type = sdo.read(communication, 2)?;
+        // Inhibit time and event timer are optional.
+        pdo.inhibit_time = optional(sdo.read(communication, 3))?;
+        pdo.event_timer = optional(sdo.read(communication, 5))?;
+        let count: u8 = sdo.read(mapping, 0)?;
+        for subindex in 1..=count {
+            let entry = sdo.read(mapping, subindex)?;
+            pdo.map(entry, od).map_err(Error::InvalidMapping)?;
+        }
+        Ok(pdo)
+    }
+
+    /// Configures PDO `number` of a node.
+    ///
+    /// The PDO is invalidated while the mapping is changed. Inhibit time and
+    /// event timer are only written if not 0.
+    pub fn write<C, K>(
+        &self,
+        sdo: &mut Client<C, K>,
+        kind: Kind,
+        number: u16,
+    ) -> Result<(), sdo::Error<C::Error>>
+    where
+        C: ReadTimeout,
+        C::Frame: Frame,
+        K: Clock,
+    {
+        let communication = kind.communication_index(number);
+        let mapping = kind.mapping_index(number);
+
+        sdo.write(communication, 1, &(self.cob_id | INVALID))?;
+        sdo.write(communication, 2, &self.transmission_type)?;
+        if self.inhibit_time != 0 {
+            sdo.write(communication, 3, &self.inhibit_time)?;
+        }
+        if self.event_timer != 0 {
+            sdo.write(communication, 5, &self.event_timer)?;
+        }
+        sdo.write(mapping, 0, &0u8)?;
+        for (subindex, signal) in (1..).zip(&self.signals) {
+            sdo.write(mapping, subindex, &signal.entry())?;
+        }
+        sdo.write(mapping, 0, &(self.signals.len() as u8))?;
+        sdo.write(communication, 1, &self.cob_id)
+    }
+
+    /// CAN identifier, `None` if the COB-ID is not valid.
+    pub fn id(&self) -> Option<Id> {
+        if self.cob_id & INVALID != 0 {
+            None
+        } else if self.cob_id & EXTENDED != 0 {
+            ExtendedId::new(self.cob_id & ExtendedId::MAX.as_raw()).map(Id::Extended)
+        } else {
+            StandardId::new(self.cob_id as u16 & StandardId::MAX.as_raw()).map(Id::Standard)
+        }
+    }
+
+    /// Number of mapped bits.
+    pub fn bits(&self) -> u16 {
+        self.signals.last().map_or(0, |s| s.offset + s.bits as u16)
+    }
+
+    /// Number of bytes of the PDO.
+    pub fn len(&self) -> usize {
+        (self.bits() as usize).div_ceil(8)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signals.is_empty()
+    }
+
+    pub fn signal(&self, name: &str) -> Option<&Signal> {
+        self.signals.iter().find(|s| s.name == name)
+    }
+
+    /// Values of the signals, `None` if `data` is shorter than the mapping.
+    pub fn decode(&self, data: &[u8]) -> Option<Vec<(&Signal, Value)>> {
+        if data.len() < self.len() {
+            return None;
+        }
+        let raw = raw(data);
+        let values = self
+            .signals
+            .iter()
+            .filter(|s| !s.is_dummy())
+            .map(|signal| (signal, decode_signal(signal, raw)))
+            .collect();
+        Some(values)
+    }
+
+    /// Values of the signals of a frame with the identifier of the PDO.
+    pub fn decode_frame<F: Frame>(&self, frame: &F) -> Option<Vec<(&Signal, Value)>> {
+        if frame.is_remote_frame() || Some(frame.id()) != self.id() {
+            return None;
+        }
+        self.decode(frame.data())
+    }
+
+    /// Packs the values of signals by name, missing signals are 0.
+    pub fn encode(&self, values: &[(&str, Value)]) -> Vec<u8> {
+        self.encode_with(|signal| {
+            values
+                .iter()
+                .find(|(name, _)| *name == signal.name)
+                .map(|(_, value)| value.clone())
+        })
+    }
+
+    /// Packs the values of signals returned by `value`, missing signals are 0.
+    pub fn encode_with(&self, mut value: impl FnMut(&Signal) -> Option<Value>) -> Vec<u8> {
+        let mut raw = 0u64;
+        for signal in self.signals.iter().filter(|s| !s.is_dummy()) {
+            let bits = value(signal).and_then(|v| encode_signal(&v)).unwrap_or(0);
+            raw |= (bits & mask(signal.bits)) << signal.offset;
+        }
+        raw.to_le_bytes()[..self.len()].to_vec()
+    }
+}
+
+/// Treats a missing object like the value 0.
+fn optional<T: Default, E>(result: Result<T, sdo::Error<E>>) -> Result<T, sdo::Error<E>> {
+    match result {
+        Err(sdo::Error::Abort { code, .. })
+            if code == sdo::abort::NO_OBJECT || code == sdo::abort::NO_SUBINDEX =>
+        {
+            Ok(T::default())
+        }
+        result => result,
+    }
+}
+
+fn raw(data: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    let len = data.len().min(8);
+    bytes[..len].copy_from_slice(&data[..len]);
+    u64::from_le_bytes(bytes)
+}
+
+fn mask(bits: u8) -> u64 {
+    u64::MAX >> (64 - bits as u32)
+}
+
+fn decode_signal(signal: &Signal, raw: u64) -> Value {
+    let mut bits = raw >> signal.offset & mask(signal.bits);
+    let data_type = signal.data_type;
+    let signed = matches!(
+        data_type,
+        DataType::Integer8 | DataType::Integer16 | DataType::Integer32 | DataType::Integer64
+    );
+    if signed && bits >> (signal.bits - 1) & 1 != 0 {
+        bits |= !mask(signal.bits);
+    }
+    Value::from_bits(data_type, bits).unwrap_or_else(|| {
+        let bytes = bits.to_le_bytes();
+        Value::decode(data_type, &bytes[..(signal.bits as usize).div_ceil(8)])
+            .unwrap_or_else(|| Value::Domain(bytes.to_vec()))
+    })
+}
+
+fn encode_signal(value: &Value) -> Option<u64> {
+    value.to_bits().or_else(|| {
+        let data = value.encode();
+        (data.len() <= 8).then(|| raw(&data))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canopen::od::{AccessType, Object, ObjectType, Variable};
+
+    fn dictionary() -> ObjectDictionary {
+        let mut od = ObjectDictionary::new();
+        let mut status = Variable::new("Statusword", DataType::Unsigned16, AccessType::ReadOnly);
+        status.pdo_mapping = true;
+        od.insert(0x6041, Object::var(status));
+        od.insert(
+            0x6064,
+            Object::var(Variable::new(
+                "Position actual value",
+                DataType::Integer32,
+                AccessType::ReadOnly,
+            )),
+        );
+        od.insert(
+            0x2000,
+            Object {
+                name: "Inputs".to_string(),
+                object_type: ObjectType::Array,
+                entries: (1..=2)
+                    .map(|i| {
+                        let name = format!("Input{}", i);
+                        (
+                            i,
+                            Variable::new(name, DataType::Boolean, AccessType::ReadOnly),
+                        )
+                    })
+                    .collect(),
+            },
+        );
+
+        let record = |entries: Vec<(u8, Value)>| Object {
+            name: String::new(),
+            object_type: ObjectType::Record,
+            entries: entries
+                .into_iter()
+                .map(|(subindex, value)| {
+                    let mut variable = Variable::new("", value.data_type(), AccessType::ReadWrite);
+                    variable.default = Some(value);
+                    (subindex, variable)
+                })
+                .collect(),
+        };
+        od.insert(
+            0x1800,
+            record(vec![
+                (1, Value::Unsigned32(0x185)),
+                (2, Value::Unsigned8(1)),
+            ]),
+        );
+        od.insert(
+            0x1A00,
+            record(vec![
+                (0, Value::Unsigned8(5)),
+                (1, Value::Unsigned32(0x6041_0010)),
+                (2, Value::Unsigned32(0x2000_0101)),
+                (3, Value::Unsigned32(0x0005_0006)),
+                (4, Value::Unsigned32(0x2000_0201)),
+                (5, Value::Unsigned32(0x6064_0020)),
+            ]),
+        );
+        od
+    }
+
+    #[test]
+    fn decode_and_encode() {
+        let od = dictionary();
+        let pdo = Pdo::from_dictionary(&od, Kind::Transmit, 1).unwrap();
+        assert_eq!(
+            pdo.id(),
+            Some(Id::Standard(StandardId::new(0x185).unwrap()))
+        );
+        assert_eq!(pdo.transmission_type, 1);
+        assert_eq!(pdo.bits(), 56);
+        assert_eq!(pdo.signals[1].name, "Inputs.Input1");
+        assert_eq!(pdo.signals[4].offset, 24);
+
+        let data = [0x37, 0x06, 0b1000_0001, 0xFE, 0xFF, 0xFF, 0xFF];
+        let values = pdo.decode(&data).unwrap();
+        let values: Vec<_> = values.iter().map(|(s, v)| (s.name.as_str(), v)).collect();
+        assert_eq!(
+            values,
+            [
+                ("Statusword", &Value::Unsigned16(0x0637)),
+                ("Inputs.Input1", &Value::Boolean(true)),
+                ("Inputs.Input2", &Value::Boolean(true)),
+                ("Position actual value", &Value::Integer32(-2)),
+            ]
+        );
+        assert!(pdo.decode(&data[..6]).is_none());
+        let frame = crate::Frame::new(StandardId::new(0x185).unwrap(), &data).unwrap();
+        assert_eq!(pdo.decode_frame(&frame).unwrap().len(), 4);
+
+        let encoded = pdo.encode(&[
+            ("Statusword", Value::Unsigned16(0x0637)),
+            ("Inputs.Input1", Value::Boolean(true)),
+            ("Inputs.Input2", Value::Boolean(true)),
+            ("Position actual value", Value::Integer32(-2)),
+        ]);
+        assert_eq!(encoded, data);
+
+        let mut unknown = Pdo::new(Id::Standard(StandardId::new(0x201).unwrap()));
+        unknown.map(0x6040_0010, None).unwrap();
+        assert_eq!(unknown.signals[0].name, "6040sub0");
+        assert_eq!(unknown.signals[0].data_type, DataType::Unsigned16);
+        assert_eq!(unknown.map(0x6060_0038, None), Err(0x6060_0038));
+    }
+}
@@ -0,0 +1,715 @@
+//! SDO client with expedited, segmented and block transfers.
+//!
+//! Upload reads an object dictionary entry of the server, download writes it.
+//! Expedited transfers carry up to 4 bytes in the initiate message, larger
+//! values are sent in segments of 7 bytes. Block transfers send up to 127
+//! segments before waiting for a confirmation and protect the data with a
+//! CRC.
+//!
+//! ```no_run
+//! use pcan_basic::{canopen::sdo::Client, Interface, SystemClock};
+//!
+//! let can = Interface::init()?;
+//! let mut sdo = Client::new(can, SystemClock::new(), 5);
+//! let device_type: u32 = sdo.read(0x1000, 0)?;
+//! let name: String = sdo.read(0x1008, 0)?;
+//! sdo.write(0x1017, 0, &1000u16)?;
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{convert::TryInto, fmt, time::Duration};
+
+use embedded_can::{Frame, Id};
+
+use super::{cob, cob_id};
+use crate::{Clock, ReadTimeout, StandardId};
+
+/// Abort codes.
+pub mod abort {
+    pub const TOGGLE_BIT: u32 = 0x0503_0000;
+    pub const TIMEOUT: u32 = 0x0504_0000;
+    pub const COMMAND: u32 = 0x0504_0001;
+    pub const BLOCK_SIZE: u32 = 0x0504_0002;
+    pub const SEQUENCE_NUMBER: u32 = 0x0504_0003;
+    pub const CRC: u32 = 0x0504_0004;
+    pub const OUT_OF_MEMORY: u32 = 0x0504_0005;
+    pub const UNSUPPORTED_ACCESS: u32 = 0x0601_0000;
+    pub const WRITE_ONLY: u32 = 0x0601_0001;
+    pub const READ_ONLY: u32 = 0x0601_0002;
+    pub const NO_OBJECT: u32 = 0x0602_0000;
+    pub const NOT_MAPPABLE: u32 = 0x0604_0041;
+    pub const PDO_LENGTH: u32 = 0x0604_0042;
+    pub const INCOMPATIBLE_PARAMETER: u32 = 0x0604_0043;
+    pub const INCOMPATIBLE_DEVICE: u32 = 0x0604_0047;
+    pub const HARDWARE: u32 = 0x0606_0000;
+    pub const LENGTH: u32 = 0x0607_0010;
+    pub const LENGTH_TOO_HIGH: u32 = 0x0607_0012;
+    pub const LENGTH_TOO_LOW: u32 = 0x0607_0013;
+    pub const NO_SUBINDEX: u32 = 0x0609_0011;
+    pub const INVALID_VALUE: u32 = 0x0609_0030;
+    pub const VALUE_TOO_HIGH: u32 = 0x0609_0031;
+    pub const VALUE_TOO_LOW: u32 = 0x0609_0032;
+    pub const MAX_BELOW_MIN: u32 = 0x0609_0036;
+    pub const RESOURCE: u32 = 0x060A_0023;
+    pub const GENERAL: u32 = 0x0800_0000;
+    pub const STORE: u32 = 0x0800_0020;
+    pub const STORE_LOCAL_CONTROL: u32 = 0x0800_0021;
+    pub const STORE_DEVICE_STATE: u32 = 0x0800_0022;
+    pub const NO_DICTIONARY: u32 = 0x0800_0023;
+    pub const NO_DATA: u32 = 0x0800_0024;
+
+    /// Description of an abort code from CiA 301.
+    pub fn description(code: u32) -> &'static str {
+        match code {
+            TOGGLE_BIT => "Toggle bit not alternated",
+            TIMEOUT => "SDO protocol timed out",
+            COMMAND => "Client/server command specifier not valid or unknown",
+            BLOCK_SIZE => "Invalid block size",
+            SEQUENCE_NUMBER => "Invalid sequence number",
+            CRC => "CRC error",
+            OUT_OF_MEMORY => "Out of memory",
+            UNSUPPORTED_ACCESS => "Unsupported access to an object",
+            WRITE_ONLY => "Attempt to read a write only object",
+            READ_ONLY => "Attempt to write a read only object",
+            NO_OBJECT => "Object does not exist in the object dictionary",
+            NOT_MAPPABLE => "Object cannot be mapped to the PDO",
+            PDO_LENGTH => "Number and length of objects to be mapped exceeds PDO length",
+            INCOMPATIBLE_PARAMETER => "General parameter incompatibility",
+            INCOMPATIBLE_DEVICE => "General internal incompatibility in the device",
+            HARDWARE => "Access failed due to a hardware error",
+            LENGTH => "Data type does not match, length of service parameter does not match",
+            LENGTH_TOO_HIGH => "Data type does not match, length of service parameter too high",
+            LENGTH_TOO_LOW => "Data type does not match, length of service parameter too low",
+            NO_SUBINDEX => "Sub-index does not exist",
+            INVALID_VALUE => "Invalid value for parameter",
+            VALUE_TOO_HIGH => "Value of parameter written too high",
+            VALUE_TOO_LOW => "Value of parameter written too low",
+            MAX_BELOW_MIN => "Maximum value is less than minimum value",
+            RESOURCE => "Resource not available: SDO connection",
+            GENERAL => "General error",
+            STORE => "Data cannot be transferred or stored to the application",
+            STORE_LOCAL_CONTROL => {
+                "Data cannot be transferred or stored to the application because of local control"
+            }
+            STORE_DEVICE_STATE => {
+                "Data cannot be transferred or stored to the application because of the present device state"
+            }
+            NO_DICTIONARY => "Object dictionary not present",
+            NO_DATA => "No data available",
+            _ => "Unknown abort code",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Can(E),
+    /// The server did not respond in time, the transfer has been aborted.
+    Timeout,
+    /// The transfer was aborted by the server, or by the client after an
+    /// invalid response.
+    Abort {
+        index: u16,
+        subindex: u8,
+        code: u32,
+    },
+    /// The data read does not match the requested type.
+    InvalidValue(Vec<u8>),
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Can(err) => write!(f, "CAN error: {}", err),
+            Error::Timeout => write!(f, "SDO timeout"),
+            Error::Abort {
+                index,
+                subindex,
+                code,
+            } => write!(
+                f,
+                "SDO transfer of {:#06x}sub{} aborted with {:#010x}: {}",
+                index,
+                subindex,
+                code,
+                abort::description(*code)
+            ),
+            Error::InvalidValue(data) => write!(f, "Invalid value {:02X?}", data),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Error<E> {}
+
+/// Types of object dictionary entries, encoded little endian.
+pub trait Value: Sized {
+    fn encode(&self) -> Vec<u8>;
+    /// Returns `None` if the length does not match the type.
+    fn decode(data: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_value {
+    ($($t:ty),*) => {
+        $(
+            impl Value for $t {
+                fn encode(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                fn decode(data: &[u8]) -> Option<Self> {
+                    Some(<$t>::from_le_bytes(data.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+impl_value!(u8, u16, u32, u64, i8, i16, i32, i64, f32, f64);
+
+/// VISIBLE_STRING, trailing NUL bytes are removed.
+impl Value for String {
+    fn encode(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let len = data.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        String::from_utf8(data[..len].to_vec()).ok()
+    }
+}
+
+/// DOMAIN, arbitrary data.
+impl Value for Vec<u8> {
+    fn encode(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        Some(data.to_vec())
+    }
+}
+
+pub struct Client<C, K> {
+    can: C,
+    clock: K,
+    request_id: StandardId,
+    response_id: StandardId,
+    timeout: Duration,
+    block_size: u8,
+}
+
+impl<C, K> Client<C, K>
+where
+    C: ReadTimeout,
+    C::Frame: Frame,
+    K: Clock,
+{
+    /// Client of the default SDO of a node.
+    pub fn new(can: C, clock: K, node: u8) -> Self {
+        Self {
+            can,
+            clock,
+            request_id: cob_id(cob::SDO_RX, node),
+            response_id: cob_id(cob::SDO_TX, node),
+            timeout: Duration::from_millis(500),
+            block_size: 127,
+        }
+    }
+
+    /// COB-IDs of requests and responses, for additional SDO channels.
+    pub fn with_cob_ids(&mut self, request: StandardId, response: StandardId) -> &mut Self {
+        self.request_id = request;
+        self.response_id = response;
+        self
+    }
+
+    /// Time to wait for each response, 500 ms by default.
+    pub fn with_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Segments per block of a block upload, 1 to 127. Defaults to 127.
+    pub fn with_block_size(&mut self, block_size: u8) -> &mut Self {
+        self.block_size = block_size.clamp(1, 127);
+        self
+    }
+
+    pub fn can(&mut self) -> &mut C {
+        &mut self.can
+    }
+
+    pub fn into_inner(self) -> (C, K) {
+        (self.can, self.clock)
+    }
+
+    /// Reads an entry and converts it to `T`.
+    pub fn read<T: Value>(&mut self, index: u16, subindex: u8) -> Result<T, Error<C::Error>> {
+        let data = self.upload(index, subindex)?;
+        T::decode(&data).ok_or(Error::InvalidValue(data))
+    }
+
+    pub fn write<T: Value>(
+        &mut self,
+        index: u16,
+        subindex: u8,
+        value: &T,
+    ) -> Result<(), Error<C::Error>> {
+        self.download(index, subindex, &value.encode())
+    }
+
+    /// Reads an entry with an expedited or segmented transfer, as chosen by the server.
+    pub fn upload(&mut self, index: u16, subindex: u8) -> Result<Vec<u8>, Error<C::Error>> {
+        let response = self.request(index, subindex, 0x40, [0; 4])?;
+        if response[0] & 0xE0 != 0x40 || !multiplexer_matches(&response, index, subindex) {
+            return Err(self.abort(index, subindex, abort::COMMAND));
+        }
+
+        if response[0] & 0x02 != 0 {
+            let len = if response[0] & 0x01 != 0 {
+                4 - (response[0] >> 2 & 0x03) as usize
+            } else {
+                4
+            };
+            return Ok(response[4..4 + len].to_vec());
+        }
+        let size = if response[0] & 0x01 != 0 {
+            Some(u32::from_le_bytes(response[4..8].try_into().unwrap()) as usize)
+        } else {
+            None
+        };
+
+        let mut data = Vec::with_capacity(size.unwrap_or(0));
+        let mut toggle = 0;
+        loop {
+            self.send([0x60 | toggle << 4, 0, 0, 0, 0, 0, 0, 0])?;
+            let response = self.receive(index, subindex)?;
+            if response[0] & 0xE0 != 0x00 {
+                return Err(self.abort(index, subindex, abort::COMMAND));
+            }
+            if response[0] >> 4 & 0x01 != toggle {
+                return Err(self.abort(index, subindex, abort::TOGGLE_BIT));
+            }
+            let unused = (response[0] >> 1 & 0x07) as usize;
+            data.extend_from_slice(&response[1..8 - unused]);
+            if response[0] & 0x01 != 0 {
+                break;
+            }
+            toggle ^= 1;
+        }
+
+        if size.is_some_and(|size| size != data.len()) {
+            return Err(self.abort(index, subindex, abort::LENGTH));
+        }
+        Ok(data)
+    }
+
+    /// Writes an entry, expedited for up to 4 bytes and segmented otherwise.
+    pub fn download(
+        &mut self,
+        index: u16,
+        subindex: u8,
+        data: &[u8],
+    ) -> Result<(), Error<C::Error>> {
+        if !data.is_empty() && data.len() <= 4 {
+            let mut payload = [0; 4];
+            payload[..data.len()].copy_from_slice(data);
+            let command = 0x23 | ((4 - data.len()) as u8) << 2;
+            let response = self.request(index, subindex, command, payload)?;
+            return self.expect_download_response(&response, index, subindex);
+        }
+
+        let size = (data.len() as u32).to_le_bytes();
+        let response = self.request(index, subindex, 0x21, size)?;
+        self.expect_download_response(&response, index, subindex)?;
+
+        let mut toggle = 0;
+        let segments = segments(data);
+        for (i, segment) in segments.iter().enumerate() {
+            let mut request = [0; 8];
+            request[0] = toggle << 4 | ((7 - segment.len()) as u8) << 1;
+            if i + 1 == segments.len() {
+                request[0] |= 0x01;
+            }
+            request[1..1 + segment.len()].copy_from_slice(segment);
+            self.send(request)?;
+
+            let response = self.receive(index, subindex)?;
+            if response[0] & 0xE0 != 0x20 {
+                return Err(self.abort(index, subindex, abort::COMMAND));
+            }
+            if response[0] >> 4 & 0x01 != toggle {
+                return Err(self.abort(index, subindex, abort::TOGGLE_BIT));
+            }
+            toggle ^= 1;
+        }
+        Ok(())
+    }
+
+    /// Reads an entry with a block transfer.
+    pub fn block_upload(&mut self, index: u16, subindex: u8) -> Result<Vec<u8>, Error<C::Error>> {
+        // Client CRC support, no protocol switch to segmented transfers.
+        let response = self.request(index, subindex, 0xA4, [self.block_size, 0, 0, 0])?;
+        if response[0] & 0xE1 != 0xC0 || !multiplexer_matches(&response, index, subindex) {
+            return Err(self.abort(index, subindex, abort::COMMAND));
+        }
+        let crc_supported = response[0] & 0x04 != 0;
+        let size = if response[0] & 0x02 != 0 {
+            Some(u32::from_le_bytes(response[4..8].try_into().unwrap()) as usize)
+        } else {
+            None
+        };
+
+        self.send([0xA3, 0, 0, 0, 0, 0, 0, 0])?;
+        let mut data = Vec::with_capacity(size.unwrap_or(0));
+        loop {
+            // Segments after a lost one are ignored and repeated by the server.
+            let mut sequence = 0;
+            let mut last = false;
+            loop {
+                let segment = self.receive(index, subindex)?;
+                let number = segment[0] & 0x7F;
+                if number == sequence + 1 {
+                    sequence = number;
+                    data.extend_from_slice(&segment[1..]);
+                    last = segment[0] & 0x80 != 0;
+                }
+                if number == self.block_size || segment[0] & 0x80 != 0 {
+                    break;
+                }
+            }
+            self.send([0xA2, sequence, self.block_size, 0, 0, 0, 0, 0])?;
+            if last {
+                break;
+            }
+        }
+
+        let end = self.receive(index, subindex)?;
+        if end[0] & 0xE3 != 0xC1 {
+            return Err(self.abort(index, subindex, abort::COMMAND));
+        }
+        let unused = (end[0] >> 2 & 0x07) as usize;
+        data.truncate(data.len().saturating_sub(unused));
+        if crc_supported && crc16(&data) != u16::from_le_bytes([end[1], end[2]]) {
+            return Err(self.abort(index, subindex, abort::CRC));
+        }
+        if size.is_some_and(|size| size != data.len()) {
+            return Err(self.abort(index, subindex, abort::LENGTH));
+        }
+        self.send([0xA1, 0, 0, 0, 0, 0, 0, 0])?;
+        Ok(data)
+    }
+
+    /// Writes an entry with a block transfer.
+    pub fn block_download(
+        &mut self,
+        index: u16,
+        subindex: u8,
+        data: &[u8],
+    ) -> Result<(), Error<C::Error>> {
+        // Client CRC support, size indicated.
+        let size = (data.len() as u32).to_le_bytes();
+        let response = self.request(index, subindex, 0xC6, size)?;
+        if response[0] & 0xE3 != 0xA0 || !multiplexer_matches(&response, index, subindex) {
+            return Err(self.abort(index, subindex, abort::COMMAND));
+        }
+        let crc_supported = response[0] & 0x04 != 0;
+        let mut block_size = response[4];
+
+        let segments = segments(data);
+        let mut acknowledged = 0;
+        while acknowledged < segments.len() {
+            if !(1..=127).contains(&block_size) {
+                return Err(self.abort(index, subindex, abort::BLOCK_SIZE));
+            }
+            let block =
+                &segments[acknowledged..segments.len().min(acknowledged + block_size as usize)];
+            for (i, segment) in block.iter().enumerate() {
+                let mut request = [0; 8];
+                request[0] = i as u8 + 1;
+                if acknowledged + i + 1 == segments.len() {
+                    request[0] |= 0x80;
+                }
+                request[1..1 + segment.len()].copy_from_slice(segment);
+                self.send(request)?;
+            }
+
+            let response = self.receive(index, subindex)?;
+            if response[0] & 0xE3 != 0xA2 {
+                return Err(self.abort(index, subindex, abort::COMMAND));
+            }
+            let sequence = response[1] as usize;
+            if sequence > block.len() {
+                return Err(self.abort(index, subindex, abort::SEQUENCE_NUMBER));
+            }
+            acknowledged += sequence;
+            block_size = response[2];
+        }
+
+        let unused = 7 - segments.last().unwrap().len() as u8;
+        let crc = if crc_supported { crc16(data) } else { 0 };
+        let [crc_lo, crc_hi] = crc.to_le_bytes();
+        self.send([0xC1 | unused << 2, crc_lo, crc_hi, 0, 0, 0, 0, 0])?;
+        let response = self.receive(index, subindex)?;
+        if response[0] & 0xE3 != 0xA1 {
+            return Err(self.abort(index, subindex, abort::COMMAND));
+        }
+        Ok(())
+    }
+
+    /// Sends an initiate request with the multiplexer and waits for the response.
+    fn request(
+        &mut self,
+        index: u16,
+        subindex: u8,
+        command: u8,
+        data: [u8; 4],
+    ) -> Result<[u8; 8], Error<C::Error>> {
+        let [index_lo, index_hi] = index.to_le_bytes();
+        let [d0, d1, d2, d3] = data;
+        self.send([command, index_lo, index_hi, subindex, d0, d1, d2, d3])?;
+        self.receive(index, subindex)
+    }
+
+    fn send(&mut self, data: [u8; 8]) -> Result<(), Error<C::Error>> {
+        let frame = C::Frame::new(self.request_id, &data).unwrap();
+        self.can.try_write(&frame).map_err(Error::Can)
+    }
+
+    /// Waits for the next response, converting abort messages to errors.
+    fn receive(&mut self, index: u16, subindex: u8) -> Result<[u8; 8], Error<C::Error>> {
+        let deadline = self.clock.now() + self.timeout;
+        loop {
+            let remaining = deadline.saturating_sub(self.clock.now());
+            let frame = match self.can.try_read_timeout(remaining).map_err(Error::Can)? {
+                Some(frame) => frame,
+                None => {
+                    self.abort(index, subindex, abort::TIMEOUT);
+                    return Err(Error::Timeout);
+                }
+            };
+            if frame.id() != Id::Standard(self.response_id) || frame.is_remote_frame() {
+                continue;
+            }
+
+            let mut response = [0; 8];
+            let len = frame.data().len().min(8);
+            response[..len].copy_from_slice(&frame.data()[..len]);
+            if response[0] == 0x80 {
+                return Err(Error::Abort {
+                    index: u16::from_le_bytes([response[1], response[2]]),
+                    subindex: response[3],
+                    code: u32::from_le_bytes(response[4..8].try_into().unwrap()),
+                });
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Aborts the transfer, returning the error to report.
+    fn abort(&mut self, index: u16, subindex: u8, code: u32) -> Error<C::Error> {
+        let [index_lo, index_hi] = index.to_le_bytes();
+        let [c0, c1, c2, c3] = code.to_le_bytes();
+        if let Err(err) = self.send([0x80, index_lo, index_hi, subindex, c0, c1, c2, c3]) {
+            return err;
+        }
+        Error::Abort {
+            index,
+            subindex,
+            code,
+        }
+    }
+
+    fn expect_download_response(
+        &mut self,
+        response: &[u8; 8],
+        index: u16,
+        subindex: u8,
+    ) -> Result<(), Error<C::Error>> {
+        if response[0] & 0xE0 != 0x60 || !multiplexer_matches(response, index, subindex) {
+            return Err(self.abort(index, subindex, abort::COMMAND));
+        }
+        Ok(())
+    }
+}
+
+/// Splits data into segments of 7 bytes, empty data is sent as one empty segment.
+fn segments(data: &[u8]) -> Vec<&[u8]> {
+    if data.is_empty() {
+        vec![&[]]
+    } else {
+        data.chunks(7).collect()
+    }
+}
+
+fn multiplexer_matches(response: &[u8; 8], index: u16, subindex: u8) -> bool {
+    response[1..3] == index.to_le_bytes() && response[3] == subindex
+}
+
+/// CRC-16-CCITT of block transfers, polynomial 0x1021 with initial value 0.
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Bus, Context, Node};
+    use std::{cell::RefCell, rc::Rc};
+
+    /// Requests with the responses of the server.
+    type Script = Vec<(Vec<u8>, Vec<Vec<u8>>)>;
+
+    /// SDO server answering requests with canned responses.
+    struct Scripted {
+        script: Script,
+        requests: Vec<Vec<u8>>,
+    }
+
+    impl Node for Scripted {
+        fn on_frame(&mut self, frame: &crate::Frame, cx: &mut Context<'_>) {
+            if frame.id() != Id::Standard(cob_id(cob::SDO_RX, 5)) {
+                return;
+            }
+            self.requests.push(frame.data().to_vec());
+            if let Some(position) = self.script.iter().position(|(r, _)| r == frame.data()) {
+                let (_, responses) = self.script.remove(position);
+                for response in responses {
+                    cx.send(crate::Frame::new(cob_id(cob::SDO_TX, 5), &response).unwrap());
+                }
+            }
+        }
+    }
+
+    type TestClient = Client<sim::Endpoint, sim::VirtualClock>;
+
+    fn setup(script: Script) -> (TestClient, Rc<RefCell<Scripted>>) {
+        let bus = Bus::new();
+        let server = bus.add_node(Scripted {
+            script,
+            requests: Vec::new(),
+        });
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        (Client::new(endpoint, clock, 5), server)
+    }
+
+    #[test]
+    fn expedited_and_segmented() {
+        let (mut sdo, _) = setup(vec![
+            (
+                vec![0x40, 0x00, 0x10, 0x00, 0, 0, 0, 0],
+                vec![vec![0x43, 0x00, 0x10, 0x00, 0x92, 0x01, 0x02, 0x00]],
+            ),
+            (
+                vec![0x40, 0x08, 0x10, 0x00, 0, 0, 0, 0],
+                vec![vec![0x41, 0x08, 0x10, 0x00, 10, 0, 0, 0]],
+            ),
+            (
+                vec![0x60, 0, 0, 0, 0, 0, 0, 0],
+                vec![b"\x00Servo D".to_vec()],
+            ),
+            (
+                vec![0x70, 0, 0, 0, 0, 0, 0, 0],
+                vec![b"\x19riv\0\0\0\0".to_vec()],
+            ),
+            (
+                vec![0x2B, 0x17, 0x10, 0x00, 0xE8, 0x03, 0, 0],
+                vec![vec![0x60, 0x17, 0x10, 0x00, 0, 0, 0, 0]],
+            ),
+            (
+                vec![0x40, 0x00, 0x20, 0x01, 0, 0, 0, 0],
+                vec![vec![0x80, 0x00, 0x20, 0x01, 0x00, 0x00, 0x02, 0x06]],
+            ),
+        ]);
+
+        assert_eq!(sdo.read::<u32>(0x1000, 0).unwrap(), 0x0002_0192);
+        assert_eq!(sdo.read::<String>(0x1008, 0).unwrap(), "Servo Driv");
+        sdo.write(0x1017, 0, &1000u16).unwrap();
+        let err = sdo.read::<u8>(0x2000, 1).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Abort {
+                index: 0x2000,
+                subindex: 1,
+                code: abort::NO_OBJECT
+            }
+        ));
+        assert!(err.to_string().contains("does not exist"));
+    }
+
+    #[test]
+    fn block_transfers() {
+        let data: Vec<u8> = (0..20).collect();
+        let crc = crc16(&data).to_le_bytes();
+        let (mut sdo, server) = setup(vec![
+            // Download with a block size of 2, the second segment is repeated.
+            (
+                vec![0xC6, 0x00, 0x20, 0x00, 20, 0, 0, 0],
+                vec![vec![0xA4, 0x00, 0x20, 0x00, 2, 0, 0, 0]],
+            ),
+            (vec![0x02, 7, 8, 9, 10, 11, 12, 13], vec![vec![0xA2, 1, 2]]),
+            (
+                vec![0x82, 14, 15, 16, 17, 18, 19, 0],
+                vec![vec![0xA2, 2, 2]],
+            ),
+            (
+                vec![0xC1 | 1 << 2, crc[0], crc[1], 0, 0, 0, 0, 0],
+                vec![vec![0xA1]],
+            ),
+            // Upload with a lost segment in the first block.
+            (
+                vec![0xA4, 0x00, 0x20, 0x00, 127, 0, 0, 0],
+                vec![vec![0xC6, 0x00, 0x20, 0x00, 20, 0, 0, 0]],
+            ),
+            (
+                vec![0xA3, 0, 0, 0, 0, 0, 0, 0],
+                vec![
+                    vec![0x01, 0, 1, 2, 3, 4, 5, 6],
+                    vec![0x83, 14, 15, 16, 17, 18, 19, 0],
+                ],
+            ),
+            (
+                vec![0xA2, 1, 127, 0, 0, 0, 0, 0],
+                vec![
+                    vec![0x01, 7, 8, 9, 10, 11, 12, 13],
+                    vec![0x82, 14, 15, 16, 17, 18, 19, 0],
+                ],
+            ),
+            (
+                vec![0xA2, 2, 127, 0, 0, 0, 0, 0],
+                vec![vec![0xC5 | 1 << 2, crc[0], crc[1], 0, 0, 0, 0, 0]],
+            ),
+        ]);
+
+        sdo.block_download(0x2000, 0, &data).unwrap();
+        assert_eq!(sdo.block_upload(0x2000, 0).unwrap(), data);
+        sdo.can().clock().sleep(Duration::from_millis(1));
+        let requests = server.borrow().requests.clone();
+        assert_eq!(requests[1], [0x01, 0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(requests.last().unwrap(), &[0xA1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn timeout_aborts() {
+        let (mut sdo, server) = setup(Vec::new());
+        assert!(matches!(sdo.upload(0x1018, 1), Err(Error::Timeout)));
+        sdo.can().clock().sleep(Duration::from_millis(1));
+        assert_eq!(
+            server.borrow().requests.last().unwrap(),
+            &[0x80, 0x18, 0x10, 0x01, 0x00, 0x00, 0x04, 0x05]
+        );
+    }
+}
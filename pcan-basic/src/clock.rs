@@ -0,0 +1,54 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Time source for timeouts and pacing.
+///
+/// Protocol code takes a clock instead of using [`Instant`] directly so it can
+/// run against the virtual time of a [simulated bus](crate::sim).
+pub trait Clock {
+    /// Time since an arbitrary but fixed point in the past.
+    fn now(&self) -> Duration;
+
+    /// Blocks until [`now()`](Clock::now) reaches `deadline`.
+    fn sleep_until(&mut self, deadline: Duration);
+
+    fn sleep(&mut self, duration: Duration) {
+        let deadline = self.now() + duration;
+        self.sleep_until(deadline);
+    }
+}
+
+/// Wall clock time of the operating system.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep_until(&mut self, deadline: Duration) {
+        let now = self.now();
+        if deadline > now {
+            thread::sleep(deadline - now);
+        }
+    }
+}
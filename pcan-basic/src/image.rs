@@ -0,0 +1,542 @@
+//! Firmware images from Intel HEX, Motorola S-record, ELF and raw binary files.
+//!
+//! An [`Image`] is a sorted list of non-overlapping [`Segment`]s of memory.
+//! Adjacent data is merged into one segment.
+
+use std::{fmt, fs, io, path::Path};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+impl Segment {
+    /// Address after the last byte.
+    pub fn end(&self) -> u64 {
+        self.address as u64 + self.data.len() as u64
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A line of a HEX or S-record file could not be parsed.
+    Parse {
+        line: usize,
+        message: String,
+    },
+    Elf(String),
+    /// The file extension does not tell the format.
+    UnknownFormat,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            Error::Elf(message) => write!(f, "Invalid ELF file: {}", message),
+            Error::UnknownFormat => write!(f, "Unknown image format"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Image {
+    segments: Vec<Segment>,
+    entry: Option<u32>,
+}
+
+impl Image {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads an image, choosing the format from the file contents and extension.
+    ///
+    /// ELF files are recognized by their magic number. `.hex` and `.ihex` files
+    /// are Intel HEX, `.srec`, `.s19`, `.s28`, `.s37` and `.mot` files are
+    /// S-records. `.bin` files are placed at address 0, use
+    /// [`Image::from_binary()`] for other addresses.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        if data.starts_with(b"\x7FELF") {
+            return Self::from_elf(&data);
+        }
+
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+        let text = || String::from_utf8_lossy(&data).into_owned();
+        match extension.as_deref() {
+            Some("hex") | Some("ihex") => Self::from_ihex(&text()),
+            Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => {
+                Self::from_srec(&text())
+            }
+            Some("bin") => Ok(Self::from_binary(0, &data)),
+            _ => Err(Error::UnknownFormat),
+        }
+    }
+
+    pub fn from_binary(address: u32, data: &[u8]) -> Self {
+        let mut image = Self::new();
+        image.write(address, data);
+        image
+    }
+
+    pub fn from_ihex(text: &str) -> Result<Self, Error> {
+        let mut image = Self::new();
+        let mut base = 0u32;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |message: &str| Error::Parse {
+                line: line_number,
+                message: message.to_owned(),
+            };
+
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = line
+                .strip_prefix(':')
+                .ok_or_else(|| error("missing start code"))?;
+            let bytes = parse_hex_bytes(record).ok_or_else(|| error("invalid hex digits"))?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(error("invalid record length"));
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(error("checksum mismatch"));
+            }
+
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            match bytes[3] {
+                0x00 => image.write(base.wrapping_add(offset), data),
+                0x01 => return Ok(image),
+                0x02 if data.len() == 2 => {
+                    base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4;
+                }
+                0x04 if data.len() == 2 => {
+                    base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16;
+                }
+                0x03 if data.len() == 4 => {
+                    let segment = u16::from_be_bytes([data[0], data[1]]) as u32;
+                    let offset = u16::from_be_bytes([data[2], data[3]]) as u32;
+                    image.entry = Some((segment << 4) + offset);
+                }
+                0x05 if data.len() == 4 => {
+                    image.entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]]));
+                }
+                _ => return Err(error("invalid record type")),
+            }
+        }
+
+        Err(Error::Parse {
+            line: text.lines().count(),
+            message: "missing end of file record".to_owned(),
+        })
+    }
+
+    pub fn from_srec(text: &str) -> Result<Self, Error> {
+        let mut image = Self::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let error = |message: &str| Error::Parse {
+                line: line_number,
+                message: message.to_owned(),
+            };
+
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut chars = line.chars();
+            if chars.next() != Some('S') {
+                return Err(error("missing start code"));
+            }
+            let kind = chars.next().ok_or_else(|| error("missing record type"))?;
+            let bytes =
+                parse_hex_bytes(chars.as_str()).ok_or_else(|| error("invalid hex digits"))?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(error("invalid record length"));
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xFF {
+                return Err(error("checksum mismatch"));
+            }
+
+            let address_len = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => return Err(error("invalid record type")),
+            };
+            let payload = &bytes[1..bytes.len() - 1];
+            if payload.len() < address_len {
+                return Err(error("invalid record length"));
+            }
+            let address = payload[..address_len]
+                .iter()
+                .fold(0u32, |acc, &b| acc << 8 | b as u32);
+            let data = &payload[address_len..];
+
+            match kind {
+                '1' | '2' | '3' => image.write(address, data),
+                '7' | '8' | '9' => image.entry = Some(address),
+                // Header and record counts.
+                _ => {}
+            }
+        }
+
+        Ok(image)
+    }
+
+    /// Loads the `PT_LOAD` program headers of a 32 or 64 bit ELF file at their
+    /// physical address.
+    pub fn from_elf(data: &[u8]) -> Result<Self, Error> {
+        let error = |message: &str| Error::Elf(message.to_owned());
+        if !data.starts_with(b"\x7FELF") || data.len() < 0x34 {
+            return Err(error("missing ELF header"));
+        }
+        let is_64 = match data[4] {
+            1 => false,
+            2 => true,
+            _ => return Err(error("invalid class")),
+        };
+        let big_endian = match data[5] {
+            1 => false,
+            2 => true,
+            _ => return Err(error("invalid data encoding")),
+        };
+
+        let read = |offset: usize, len: usize| -> Result<u64, Error> {
+            let bytes = data
+                .get(offset..offset + len)
+                .ok_or_else(|| error("truncated file"))?;
+            let value = if big_endian {
+                bytes.iter().fold(0u64, |acc, &b| acc << 8 | b as u64)
+            } else {
+                bytes.iter().rev().fold(0u64, |acc, &b| acc << 8 | b as u64)
+            };
+            Ok(value)
+        };
+
+        let word = if is_64 { 8 } else { 4 };
+        let entry = read(0x18, word)?;
+        let (phoff, phentsize, phnum) = if is_64 {
+            (read(0x20, 8)?, read(0x36, 2)?, read(0x38, 2)?)
+        } else {
+            (read(0x1C, 4)?, read(0x2A, 2)?, read(0x2C, 2)?)
+        };
+
+        let mut image = Self::new();
+        image.entry = Some(entry as u32);
+        for i in 0..phnum {
+            let header = i
+                .checked_mul(phentsize)
+                .and_then(|offset| offset.checked_add(phoff))
+                .filter(|&header| header < data.len() as u64)
+                .ok_or_else(|| error("program header outside of file"))?
+                as usize;
+            let (p_type, offset, paddr, filesz) = if is_64 {
+                (
+                    read(header, 4)?,
+                    read(header + 8, 8)?,
+                    read(header + 24, 8)?,
+                    read(header + 32, 8)?,
+                )
+            } else {
+                (
+                    read(header, 4)?,
+                    read(header + 4, 4)?,
+                    read(header + 12, 4)?,
+                    read(header + 16, 4)?,
+                )
+            };
+
+            const PT_LOAD: u64 = 1;
+            if p_type != PT_LOAD || filesz == 0 {
+                continue;
+            }
+            let end = offset
+                .checked_add(filesz)
+                .filter(|&end| end <= data.len() as u64)
+                .ok_or_else(|| error("segment outside of file"))?;
+            if paddr.saturating_add(filesz) > 1 << 32 {
+                return Err(error("segment above 4 GiB"));
+            }
+            let contents = &data[offset as usize..end as usize];
+            image.write(paddr as u32, contents);
+        }
+
+        Ok(image)
+    }
+
+    /// Writes data, replacing what was at the same addresses before.
+    pub fn write(&mut self, address: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let start = address as u64;
+        let end = start + data.len() as u64;
+
+        // Segments that overlap or touch the new data are merged with it.
+        let first = self.segments.partition_point(|s| s.end() < start);
+        let last = self.segments[first..]
+            .iter()
+            .position(|s| s.address as u64 > end)
+            .map_or(self.segments.len(), |i| first + i);
+
+        let mut merged: Vec<Segment> = self.segments.drain(first..last).collect();
+        let merged_start = merged
+            .first()
+            .map_or(start, |s| start.min(s.address as u64));
+        let merged_end = merged.last().map_or(end, |s| end.max(s.end()));
+        let mut buffer = vec![0; (merged_end - merged_start) as usize];
+        for segment in merged.drain(..) {
+            let offset = (segment.address as u64 - merged_start) as usize;
+            buffer[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        let offset = (start - merged_start) as usize;
+        buffer[offset..offset + data.len()].copy_from_slice(data);
+
+        self.segments.insert(
+            first,
+            Segment {
+                address: merged_start as u32,
+                data: buffer,
+            },
+        );
+    }
+
+    /// Merges segments separated by at most `max_gap` bytes, filling the gaps with `fill`.
+    pub fn fill_gaps(&mut self, max_gap: u32, fill: u8) {
+        let mut segments = self.segments.drain(..);
+        let mut merged: Vec<Segment> = segments.next().into_iter().collect();
+        for segment in segments {
+            let previous = merged.last_mut().unwrap();
+            let gap = segment.address as u64 - previous.end();
+            if gap <= max_gap as u64 {
+                previous
+                    .data
+                    .resize(previous.data.len() + gap as usize, fill);
+                previous.data.extend_from_slice(&segment.data);
+            } else {
+                merged.push(segment);
+            }
+        }
+        self.segments = merged;
+    }
+
+    /// Splits segments so that none is longer than `max_len` or crosses a multiple of it,
+    /// for example to match flash sectors. A `max_len` of 0 leaves the segments as they are.
+    pub fn split(&self, max_len: u32) -> Vec<Segment> {
+        if max_len == 0 {
+            return self.segments.clone();
+        }
+        let mut result = Vec::new();
+        for segment in &self.segments {
+            let mut address = segment.address as u64;
+            let mut data = segment.data.as_slice();
+            while !data.is_empty() {
+                let boundary = (address / max_len as u64 + 1) * max_len as u64;
+                let len = ((boundary - address) as usize).min(data.len());
+                result.push(Segment {
+                    address: address as u32,
+                    data: data[..len].to_vec(),
+                });
+                address += len as u64;
+                data = &data[len..];
+            }
+        }
+        result
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Start address from the file, if any.
+    pub fn entry(&self) -> Option<u32> {
+        self.entry
+    }
+
+    /// Total number of bytes.
+    pub fn len(&self) -> usize {
+        self.segments.iter().map(|s| s.data.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.is_ascii() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// CRC-32 (IEEE 802.3) as used by zip and most bootloaders.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intel_hex() {
+        let text = "\
+:020000040800F2
+:10000000000102030405060708090A0B0C0D0E0F78
+:0400100010111213A6
+:04000005080001C12D
+:00000001FF
+";
+        let image = Image::from_ihex(text).unwrap();
+        let data: Vec<u8> = (0..20).collect();
+        assert_eq!(
+            image.segments(),
+            [Segment {
+                address: 0x0800_0000,
+                data
+            }]
+        );
+        assert_eq!(image.entry(), Some(0x0800_01C1));
+
+        let broken = text.replace(":0400100010111213A6", ":0400100010111213A7");
+        assert!(matches!(
+            Image::from_ihex(&broken),
+            Err(Error::Parse { line: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn s_record() {
+        let text = "\
+S00600004844521B
+S1130000285F245F2212226A000424290008237C2A
+S113001000020008000826290018265300100000DA
+S5030002FA
+S9030000FC
+";
+        let image = Image::from_srec(text).unwrap();
+        assert_eq!(image.segments().len(), 1);
+        assert_eq!(image.len(), 32);
+        assert_eq!(image.segments()[0].data[..4], [0x28, 0x5F, 0x24, 0x5F]);
+        assert_eq!(image.entry(), Some(0));
+    }
+
+    #[test]
+    fn elf() {
+        // Minimal 32 bit little endian ELF with one loadable segment.
+        let mut elf = vec![0; 0x54];
+        elf[..6].copy_from_slice(b"\x7FELF\x01\x01");
+        elf[0x18..0x1C].copy_from_slice(&0x0800_0101u32.to_le_bytes());
+        elf[0x1C..0x20].copy_from_slice(&0x34u32.to_le_bytes());
+        elf[0x2A..0x2C].copy_from_slice(&0x20u16.to_le_bytes());
+        elf[0x2C..0x2E].copy_from_slice(&1u16.to_le_bytes());
+        let header = 0x34;
+        elf[header..header + 4].copy_from_slice(&1u32.to_le_bytes());
+        elf[header + 4..header + 8].copy_from_slice(&0x54u32.to_le_bytes());
+        elf[header + 8..header + 12].copy_from_slice(&0x2000_0000u32.to_le_bytes());
+        elf[header + 12..header + 16].copy_from_slice(&0x0800_0000u32.to_le_bytes());
+        elf[header + 16..header + 20].copy_from_slice(&4u32.to_le_bytes());
+        elf.extend_from_slice(&[1, 2, 3, 4]);
+
+        let image = Image::from_elf(&elf).unwrap();
+        assert_eq!(
+            image.segments(),
+            [Segment {
+                address: 0x0800_0000,
+                data: vec![1, 2, 3, 4]
+            }]
+        );
+        assert_eq!(image.entry(), Some(0x0800_0101));
+    }
+
+    #[test]
+    fn elf_offsets_out_of_range() {
+        // 64 bit little endian ELF with one loadable segment.
+        let mut elf = vec![0; 0x78];
+        elf[..6].copy_from_slice(b"\x7FELF\x02\x01");
+        elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&0x38u16.to_le_bytes());
+        elf[0x38..0x3A].copy_from_slice(&1u16.to_le_bytes());
+        elf[0x40..0x44].copy_from_slice(&1u32.to_le_bytes());
+        elf[0x48..0x50].copy_from_slice(&u64::MAX.to_le_bytes());
+        elf[0x60..0x68].copy_from_slice(&2u64.to_le_bytes());
+        assert!(matches!(Image::from_elf(&elf), Err(Error::Elf(_))));
+
+        // Segments that don't fit into 32 bit addresses.
+        elf[0x48..0x50].copy_from_slice(&0x70u64.to_le_bytes());
+        elf[0x58..0x60].copy_from_slice(&0x1_0000_0000u64.to_le_bytes());
+        assert!(matches!(Image::from_elf(&elf), Err(Error::Elf(_))));
+        elf[0x58..0x60].copy_from_slice(&0xFFFF_FFFFu64.to_le_bytes());
+        assert!(matches!(Image::from_elf(&elf), Err(Error::Elf(_))));
+        elf[0x58..0x60].copy_from_slice(&0xFFFF_FFFEu64.to_le_bytes());
+        assert_eq!(Image::from_elf(&elf).unwrap().len(), 2);
+
+        // Program headers beyond the end of the address space.
+        elf[0x20..0x28].copy_from_slice(&(u64::MAX - 0x10).to_le_bytes());
+        elf[0x38..0x3A].copy_from_slice(&2u16.to_le_bytes());
+        assert!(matches!(Image::from_elf(&elf), Err(Error::Elf(_))));
+    }
+
+    #[test]
+    fn merge_and_split() {
+        let mut image = Image::from_binary(0x100, &[1; 8]);
+        image.write(0x108, &[2; 8]);
+        image.write(0x104, &[3; 2]);
+        image.write(0x200, &[4; 4]);
+        assert_eq!(image.segments().len(), 2);
+        assert_eq!(image.segments()[0].data[4..10], [3, 3, 1, 1, 2, 2]);
+
+        let sectors = image.split(0x0C);
+        let ranges: Vec<_> = sectors.iter().map(|s| (s.address, s.data.len())).collect();
+        assert_eq!(ranges, [(0x100, 8), (0x108, 8), (0x200, 4)]);
+        assert_eq!(image.split(0), image.segments());
+
+        image.fill_gaps(0x100, 0xFF);
+        assert_eq!(image.segments().len(), 1);
+        assert_eq!(image.len(), 0x104);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,1075 @@
+//! ISO-TP transport protocol (ISO 15765-2) for messages longer than a frame.
+//!
+//! [`Link`] implements the protocol as a state machine without any I/O. It is
+//! fed with received frames and the current time and tells which frames to
+//! transmit. [`IsoTp`] drives a link with a blocking CAN interface.
+//!
+//! ```no_run
+//! use pcan_basic::{isotp::{Config, IsoTp}, Interface, StandardId, SystemClock};
+//! use std::time::Duration;
+//!
+//! let can = Interface::init().unwrap();
+//! let mut config = Config::new(StandardId::new(0x7E0).unwrap(), StandardId::new(0x7E8).unwrap());
+//! config.with_padding(0xCC);
+//! let mut isotp = IsoTp::new(can, SystemClock::new(), config);
+//! isotp.send(&[0x22, 0xF1, 0x90]).unwrap();
+//! let response = isotp.receive(Duration::from_secs(1)).unwrap();
+//! ```
+
+use std::{collections::VecDeque, convert::Infallible, fmt, time::Duration};
+
+use embedded_can::Id;
+
+use crate::{Clock, ReadTimeout};
+
+/// Largest message with a 12 bit length, longer ones need the 32 bit escape sequence.
+const MAX_SHORT_LEN: usize = 4095;
+
+const PCI_SINGLE_FRAME: u8 = 0x00;
+const PCI_FIRST_FRAME: u8 = 0x10;
+const PCI_CONSECUTIVE_FRAME: u8 = 0x20;
+const PCI_FLOW_CONTROL: u8 = 0x30;
+
+const FLOW_STATUS_CONTINUE: u8 = 0;
+const FLOW_STATUS_WAIT: u8 = 1;
+const FLOW_STATUS_OVERFLOW: u8 = 2;
+
+/// Padding used for CAN FD frames without configured padding, which need a valid length.
+const DEFAULT_FD_PADDING: u8 = 0xCC;
+
+/// Network layer timers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    /// Transmission of a frame took too long.
+    As,
+    /// No flow control frame was received after a first frame or a block.
+    Bs,
+    /// No consecutive frame was received.
+    Cr,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Can(E),
+    Timeout(Timer),
+    /// The receiver cannot take a message of this size.
+    Overflow,
+    /// The receiver sent more wait frames than allowed.
+    WaitLimit,
+    UnexpectedSequenceNumber {
+        expected: u8,
+        received: u8,
+    },
+    /// A flow control frame had an unknown flow status.
+    InvalidFlowStatus(u8),
+    /// The message is longer than 4 GiB.
+    TooLong,
+    /// A message is already being sent.
+    Busy,
+    /// A frame of the configured length cannot be created.
+    FrameLength,
+}
+
+impl Error<Infallible> {
+    pub(crate) fn cast<E>(self) -> Error<E> {
+        match self {
+            Error::Can(never) => match never {},
+            Error::Timeout(timer) => Error::Timeout(timer),
+            Error::Overflow => Error::Overflow,
+            Error::WaitLimit => Error::WaitLimit,
+            Error::UnexpectedSequenceNumber { expected, received } => {
+                Error::UnexpectedSequenceNumber { expected, received }
+            }
+            Error::InvalidFlowStatus(status) => Error::InvalidFlowStatus(status),
+            Error::TooLong => Error::TooLong,
+            Error::Busy => Error::Busy,
+            Error::FrameLength => Error::FrameLength,
+        }
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Can(err) => write!(f, "CAN error: {}", err),
+            Error::Timeout(timer) => write!(f, "ISO-TP timeout N_{:?}", timer),
+            Error::Overflow => write!(f, "Message too long for the receiver"),
+            Error::WaitLimit => write!(f, "Receiver sent too many wait frames"),
+            Error::UnexpectedSequenceNumber { expected, received } => write!(
+                f,
+                "Expected consecutive frame {} but received {}",
+                expected, received
+            ),
+            Error::InvalidFlowStatus(status) => write!(f, "Invalid flow status {}", status),
+            Error::TooLong => write!(f, "Message longer than 4 GiB"),
+            Error::Busy => write!(f, "Another message is being sent"),
+            Error::FrameLength => write!(f, "Cannot create frame of the configured length"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Error<E> {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Addressing {
+    /// The CAN identifier is the only address information.
+    Normal,
+    /// The first data byte holds the target address, `source` is the own address
+    /// expected in received frames.
+    Extended { source: u8, target: u8 },
+    /// The first data byte holds the address extension, in both directions.
+    Mixed { address_extension: u8 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    tx_id: Id,
+    rx_id: Id,
+    addressing: Addressing,
+    padding: Option<u8>,
+    frame_len: usize,
+    block_size: u8,
+    st_min: Duration,
+    n_as: Duration,
+    n_bs: Duration,
+    n_cr: Duration,
+    max_wait_frames: u32,
+    max_len: usize,
+}
+
+impl Config {
+    /// Sends frames with `tx_id`, receives frames with `rx_id`.
+    pub fn new(tx_id: impl Into<Id>, rx_id: impl Into<Id>) -> Self {
+        Self {
+            tx_id: tx_id.into(),
+            rx_id: rx_id.into(),
+            addressing: Addressing::Normal,
+            padding: None,
+            frame_len: 8,
+            block_size: 0,
+            st_min: Duration::default(),
+            n_as: Duration::from_millis(1000),
+            n_bs: Duration::from_millis(1000),
+            n_cr: Duration::from_millis(1000),
+            max_wait_frames: 10,
+            max_len: MAX_SHORT_LEN,
+        }
+    }
+
+    pub fn with_tx_id(&mut self, id: impl Into<Id>) -> &mut Self {
+        self.tx_id = id.into();
+        self
+    }
+
+    pub fn with_rx_id(&mut self, id: impl Into<Id>) -> &mut Self {
+        self.rx_id = id.into();
+        self
+    }
+
+    pub fn with_addressing(&mut self, addressing: Addressing) -> &mut Self {
+        self.addressing = addressing;
+        self
+    }
+
+    /// Fills frames up to the full length with `padding`.
+    /// Without padding frames are as short as possible.
+    pub fn with_padding(&mut self, padding: u8) -> &mut Self {
+        self.padding = Some(padding);
+        self
+    }
+
+    /// Length of transmitted frames (TX_DL), 8 for classic CAN and up to 64 for CAN FD.
+    ///
+    /// # Panics
+    ///
+    /// If `len` is not a CAN FD frame length of at least 8.
+    pub fn with_frame_len(&mut self, len: usize) -> &mut Self {
+        assert!(
+            len >= 8 && crate::len_to_dlc(len).is_some(),
+            "invalid frame length {}",
+            len
+        );
+        self.frame_len = len;
+        self
+    }
+
+    /// Number of consecutive frames the sender may send before waiting for the next
+    /// flow control frame, 0 for no limit.
+    pub fn with_block_size(&mut self, block_size: u8) -> &mut Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Minimum gap between consecutive frames requested from the sender.
+    pub fn with_st_min(&mut self, st_min: Duration) -> &mut Self {
+        self.st_min = st_min;
+        self
+    }
+
+    /// Timeouts for transmission, flow control frames and consecutive frames.
+    /// All default to 1 s.
+    pub fn with_timeouts(&mut self, n_as: Duration, n_bs: Duration, n_cr: Duration) -> &mut Self {
+        self.n_as = n_as;
+        self.n_bs = n_bs;
+        self.n_cr = n_cr;
+        self
+    }
+
+    /// Number of wait frames accepted in a row before sending fails, defaults to 10.
+    pub fn with_max_wait_frames(&mut self, count: u32) -> &mut Self {
+        self.max_wait_frames = count;
+        self
+    }
+
+    /// Longest message accepted, longer ones are rejected with an overflow.
+    /// Defaults to 4095 bytes.
+    pub fn with_max_len(&mut self, len: usize) -> &mut Self {
+        self.max_len = len;
+        self
+    }
+
+    fn address_len(&self) -> usize {
+        match self.addressing {
+            Addressing::Normal => 0,
+            _ => 1,
+        }
+    }
+}
+
+/// Something the user of a [`Link`] has to act on.
+#[derive(Debug)]
+pub enum Event<F> {
+    /// A frame to write to the bus.
+    Transmit(F),
+    /// A message was received completely.
+    Received(Vec<u8>),
+    /// The message passed to [`Link::send()`] was transmitted completely.
+    Sent,
+    SendFailed(Error<Infallible>),
+    ReceiveFailed(Error<Infallible>),
+}
+
+enum TxPhase {
+    Start,
+    WaitFlowControl {
+        deadline: Duration,
+        waits: u32,
+    },
+    Consecutive {
+        next: Duration,
+        // Frames left in the current block, `None` without a block limit.
+        block_left: Option<u8>,
+        st_min: Duration,
+    },
+}
+
+struct Tx {
+    data: Vec<u8>,
+    offset: usize,
+    sequence: u8,
+    phase: TxPhase,
+}
+
+struct Rx {
+    data: Vec<u8>,
+    len: usize,
+    sequence: u8,
+    block_left: u8,
+    deadline: Duration,
+}
+
+/// ISO-TP protocol state of one connection, without I/O.
+///
+/// Call [`Link::handle_frame()`] for every received frame and [`Link::poll()`]
+/// until it returns `None` after every interaction and when the time returned
+/// by [`Link::next_deadline()`] is reached.
+pub struct Link<F> {
+    config: Config,
+    tx: Option<Tx>,
+    rx: Option<Rx>,
+    events: VecDeque<Event<F>>,
+}
+
+impl<F: embedded_can::Frame> Link<F> {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            tx: None,
+            rx: None,
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn is_sending(&self) -> bool {
+        self.tx.is_some()
+    }
+
+    pub fn is_receiving(&self) -> bool {
+        self.rx.is_some()
+    }
+
+    /// Starts sending a message. Progress is reported by [`Link::poll()`].
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error<Infallible>> {
+        if self.tx.is_some() {
+            return Err(Error::Busy);
+        }
+        if data.len() > u32::MAX as usize {
+            return Err(Error::TooLong);
+        }
+        self.tx = Some(Tx {
+            data: data.to_vec(),
+            offset: 0,
+            sequence: 1,
+            phase: TxPhase::Start,
+        });
+        Ok(())
+    }
+
+    /// Time at which [`Link::poll()`] has to be called again to send the next
+    /// frame or detect a timeout.
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let tx = self.tx.as_ref().map(|tx| match tx.phase {
+            TxPhase::Start => Duration::default(),
+            TxPhase::WaitFlowControl { deadline, .. } => deadline,
+            TxPhase::Consecutive { next, .. } => next,
+        });
+        let rx = self.rx.as_ref().map(|rx| rx.deadline);
+        match (tx, rx) {
+            (Some(tx), Some(rx)) => Some(tx.min(rx)),
+            (tx, rx) => tx.or(rx),
+        }
+    }
+
+    /// Returns the next event that is due at `now`.
+    pub fn poll(&mut self, now: Duration) -> Option<Event<F>> {
+        if self.events.is_empty() {
+            self.check_rx_timeout(now);
+            self.progress_tx(now);
+        }
+        self.events.pop_front()
+    }
+
+    /// Processes a received frame, frames for other connections are ignored.
+    pub fn handle_frame(&mut self, now: Duration, frame: &F) {
+        if frame.id() != self.config.rx_id || frame.is_remote_frame() {
+            return;
+        }
+        let data = match self.config.addressing {
+            Addressing::Normal => frame.data(),
+            Addressing::Extended {
+                source: address, ..
+            }
+            | Addressing::Mixed {
+                address_extension: address,
+            } => match frame.data().split_first() {
+                Some((first, rest)) if *first == address => rest,
+                _ => return,
+            },
+        };
+        let frame_len = frame.data().len();
+        // Longest payload of a single frame with the length of this frame.
+        let single_max = if frame_len > 8 { frame_len - 2 } else { 7 } - (frame_len - data.len());
+        let pci = match data.first() {
+            Some(pci) => *pci,
+            None => return,
+        };
+
+        match pci & 0xF0 {
+            PCI_SINGLE_FRAME => self.handle_single_frame(data, frame_len > 8),
+            PCI_FIRST_FRAME => self.handle_first_frame(now, data, single_max),
+            PCI_CONSECUTIVE_FRAME => self.handle_consecutive_frame(now, data),
+            PCI_FLOW_CONTROL => self.handle_flow_control(now, data),
+            _ => {}
+        }
+    }
+
+    fn handle_single_frame(&mut self, data: &[u8], is_long: bool) {
+        let (len, payload) = match data[0] & 0x0F {
+            // Escape sequence for CAN FD frames longer than 8 bytes.
+            0 if is_long && data.len() > 2 => (data[1] as usize, &data[2..]),
+            0 => return,
+            len => (len as usize, &data[1..]),
+        };
+        if len > payload.len() {
+            return;
+        }
+        // A new message aborts the current reception.
+        self.rx = None;
+        self.events
+            .push_back(Event::Received(payload[..len].to_vec()));
+    }
+
+    /// Ignores messages that would have fit into a single frame of `single_max` bytes.
+    fn handle_first_frame(&mut self, now: Duration, data: &[u8], single_max: usize) {
+        if data.len() < 2 {
+            return;
+        }
+        let short_len = ((data[0] as usize & 0x0F) << 8) | data[1] as usize;
+        let (len, payload) = if short_len == 0 {
+            if data.len() < 6 {
+                return;
+            }
+            let len = u32::from_be_bytes([data[2], data[3], data[4], data[5]]);
+            (len as usize, &data[6..])
+        } else {
+            (short_len, &data[2..])
+        };
+        if len <= single_max {
+            return;
+        }
+
+        self.rx = None;
+        if len > self.config.max_len {
+            self.push_flow_control(FLOW_STATUS_OVERFLOW);
+            return;
+        }
+
+        let mut buffer = Vec::with_capacity(len);
+        buffer.extend_from_slice(&payload[..payload.len().min(len)]);
+        self.rx = Some(Rx {
+            data: buffer,
+            len,
+            sequence: 1,
+            block_left: self.config.block_size,
+            deadline: now + self.config.n_cr,
+        });
+        self.push_flow_control(FLOW_STATUS_CONTINUE);
+    }
+
+    fn handle_consecutive_frame(&mut self, now: Duration, data: &[u8]) {
+        let rx = match &mut self.rx {
+            Some(rx) => rx,
+            None => return,
+        };
+
+        let sequence = data[0] & 0x0F;
+        if sequence != rx.sequence {
+            let expected = rx.sequence;
+            self.rx = None;
+            self.events
+                .push_back(Event::ReceiveFailed(Error::UnexpectedSequenceNumber {
+                    expected,
+                    received: sequence,
+                }));
+            return;
+        }
+
+        let take = (rx.len - rx.data.len()).min(data.len() - 1);
+        rx.data.extend_from_slice(&data[1..1 + take]);
+        rx.sequence = (rx.sequence + 1) & 0x0F;
+        rx.deadline = now + self.config.n_cr;
+
+        if rx.data.len() == rx.len {
+            let rx = self.rx.take().unwrap();
+            self.events.push_back(Event::Received(rx.data));
+        } else if self.config.block_size > 0 {
+            rx.block_left -= 1;
+            if rx.block_left == 0 {
+                rx.block_left = self.config.block_size;
+                self.push_flow_control(FLOW_STATUS_CONTINUE);
+            }
+        }
+    }
+
+    fn handle_flow_control(&mut self, now: Duration, data: &[u8]) {
+        let tx = match &mut self.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+        let waits = match tx.phase {
+            TxPhase::WaitFlowControl { waits, .. } => waits,
+            _ => return,
+        };
+        if data.len() < 3 {
+            return;
+        }
+
+        match data[0] & 0x0F {
+            FLOW_STATUS_CONTINUE => {
+                tx.phase = TxPhase::Consecutive {
+                    next: now,
+                    block_left: if data[1] == 0 { None } else { Some(data[1]) },
+                    st_min: decode_st_min(data[2]),
+                };
+            }
+            FLOW_STATUS_WAIT => {
+                if waits >= self.config.max_wait_frames {
+                    self.fail_tx(Error::WaitLimit);
+                } else {
+                    tx.phase = TxPhase::WaitFlowControl {
+                        deadline: now + self.config.n_bs,
+                        waits: waits + 1,
+                    };
+                }
+            }
+            FLOW_STATUS_OVERFLOW => self.fail_tx(Error::Overflow),
+            status => self.fail_tx(Error::InvalidFlowStatus(status)),
+        }
+    }
+
+    fn check_rx_timeout(&mut self, now: Duration) {
+        if self.rx.as_ref().is_some_and(|rx| now >= rx.deadline) {
+            self.rx = None;
+            self.events
+                .push_back(Event::ReceiveFailed(Error::Timeout(Timer::Cr)));
+        }
+    }
+
+    fn progress_tx(&mut self, now: Duration) {
+        let address_len = self.config.address_len();
+        let frame_len = self.config.frame_len;
+        let tx = match &mut self.tx {
+            Some(tx) => tx,
+            None => return,
+        };
+
+        match tx.phase {
+            TxPhase::Start => {
+                let len = tx.data.len();
+                // The short single frame format fits into a classic frame.
+                let short = len <= 7 - address_len;
+                if short || (frame_len > 8 && len <= frame_len - address_len - 2) {
+                    let mut pdu = Vec::with_capacity(len + 2);
+                    if short {
+                        pdu.push(PCI_SINGLE_FRAME | len as u8);
+                    } else {
+                        pdu.extend_from_slice(&[PCI_SINGLE_FRAME, len as u8]);
+                    }
+                    pdu.extend_from_slice(&tx.data);
+                    self.tx = None;
+                    self.push_frame(&pdu, true);
+                    self.events.push_back(Event::Sent);
+                    return;
+                }
+
+                let mut pdu = Vec::with_capacity(frame_len);
+                if len <= MAX_SHORT_LEN {
+                    pdu.extend_from_slice(&[PCI_FIRST_FRAME | (len >> 8) as u8, len as u8]);
+                } else {
+                    pdu.extend_from_slice(&[PCI_FIRST_FRAME, 0]);
+                    pdu.extend_from_slice(&(len as u32).to_be_bytes());
+                }
+                let take = frame_len - address_len - pdu.len();
+                pdu.extend_from_slice(&tx.data[..take]);
+                tx.offset = take;
+                tx.phase = TxPhase::WaitFlowControl {
+                    deadline: now + self.config.n_bs,
+                    waits: 0,
+                };
+                self.push_frame(&pdu, true);
+            }
+            TxPhase::WaitFlowControl { deadline, .. } => {
+                if now >= deadline {
+                    self.fail_tx(Error::Timeout(Timer::Bs));
+                }
+            }
+            TxPhase::Consecutive {
+                next,
+                block_left,
+                st_min,
+            } => {
+                if now < next {
+                    return;
+                }
+
+                let take = (tx.data.len() - tx.offset).min(frame_len - address_len - 1);
+                let mut pdu = Vec::with_capacity(frame_len);
+                pdu.push(PCI_CONSECUTIVE_FRAME | tx.sequence);
+                pdu.extend_from_slice(&tx.data[tx.offset..tx.offset + take]);
+                tx.offset += take;
+                tx.sequence = (tx.sequence + 1) & 0x0F;
+
+                let done = tx.offset == tx.data.len();
+                if done {
+                    self.tx = None;
+                } else {
+                    tx.phase = match block_left {
+                        Some(1) => TxPhase::WaitFlowControl {
+                            deadline: now + self.config.n_bs,
+                            waits: 0,
+                        },
+                        block_left => TxPhase::Consecutive {
+                            next: now + st_min,
+                            block_left: block_left.map(|n| n - 1),
+                            st_min,
+                        },
+                    };
+                }
+                // Only the last consecutive frame may be shorter than the frame length.
+                self.push_frame(&pdu, done);
+                if done {
+                    self.events.push_back(Event::Sent);
+                }
+            }
+        }
+    }
+
+    fn push_flow_control(&mut self, status: u8) {
+        let pdu = [
+            PCI_FLOW_CONTROL | status,
+            self.config.block_size,
+            encode_st_min(self.config.st_min),
+        ];
+        self.push_frame(&pdu, true);
+    }
+
+    fn fail_tx(&mut self, error: Error<Infallible>) {
+        self.tx = None;
+        self.events.push_back(Event::SendFailed(error));
+    }
+
+    /// Queues a frame with the address byte and padding added.
+    fn push_frame(&mut self, pdu: &[u8], may_be_short: bool) {
+        let mut data = Vec::with_capacity(self.config.frame_len);
+        match self.config.addressing {
+            Addressing::Normal => {}
+            Addressing::Extended { target, .. } => data.push(target),
+            Addressing::Mixed { address_extension } => data.push(address_extension),
+        }
+        data.extend_from_slice(pdu);
+
+        let len = if !may_be_short {
+            self.config.frame_len
+        } else if self.config.padding.is_some() {
+            self.config.frame_len.max(data.len())
+        } else {
+            data.len()
+        };
+        // CAN FD frames longer than 8 bytes only come in a few lengths.
+        let len = if len > 8 {
+            (9..=15)
+                .map(crate::dlc_to_len)
+                .find(|&l| l >= len)
+                .unwrap_or(len)
+        } else {
+            len
+        };
+        let padding = self.config.padding.unwrap_or(DEFAULT_FD_PADDING);
+        data.resize(len.max(data.len()), padding);
+
+        match F::new(self.config.tx_id, &data) {
+            Ok(frame) => self.events.push_back(Event::Transmit(frame)),
+            Err(_) => {
+                self.tx = None;
+                self.rx = None;
+                self.events.push_back(Event::SendFailed(Error::FrameLength));
+            }
+        }
+    }
+}
+
+fn encode_st_min(st_min: Duration) -> u8 {
+    let micros = st_min.as_micros();
+    if micros > 0 && micros < 1000 {
+        0xF0 + micros.div_ceil(100) as u8
+    } else {
+        (st_min.as_millis().min(0x7F)) as u8
+    }
+}
+
+fn decode_st_min(value: u8) -> Duration {
+    match value {
+        0x00..=0x7F => Duration::from_millis(value as u64),
+        0xF1..=0xF9 => Duration::from_micros((value - 0xF0) as u64 * 100),
+        // Reserved values are to be treated as the maximum.
+        _ => Duration::from_millis(0x7F),
+    }
+}
+
+/// Blocking ISO-TP connection over a CAN interface.
+///
+/// Frames of other connections received while sending or receiving are dropped.
+pub struct IsoTp<C: ReadTimeout, K> {
+    can: C,
+    clock: K,
+    link: Link<C::Frame>,
+    received: VecDeque<Vec<u8>>,
+}
+
+impl<C, K> IsoTp<C, K>
+where
+    C: ReadTimeout,
+    C::Frame: embedded_can::Frame,
+    K: Clock,
+{
+    pub fn new(can: C, clock: K, config: Config) -> Self {
+        Self {
+            can,
+            clock,
+            link: Link::new(config),
+            received: VecDeque::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> (C, K) {
+        (self.can, self.clock)
+    }
+
+    pub fn can(&mut self) -> &mut C {
+        &mut self.can
+    }
+
+    pub fn clock(&mut self) -> &mut K {
+        &mut self.clock
+    }
+
+    /// Sends a message and blocks until the last frame has been written.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), Error<C::Error>> {
+        self.link.send(data).map_err(Error::cast)?;
+        loop {
+            while let Some(event) = self.link.poll(self.clock.now()) {
+                match event {
+                    Event::Transmit(frame) => self.write(&frame)?,
+                    Event::Sent => return Ok(()),
+                    Event::SendFailed(err) => return Err(err.cast()),
+                    Event::Received(message) => self.received.push_back(message),
+                    Event::ReceiveFailed(_) => {}
+                }
+            }
+            self.wait(None)?;
+        }
+    }
+
+    /// Waits for a message. Returns `Ok(None)` if no message started within `timeout`,
+    /// a message that has started is received completely.
+    pub fn receive(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, Error<C::Error>> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            while let Some(event) = self.link.poll(self.clock.now()) {
+                match event {
+                    Event::Transmit(frame) => self.write(&frame)?,
+                    Event::Received(message) => self.received.push_back(message),
+                    Event::ReceiveFailed(err) => return Err(err.cast()),
+                    Event::Sent | Event::SendFailed(_) => {}
+                }
+            }
+            if let Some(message) = self.received.pop_front() {
+                return Ok(Some(message));
+            }
+            let now = self.clock.now();
+            if !self.link.is_receiving() && now >= deadline {
+                return Ok(None);
+            }
+            // Past the deadline only the link timers end a started message.
+            self.wait(Some(deadline).filter(|&deadline| now < deadline))?;
+        }
+    }
+
+    /// Sends a request and waits for the response.
+    pub fn request(
+        &mut self,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<Option<Vec<u8>>, Error<C::Error>> {
+        self.received.clear();
+        self.send(data)?;
+        self.receive(timeout)
+    }
+
+    fn write(&mut self, frame: &C::Frame) -> Result<(), Error<C::Error>> {
+        let start = self.clock.now();
+        self.can.try_write(frame).map_err(Error::Can)?;
+        if self.clock.now().saturating_sub(start) > self.link.config().n_as {
+            return Err(Error::Timeout(Timer::As));
+        }
+        Ok(())
+    }
+
+    /// Reads frames until the link needs attention or `deadline` is reached.
+    fn wait(&mut self, deadline: Option<Duration>) -> Result<(), Error<C::Error>> {
+        let until = match (self.link.next_deadline(), deadline) {
+            (Some(link), Some(deadline)) => link.min(deadline),
+            (link, deadline) => link.or(deadline).unwrap_or_default(),
+        };
+        let timeout = until.saturating_sub(self.clock.now());
+        if let Some(frame) = self.can.try_read_timeout(timeout).map_err(Error::Can)? {
+            self.link.handle_frame(self.clock.now(), &frame);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        prelude::*,
+        sim::{self, Bus, Context, Node},
+        Frame, StandardId,
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    fn id(raw: u16) -> StandardId {
+        StandardId::new(raw).unwrap()
+    }
+
+    /// Sends every received message back, driven by a `Link` of its own.
+    struct Echo {
+        link: Link<Frame>,
+    }
+
+    impl Echo {
+        fn drive(&mut self, cx: &mut Context<'_>) {
+            while let Some(event) = self.link.poll(cx.now()) {
+                match event {
+                    Event::Transmit(frame) => cx.send(frame),
+                    Event::Received(message) => self.link.send(&message).unwrap(),
+                    _ => {}
+                }
+            }
+            if let Some(deadline) = self.link.next_deadline() {
+                cx.wake_after(deadline.saturating_sub(cx.now()));
+            }
+        }
+    }
+
+    impl Node for Echo {
+        fn on_frame(&mut self, frame: &Frame, cx: &mut Context<'_>) {
+            self.link.handle_frame(cx.now(), frame);
+            self.drive(cx);
+        }
+
+        fn on_timer(&mut self, cx: &mut Context<'_>) {
+            self.drive(cx);
+        }
+    }
+
+    /// Answers every frame with the first frame of a 20 byte message and nothing more.
+    struct Stalled;
+
+    impl Node for Stalled {
+        fn on_frame(&mut self, frame: &Frame, cx: &mut Context<'_>) {
+            if frame.data()[0] & 0xF0 == PCI_SINGLE_FRAME {
+                cx.send(Frame::new(id(0x7E8), &[0x10, 20, 0, 1, 2, 3, 4, 5]).unwrap());
+            }
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        frames: Vec<(Duration, Frame)>,
+    }
+
+    impl Node for Recorder {
+        fn on_frame(&mut self, frame: &Frame, cx: &mut Context<'_>) {
+            self.frames.push((cx.now(), *frame));
+        }
+    }
+
+    fn setup(
+        tester: &Config,
+        ecu: &Config,
+    ) -> (
+        IsoTp<sim::Endpoint, sim::VirtualClock>,
+        Rc<RefCell<Recorder>>,
+    ) {
+        let bus = Bus::new();
+        bus.add_node(Echo {
+            link: Link::new(ecu.clone()),
+        });
+        let recorder = bus.add_node(Recorder::default());
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        (IsoTp::new(endpoint, clock, tester.clone()), recorder)
+    }
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn single_frame_with_padding() {
+        let mut tester = Config::new(id(0x7E0), id(0x7E8));
+        tester.with_padding(0xAA);
+        let mut ecu = Config::new(id(0x7E8), id(0x7E0));
+        ecu.with_padding(0x55);
+        let (mut isotp, recorder) = setup(&tester, &ecu);
+
+        let response = isotp
+            .request(&[0x3E, 0x00], Duration::from_secs(1))
+            .unwrap();
+        assert_eq!(response.as_deref(), Some(&[0x3E, 0x00][..]));
+
+        let recorder = recorder.borrow();
+        let frames: Vec<_> = recorder.frames.iter().map(|(_, f)| f.data()).collect();
+        assert_eq!(
+            frames,
+            [
+                &[0x02, 0x3E, 0x00, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA][..],
+                &[0x02, 0x3E, 0x00, 0x55, 0x55, 0x55, 0x55, 0x55][..],
+            ]
+        );
+    }
+
+    #[test]
+    fn segmented_with_block_size_and_st_min() {
+        let mut tester = Config::new(id(0x7E0), id(0x7E8));
+        tester.with_block_size(2);
+        let mut ecu = Config::new(id(0x7E8), id(0x7E0));
+        ecu.with_block_size(4).with_st_min(Duration::from_millis(5));
+        let (mut isotp, recorder) = setup(&tester, &ecu);
+
+        let message = payload(100);
+        let response = isotp.request(&message, Duration::from_secs(1)).unwrap();
+        assert_eq!(response, Some(message));
+
+        let recorder = recorder.borrow();
+        let requests: Vec<_> = recorder
+            .frames
+            .iter()
+            .filter(|(_, f)| f.id() == Id::from(id(0x7E0)))
+            .collect();
+        // First frame with 6 bytes, then 13 full consecutive frames and one with 3 bytes.
+        let consecutive: Vec<_> = requests
+            .iter()
+            .filter(|(_, f)| f.data()[0] & 0xF0 == 0x20)
+            .collect();
+        assert_eq!(consecutive.len(), 14);
+        assert_eq!(consecutive[13].1.data().len(), 4);
+        for pair in consecutive[..4].windows(2) {
+            assert_eq!(pair[1].0 - pair[0].0, Duration::from_millis(5));
+        }
+        // The tester asks for a flow control after every second frame of the response.
+        let flow_controls = requests.iter().filter(|(_, f)| f.data()[0] == 0x30).count();
+        assert_eq!(flow_controls, 7);
+    }
+
+    #[test]
+    fn extended_and_mixed_addressing() {
+        let mut tester = Config::new(id(0x600), id(0x601));
+        tester.with_addressing(Addressing::Extended {
+            source: 0xF1,
+            target: 0x10,
+        });
+        let mut ecu = Config::new(id(0x601), id(0x600));
+        ecu.with_addressing(Addressing::Extended {
+            source: 0x10,
+            target: 0xF1,
+        });
+        let (mut isotp, recorder) = setup(&tester, &ecu);
+        let message = payload(20);
+        assert_eq!(
+            isotp.request(&message, Duration::from_secs(1)).unwrap(),
+            Some(message)
+        );
+        assert_eq!(recorder.borrow().frames[0].1.data()[..2], [0x10, 0x10]);
+
+        let mut tester = Config::new(id(0x600), id(0x601));
+        tester.with_addressing(Addressing::Mixed {
+            address_extension: 0x42,
+        });
+        let mut ecu = Config::new(id(0x601), id(0x600));
+        ecu.with_addressing(Addressing::Mixed {
+            address_extension: 0x42,
+        });
+        let (mut isotp, _) = setup(&tester, &ecu);
+        assert_eq!(
+            isotp
+                .request(&[1, 2, 3, 4, 5, 6], Duration::from_secs(1))
+                .unwrap(),
+            Some(vec![1, 2, 3, 4, 5, 6])
+        );
+    }
+
+    #[test]
+    fn escape_sequence_for_long_messages() {
+        let mut tester = Config::new(id(0x7E0), id(0x7E8));
+        tester.with_max_len(10_000);
+        let mut ecu = Config::new(id(0x7E8), id(0x7E0));
+        ecu.with_max_len(10_000);
+        let (mut isotp, recorder) = setup(&tester, &ecu);
+
+        let message = payload(5000);
+        let response = isotp.request(&message, Duration::from_secs(1)).unwrap();
+        assert_eq!(response, Some(message));
+        assert_eq!(
+            recorder.borrow().frames[0].1.data()[..6],
+            [0x10, 0x00, 0x00, 0x00, 0x13, 0x88]
+        );
+    }
+
+    #[test]
+    fn overflow_and_timeouts() {
+        let tester = Config::new(id(0x7E0), id(0x7E8));
+        let mut ecu = Config::new(id(0x7E8), id(0x7E0));
+        ecu.with_max_len(50);
+        let (mut isotp, _) = setup(&tester, &ecu);
+        assert!(matches!(isotp.send(&payload(51)), Err(Error::Overflow)));
+
+        // Nobody answers on another ID.
+        let tester = Config::new(id(0x123), id(0x124));
+        let (mut isotp, _) = setup(&tester, &ecu);
+        let start = isotp.clock().now();
+        assert!(matches!(
+            isotp.send(&payload(20)),
+            Err(Error::Timeout(Timer::Bs))
+        ));
+        assert_eq!(isotp.clock().now() - start, Duration::from_secs(1));
+        assert_eq!(isotp.receive(Duration::from_millis(10)).unwrap(), None);
+
+        // A started response ends with N_Cr after the timeout has passed.
+        let bus = Bus::new();
+        bus.add_node(Stalled);
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        let mut isotp = IsoTp::new(endpoint, clock, Config::new(id(0x7E0), id(0x7E8)));
+        isotp.send(&[0x3E, 0x00]).unwrap();
+        let start = isotp.clock().now();
+        assert!(matches!(
+            isotp.receive(Duration::from_millis(10)),
+            Err(Error::Timeout(Timer::Cr))
+        ));
+        assert_eq!(isotp.clock().now() - start, Duration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid frame length")]
+    fn frame_len_below_classic() {
+        Config::new(id(0x7E0), id(0x7E8)).with_frame_len(4);
+    }
+
+    #[test]
+    fn receive_errors() {
+        let mut link = Link::<Frame>::new(Config::new(id(0x7E8), id(0x7E0)));
+        let frame = |data: &[u8]| Frame::new(id(0x7E0), data).unwrap();
+        let now = Duration::default();
+
+        link.handle_frame(now, &frame(&[0x10, 0x14, 0, 1, 2, 3, 4, 5]));
+        assert!(matches!(link.poll(now), Some(Event::Transmit(_))));
+        link.handle_frame(now, &frame(&[0x22, 6, 7, 8, 9, 10, 11, 12]));
+        assert!(matches!(
+            link.poll(now),
+            Some(Event::ReceiveFailed(Error::UnexpectedSequenceNumber {
+                expected: 1,
+                received: 2
+            }))
+        ));
+
+        link.handle_frame(now, &frame(&[0x10, 0x14, 0, 1, 2, 3, 4, 5]));
+        assert!(matches!(link.poll(now), Some(Event::Transmit(_))));
+        assert_eq!(link.next_deadline(), Some(Duration::from_secs(1)));
+        assert!(matches!(
+            link.poll(Duration::from_secs(1)),
+            Some(Event::ReceiveFailed(Error::Timeout(Timer::Cr)))
+        ));
+        assert!(link.poll(Duration::from_secs(1)).is_none());
+
+        // The CAN FD single frame escape in a classic frame, and first frames
+        // of messages that fit into a single frame, are ignored.
+        link.handle_frame(now, &frame(&[0x00, 0x03, 1, 2, 3]));
+        link.handle_frame(now, &frame(&[0x10, 0x07, 0, 1, 2, 3, 4, 5]));
+        assert!(link.poll(now).is_none());
+    }
+
+    #[test]
+    fn st_min_encoding() {
+        assert_eq!(encode_st_min(Duration::from_micros(300)), 0xF3);
+        assert_eq!(encode_st_min(Duration::from_millis(200)), 0x7F);
+        assert_eq!(decode_st_min(0xF9), Duration::from_micros(900));
+        assert_eq!(decode_st_min(0x80), Duration::from_millis(127));
+    }
+}
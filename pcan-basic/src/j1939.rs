@@ -0,0 +1,765 @@
+//! SAE J1939 on 29-bit identifiers.
+//!
+//! The identifier holds the priority, the parameter group number (PGN) and
+//! the source address. PGNs with a PDU format below 240 (PDU1) are sent to a
+//! destination address in the PDU specific byte, all others are broadcast.
+//!
+//! [`Stack`] claims an address, reassembles messages of the transport
+//! protocol and answers requests:
+//!
+//! ```no_run
+//! use pcan_basic::{j1939::{Name, Stack}, Interface, SystemClock};
+//! use std::time::Duration;
+//!
+//! // No filter: the stack needs address claims, requests and transport
+//! // protocol frames besides the parameter groups of interest.
+//! let can = Interface::init()?;
+//!
+//! let name = Name {
+//!     arbitrary_address_capable: true,
+//!     industry_group: 1,
+//!     identity_number: 42,
+//!     ..Name::default()
+//! };
+//! let mut j1939 = Stack::new(can, SystemClock::new(), name, 0x80);
+//! j1939.claim_address()?;
+//! while let Some(message) = j1939.receive(Duration::from_secs(1))? {
+//!     println!("{:02X} sent {:X?}", message.source, message.data);
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub mod address;
+pub mod dm;
+pub mod tp;
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    time::Duration,
+};
+
+use embedded_can::{Frame, Id};
+
+use crate::{Clock, ExtendedId, Filter, ReadTimeout};
+
+use self::{address::Claimer, tp::Transport};
+
+/// Destination address of broadcasts.
+pub const GLOBAL: u8 = 0xFF;
+/// Source address of nodes without an address.
+pub const NULL: u8 = 0xFE;
+
+/// Parameter groups of the network layers.
+pub mod pgn {
+    pub const ACKNOWLEDGEMENT: u32 = 0xE800;
+    pub const REQUEST: u32 = 0xEA00;
+    pub const TP_DT: u32 = 0xEB00;
+    pub const TP_CM: u32 = 0xEC00;
+    pub const ADDRESS_CLAIMED: u32 = 0xEE00;
+    pub const COMMANDED_ADDRESS: u32 = 0xFED8;
+}
+
+/// Whether `pgn` is sent to a destination address.
+pub fn is_pdu1(pgn: u32) -> bool {
+    (pgn >> 8) & 0xFF < 240
+}
+
+/// Fields of a J1939 identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// 0 is the highest priority, 7 the lowest.
+    pub priority: u8,
+    /// PGN without the destination address.
+    pub pgn: u32,
+    pub source: u8,
+    /// [`GLOBAL`] for broadcast PGNs.
+    pub destination: u8,
+}
+
+impl Header {
+    /// Header with the default priority 6.
+    pub fn new(pgn: u32, source: u8, destination: u8) -> Self {
+        Self {
+            priority: 6,
+            pgn,
+            source,
+            destination,
+        }
+    }
+
+    pub fn from_id(id: ExtendedId) -> Self {
+        let raw = id.as_raw();
+        let mut pgn = (raw >> 8) & 0x3_FFFF;
+        let mut destination = GLOBAL;
+        if is_pdu1(pgn) {
+            destination = pgn as u8;
+            pgn &= !0xFF;
+        }
+        Self {
+            priority: (raw >> 26) as u8 & 0x7,
+            pgn,
+            source: raw as u8,
+            destination,
+        }
+    }
+
+    pub fn id(&self) -> ExtendedId {
+        let mut pgn = self.pgn & 0x3_FFFF;
+        if is_pdu1(pgn) {
+            pgn = pgn & !0xFF | self.destination as u32;
+        }
+        let raw = (self.priority as u32 & 0x7) << 26 | pgn << 8 | self.source as u32;
+        ExtendedId::new(raw).unwrap()
+    }
+}
+
+/// Filter for frames of `pgn` from any source.
+///
+/// PDU1 PGNs match any destination address. A [`Stack`] behind such a filter
+/// misses the address claims, requests and transport protocol frames it needs.
+pub fn filter(pgn: u32) -> Filter {
+    let id = Header::new(pgn, 0, 0).id();
+    let mask = if is_pdu1(pgn) {
+        0x03FF_0000
+    } else {
+        0x03FF_FF00
+    };
+    let mut filter = Filter::new(Id::Extended(id));
+    filter.with_mask(mask);
+    filter
+}
+
+/// 64-bit NAME identifying the function of a node.
+///
+/// Lower NAMEs win address arbitration.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Name {
+    pub arbitrary_address_capable: bool,
+    pub industry_group: u8,
+    pub vehicle_system_instance: u8,
+    pub vehicle_system: u8,
+    pub function: u8,
+    pub function_instance: u8,
+    pub ecu_instance: u8,
+    pub manufacturer_code: u16,
+    pub identity_number: u32,
+}
+
+impl Name {
+    pub fn from_raw(raw: u64) -> Self {
+        Self {
+            arbitrary_address_capable: raw >> 63 != 0,
+            industry_group: (raw >> 60) as u8 & 0x7,
+            vehicle_system_instance: (raw >> 56) as u8 & 0xF,
+            vehicle_system: (raw >> 49) as u8 & 0x7F,
+            function: (raw >> 40) as u8,
+            function_instance: (raw >> 35) as u8 & 0x1F,
+            ecu_instance: (raw >> 32) as u8 & 0x7,
+            manufacturer_code: (raw >> 21) as u16 & 0x7FF,
+            identity_number: raw as u32 & 0x1F_FFFF,
+        }
+    }
+
+    pub fn to_raw(&self) -> u64 {
+        (self.arbitrary_address_capable as u64) << 63
+            | (self.industry_group as u64 & 0x7) << 60
+            | (self.vehicle_system_instance as u64 & 0xF) << 56
+            | (self.vehicle_system as u64 & 0x7F) << 49
+            | (self.function as u64) << 40
+            | (self.function_instance as u64 & 0x1F) << 35
+            | (self.ecu_instance as u64 & 0x7) << 32
+            | (self.manufacturer_code as u64 & 0x7FF) << 21
+            | self.identity_number as u64 & 0x1F_FFFF
+    }
+}
+
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Name {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.to_raw().cmp(&other.to_raw())
+    }
+}
+
+/// A complete message, of a single frame or reassembled by the transport
+/// protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub priority: u8,
+    pub pgn: u32,
+    pub source: u8,
+    pub destination: u8,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Can(E),
+    /// No address has been claimed.
+    NoAddress,
+    /// The address was lost and no other address is available.
+    CannotClaim,
+    /// The message does not fit into the transport protocol.
+    TooLong,
+    /// A transfer to the same destination is in progress.
+    Busy,
+    /// The transport protocol was aborted with a reason from [`tp::reason`].
+    Aborted(u8),
+    /// No response to a request in time.
+    Timeout,
+    /// A request was answered with a negative acknowledgement, the control
+    /// byte is 1 for NACK, 2 for access denied and 3 for cannot respond.
+    Rejected(u8),
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Can(err) => write!(f, "CAN error: {}", err),
+            Error::NoAddress => write!(f, "No address claimed"),
+            Error::CannotClaim => write!(f, "Cannot claim an address"),
+            Error::TooLong => write!(f, "Message too long for the transport protocol"),
+            Error::Busy => write!(f, "Transfer to the destination in progress"),
+            Error::Aborted(reason) => write!(f, "Transfer aborted with reason {}", reason),
+            Error::Timeout => write!(f, "J1939 timeout"),
+            Error::Rejected(control) => write!(f, "Request rejected with control byte {}", control),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Error<E> {}
+
+impl<E> From<E> for Error<E> {
+    fn from(err: E) -> Self {
+        Error::Can(err)
+    }
+}
+
+/// J1939 node on a CAN interface.
+pub struct Stack<C, K> {
+    can: C,
+    clock: K,
+    claimer: Claimer,
+    transport: Transport,
+    cannot_claim: bool,
+    responses: BTreeMap<u32, Vec<u8>>,
+    /// Responses waiting for a transfer to the same destination to finish.
+    deferred: VecDeque<Message>,
+    /// Parameter groups and destinations of responses being transferred.
+    responding: Vec<(u32, u8)>,
+    received: VecDeque<Message>,
+    completed: VecDeque<tp::Event>,
+}
+
+impl<C, K> Stack<C, K>
+where
+    C: ReadTimeout,
+    C::Frame: Frame,
+    K: Clock,
+{
+    pub fn new(can: C, clock: K, name: Name, preferred: u8) -> Self {
+        Self {
+            can,
+            clock,
+            claimer: Claimer::new(name, preferred),
+            transport: Transport::new(NULL),
+            cannot_claim: false,
+            responses: BTreeMap::new(),
+            deferred: VecDeque::new(),
+            responding: Vec::new(),
+            received: VecDeque::new(),
+            completed: VecDeque::new(),
+        }
+    }
+
+    /// Answers requests for `pgn` with `data`.
+    pub fn with_response(&mut self, pgn: u32, data: &[u8]) -> &mut Self {
+        self.responses.insert(pgn, data.to_vec());
+        self
+    }
+
+    /// The claimed address.
+    pub fn address(&self) -> Option<u8> {
+        self.claimer.address()
+    }
+
+    pub fn claimer(&self) -> &Claimer {
+        &self.claimer
+    }
+
+    pub fn can(&mut self) -> &mut C {
+        &mut self.can
+    }
+
+    pub fn into_inner(self) -> (C, K) {
+        (self.can, self.clock)
+    }
+
+    /// Claims the preferred address or, if arbitrary address capable, another
+    /// free one.
+    pub fn claim_address(&mut self) -> Result<u8, Error<C::Error>> {
+        self.cannot_claim = false;
+        self.claimer.start(self.clock.now());
+        loop {
+            if let Some(address) = self.claimer.address() {
+                return Ok(address);
+            }
+            if self.cannot_claim {
+                return Err(Error::CannotClaim);
+            }
+            let deadline = self.clock.now() + address::CLAIM_TIME;
+            self.process(deadline)?;
+        }
+    }
+
+    /// Sends a message, using the transport protocol for more than 8 bytes.
+    ///
+    /// Blocks until the transfer is complete.
+    pub fn send(
+        &mut self,
+        priority: u8,
+        pgn: u32,
+        destination: u8,
+        data: &[u8],
+    ) -> Result<(), Error<C::Error>> {
+        let source = self.address().ok_or(Error::NoAddress)?;
+        let destination = if is_pdu1(pgn) { destination } else { GLOBAL };
+        if data.len() <= 8 {
+            let header = Header {
+                priority,
+                pgn,
+                source,
+                destination,
+            };
+            return self.write(&header, data);
+        }
+
+        let message = Message {
+            priority,
+            pgn,
+            source,
+            destination,
+            data: data.to_vec(),
+        };
+        self.transport
+            .send(self.clock.now(), message)
+            .map_err(|err| match err {
+                tp::SendError::TooLong => Error::TooLong,
+                tp::SendError::Busy => Error::Busy,
+            })?;
+        loop {
+            let position = self.completed.iter().position(|event| match *event {
+                tp::Event::Sent {
+                    pgn: p,
+                    destination: d,
+                } => p == pgn && d == destination,
+                tp::Event::Aborted { pgn: p, peer, .. } => p == pgn && peer == destination,
+                tp::Event::Received(_) => false,
+            });
+            match position.and_then(|i| self.completed.remove(i)) {
+                Some(tp::Event::Aborted { reason, .. }) => return Err(Error::Aborted(reason)),
+                Some(_) => return Ok(()),
+                None => {
+                    let deadline = self.clock.now() + tp::T3;
+                    self.process(deadline)?;
+                }
+            }
+        }
+    }
+
+    /// Requests `pgn` from `destination`, or from all nodes if it is
+    /// [`GLOBAL`].
+    pub fn request(&mut self, pgn: u32, destination: u8) -> Result<(), Error<C::Error>> {
+        let data = pgn.to_le_bytes();
+        self.send(6, self::pgn::REQUEST, destination, &data[..3])
+    }
+
+    /// Requests `pgn` and waits up to `timeout` for the response, which is
+    /// either the parameter group or an acknowledgement for it to this node.
+    ///
+    /// Other messages received while waiting are kept for
+    /// [`receive()`](Self::receive).
+    pub fn query(
+        &mut self,
+        pgn: u32,
+        destination: u8,
+        timeout: Duration,
+    ) -> Result<Option<Message>, Error<C::Error>> {
+        self.request(pgn, destination)?;
+        let deadline = self.clock.now() + timeout;
+        let address = self.address();
+        let is_response = |message: &Message| {
+            let from = destination == GLOBAL || message.source == destination;
+            // Acknowledgements are broadcast with the requester in byte 4.
+            let acknowledged = message.pgn == self::pgn::ACKNOWLEDGEMENT
+                && message.data.len() >= 8
+                && Some(message.data[4]) == address
+                && u32::from_le_bytes([message.data[5], message.data[6], message.data[7], 0])
+                    == pgn;
+            from && (message.pgn == pgn || acknowledged)
+        };
+        loop {
+            if let Some(i) = self.received.iter().position(is_response) {
+                return Ok(self.received.remove(i));
+            }
+            if self.clock.now() >= deadline {
+                return Ok(None);
+            }
+            self.process(deadline)?;
+        }
+    }
+
+    /// Waits up to `timeout` for a message to this node or a broadcast.
+    ///
+    /// Requests, address claims and the transport protocol are handled while
+    /// waiting.
+    pub fn receive(&mut self, timeout: Duration) -> Result<Option<Message>, Error<C::Error>> {
+        let deadline = self.clock.now() + timeout;
+        loop {
+            if let Some(message) = self.received.pop_front() {
+                return Ok(Some(message));
+            }
+            if self.clock.now() >= deadline {
+                return Ok(None);
+            }
+            self.process(deadline)?;
+        }
+    }
+
+    /// Sends pending frames, handles timeouts and receives up to one frame
+    /// before `deadline`.
+    fn process(&mut self, deadline: Duration) -> Result<(), Error<C::Error>> {
+        let now = self.clock.now();
+        while let Some(event) = self.claimer.poll(now) {
+            match event {
+                address::Event::Claimed(address) => self.transport.set_address(address),
+                address::Event::CannotClaim => {
+                    self.transport.set_address(NULL);
+                    self.cannot_claim = true;
+                }
+            }
+        }
+        if self.address().is_none() {
+            self.deferred.clear();
+        }
+        for _ in 0..self.deferred.len() {
+            let message = self.deferred.pop_front().unwrap();
+            let key = (message.pgn, message.destination);
+            match self.transport.send(now, message.clone()) {
+                Ok(()) => self.responding.push(key),
+                Err(tp::SendError::Busy) => self.deferred.push_back(message),
+                Err(tp::SendError::TooLong) => {}
+            }
+        }
+        while let Some(event) = self.transport.poll(now) {
+            let key = match event {
+                tp::Event::Received(message) => {
+                    self.received.push_back(message);
+                    continue;
+                }
+                tp::Event::Sent { pgn, destination } => (pgn, destination),
+                tp::Event::Aborted { pgn, peer, .. } => (pgn, peer),
+            };
+            // Nobody waits for the end of a response.
+            match self.responding.iter().position(|&r| r == key) {
+                Some(i) => {
+                    self.responding.remove(i);
+                }
+                None => self.completed.push_back(event),
+            }
+        }
+        while let Some((header, data)) = self.claimer.poll_transmit() {
+            self.write(&header, &data)?;
+        }
+        while let Some((header, data)) = self.transport.poll_transmit() {
+            self.write(&header, &data)?;
+        }
+
+        let wake = [self.claimer.next_deadline(), self.transport.next_deadline()]
+            .iter()
+            .flatten()
+            .fold(deadline, |wake, &d| wake.min(d));
+        let frame = match self.can.try_read_timeout(wake.saturating_sub(now))? {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let header = match frame.id() {
+            Id::Extended(id) if !frame.is_remote_frame() => Header::from_id(id),
+            _ => return Ok(()),
+        };
+        let now = self.clock.now();
+        let data = frame.data();
+        self.claimer.handle(now, &header, data);
+        self.transport.handle(now, &header, data);
+
+        let address = self.address();
+        if header.destination != GLOBAL && Some(header.destination) != address {
+            return Ok(());
+        }
+        match header.pgn {
+            pgn::TP_CM | pgn::TP_DT | pgn::ADDRESS_CLAIMED => {}
+            pgn::REQUEST if data.len() >= 3 => {
+                let requested = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+                if address.is_some() && requested != pgn::ADDRESS_CLAIMED {
+                    self.respond(&header, requested)?;
+                }
+            }
+            _ => self.received.push_back(Message {
+                priority: header.priority,
+                pgn: header.pgn,
+                source: header.source,
+                destination: header.destination,
+                data: data.to_vec(),
+            }),
+        }
+        Ok(())
+    }
+
+    /// Answers a request with a configured response or, if it was sent to
+    /// this node, a negative acknowledgement.
+    ///
+    /// Responses using the transport protocol are left to
+    /// [`process()`](Self::process) instead of blocking.
+    fn respond(&mut self, request: &Header, requested: u32) -> Result<(), Error<C::Error>> {
+        let source = self.address().ok_or(Error::NoAddress)?;
+        if let Some(data) = self.responses.get(&requested).cloned() {
+            let destination = if request.destination != GLOBAL && is_pdu1(requested) {
+                request.source
+            } else {
+                GLOBAL
+            };
+            let message = Message {
+                priority: 6,
+                pgn: requested,
+                source,
+                destination,
+                data,
+            };
+            if message.data.len() <= 8 {
+                let header = Header {
+                    priority: message.priority,
+                    pgn: message.pgn,
+                    source,
+                    destination,
+                };
+                return self.write(&header, &message.data);
+            }
+            let queued = self
+                .deferred
+                .iter()
+                .any(|m| (m.pgn, m.destination) == (requested, destination));
+            if !queued {
+                self.deferred.push_back(message);
+            }
+            return Ok(());
+        }
+        if request.destination == GLOBAL {
+            return Ok(());
+        }
+        let mut data = vec![1, 0xFF, 0xFF, 0xFF, request.source];
+        data.extend_from_slice(&requested.to_le_bytes()[..3]);
+        let header = Header {
+            priority: 6,
+            pgn: pgn::ACKNOWLEDGEMENT,
+            source,
+            destination: GLOBAL,
+        };
+        self.write(&header, &data)
+    }
+
+    fn write(&mut self, header: &Header, data: &[u8]) -> Result<(), Error<C::Error>> {
+        let frame = C::Frame::new(Id::Extended(header.id()), data).unwrap();
+        Ok(self.can.try_write(&frame)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_can::blocking::Can as _;
+
+    use super::*;
+    use crate::sim::{Bus, Context, Node};
+
+    #[test]
+    fn header() {
+        // Engine speed from the engine, EEC1.
+        let id = ExtendedId::new(0x0CF0_0400).unwrap();
+        let header = Header::from_id(id);
+        assert_eq!(
+            header,
+            Header {
+                priority: 3,
+                pgn: 0xF004,
+                source: 0x00,
+                destination: GLOBAL,
+            }
+        );
+        assert_eq!(header.id(), id);
+
+        // Request from 0xF9 to 0x00.
+        let id = ExtendedId::new(0x18EA_00F9).unwrap();
+        let header = Header::from_id(id);
+        assert_eq!(header, Header::new(pgn::REQUEST, 0xF9, 0x00));
+        assert_eq!(header.id(), id);
+
+        let filter = filter(pgn::REQUEST);
+        assert!(filter.matches(Id::Extended(id)));
+        assert!(filter.matches(Header::new(pgn::REQUEST, 0x10, GLOBAL).id().into()));
+        assert!(!filter.matches(Header::new(pgn::TP_CM, 0xF9, 0x00).id().into()));
+        let filter = super::filter(0xF004);
+        assert!(filter.matches(Header::new(0xF004, 0x01, GLOBAL).id().into()));
+        assert!(!filter.matches(Header::new(0xF003, 0x00, GLOBAL).id().into()));
+
+        let name = Name {
+            arbitrary_address_capable: true,
+            industry_group: 2,
+            vehicle_system_instance: 3,
+            vehicle_system: 4,
+            function: 5,
+            function_instance: 6,
+            ecu_instance: 7,
+            manufacturer_code: 0x123,
+            identity_number: 0x12345,
+        };
+        assert_eq!(name.to_raw(), 0xA308_0537_2461_2345);
+        assert_eq!(Name::from_raw(name.to_raw()), name);
+    }
+
+    /// Node with a lower NAME claiming the same address, with a 20 byte
+    /// response to requests for PGN 0xFEDA.
+    ///
+    /// Its claim is sent with the first reply to the stack.
+    struct Peer {
+        claimer: Claimer,
+        transport: Transport,
+        received: Vec<Message>,
+    }
+
+    impl Peer {
+        fn flush(&mut self, cx: &mut Context<'_>) {
+            while let Some(event) = self.claimer.poll(cx.now()) {
+                if let address::Event::Claimed(address) = event {
+                    self.transport.set_address(address);
+                }
+            }
+            while let Some(event) = self.transport.poll(cx.now()) {
+                if let tp::Event::Received(message) = event {
+                    self.received.push(message);
+                }
+            }
+            while let Some((header, data)) = self
+                .claimer
+                .poll_transmit()
+                .or_else(|| self.transport.poll_transmit())
+            {
+                cx.send(crate::Frame::new(Id::Extended(header.id()), &data).unwrap());
+            }
+            let deadline = [self.claimer.next_deadline(), self.transport.next_deadline()]
+                .iter()
+                .flatten()
+                .min()
+                .copied();
+            if let Some(deadline) = deadline {
+                cx.wake_after(deadline.saturating_sub(cx.now()));
+            }
+        }
+    }
+
+    impl Node for Peer {
+        fn on_frame(&mut self, frame: &crate::Frame, cx: &mut Context<'_>) {
+            let header = match frame.id() {
+                Id::Extended(id) => Header::from_id(id),
+                Id::Standard(_) => return,
+            };
+            let now = cx.now();
+            self.claimer.handle(now, &header, frame.data());
+            self.transport.handle(now, &header, frame.data());
+            if header.pgn == pgn::REQUEST && frame.data()[..3] == [0xDA, 0xFE, 0x00] {
+                let message = Message {
+                    priority: 6,
+                    pgn: 0xFEDA,
+                    source: self.claimer.address().unwrap(),
+                    destination: GLOBAL,
+                    data: (0..20).collect(),
+                };
+                self.transport.send(now, message).unwrap();
+            }
+            self.flush(cx);
+        }
+
+        fn on_timer(&mut self, cx: &mut Context<'_>) {
+            self.flush(cx);
+        }
+    }
+
+    #[test]
+    fn address_claim_and_transport() {
+        let bus = Bus::new();
+        let mut claimer = Claimer::new(Name::from_raw(0x8000_0000_0000_0001), 0x80);
+        claimer.start(Duration::ZERO);
+        let peer = bus.add_node(Peer {
+            claimer,
+            transport: Transport::new(NULL),
+            received: Vec::new(),
+        });
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        let name = Name::from_raw(0x8000_0000_0000_0002);
+        let mut stack = Stack::new(endpoint, clock, name, 0x80);
+
+        // The peer wins 0x80.
+        assert_eq!(stack.claim_address().unwrap(), 0x81);
+        assert_eq!(peer.borrow().claimer.address(), Some(0x80));
+        assert_eq!(
+            stack.claimer().others().collect::<Vec<_>>(),
+            [(0x80, Name::from_raw(0x8000_0000_0000_0001))]
+        );
+
+        // Broadcast response to a global request.
+        let response = stack.query(0xFEDA, GLOBAL, Duration::from_secs(1)).unwrap();
+        assert_eq!(response.unwrap().data, (0..20).collect::<Vec<_>>());
+
+        // Connection mode to the peer.
+        stack.send(7, 0xEF00, 0x80, &[0xAA; 100]).unwrap();
+        let received = peer.borrow_mut().received.pop().unwrap();
+        assert_eq!((received.pgn, received.source), (0xEF00, 0x81));
+        assert_eq!(received.data, [0xAA; 100]);
+
+        // Global requests for a long response while a broadcast is sent. The
+        // response follows the broadcast, once.
+        let mut tester = bus.endpoint();
+        let request = crate::Frame::new(
+            Id::Extended(Header::new(pgn::REQUEST, 0x90, GLOBAL).id()),
+            &[0xEB, 0xFE, 0x00],
+        )
+        .unwrap();
+        stack.with_response(0xFEEB, &[0x11; 20]);
+        stack.send(6, 0xFECA, GLOBAL, &[0xBB; 30]).unwrap();
+        tester.try_write(&request).unwrap();
+        tester.try_write(&request).unwrap();
+        stack.send(6, 0xFECB, GLOBAL, &[0xCC; 30]).unwrap();
+        assert_eq!(stack.receive(Duration::from_secs(1)).unwrap(), None);
+
+        let mut announced = Vec::new();
+        while let Some(frame) = tester.try_read_timeout(Duration::ZERO).unwrap() {
+            let header = Header::from_id(match frame.id() {
+                Id::Extended(id) => id,
+                Id::Standard(_) => continue,
+            });
+            if header.source == 0x81 && header.pgn == pgn::TP_CM {
+                announced.push(u32::from_le_bytes([
+                    frame.data()[5],
+                    frame.data()[6],
+                    frame.data()[7],
+                    0,
+                ]));
+            }
+        }
+        assert_eq!(announced, [0xFECA, 0xFECB, 0xFEEB]);
+    }
+}
@@ -0,0 +1,170 @@
+//! Address claiming (J1939-81).
+//!
+//! A node claims its address with its [`Name`]. If two nodes claim the same
+//! address, the one with the lower NAME keeps it. The other one claims a free
+//! address from the range for self-configurable addresses if it is
+//! arbitrary address capable, or sends a cannot claim message.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::TryInto,
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+use super::{pgn, Header, Name, GLOBAL, NULL};
+
+/// Time without contention until a claimed address may be used.
+pub const CLAIM_TIME: Duration = Duration::from_millis(250);
+
+/// Addresses for arbitrary address capable nodes.
+pub const SELF_CONFIGURABLE: RangeInclusive<u8> = 128..=247;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The address may be used.
+    Claimed(u8),
+    /// The address was lost and no other address is available.
+    CannotClaim,
+}
+
+/// Sans-IO address claim procedure of a node.
+#[derive(Debug)]
+pub struct Claimer {
+    name: Name,
+    preferred: u8,
+    started: bool,
+    /// Address being claimed or claimed.
+    address: Option<u8>,
+    claimed: bool,
+    /// When the claim is valid without contention.
+    valid_at: Option<Duration>,
+    others: BTreeMap<u8, Name>,
+    transmit: VecDeque<(Header, Vec<u8>)>,
+    events: VecDeque<Event>,
+}
+
+impl Claimer {
+    pub fn new(name: Name, preferred: u8) -> Self {
+        Self {
+            name,
+            preferred,
+            started: false,
+            address: None,
+            claimed: false,
+            valid_at: None,
+            others: BTreeMap::new(),
+            transmit: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    pub fn name(&self) -> Name {
+        self.name
+    }
+
+    /// The claimed address, `None` while claiming or if no address could be
+    /// claimed.
+    pub fn address(&self) -> Option<u8> {
+        self.address.filter(|_| self.claimed)
+    }
+
+    /// Addresses claimed by other nodes.
+    pub fn others(&self) -> impl Iterator<Item = (u8, Name)> + '_ {
+        self.others.iter().map(|(&address, &name)| (address, name))
+    }
+
+    /// Starts claiming the preferred address.
+    pub fn start(&mut self, now: Duration) {
+        self.started = true;
+        self.claim(now, self.preferred);
+    }
+
+    pub fn handle(&mut self, now: Duration, header: &Header, data: &[u8]) {
+        match header.pgn {
+            pgn::ADDRESS_CLAIMED if data.len() == 8 => {
+                let name = Name::from_raw(u64::from_le_bytes(data.try_into().unwrap()));
+                self.others.retain(|_, other| *other != name);
+                if header.source == NULL || name == self.name {
+                    return;
+                }
+                self.others.insert(header.source, name);
+                if self.address != Some(header.source) {
+                    return;
+                }
+                if self.name < name {
+                    // Defend the address.
+                    self.send_claim(header.source);
+                } else if let Some(address) = self.free_address() {
+                    self.claim(now, address);
+                } else {
+                    self.cannot_claim();
+                }
+            }
+            pgn::REQUEST if data.len() >= 3 => {
+                let requested = u32::from_le_bytes([data[0], data[1], data[2], 0]);
+                let to_us =
+                    header.destination == GLOBAL || Some(header.destination) == self.address;
+                if requested == pgn::ADDRESS_CLAIMED && to_us && self.started {
+                    // Cannot claim if there is no address.
+                    self.send_claim(self.address.unwrap_or(NULL));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn poll(&mut self, now: Duration) -> Option<Event> {
+        if self.valid_at.is_some_and(|at| now >= at) {
+            self.valid_at = None;
+            self.claimed = true;
+            self.events.push_back(Event::Claimed(self.address.unwrap()));
+        }
+        self.events.pop_front()
+    }
+
+    /// Returns the next message to send, with a data length of 8.
+    pub fn poll_transmit(&mut self) -> Option<(Header, Vec<u8>)> {
+        self.transmit.pop_front()
+    }
+
+    pub fn next_deadline(&self) -> Option<Duration> {
+        self.valid_at
+    }
+
+    fn claim(&mut self, now: Duration, address: u8) {
+        self.address = Some(address);
+        self.claimed = false;
+        self.valid_at = Some(now + CLAIM_TIME);
+        self.send_claim(address);
+    }
+
+    fn cannot_claim(&mut self) {
+        self.address = None;
+        self.claimed = false;
+        self.valid_at = None;
+        self.send_claim(NULL);
+        self.events.push_back(Event::CannotClaim);
+    }
+
+    fn send_claim(&mut self, source: u8) {
+        let header = Header::new(pgn::ADDRESS_CLAIMED, source, GLOBAL);
+        self.transmit
+            .push_back((header, self.name.to_raw().to_le_bytes().to_vec()));
+    }
+
+    /// Preferred address if free, otherwise the first free self-configurable one.
+    fn free_address(&self) -> Option<u8> {
+        if !self.name.arbitrary_address_capable {
+            return None;
+        }
+        let taken = |address: &u8| {
+            self.others
+                .get(address)
+                .is_some_and(|other| *other < self.name)
+        };
+        std::iter::once(self.preferred)
+            .chain(SELF_CONFIGURABLE)
+            .find(|address| !taken(address))
+    }
+}
@@ -0,0 +1,530 @@
+//! Diagnostic messages (J1939-73) with lamp status and trouble codes.
+//!
+//! DM1 is broadcast once per second while there are active trouble codes,
+//! DM2 is only sent on request. Both have the same layout and decode to a
+//! [`DiagnosticMessage`]:
+//!
+//! ```no_run
+//! use pcan_basic::{j1939::{dm, Name, Stack}, Interface, SystemClock};
+//! use std::{collections::BTreeMap, time::Duration};
+//!
+//! let mut names = BTreeMap::new();
+//! names.insert(110, "Engine Coolant Temperature".to_string());
+//!
+//! let name = Name { arbitrary_address_capable: true, ..Name::default() };
+//! let mut j1939 = Stack::new(Interface::init()?, SystemClock::new(), name, 0xF9);
+//! j1939.claim_address()?;
+//! while let Some(message) = j1939.receive(Duration::from_secs(5))? {
+//!     if message.pgn == dm::DM1 {
+//!         let dm1 = dm::DiagnosticMessage::decode(&message.data, dm::Version::V4);
+//!         for dtc in &dm1.dtcs {
+//!             println!("{:02X}: {}", message.source, dtc.describe(&names));
+//!         }
+//!     }
+//! }
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    time::Duration,
+};
+
+use embedded_can::Frame;
+
+use super::{pgn, Error, Stack};
+use crate::{Clock, ReadTimeout};
+
+/// Active diagnostic trouble codes.
+pub const DM1: u32 = 0xFECA;
+/// Previously active diagnostic trouble codes.
+pub const DM2: u32 = 0xFECB;
+/// Clear previously active diagnostic trouble codes.
+pub const DM3: u32 = 0xFECC;
+/// Clear active diagnostic trouble codes.
+pub const DM11: u32 = 0xFED3;
+
+/// Occurrence count value for not available.
+const OC_NOT_AVAILABLE: u8 = 0x7F;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LampStatus {
+    Off,
+    On,
+    Reserved,
+    NotAvailable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flash {
+    /// 1 Hz.
+    Slow,
+    /// 2 Hz.
+    Fast,
+    Reserved,
+    /// Not available or the lamp does not flash.
+    Off,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lamp {
+    pub status: LampStatus,
+    pub flash: Flash,
+}
+
+impl Lamp {
+    fn from_bits(status: u8, flash: u8) -> Self {
+        let status = match status & 0x3 {
+            0 => LampStatus::Off,
+            1 => LampStatus::On,
+            2 => LampStatus::Reserved,
+            _ => LampStatus::NotAvailable,
+        };
+        let flash = match flash & 0x3 {
+            0 => Flash::Slow,
+            1 => Flash::Fast,
+            2 => Flash::Reserved,
+            _ => Flash::Off,
+        };
+        Self { status, flash }
+    }
+
+    fn to_bits(self) -> (u8, u8) {
+        (self.status as u8, self.flash as u8)
+    }
+}
+
+/// The four lamps in the first two bytes of DM1 and DM2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lamps {
+    pub malfunction: Lamp,
+    pub red_stop: Lamp,
+    pub amber_warning: Lamp,
+    pub protect: Lamp,
+}
+
+impl Lamps {
+    pub fn from_bytes(bytes: [u8; 2]) -> Self {
+        let lamp = |shift| Lamp::from_bits(bytes[0] >> shift, bytes[1] >> shift);
+        Self {
+            malfunction: lamp(6),
+            red_stop: lamp(4),
+            amber_warning: lamp(2),
+            protect: lamp(0),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 2] {
+        let lamps = [
+            self.malfunction,
+            self.red_stop,
+            self.amber_warning,
+            self.protect,
+        ];
+        lamps.iter().fold([0, 0], |[status, flash], lamp| {
+            let (s, f) = lamp.to_bits();
+            [status << 2 | s, flash << 2 | f]
+        })
+    }
+}
+
+/// Byte order of the SPN in a trouble code.
+///
+/// The conversion method bit is 0 for version 4 and 1 for the older
+/// versions, which cannot be told apart from the message itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    /// All 19 bits most significant bit first.
+    V1,
+    /// The lower 16 bits most significant byte first, then the upper 3 bits.
+    V2,
+    /// Like version 4 with the conversion method bit set.
+    V3,
+    /// The lower 16 bits least significant byte first, then the upper 3 bits.
+    V4,
+}
+
+/// Diagnostic trouble code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dtc {
+    /// Suspect parameter number.
+    pub spn: u32,
+    /// Failure mode identifier.
+    pub fmi: u8,
+    /// Occurrence count, `None` if not available.
+    pub occurrences: Option<u8>,
+}
+
+impl Dtc {
+    /// Decodes a trouble code, using `legacy` if the conversion method bit is
+    /// set.
+    pub fn from_bytes(bytes: [u8; 4], legacy: Version) -> Self {
+        let version = if bytes[3] & 0x80 == 0 {
+            Version::V4
+        } else {
+            legacy
+        };
+        let [b0, b1, b2, b3] = bytes.map(u32::from);
+        let spn = match version {
+            Version::V1 => b0 << 11 | b1 << 3 | b2 >> 5,
+            Version::V2 => (b2 >> 5) << 16 | b0 << 8 | b1,
+            Version::V3 | Version::V4 => (b2 >> 5) << 16 | b1 << 8 | b0,
+        };
+        let occurrences = b3 as u8 & 0x7F;
+        Self {
+            spn,
+            fmi: bytes[2] & 0x1F,
+            occurrences: Some(occurrences).filter(|&oc| oc != OC_NOT_AVAILABLE),
+        }
+    }
+
+    pub fn to_bytes(&self, version: Version) -> [u8; 4] {
+        let spn = self.spn & 0x7_FFFF;
+        let fmi = self.fmi & 0x1F;
+        let upper = ((spn >> 16) as u8) << 5 | fmi;
+        let mut bytes = match version {
+            Version::V1 => [
+                (spn >> 11) as u8,
+                (spn >> 3) as u8,
+                (spn as u8) << 5 | fmi,
+                0,
+            ],
+            Version::V2 => [(spn >> 8) as u8, spn as u8, upper, 0],
+            Version::V3 | Version::V4 => [spn as u8, (spn >> 8) as u8, upper, 0],
+        };
+        bytes[3] = self.occurrences.unwrap_or(OC_NOT_AVAILABLE) & 0x7F;
+        if version != Version::V4 {
+            bytes[3] |= 0x80;
+        }
+        bytes
+    }
+
+    /// SPN name from `names` and the failure mode, for example
+    /// `SPN 110 Engine Coolant Temperature: Voltage above normal, or shorted
+    /// to high source (2 occurrences)`.
+    pub fn describe(&self, names: &dyn SpnDatabase) -> String {
+        let mut description = format!("SPN {}", self.spn);
+        if let Some(name) = names.name(self.spn) {
+            description += " ";
+            description += name;
+        }
+        description += ": ";
+        description += fmi_description(self.fmi);
+        if let Some(occurrences) = self.occurrences {
+            description += &format!(" ({} occurrences)", occurrences);
+        }
+        description
+    }
+}
+
+impl fmt::Display for Dtc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SPN {} FMI {}", self.spn, self.fmi)?;
+        if let Some(occurrences) = self.occurrences {
+            write!(f, " OC {}", occurrences)?;
+        }
+        Ok(())
+    }
+}
+
+/// Meaning of a failure mode identifier.
+pub fn fmi_description(fmi: u8) -> &'static str {
+    match fmi {
+        0 => "Data valid but above normal operational range - most severe level",
+        1 => "Data valid but below normal operational range - most severe level",
+        2 => "Data erratic, intermittent or incorrect",
+        3 => "Voltage above normal, or shorted to high source",
+        4 => "Voltage below normal, or shorted to low source",
+        5 => "Current below normal or open circuit",
+        6 => "Current above normal or grounded circuit",
+        7 => "Mechanical system not responding or out of adjustment",
+        8 => "Abnormal frequency or pulse width or period",
+        9 => "Abnormal update rate",
+        10 => "Abnormal rate of change",
+        11 => "Root cause not known",
+        12 => "Bad intelligent device or component",
+        13 => "Out of calibration",
+        14 => "Special instructions",
+        15 => "Data valid but above normal operating range - least severe level",
+        16 => "Data valid but above normal operating range - moderately severe level",
+        17 => "Data valid but below normal operating range - least severe level",
+        18 => "Data valid but below normal operating range - moderately severe level",
+        19 => "Received network data in error",
+        20 => "Data drifted high",
+        21 => "Data drifted low",
+        31 => "Condition exists",
+        _ => "Reserved",
+    }
+}
+
+/// Lamp status and trouble codes of DM1 or DM2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiagnosticMessage {
+    pub lamps: Lamps,
+    pub dtcs: Vec<Dtc>,
+}
+
+impl DiagnosticMessage {
+    /// Decodes a message, using `legacy` for trouble codes with the
+    /// conversion method bit set.
+    ///
+    /// Missing lamp bytes decode as not available. The placeholder for no
+    /// trouble codes, SPN 0, is skipped.
+    pub fn decode(data: &[u8], legacy: Version) -> Self {
+        let lamps = [
+            data.first().copied().unwrap_or(0xFF),
+            data.get(1).copied().unwrap_or(0xFF),
+        ];
+        let dtcs = data
+            .get(2..)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .filter(|bytes| *bytes != [0xFF; 4])
+            .map(|bytes| Dtc::from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]], legacy))
+            .filter(|dtc| dtc.spn != 0)
+            .collect();
+        Self {
+            lamps: Lamps::from_bytes(lamps),
+            dtcs,
+        }
+    }
+
+    /// Encodes the message, with the placeholder if there are no trouble
+    /// codes.
+    pub fn encode(&self, version: Version) -> Vec<u8> {
+        let mut data = self.lamps.to_bytes().to_vec();
+        if self.dtcs.is_empty() {
+            data.extend_from_slice(&[0, 0, 0, 0, 0xFF, 0xFF]);
+        }
+        for dtc in &self.dtcs {
+            data.extend_from_slice(&dtc.to_bytes(version));
+        }
+        if data.len() < 8 {
+            data.resize(8, 0xFF);
+        }
+        data
+    }
+}
+
+/// Names of suspect parameter numbers.
+pub trait SpnDatabase {
+    fn name(&self, spn: u32) -> Option<&str>;
+}
+
+impl SpnDatabase for BTreeMap<u32, String> {
+    fn name(&self, spn: u32) -> Option<&str> {
+        self.get(&spn).map(String::as_str)
+    }
+}
+
+impl SpnDatabase for HashMap<u32, String> {
+    fn name(&self, spn: u32) -> Option<&str> {
+        self.get(&spn).map(String::as_str)
+    }
+}
+
+/// No names.
+impl SpnDatabase for () {
+    fn name(&self, _spn: u32) -> Option<&str> {
+        None
+    }
+}
+
+/// Parses SPN names with one `spn,name` or `spn<TAB>name` per line.
+///
+/// Lines not starting with a number, like a header, are skipped.
+pub fn parse_spn_names(text: &str) -> BTreeMap<u32, String> {
+    text.lines()
+        .filter_map(|line| {
+            let (spn, name) = line.split_once([',', '\t', ';'])?;
+            let name = name.trim().trim_matches('"');
+            Some((spn.trim().parse().ok()?, name.to_string()))
+        })
+        .collect()
+}
+
+/// Requests DM1 or DM2 from `destination`.
+pub fn request<C, K>(
+    stack: &mut Stack<C, K>,
+    pgn: u32,
+    destination: u8,
+    timeout: Duration,
+    legacy: Version,
+) -> Result<DiagnosticMessage, Error<C::Error>>
+where
+    C: ReadTimeout,
+    C::Frame: Frame,
+    K: Clock,
+{
+    let message = query(stack, pgn, destination, timeout)?;
+    Ok(DiagnosticMessage::decode(&message.data, legacy))
+}
+
+/// Clears the previously active trouble codes of `destination` with DM3 or
+/// the active ones with DM11.
+///
+/// Nodes don't acknowledge a request to [`GLOBAL`](super::GLOBAL), so that
+/// returns as soon as the request is sent.
+pub fn clear<C, K>(
+    stack: &mut Stack<C, K>,
+    pgn: u32,
+    destination: u8,
+    timeout: Duration,
+) -> Result<(), Error<C::Error>>
+where
+    C: ReadTimeout,
+    C::Frame: Frame,
+    K: Clock,
+{
+    if destination == super::GLOBAL {
+        return stack.request(pgn, destination);
+    }
+    query(stack, pgn, destination, timeout).map(drop)
+}
+
+/// Returns the response to a request, a positive acknowledgement or an
+/// error for a negative one.
+fn query<C, K>(
+    stack: &mut Stack<C, K>,
+    pgn: u32,
+    destination: u8,
+    timeout: Duration,
+) -> Result<super::Message, Error<C::Error>>
+where
+    C: ReadTimeout,
+    C::Frame: Frame,
+    K: Clock,
+{
+    let message = stack
+        .query(pgn, destination, timeout)?
+        .ok_or(Error::Timeout)?;
+    match message.data.first() {
+        Some(&control) if message.pgn == pgn::ACKNOWLEDGEMENT && control != 0 => {
+            Err(Error::Rejected(control))
+        }
+        _ => Ok(message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        j1939::{Header, Name, GLOBAL},
+        sim::{Bus, Context, Node},
+    };
+    use embedded_can::Id;
+
+    #[test]
+    fn conversion_methods() {
+        let dtc = Dtc {
+            spn: 0x5_1234,
+            fmi: 3,
+            occurrences: Some(5),
+        };
+        assert_eq!(dtc.to_bytes(Version::V4), [0x34, 0x12, 0xA3, 0x05]);
+        assert_eq!(dtc.to_bytes(Version::V3), [0x34, 0x12, 0xA3, 0x85]);
+        assert_eq!(dtc.to_bytes(Version::V2), [0x12, 0x34, 0xA3, 0x85]);
+        assert_eq!(dtc.to_bytes(Version::V1), [0xA2, 0x46, 0x83, 0x85]);
+        for &version in &[Version::V1, Version::V2, Version::V3, Version::V4] {
+            assert_eq!(Dtc::from_bytes(dtc.to_bytes(version), version), dtc);
+            // Version 4 is recognized by the conversion method bit.
+            assert_eq!(Dtc::from_bytes(dtc.to_bytes(Version::V4), version), dtc);
+        }
+
+        // Amber warning lamp on, SPN 100 FMI 1 with 3 occurrences.
+        let data = [0x04, 0xFF, 0x64, 0x00, 0x01, 0x03, 0xFF, 0xFF];
+        let dm1 = DiagnosticMessage::decode(&data, Version::V1);
+        assert_eq!(dm1.lamps.amber_warning.status, LampStatus::On);
+        assert_eq!(dm1.lamps.malfunction.status, LampStatus::Off);
+        assert_eq!(dm1.lamps.red_stop.flash, Flash::Off);
+        assert_eq!(dm1.encode(Version::V4), data);
+
+        let names = parse_spn_names(
+            "SPN,Name\n100,\"Engine Oil Pressure\"\n110\tEngine Coolant Temperature\n",
+        );
+        assert_eq!(names.len(), 2);
+        assert_eq!(
+            dm1.dtcs[0].describe(&names),
+            "SPN 100 Engine Oil Pressure: Data valid but below normal operational range - most \
+             severe level (3 occurrences)"
+        );
+        assert_eq!(dm1.dtcs[0].to_string(), "SPN 100 FMI 1 OC 3");
+
+        let none = DiagnosticMessage::decode(&[0x00, 0xFF, 0, 0, 0, 0, 0xFF, 0xFF], Version::V4);
+        assert!(none.dtcs.is_empty());
+        assert_eq!(
+            none.encode(Version::V4),
+            [0x00, 0xFF, 0, 0, 0, 0, 0xFF, 0xFF]
+        );
+    }
+
+    /// Engine control module at address 0 that allows clearing the active
+    /// trouble codes only.
+    struct Engine;
+
+    impl Node for Engine {
+        fn on_frame(&mut self, frame: &crate::Frame, cx: &mut Context<'_>) {
+            let request = match frame.id() {
+                Id::Extended(id) => Header::from_id(id),
+                Id::Standard(_) => return,
+            };
+            if request.pgn != pgn::REQUEST || request.destination != 0 {
+                return;
+            }
+            let requested =
+                u32::from_le_bytes([frame.data()[0], frame.data()[1], frame.data()[2], 0]);
+            let (header, data) = match requested {
+                DM2 => {
+                    let header = Header::new(DM2, 0, GLOBAL);
+                    (header, vec![0x00, 0xFF, 0x6E, 0x00, 0x03, 0x02, 0xFF, 0xFF])
+                }
+                DM3 | DM11 => {
+                    // Acknowledgements to other nodes come first.
+                    let mut data = vec![0, 0xFF, 0xFF, 0xFF, 0x42];
+                    data.extend_from_slice(&requested.to_le_bytes()[..3]);
+                    let header = Header::new(pgn::ACKNOWLEDGEMENT, 0, GLOBAL);
+                    cx.send(crate::Frame::new(Id::Extended(header.id()), &data).unwrap());
+
+                    let control = if requested == DM11 { 0 } else { 2 };
+                    let mut data = vec![control, 0xFF, 0xFF, 0xFF, request.source];
+                    data.extend_from_slice(&requested.to_le_bytes()[..3]);
+                    (Header::new(pgn::ACKNOWLEDGEMENT, 0, GLOBAL), data)
+                }
+                _ => return,
+            };
+            cx.send(crate::Frame::new(Id::Extended(header.id()), &data).unwrap());
+        }
+    }
+
+    #[test]
+    fn request_and_clear() {
+        let bus = Bus::new();
+        bus.add_node(Engine);
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        let mut stack = Stack::new(endpoint, clock, Name::default(), 0xF9);
+        stack.claim_address().unwrap();
+        let timeout = Duration::from_millis(100);
+
+        let dm2 = request(&mut stack, DM2, 0, timeout, Version::V4).unwrap();
+        let dtc = Dtc {
+            spn: 110,
+            fmi: 3,
+            occurrences: Some(2),
+        };
+        assert_eq!(dm2.dtcs, [dtc]);
+
+        clear(&mut stack, DM11, 0, timeout).unwrap();
+        clear(&mut stack, DM3, GLOBAL, timeout).unwrap();
+        assert!(matches!(
+            clear(&mut stack, DM3, 0, timeout),
+            Err(Error::Rejected(2))
+        ));
+        assert!(matches!(
+            request(&mut stack, DM2, 0x01, timeout, Version::V4),
+            Err(Error::Timeout)
+        ));
+    }
+}
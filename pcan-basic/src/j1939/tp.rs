@@ -0,0 +1,580 @@
+//! Transport protocol (J1939-21) for messages of 9 to 1785 bytes.
+//!
+//! Broadcast messages are announced with BAM and sent in packets of 7 bytes
+//! at a fixed interval. Messages to a destination use connection mode, the
+//! receiver controls the flow with clear to send messages and acknowledges
+//! the complete message.
+
+use std::{collections::VecDeque, fmt, time::Duration};
+
+use super::{pgn, Header, Message, GLOBAL};
+
+/// Control bytes of connection management messages.
+pub mod control {
+    pub const RTS: u8 = 16;
+    pub const CTS: u8 = 17;
+    pub const END_OF_MESSAGE_ACK: u8 = 19;
+    pub const BAM: u8 = 32;
+    pub const ABORT: u8 = 255;
+}
+
+/// Reasons of connection abort messages.
+pub mod reason {
+    pub const BUSY: u8 = 1;
+    pub const RESOURCES: u8 = 2;
+    pub const TIMEOUT: u8 = 3;
+    pub const CTS_WHILE_SENDING: u8 = 4;
+    pub const RETRANSMIT_LIMIT: u8 = 5;
+    pub const UNEXPECTED_PACKET: u8 = 6;
+    pub const BAD_SEQUENCE: u8 = 7;
+    pub const DUPLICATE_SEQUENCE: u8 = 8;
+    pub const TOO_LONG: u8 = 9;
+}
+
+pub const MAX_LEN: usize = 255 * 7;
+
+/// Interval of BAM data packets.
+pub const BAM_INTERVAL: Duration = Duration::from_millis(50);
+/// Maximum time between data packets.
+pub const T1: Duration = Duration::from_millis(750);
+/// Maximum time from clear to send until the first data packet.
+pub const T2: Duration = Duration::from_millis(1250);
+/// Maximum time from the last data packet until clear to send or the
+/// acknowledgement.
+pub const T3: Duration = Duration::from_millis(1250);
+/// Maximum time a receiver may hold the connection open.
+pub const T4: Duration = Duration::from_millis(1050);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The message does not fit into 255 packets.
+    TooLong,
+    /// A transfer to the same destination is in progress.
+    Busy,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::TooLong => write!(f, "Message too long for the transport protocol"),
+            SendError::Busy => write!(f, "Transfer to the destination in progress"),
+        }
+    }
+}
+
+impl std::error::Error for SendError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Received(Message),
+    Sent {
+        pgn: u32,
+        destination: u8,
+    },
+    /// A transfer was aborted by this node or the peer.
+    Aborted {
+        pgn: u32,
+        peer: u8,
+        reason: u8,
+    },
+}
+
+struct Outgoing {
+    message: Message,
+    packets: u8,
+    /// Next packet to send, starting at 1.
+    next: u8,
+    /// Last packet the receiver is ready for.
+    last: u8,
+    /// Timeout while waiting for the receiver, or when to send the next BAM
+    /// packet.
+    deadline: Duration,
+}
+
+impl Outgoing {
+    fn is_bam(&self) -> bool {
+        self.message.destination == GLOBAL
+    }
+}
+
+struct Incoming {
+    message: Message,
+    size: usize,
+    packets: u8,
+    next: u8,
+    /// Last packet of the current clear to send window.
+    last: u8,
+    /// Packets per clear to send, at most what the sender allows.
+    window: u8,
+    deadline: Duration,
+}
+
+/// Sans-IO transport protocol sessions of a node.
+pub struct Transport {
+    address: u8,
+    window: u8,
+    outgoing: Vec<Outgoing>,
+    incoming: Vec<Incoming>,
+    transmit: VecDeque<(Header, Vec<u8>)>,
+    events: VecDeque<Event>,
+}
+
+impl Transport {
+    pub fn new(address: u8) -> Self {
+        Self {
+            address,
+            window: 16,
+            outgoing: Vec::new(),
+            incoming: Vec::new(),
+            transmit: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Packets requested per clear to send, 16 by default.
+    pub fn with_window(&mut self, packets: u8) -> &mut Self {
+        self.window = packets.max(1);
+        self
+    }
+
+    /// Address of this node, connection mode transfers to other addresses
+    /// are ignored.
+    pub fn set_address(&mut self, address: u8) {
+        self.address = address;
+    }
+
+    /// Starts sending a message from this node.
+    pub fn send(&mut self, now: Duration, message: Message) -> Result<(), SendError> {
+        if message.data.len() > MAX_LEN {
+            return Err(SendError::TooLong);
+        }
+        if self
+            .outgoing
+            .iter()
+            .any(|o| o.message.destination == message.destination)
+        {
+            return Err(SendError::Busy);
+        }
+
+        let packets = message.data.len().div_ceil(7) as u8;
+        let (command, deadline) = if message.destination == GLOBAL {
+            (control::BAM, now + BAM_INTERVAL)
+        } else {
+            (control::RTS, now + T3)
+        };
+        let mut data = connection(command, &message, packets);
+        if command == control::RTS {
+            // No limit of packets per clear to send.
+            data[4] = 0xFF;
+        }
+        self.transmit
+            .push_back((self.header(pgn::TP_CM, message.destination), data));
+        self.outgoing.push(Outgoing {
+            message,
+            packets,
+            next: 1,
+            last: 0,
+            deadline,
+        });
+        Ok(())
+    }
+
+    pub fn handle(&mut self, now: Duration, header: &Header, data: &[u8]) {
+        if data.len() != 8 {
+            return;
+        }
+        let to_us = header.destination == self.address;
+        if header.destination != GLOBAL && !to_us {
+            return;
+        }
+        match header.pgn {
+            pgn::TP_CM => self.connection_management(now, header, data),
+            pgn::TP_DT => self.data_transfer(now, header, data),
+            _ => {}
+        }
+    }
+
+    pub fn poll(&mut self, now: Duration) -> Option<Event> {
+        for i in (0..self.outgoing.len()).rev() {
+            let outgoing = &mut self.outgoing[i];
+            if now < outgoing.deadline {
+                continue;
+            }
+            if outgoing.is_bam() {
+                let packet = data_packet(&outgoing.message.data, outgoing.next);
+                let header = self.header(pgn::TP_DT, GLOBAL);
+                let outgoing = &mut self.outgoing[i];
+                outgoing.next += 1;
+                outgoing.deadline = now + BAM_INTERVAL;
+                self.transmit.push_back((header, packet));
+                if outgoing.next > outgoing.packets {
+                    let outgoing = self.outgoing.remove(i);
+                    self.events.push_back(Event::Sent {
+                        pgn: outgoing.message.pgn,
+                        destination: GLOBAL,
+                    });
+                }
+            } else {
+                let outgoing = self.outgoing.remove(i);
+                self.abort(
+                    &outgoing.message,
+                    outgoing.message.destination,
+                    reason::TIMEOUT,
+                );
+            }
+        }
+
+        for i in (0..self.incoming.len()).rev() {
+            if now >= self.incoming[i].deadline {
+                let incoming = self.incoming.remove(i);
+                // Broadcasts are dropped silently.
+                if incoming.message.destination != GLOBAL {
+                    self.abort(&incoming.message, incoming.message.source, reason::TIMEOUT);
+                }
+            }
+        }
+
+        self.events.pop_front()
+    }
+
+    /// Returns the next frame to send, all with a data length of 8.
+    pub fn poll_transmit(&mut self) -> Option<(Header, Vec<u8>)> {
+        self.transmit.pop_front()
+    }
+
+    pub fn next_deadline(&self) -> Option<Duration> {
+        let outgoing = self.outgoing.iter().map(|o| o.deadline);
+        let incoming = self.incoming.iter().map(|i| i.deadline);
+        outgoing.chain(incoming).min()
+    }
+
+    fn connection_management(&mut self, now: Duration, header: &Header, data: &[u8]) {
+        let pgn = u32::from_le_bytes([data[5], data[6], data[7], 0]);
+        let size = u16::from_le_bytes([data[1], data[2]]) as usize;
+        let peer = header.source;
+        let outgoing = self
+            .outgoing
+            .iter()
+            .position(|o| o.message.destination == peer && o.message.pgn == pgn);
+        let incoming = self
+            .incoming
+            .iter()
+            .position(|i| i.message.source == peer && i.message.destination == header.destination);
+
+        match data[0] {
+            control::BAM | control::RTS => {
+                if let Some(i) = incoming {
+                    // A new transfer replaces an unfinished one.
+                    self.incoming.remove(i);
+                }
+                let packets = data[3];
+                if packets == 0 || size > packets as usize * 7 {
+                    return;
+                }
+                let message = Message {
+                    priority: header.priority,
+                    pgn,
+                    source: peer,
+                    destination: header.destination,
+                    data: Vec::with_capacity(size),
+                };
+                let bam = data[0] == control::BAM;
+                if bam != (header.destination == GLOBAL) {
+                    return;
+                }
+                let mut incoming = Incoming {
+                    message,
+                    size,
+                    packets,
+                    next: 1,
+                    last: packets,
+                    window: data[4].clamp(1, self.window),
+                    deadline: now + T1,
+                };
+                if !bam {
+                    incoming.last = packets.min(incoming.window);
+                    incoming.deadline = now + T2;
+                    let cts = clear_to_send(pgn, incoming.last, 1);
+                    self.transmit
+                        .push_back((self.header(pgn::TP_CM, peer), cts));
+                }
+                self.incoming.push(incoming);
+            }
+            control::CTS => {
+                let i = match outgoing {
+                    Some(i) => i,
+                    None => return,
+                };
+                let (count, next) = (data[1], data[2]);
+                let outgoing = &mut self.outgoing[i];
+                if count == 0 {
+                    // The receiver holds the connection open.
+                    outgoing.deadline = now + T4;
+                    return;
+                }
+                if next == 0 || next > outgoing.packets {
+                    let outgoing = self.outgoing.remove(i);
+                    self.abort(&outgoing.message, peer, reason::BAD_SEQUENCE);
+                    return;
+                }
+                let last = next.saturating_add(count - 1).min(outgoing.packets);
+                outgoing.next = next;
+                outgoing.last = last;
+                outgoing.deadline = now + T3;
+                let packets: Vec<_> = (next..=last)
+                    .map(|n| data_packet(&outgoing.message.data, n))
+                    .collect();
+                for packet in packets {
+                    self.transmit
+                        .push_back((self.header(pgn::TP_DT, peer), packet));
+                }
+            }
+            control::END_OF_MESSAGE_ACK => {
+                if let Some(i) = outgoing {
+                    let outgoing = self.outgoing.remove(i);
+                    self.events.push_back(Event::Sent {
+                        pgn: outgoing.message.pgn,
+                        destination: peer,
+                    });
+                }
+            }
+            control::ABORT => {
+                if let Some(i) = outgoing {
+                    self.outgoing.remove(i);
+                } else if let Some(i) = incoming {
+                    self.incoming.remove(i);
+                } else {
+                    return;
+                }
+                self.events.push_back(Event::Aborted {
+                    pgn,
+                    peer,
+                    reason: data[1],
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn data_transfer(&mut self, now: Duration, header: &Header, data: &[u8]) {
+        let i = match self.incoming.iter().position(|i| {
+            i.message.source == header.source && i.message.destination == header.destination
+        }) {
+            Some(i) => i,
+            None => return,
+        };
+        let incoming = &mut self.incoming[i];
+        let sequence = data[0];
+        if sequence != incoming.next {
+            if incoming.message.destination == GLOBAL {
+                // A broadcast cannot be repeated.
+                self.incoming.remove(i);
+            } else {
+                let incoming = self.incoming.remove(i);
+                self.abort(&incoming.message, header.source, reason::BAD_SEQUENCE);
+            }
+            return;
+        }
+
+        incoming.message.data.extend_from_slice(&data[1..]);
+        incoming.next += 1;
+        incoming.deadline = now + T1;
+        if sequence == incoming.packets {
+            let mut incoming = self.incoming.remove(i);
+            incoming.message.data.truncate(incoming.size);
+            if incoming.message.destination != GLOBAL {
+                let ack = connection(
+                    control::END_OF_MESSAGE_ACK,
+                    &incoming.message,
+                    incoming.packets,
+                );
+                let header = self.header(pgn::TP_CM, header.source);
+                self.transmit.push_back((header, ack));
+            }
+            self.events.push_back(Event::Received(incoming.message));
+        } else if sequence == incoming.last {
+            let count = (incoming.packets - sequence).min(incoming.window);
+            incoming.last = sequence + count;
+            incoming.deadline = now + T2;
+            let cts = clear_to_send(incoming.message.pgn, count, sequence + 1);
+            let header = self.header(pgn::TP_CM, header.source);
+            self.transmit.push_back((header, cts));
+        }
+    }
+
+    fn abort(&mut self, message: &Message, peer: u8, reason: u8) {
+        let mut data = vec![control::ABORT, reason, 0xFF, 0xFF, 0xFF];
+        data.extend_from_slice(&message.pgn.to_le_bytes()[..3]);
+        self.transmit
+            .push_back((self.header(pgn::TP_CM, peer), data));
+        self.events.push_back(Event::Aborted {
+            pgn: message.pgn,
+            peer,
+            reason,
+        });
+    }
+
+    fn header(&self, pgn: u32, destination: u8) -> Header {
+        Header {
+            priority: 7,
+            pgn,
+            source: self.address,
+            destination,
+        }
+    }
+}
+
+/// Connection management message with size and packet count.
+fn connection(command: u8, message: &Message, packets: u8) -> Vec<u8> {
+    let size = (message.data.len() as u16).to_le_bytes();
+    let mut data = vec![command, size[0], size[1], packets, 0xFF];
+    data.extend_from_slice(&message.pgn.to_le_bytes()[..3]);
+    data
+}
+
+fn clear_to_send(pgn: u32, count: u8, next: u8) -> Vec<u8> {
+    let mut data = vec![control::CTS, count, next, 0xFF, 0xFF];
+    data.extend_from_slice(&pgn.to_le_bytes()[..3]);
+    data
+}
+
+/// Data packet `sequence`, starting at 1, padded with 0xFF.
+fn data_packet(data: &[u8], sequence: u8) -> Vec<u8> {
+    let start = (sequence as usize - 1) * 7;
+    let chunk = &data[start..data.len().min(start + 7)];
+    let mut packet = vec![0xFF; 8];
+    packet[0] = sequence;
+    packet[1..1 + chunk.len()].copy_from_slice(chunk);
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Delivers frames between two nodes until both are idle, returning the
+    /// events of both and the number of frames sent.
+    fn run(a: &mut Transport, b: &mut Transport, now: &mut Duration) -> (Vec<Event>, usize) {
+        let mut events = Vec::new();
+        let mut frames = 0;
+        loop {
+            let before = events.len();
+            let sent = deliver(a, b, *now, &mut events) + deliver(b, a, *now, &mut events);
+            frames += sent;
+            if sent == 0 && events.len() == before {
+                match a.next_deadline().into_iter().chain(b.next_deadline()).min() {
+                    Some(deadline) => *now = (*now).max(deadline),
+                    None => return (events, frames),
+                }
+            }
+        }
+    }
+
+    fn deliver(
+        from: &mut Transport,
+        to: &mut Transport,
+        now: Duration,
+        events: &mut Vec<Event>,
+    ) -> usize {
+        events.extend(std::iter::from_fn(|| from.poll(now)));
+        let mut frames = 0;
+        while let Some((header, data)) = from.poll_transmit() {
+            to.handle(now, &header, &data);
+            frames += 1;
+        }
+        frames
+    }
+
+    fn message(destination: u8, len: usize) -> Message {
+        Message {
+            // Priority of the connection management messages.
+            priority: 7,
+            pgn: 0xFECA,
+            source: 0x10,
+            destination,
+            data: (0..len).map(|i| i as u8).collect(),
+        }
+    }
+
+    #[test]
+    fn broadcast() {
+        let (mut a, mut b) = (Transport::new(0x10), Transport::new(0x20));
+        let mut now = Duration::default();
+        a.send(now, message(GLOBAL, 20)).unwrap();
+        assert_eq!(a.send(now, message(GLOBAL, 9)), Err(SendError::Busy));
+
+        let (events, frames) = run(&mut a, &mut b, &mut now);
+        assert_eq!(frames, 4);
+        assert_eq!(now, BAM_INTERVAL * 3);
+        assert_eq!(
+            events,
+            [
+                Event::Sent {
+                    pgn: 0xFECA,
+                    destination: GLOBAL
+                },
+                Event::Received(message(GLOBAL, 20)),
+            ]
+        );
+        assert_eq!(
+            a.send(now, message(GLOBAL, MAX_LEN + 1)),
+            Err(SendError::TooLong)
+        );
+    }
+
+    #[test]
+    fn connection_mode() {
+        let (mut a, mut b) = (Transport::new(0x10), Transport::new(0x20));
+        b.with_window(2);
+        let mut now = Duration::default();
+        a.send(now, message(0x20, 30)).unwrap();
+
+        // RTS, 3 CTS, 5 data packets and the acknowledgement.
+        let (events, frames) = run(&mut a, &mut b, &mut now);
+        assert_eq!(frames, 10);
+        assert_eq!(
+            events,
+            [
+                Event::Received(message(0x20, 30)),
+                Event::Sent {
+                    pgn: 0xFECA,
+                    destination: 0x20
+                },
+            ]
+        );
+
+        // The sender allows 2 packets per clear to send, less than the window.
+        let mut b = Transport::new(0x20);
+        let header = |pgn| Header {
+            priority: 7,
+            pgn,
+            source: 0x10,
+            destination: 0x20,
+        };
+        let mut rts = connection(control::RTS, &message(0x20, 30), 5);
+        rts[4] = 2;
+        b.handle(now, &header(pgn::TP_CM), &rts);
+        let mut windows = Vec::new();
+        for sequence in 1..=5 {
+            while let Some((_, cts)) = b.poll_transmit() {
+                windows.push((cts[1], cts[2]));
+            }
+            let packet = data_packet(&message(0x20, 30).data, sequence);
+            b.handle(now, &header(pgn::TP_DT), &packet);
+        }
+        assert_eq!(windows, [(2, 1), (2, 3), (1, 5)]);
+        assert_eq!(b.poll(now), Some(Event::Received(message(0x20, 30))));
+
+        // Transfers to other nodes are ignored, the sender times out.
+        a.send(now, message(0x30, 30)).unwrap();
+        let (events, _) = run(&mut a, &mut b, &mut now);
+        assert_eq!(
+            events,
+            [Event::Aborted {
+                pgn: 0xFECA,
+                peer: 0x30,
+                reason: reason::TIMEOUT
+            }]
+        );
+    }
+}
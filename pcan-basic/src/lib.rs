@@ -2,19 +2,49 @@ pub mod prelude {
     pub use embedded_can::{Can as _, Frame as _};
 }
 
+pub mod asc;
+pub mod blf;
+mod calendar;
+pub mod candump;
+pub mod canopen;
+mod clock;
+pub mod image;
+pub mod isotp;
+pub mod j1939;
+pub mod log;
+pub mod obd;
+pub mod pcapng;
+mod record;
+pub mod replay;
+pub mod sim;
+pub mod stm32;
+mod trace;
+pub mod trc;
+pub mod uds;
+
+pub use candump::ParseFrameError;
+pub use clock::{Clock, SystemClock};
 pub use embedded_can::{ExtendedId, Id, StandardId};
+pub use record::{Capture, Direction, ErrorFrame, ErrorKind, Message, Record};
+pub use trace::TraceConfig;
 
 use std::{
     ffi::{c_void, CString},
     fmt,
     mem::{self, MaybeUninit},
+    path::{Path, PathBuf},
     ptr,
+    time::{Duration, Instant},
 };
 
 use pcan_basic_sys::*;
 use winapi::{
     shared::minwindef::FALSE,
-    um::{synchapi, winbase::INFINITE, winnt::HANDLE},
+    um::{
+        profileapi, synchapi,
+        winbase::INFINITE,
+        winnt::{HANDLE, LARGE_INTEGER},
+    },
 };
 
 #[derive(Debug)]
@@ -22,13 +52,20 @@ pub struct Error(String);
 
 impl Error {
     fn new(error_code: u32) -> Self {
-        unsafe {
+        let error = unsafe {
             let raw_error_msg = CString::from_vec_unchecked(Vec::with_capacity(256)).into_raw();
             CAN_GetErrorText(error_code, 0, raw_error_msg);
             Self(String::from_utf8_unchecked(
                 CString::from_raw(raw_error_msg).into_bytes(),
             ))
-        }
+        };
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            error_code = format_args!("{:#x}", error_code),
+            message = %error.0,
+            "PCAN-Basic call failed"
+        );
+        error
     }
 }
 
@@ -40,20 +77,86 @@ impl fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
+fn set_value<T: ?Sized>(channel: u16, parameter: u32, value: &mut T) -> Result<(), Error> {
+    let result = unsafe {
+        CAN_SetValue(
+            channel,
+            parameter as u8,
+            value as *mut _ as *mut c_void,
+            mem::size_of_val(value) as u32,
+        )
+    };
+    if result != PCAN_ERROR_OK {
+        return Err(Error::new(result));
+    }
+    Ok(())
+}
+
+fn set_path(channel: u16, parameter: u32, path: &Path) -> Result<(), Error> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| Error(format!("Path is not valid unicode: {}", path.display())))?;
+    let mut path = CString::new(path)
+        .map_err(|_| Error("Path must not contain a nul byte".to_string()))?
+        .into_bytes_with_nul();
+    set_value(channel, parameter, path.as_mut_slice())
+}
+
+fn get_path(channel: u16, parameter: u32) -> Result<PathBuf, Error> {
+    // The API does not report the length, MAX_PATH is the documented upper limit.
+    let mut buf = [0u8; 260];
+    get_value(channel, parameter, &mut buf)?;
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    Ok(PathBuf::from(
+        String::from_utf8_lossy(&buf[..len]).into_owned(),
+    ))
+}
+
+fn get_value<T: ?Sized>(channel: u16, parameter: u32, value: &mut T) -> Result<(), Error> {
+    let result = unsafe {
+        CAN_GetValue(
+            channel,
+            parameter as u8,
+            value as *mut _ as *mut c_void,
+            mem::size_of_val(value) as u32,
+        )
+    };
+    if result != PCAN_ERROR_OK {
+        return Err(Error::new(result));
+    }
+    Ok(())
+}
+
+/// 125kbit/s with a nominal sample point of 75%.
+///
+/// When running with 125kbps the STM32 bootloader sets the acknowledge bit early.
+/// The later sample point prevents form errors in the CRC delimiter.
+/// Value calculated using http://www.bittiming.can-wiki.info/ (NXP SJA1000)
+pub const BTR0BTR1_125K_STM32: u16 = 0x033A;
+
 pub struct Interface {
     channel: u16,
     event_handle: HANDLE,
+    captures: Vec<(u8, Box<dyn Capture>)>,
+    // First error of a capture, which is removed when it fails.
+    capture_error: Option<Error>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    // Last bus status reported by the driver, to log changes only.
+    #[cfg(feature = "tracing")]
+    status: u32,
 }
 
 impl Interface {
+    /// Opens the first PCAN-USB channel with 125kbit/s, suited for the STM32 bootloader.
     pub fn init() -> Result<Self, Error> {
-        let pcan_channel = PCAN_USBBUS1 as u16;
+        Self::open(PCAN_USBBUS1 as u16, BTR0BTR1_125K_STM32)
+    }
 
-        // When running with 125kbps the STM32 bootloader sets the acknowledge bit early.
-        // Choose a nominal sample point of 75% to prevent form errors in the CRC delimiter.
-        // Value calculated using http://www.bittiming.can-wiki.info/ (NXP SJA1000)
-        const BAUDRATE_CONFIG: u16 = 0x033A;
-        let result = unsafe { CAN_Initialize(pcan_channel, BAUDRATE_CONFIG, 0, 0, 0) };
+    /// Opens a channel (`PCAN_USBBUS1`, ...) with bit timing given as the
+    /// BTR0/BTR1 register values of a SJA1000 (`PCAN_BAUD_500K`, ...).
+    pub fn open(pcan_channel: u16, btr0btr1: u16) -> Result<Self, Error> {
+        let result = unsafe { CAN_Initialize(pcan_channel, btr0btr1, 0, 0, 0) };
         if result != PCAN_ERROR_OK {
             return Err(Error::new(result));
         }
@@ -86,7 +189,19 @@ impl Interface {
         let mut this = Self {
             channel: pcan_channel,
             event_handle,
+            captures: Vec::new(),
+            capture_error: None,
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!("pcan_interface", channel = pcan_channel),
+            #[cfg(feature = "tracing")]
+            status: PCAN_ERROR_OK,
         };
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            parent: &this.span,
+            btr0btr1 = format_args!("{:#06x}", btr0btr1),
+            "interface opened"
+        );
 
         // Drain all messages that were received since `init()` has been called.
         loop {
@@ -102,10 +217,12 @@ impl Interface {
 impl Drop for Interface {
     fn drop(&mut self) {
         unsafe { CAN_Uninitialize(self.channel) };
+        #[cfg(feature = "tracing")]
+        tracing::info!(parent: &self.span, "interface closed");
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub struct Frame(TPCANMsg);
 
 impl embedded_can::Frame for Frame {
@@ -130,7 +247,7 @@ impl embedded_can::Frame for Frame {
     }
 
     fn new_remote(id: impl Into<Id>, dlc: usize) -> Result<Frame, ()> {
-        if dlc >= 8 {
+        if dlc > 8 {
             return Err(());
         }
 
@@ -165,10 +282,186 @@ impl embedded_can::Frame for Frame {
     }
 }
 
+impl PartialEq for Frame {
+    fn eq(&self, other: &Frame) -> bool {
+        use embedded_can::Frame as _;
+
+        self.id() == other.id()
+            && self.is_remote_frame() == other.is_remote_frame()
+            && self.dlc() == other.dlc()
+            && self.data() == other.data()
+    }
+}
+
+impl Eq for Frame {}
+
+/// Payload lengths a CAN FD frame can carry, indexed by the DLC.
+const FD_LENGTHS: [usize; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 12, 16, 20, 24, 32, 48, 64];
+
+pub(crate) fn dlc_to_len(dlc: u8) -> usize {
+    FD_LENGTHS[dlc.min(15) as usize]
+}
+
+pub(crate) fn len_to_dlc(len: usize) -> Option<u8> {
+    FD_LENGTHS
+        .iter()
+        .position(|&l| l == len)
+        .map(|dlc| dlc as u8)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FdFrame(TPCANMsgFD);
+
+impl FdFrame {
+    /// Creates a CAN FD frame with bit rate switching enabled.
+    ///
+    /// Returns `None` if the data length cannot be encoded in a DLC
+    /// (valid lengths are 0 to 8, 12, 16, 20, 24, 32, 48 and 64).
+    pub fn new(id: impl Into<Id>, data: &[u8]) -> Option<FdFrame> {
+        let dlc = len_to_dlc(data.len())?;
+        let (id, msg_type) = match id.into() {
+            Id::Standard(id) => (id.as_raw() as u32, PCAN_MESSAGE_STANDARD),
+            Id::Extended(id) => (id.as_raw(), PCAN_MESSAGE_EXTENDED),
+        };
+
+        let mut msg = TPCANMsgFD {
+            ID: id,
+            MSGTYPE: (msg_type | PCAN_MESSAGE_FD | PCAN_MESSAGE_BRS) as u8,
+            DLC: dlc,
+            DATA: [0; 64],
+        };
+        msg.DATA[0..data.len()].copy_from_slice(data);
+        Some(FdFrame(msg))
+    }
+
+    pub fn is_extended(&self) -> bool {
+        self.0.MSGTYPE & PCAN_MESSAGE_EXTENDED as u8 != 0
+    }
+
+    pub fn id(&self) -> Id {
+        if self.is_extended() {
+            ExtendedId::new(self.0.ID).unwrap().into()
+        } else {
+            StandardId::new(self.0.ID as u16).unwrap().into()
+        }
+    }
+
+    pub fn dlc(&self) -> usize {
+        self.0.DLC as usize
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.0.DATA[0..dlc_to_len(self.0.DLC)]
+    }
+
+    /// The data phase is transmitted with the higher data bit rate.
+    pub fn bit_rate_switch(&self) -> bool {
+        self.0.MSGTYPE & PCAN_MESSAGE_BRS as u8 != 0
+    }
+
+    pub fn set_bit_rate_switch(&mut self, brs: bool) {
+        if brs {
+            self.0.MSGTYPE |= PCAN_MESSAGE_BRS as u8;
+        } else {
+            self.0.MSGTYPE &= !PCAN_MESSAGE_BRS as u8;
+        }
+    }
+
+    /// The transmitter was error passive.
+    pub fn error_state_indicator(&self) -> bool {
+        self.0.MSGTYPE & PCAN_MESSAGE_ESI as u8 != 0
+    }
+
+    pub fn set_error_state_indicator(&mut self, esi: bool) {
+        if esi {
+            self.0.MSGTYPE |= PCAN_MESSAGE_ESI as u8;
+        } else {
+            self.0.MSGTYPE &= !PCAN_MESSAGE_ESI as u8;
+        }
+    }
+}
+
+impl PartialEq for FdFrame {
+    fn eq(&self, other: &FdFrame) -> bool {
+        self.id() == other.id()
+            && self.bit_rate_switch() == other.bit_rate_switch()
+            && self.error_state_indicator() == other.error_state_indicator()
+            && self.data() == other.data()
+    }
+}
+
+impl Eq for FdFrame {}
+
 impl Interface {
+    /// Records every frame read from or written to this interface.
+    ///
+    /// Received frames carry the hardware timestamp of the driver, transmitted
+    /// frames the system uptime at the time of the write, which is the same time base.
+    /// `channel` is the bus number written to the records.
+    ///
+    /// A capture that fails is removed without affecting the bus traffic, see
+    /// [`take_capture_error()`](Self::take_capture_error).
+    pub fn add_capture(&mut self, channel: u8, capture: impl Capture + 'static) {
+        self.captures.push((channel, Box::new(capture)));
+    }
+
+    /// Returns the first error of a capture since the last call.
+    pub fn take_capture_error(&mut self) -> Option<Error> {
+        self.capture_error.take()
+    }
+
+    fn capture(&mut self, direction: Direction, msg: &TPCANMsg, timestamp: Duration) {
+        let message = if msg.MSGTYPE & PCAN_MESSAGE_ERRFRAME as u8 != 0 {
+            // The identifier holds the error type, the data the error details.
+            Message::Error(ErrorFrame {
+                kind: ErrorFrame::kind_from_bits(msg.ID as u8),
+                direction: if msg.DATA[0] == 0 {
+                    Direction::Tx
+                } else {
+                    Direction::Rx
+                },
+                ecc: msg.DATA[1],
+                rx_error_counter: msg.DATA[2],
+                tx_error_counter: msg.DATA[3],
+            })
+        } else if msg.MSGTYPE & PCAN_MESSAGE_STATUS as u8 != 0 {
+            return;
+        } else {
+            Message::Can(Frame(*msg))
+        };
+
+        let mut failed = None;
+        self.captures.retain_mut(|(channel, capture)| {
+            let record = Record {
+                timestamp,
+                channel: *channel,
+                direction,
+                message,
+            };
+            match capture.capture(&record) {
+                Ok(()) => true,
+                Err(err) => {
+                    failed.get_or_insert(err);
+                    false
+                }
+            }
+        });
+        if let Some(err) = failed {
+            #[cfg(feature = "tracing")]
+            tracing::warn!(parent: &self.span, error = %err, "capture removed");
+            let err = Error(format!("Failed to capture frame: {}", err));
+            self.capture_error.get_or_insert(err);
+        }
+    }
+
     fn transmit(&mut self, frame: &Frame) -> nb::Result<Option<Frame>, Error> {
         let result = unsafe { CAN_Write(self.channel, &frame.0 as *const _ as *mut _) };
         if result == PCAN_ERROR_OK {
+            #[cfg(feature = "tracing")]
+            self.trace_frame(Direction::Tx, &frame.0, uptime());
+            if !self.captures.is_empty() {
+                self.capture(Direction::Tx, &frame.0, uptime());
+            }
             Ok(None)
         } else {
             Err(nb::Error::Other(Error::new(result)))
@@ -177,21 +470,98 @@ impl Interface {
 
     fn receive(&mut self) -> nb::Result<Frame, Error> {
         let mut msg = MaybeUninit::<TPCANMsg>::uninit();
-        let (result, msg) = unsafe {
+        let mut timestamp = MaybeUninit::<TPCANTimestamp>::uninit();
+        let (result, msg, timestamp) = unsafe {
             (
-                CAN_Read(self.channel, msg.as_mut_ptr(), ptr::null_mut()),
+                CAN_Read(self.channel, msg.as_mut_ptr(), timestamp.as_mut_ptr()),
                 msg.assume_init(),
+                timestamp.assume_init(),
             )
         };
 
+        #[cfg(feature = "tracing")]
+        self.trace_status(result);
+
         match result {
             PCAN_ERROR_QRCVEMPTY => Err(nb::Error::WouldBlock),
-            PCAN_ERROR_OK => Ok(Frame(msg)),
+            PCAN_ERROR_OK => {
+                #[cfg(feature = "tracing")]
+                self.trace_frame(Direction::Rx, &msg, hardware_timestamp(&timestamp));
+                if !self.captures.is_empty() {
+                    self.capture(Direction::Rx, &msg, hardware_timestamp(&timestamp));
+                }
+                Ok(Frame(msg))
+            }
             _ => Err(nb::Error::Other(Error::new(result))),
         }
     }
 }
 
+#[cfg(feature = "tracing")]
+impl Interface {
+    fn trace_frame(&self, direction: Direction, msg: &TPCANMsg, timestamp: Duration) {
+        let direction = match direction {
+            Direction::Rx => "rx",
+            Direction::Tx => "tx",
+        };
+        let len = (msg.LEN as usize).min(msg.DATA.len());
+        tracing::trace!(
+            parent: &self.span,
+            direction,
+            id = format_args!("{:#x}", msg.ID),
+            extended = msg.MSGTYPE & PCAN_MESSAGE_EXTENDED as u8 != 0,
+            remote = msg.MSGTYPE & PCAN_MESSAGE_RTR as u8 != 0,
+            error_frame = msg.MSGTYPE & PCAN_MESSAGE_ERRFRAME as u8 != 0,
+            dlc = msg.LEN,
+            data = ?&msg.DATA[..len],
+            timestamp_us = timestamp.as_micros() as u64,
+            "frame"
+        );
+    }
+
+    fn trace_status(&mut self, result: u32) {
+        let status = result & PCAN_ERROR_ANYBUSERR;
+        if status == self.status {
+            return;
+        }
+        self.status = status;
+
+        let name = if status & PCAN_ERROR_BUSOFF != 0 {
+            "bus off"
+        } else if status & PCAN_ERROR_BUSPASSIVE != 0 {
+            "error passive"
+        } else if status & PCAN_ERROR_BUSHEAVY != 0 {
+            "error warning"
+        } else if status & PCAN_ERROR_BUSLIGHT != 0 {
+            "bus light"
+        } else {
+            "ok"
+        };
+        if status == PCAN_ERROR_OK {
+            tracing::info!(parent: &self.span, status = name, "bus status changed");
+        } else {
+            tracing::warn!(parent: &self.span, status = name, "bus status changed");
+        }
+    }
+}
+
+fn hardware_timestamp(timestamp: &TPCANTimestamp) -> Duration {
+    let millis = timestamp.millis as u64 + ((timestamp.millis_overflow as u64) << 32);
+    Duration::from_millis(millis) + Duration::from_micros(timestamp.micros as u64)
+}
+
+/// Time since the system started, the time base of the driver timestamps.
+fn uptime() -> Duration {
+    unsafe {
+        let mut count: LARGE_INTEGER = mem::zeroed();
+        let mut frequency: LARGE_INTEGER = mem::zeroed();
+        profileapi::QueryPerformanceCounter(&mut count);
+        profileapi::QueryPerformanceFrequency(&mut frequency);
+        let (count, frequency) = (*count.QuadPart() as u128, *frequency.QuadPart() as u128);
+        Duration::from_micros((count * 1_000_000 / frequency) as u64)
+    }
+}
+
 impl embedded_can::Can for Interface {
     type Frame = Frame;
     type Error = Error;
@@ -233,6 +603,102 @@ impl embedded_can::blocking::Can for Interface {
     }
 }
 
+/// A blocking CAN interface that can stop waiting for a frame.
+///
+/// `embedded_can::blocking::Can::try_read()` waits forever, protocols with
+/// response timeouts need this instead.
+pub trait ReadTimeout: embedded_can::blocking::Can {
+    /// Returns `Ok(None)` if no frame was received within `timeout`.
+    fn try_read_timeout(&mut self, timeout: Duration) -> Result<Option<Self::Frame>, Self::Error>;
+}
+
+impl ReadTimeout for Interface {
+    fn try_read_timeout(&mut self, timeout: Duration) -> Result<Option<Frame>, Error> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.receive() {
+                Ok(frame) => return Ok(Some(frame)),
+                Err(nb::Error::Other(err)) => return Err(err),
+                Err(nb::Error::WouldBlock) => {}
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Ok(None);
+            }
+            // Round up, waking up early would only cost another iteration.
+            let millis = remaining
+                .as_micros()
+                .div_ceil(1000)
+                .min(INFINITE as u128 - 1);
+            unsafe { synchapi::WaitForSingleObject(self.event_handle, millis as u32) };
+        }
+    }
+}
+
+/// Adapter for protocols written against `embedded_can::blocking::Can`, whose
+/// `try_read()` fails with [`TimeoutError::Timeout`] instead of waiting forever.
+pub struct Timeout<C> {
+    can: C,
+    timeout: Duration,
+}
+
+impl<C> Timeout<C> {
+    pub fn new(can: C, timeout: Duration) -> Self {
+        Self { can, timeout }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn inner(&mut self) -> &mut C {
+        &mut self.can
+    }
+
+    pub fn into_inner(self) -> C {
+        self.can
+    }
+}
+
+#[derive(Debug)]
+pub enum TimeoutError<E> {
+    /// No frame was received within the timeout.
+    Timeout,
+    Can(E),
+}
+
+impl<E: fmt::Display> fmt::Display for TimeoutError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeoutError::Timeout => write!(f, "No frame received within the timeout"),
+            TimeoutError::Can(err) => err.fmt(f),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for TimeoutError<E> {}
+
+impl<C: ReadTimeout> embedded_can::blocking::Can for Timeout<C> {
+    type Frame = C::Frame;
+    type Error = TimeoutError<C::Error>;
+
+    fn try_write(&mut self, frame: &Self::Frame) -> Result<(), Self::Error> {
+        self.can.try_write(frame).map_err(TimeoutError::Can)
+    }
+
+    fn try_read(&mut self) -> Result<Self::Frame, Self::Error> {
+        self.can
+            .try_read_timeout(self.timeout)
+            .map_err(TimeoutError::Can)?
+            .ok_or(TimeoutError::Timeout)
+    }
+}
+
 pub struct Filter {
     accept_all: bool,
     is_extended: bool,
@@ -272,10 +738,31 @@ impl Filter {
         self.mask = mask;
         self
     }
+
+    pub fn matches(&self, id: Id) -> bool {
+        if self.accept_all {
+            return true;
+        }
+
+        let (is_extended, raw) = match id {
+            Id::Standard(id) => (false, id.as_raw() as u32),
+            Id::Extended(id) => (true, id.as_raw()),
+        };
+        is_extended == self.is_extended && raw & self.mask == self.id & self.mask
+    }
 }
 
 impl Interface {
     pub fn add_filter(&mut self, filter: &Filter) -> Result<(), Error> {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            parent: &self.span,
+            accept_all = filter.accept_all,
+            extended = filter.is_extended,
+            id = format_args!("{:#x}", filter.id),
+            mask = format_args!("{:#x}", filter.mask),
+            "adding filter"
+        );
         let mut filter_state = 0u32;
         unsafe {
             CAN_GetValue(
@@ -319,6 +806,8 @@ impl Interface {
     }
 
     pub fn clear_filters(&mut self) {
+        #[cfg(feature = "tracing")]
+        tracing::debug!(parent: &self.span, "clearing filters");
         let mut filter_open = PCAN_FILTER_CLOSE;
         unsafe {
             CAN_SetValue(
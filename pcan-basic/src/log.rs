@@ -0,0 +1,102 @@
+//! Debug log of the PCAN-Basic library.
+//!
+//! The log is written by the library itself (`PCANBasic.log`) and records API
+//! calls of the whole process. It is not bound to a channel, so the settings
+//! here affect every [`Interface`](crate::Interface).
+
+use std::{
+    ffi::CString,
+    ops::{BitOr, BitOrAssign},
+    path::{Path, PathBuf},
+};
+
+use pcan_basic_sys::*;
+
+use crate::{get_path, get_value, set_path, set_value, Error};
+
+/// Selection of the information written to the debug log.
+///
+/// Combine multiple values with `|`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogFunctions(u32);
+
+impl LogFunctions {
+    /// System exceptions and errors only.
+    pub const DEFAULT: Self = Self(LOG_FUNCTION_DEFAULT);
+    /// Entries into the API functions.
+    pub const ENTRY: Self = Self(LOG_FUNCTION_ENTRY);
+    /// Parameters passed to the API functions.
+    pub const PARAMETERS: Self = Self(LOG_FUNCTION_PARAMETERS);
+    /// Exits from the API functions.
+    pub const LEAVE: Self = Self(LOG_FUNCTION_LEAVE);
+    /// Frames passed to `CAN_Write()`.
+    pub const WRITE: Self = Self(LOG_FUNCTION_WRITE);
+    /// Frames returned by `CAN_Read()`.
+    pub const READ: Self = Self(LOG_FUNCTION_READ);
+    /// Everything the library can log.
+    pub const ALL: Self = Self(LOG_FUNCTION_ALL);
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for LogFunctions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for LogFunctions {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Sets the directory of the log file. Defaults to the directory of the calling process.
+pub fn set_location(location: impl AsRef<Path>) -> Result<(), Error> {
+    set_path(PCAN_NONEBUS as u16, PCAN_LOG_LOCATION, location.as_ref())
+}
+
+pub fn location() -> Result<PathBuf, Error> {
+    get_path(PCAN_NONEBUS as u16, PCAN_LOG_LOCATION)
+}
+
+pub fn enable() -> Result<(), Error> {
+    let mut parameter_on = PCAN_PARAMETER_ON;
+    set_value(PCAN_NONEBUS as u16, PCAN_LOG_STATUS, &mut parameter_on)
+}
+
+pub fn disable() -> Result<(), Error> {
+    let mut parameter_off = PCAN_PARAMETER_OFF;
+    set_value(PCAN_NONEBUS as u16, PCAN_LOG_STATUS, &mut parameter_off)
+}
+
+pub fn is_enabled() -> Result<bool, Error> {
+    let mut status = PCAN_PARAMETER_OFF;
+    get_value(PCAN_NONEBUS as u16, PCAN_LOG_STATUS, &mut status)?;
+    Ok(status == PCAN_PARAMETER_ON)
+}
+
+pub fn configure(functions: LogFunctions) -> Result<(), Error> {
+    let mut functions = functions.0;
+    set_value(PCAN_NONEBUS as u16, PCAN_LOG_CONFIGURE, &mut functions)
+}
+
+pub fn configuration() -> Result<LogFunctions, Error> {
+    let mut functions = LOG_FUNCTION_DEFAULT;
+    get_value(PCAN_NONEBUS as u16, PCAN_LOG_CONFIGURE, &mut functions)?;
+    Ok(LogFunctions(functions))
+}
+
+/// Inserts a custom line into the log file, e.g. to mark the start of a test case.
+///
+/// The log must be enabled for the text to be written.
+pub fn write_text(text: &str) -> Result<(), Error> {
+    let mut text = CString::new(text)
+        .map_err(|_| Error("Log text must not contain a nul byte".to_string()))?
+        .into_bytes_with_nul();
+    set_value(PCAN_NONEBUS as u16, PCAN_LOG_TEXT, text.as_mut_slice())
+}
@@ -0,0 +1,596 @@
+//! OBD-II (SAE J1979) diagnostics over ISO-TP with 11 bit identifiers.
+//!
+//! Requests go to all emission related ECUs at the functional address `0x7DF`,
+//! which answer from `0x7E8` to `0x7EF`. Multi-frame responses are
+//! acknowledged on the physical request identifiers `0x7E0` to `0x7E7`.
+//!
+//! ```no_run
+//! use pcan_basic::{obd::{self, Client}, Interface, SystemClock};
+//!
+//! let can = Interface::init().unwrap();
+//! let mut obd = Client::new(can, SystemClock::new());
+//! for response in obd.read_pid(0x0C).unwrap() {
+//!     let rpm = obd::decode(0x0C, &response.data).unwrap();
+//!     println!("ECU {}: {} {}", response.ecu, rpm.value, rpm.unit);
+//! }
+//! ```
+
+use std::{fmt, time::Duration};
+
+use crate::{
+    isotp::{self, Config, Event, Link},
+    uds::{classify_response, ResponseKind},
+    Clock, ReadTimeout, StandardId,
+};
+
+pub const FUNCTIONAL_ID: u16 = 0x7DF;
+/// Physical request identifier of the first ECU, ECU `n` uses `0x7E0 + n`.
+pub const REQUEST_ID: u16 = 0x7E0;
+/// Response identifier of the first ECU, ECU `n` uses `0x7E8 + n`.
+pub const RESPONSE_ID: u16 = 0x7E8;
+const ECUS: u16 = 8;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Transport(isotp::Error<E>),
+    /// No ECU answered the request.
+    NoResponse,
+    InvalidResponse(Vec<u8>),
+    /// The request is empty.
+    InvalidRequest,
+}
+
+impl<E> From<isotp::Error<E>> for Error<E> {
+    fn from(err: isotp::Error<E>) -> Self {
+        Error::Transport(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(err) => err.fmt(f),
+            Error::NoResponse => write!(f, "No ECU answered the request"),
+            Error::InvalidResponse(response) => write!(f, "Invalid response {:02X?}", response),
+            Error::InvalidRequest => write!(f, "Invalid request"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Error<E> {}
+
+/// Values by ECU number.
+pub type PerEcu<T> = Vec<(u8, T)>;
+
+/// Positive response of one ECU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    /// ECU number from 0 to 7, by response identifier.
+    pub ecu: u8,
+    /// Response without the service and parameter identifier bytes.
+    pub data: Vec<u8>,
+}
+
+/// Diagnostic trouble code as shown to users, for example `P0301`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dtc(pub u16);
+
+impl fmt::Display for Dtc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let system = ['P', 'C', 'B', 'U'][(self.0 >> 14) as usize];
+        write!(f, "{}{:04X}", system, self.0 & 0x3FFF)
+    }
+}
+
+/// Decoded value of a parameter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Value {
+    pub value: f64,
+    pub unit: &'static str,
+}
+
+type Conversion = fn(&[u8]) -> f64;
+
+fn word(d: &[u8]) -> f64 {
+    (d[0] as f64) * 256.0 + d[1] as f64
+}
+
+fn percent(d: &[u8]) -> f64 {
+    d[0] as f64 * 100.0 / 255.0
+}
+
+fn temperature(d: &[u8]) -> f64 {
+    d[0] as f64 - 40.0
+}
+
+fn fuel_trim(d: &[u8]) -> f64 {
+    d[0] as f64 * 100.0 / 128.0 - 100.0
+}
+
+fn byte(d: &[u8]) -> f64 {
+    d[0] as f64
+}
+
+// Mode 01 parameters with a single scalar value, SAE J1979 appendix B:
+// identifier, name, unit, data length and conversion.
+static PIDS: &[(u8, &str, &str, usize, Conversion)] = &[
+    (0x04, "Calculated engine load", "%", 1, percent),
+    (0x05, "Engine coolant temperature", "°C", 1, temperature),
+    (0x06, "Short term fuel trim bank 1", "%", 1, fuel_trim),
+    (0x07, "Long term fuel trim bank 1", "%", 1, fuel_trim),
+    (0x08, "Short term fuel trim bank 2", "%", 1, fuel_trim),
+    (0x09, "Long term fuel trim bank 2", "%", 1, fuel_trim),
+    (0x0A, "Fuel pressure", "kPa", 1, |d| d[0] as f64 * 3.0),
+    (0x0B, "Intake manifold absolute pressure", "kPa", 1, byte),
+    (0x0C, "Engine speed", "rpm", 2, |d| word(d) / 4.0),
+    (0x0D, "Vehicle speed", "km/h", 1, byte),
+    (0x0E, "Timing advance", "°", 1, |d| {
+        d[0] as f64 / 2.0 - 64.0
+    }),
+    (0x0F, "Intake air temperature", "°C", 1, temperature),
+    (0x10, "Mass air flow rate", "g/s", 2, |d| word(d) / 100.0),
+    (0x11, "Throttle position", "%", 1, percent),
+    (0x1F, "Run time since engine start", "s", 2, word),
+    (0x21, "Distance traveled with MIL on", "km", 2, word),
+    (0x2F, "Fuel tank level", "%", 1, percent),
+    (0x31, "Distance traveled since codes cleared", "km", 2, word),
+    (0x33, "Absolute barometric pressure", "kPa", 1, byte),
+    (0x42, "Control module voltage", "V", 2, |d| word(d) / 1000.0),
+    (0x46, "Ambient air temperature", "°C", 1, temperature),
+    (0x5C, "Engine oil temperature", "°C", 1, temperature),
+    (0x5E, "Engine fuel rate", "L/h", 2, |d| word(d) / 20.0),
+];
+
+/// Name of a mode 01 parameter that [`decode()`] understands.
+pub fn pid_name(pid: u8) -> Option<&'static str> {
+    PIDS.iter().find(|p| p.0 == pid).map(|p| p.1)
+}
+
+/// Decodes the data bytes of a mode 01 or mode 02 parameter.
+pub fn decode(pid: u8, data: &[u8]) -> Option<Value> {
+    let (_, _, unit, len, decode) = PIDS.iter().find(|p| p.0 == pid)?;
+    if data.len() < *len {
+        return None;
+    }
+    Some(Value {
+        value: decode(data),
+        unit,
+    })
+}
+
+/// Parses the two byte DTCs following the count byte of a mode 03, 07 or 0A response.
+fn parse_dtcs(data: &[u8]) -> Vec<Dtc> {
+    data.get(1..)
+        .unwrap_or_default()
+        .chunks_exact(2)
+        .map(|d| Dtc(u16::from_be_bytes([d[0], d[1]])))
+        .filter(|dtc| dtc.0 != 0)
+        .collect()
+}
+
+/// Blocking OBD-II scan tool.
+pub struct Client<C: ReadTimeout, K> {
+    can: C,
+    clock: K,
+    functional: Link<C::Frame>,
+    ecus: Vec<Link<C::Frame>>,
+    timeout: Duration,
+    pending_timeout: Duration,
+}
+
+impl<C, K> Client<C, K>
+where
+    C: ReadTimeout,
+    C::Frame: embedded_can::Frame,
+    K: Clock,
+{
+    pub fn new(can: C, clock: K) -> Self {
+        let id = |raw| StandardId::new(raw).unwrap();
+        let config = |tx, rx| {
+            let mut config = Config::new(id(tx), id(rx));
+            config.with_padding(0xCC);
+            config
+        };
+        Self {
+            can,
+            clock,
+            functional: Link::new(config(FUNCTIONAL_ID, FUNCTIONAL_ID)),
+            ecus: (0..ECUS)
+                .map(|n| Link::new(config(REQUEST_ID + n, RESPONSE_ID + n)))
+                .collect(),
+            timeout: Duration::from_millis(100),
+            pending_timeout: Duration::from_millis(5000),
+        }
+    }
+
+    /// Time to wait for responses after a request, defaults to 100 ms.
+    /// ECUs that answer with response pending get 5 s.
+    pub fn with_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn into_inner(self) -> (C, K) {
+        (self.can, self.clock)
+    }
+
+    /// Sends a request to all ECUs and returns the complete positive responses,
+    /// ordered by ECU. Negative responses are dropped.
+    pub fn request(&mut self, request: &[u8]) -> Result<Vec<Response>, Error<C::Error>> {
+        self.exchange(None, request)
+    }
+
+    /// Sends a request to one ECU only.
+    pub fn request_physical(
+        &mut self,
+        ecu: u8,
+        request: &[u8],
+    ) -> Result<Option<Vec<u8>>, Error<C::Error>> {
+        let responses = self.exchange(Some(ecu as usize % ECUS as usize), request)?;
+        Ok(responses.into_iter().next().map(|r| r.data))
+    }
+
+    /// Returns the supported mode 01 parameters of every ECU.
+    pub fn supported_pids(&mut self) -> Result<PerEcu<Vec<u8>>, Error<C::Error>> {
+        let mut supported: PerEcu<Vec<u8>> = Vec::new();
+        let mut base = 0u8;
+        loop {
+            let responses = self.request_with_pid(&[0x01, base])?;
+            let mut more = false;
+            for response in responses {
+                if response.data.len() < 4 {
+                    return Err(Error::InvalidResponse(response.data));
+                }
+                let bitmap = u32::from_be_bytes([
+                    response.data[0],
+                    response.data[1],
+                    response.data[2],
+                    response.data[3],
+                ]);
+                let pids = (0..32)
+                    .filter(|bit| bitmap & (0x8000_0000 >> bit) != 0)
+                    .map(|bit| base + bit as u8 + 1);
+                match supported.iter_mut().find(|(ecu, _)| *ecu == response.ecu) {
+                    Some((_, list)) => list.extend(pids),
+                    None => supported.push((response.ecu, pids.collect())),
+                }
+                // The last bit tells if the next range is supported.
+                more |= bitmap & 1 != 0;
+            }
+            if !more || base == 0xE0 {
+                break;
+            }
+            base += 0x20;
+        }
+        supported.sort_by_key(|(ecu, _)| *ecu);
+        Ok(supported)
+    }
+
+    /// Reads a mode 01 parameter. Use [`decode()`] for the value.
+    ///
+    /// Fails with [`Error::NoResponse`] if no ECU supports the parameter.
+    pub fn read_pid(&mut self, pid: u8) -> Result<Vec<Response>, Error<C::Error>> {
+        let responses = self.request_with_pid(&[0x01, pid])?;
+        if responses.is_empty() {
+            return Err(Error::NoResponse);
+        }
+        Ok(responses)
+    }
+
+    /// Reads a parameter of a freeze frame (mode 02).
+    /// Parameter 0x02 returns the DTC that caused the freeze frame.
+    pub fn read_freeze_frame(
+        &mut self,
+        pid: u8,
+        frame: u8,
+    ) -> Result<Vec<Response>, Error<C::Error>> {
+        let mut responses = self.request_with_pid(&[0x02, pid, frame])?;
+        for response in &mut responses {
+            // Drop the frame number.
+            if response.data.is_empty() {
+                return Err(Error::InvalidResponse(Vec::new()));
+            }
+            response.data.remove(0);
+        }
+        Ok(responses)
+    }
+
+    /// Emission related DTCs (mode 03).
+    pub fn stored_dtcs(&mut self) -> Result<PerEcu<Vec<Dtc>>, Error<C::Error>> {
+        self.read_dtcs(0x03)
+    }
+
+    /// DTCs detected during the current or last driving cycle (mode 07).
+    pub fn pending_dtcs(&mut self) -> Result<PerEcu<Vec<Dtc>>, Error<C::Error>> {
+        self.read_dtcs(0x07)
+    }
+
+    /// DTCs that cannot be cleared by a scan tool (mode 0A).
+    pub fn permanent_dtcs(&mut self) -> Result<PerEcu<Vec<Dtc>>, Error<C::Error>> {
+        self.read_dtcs(0x0A)
+    }
+
+    /// Clears DTCs, freeze frames and test results (mode 04).
+    /// Returns the ECUs that confirmed.
+    pub fn clear_dtcs(&mut self) -> Result<Vec<u8>, Error<C::Error>> {
+        let responses = self.request(&[0x04])?;
+        Ok(responses.into_iter().map(|r| r.ecu).collect())
+    }
+
+    /// Reads the vehicle identification number (mode 09, info type 0x02).
+    pub fn vin(&mut self) -> Result<String, Error<C::Error>> {
+        let response = self
+            .request_with_pid(&[0x09, 0x02])?
+            .into_iter()
+            .next()
+            .ok_or(Error::NoResponse)?;
+        // Skip the number of data items, some ECUs pad the VIN with zeros.
+        match response.data.split_first() {
+            Some((_, vin)) => Ok(vin
+                .iter()
+                .filter(|&&b| b != 0)
+                .map(|&b| b as char)
+                .collect()),
+            None => Err(Error::InvalidResponse(response.data)),
+        }
+    }
+
+    fn read_dtcs(&mut self, mode: u8) -> Result<PerEcu<Vec<Dtc>>, Error<C::Error>> {
+        let responses = self.request(&[mode])?;
+        Ok(responses
+            .into_iter()
+            .map(|r| (r.ecu, parse_dtcs(&r.data)))
+            .collect())
+    }
+
+    /// Requests a parameter and strips its echo from the responses.
+    fn request_with_pid(&mut self, request: &[u8]) -> Result<Vec<Response>, Error<C::Error>> {
+        let mut responses = self.request(request)?;
+        for response in &mut responses {
+            if response.data.first() != Some(&request[1]) {
+                return Err(Error::InvalidResponse(response.data.clone()));
+            }
+            response.data.remove(0);
+        }
+        Ok(responses)
+    }
+
+    fn exchange(
+        &mut self,
+        target: Option<usize>,
+        request: &[u8],
+    ) -> Result<Vec<Response>, Error<C::Error>> {
+        let service = *request.first().ok_or(Error::InvalidRequest)?;
+        match target {
+            Some(ecu) => self.ecus[ecu].send(request),
+            None => self.functional.send(request),
+        }
+        .map_err(|err| Error::Transport(err.cast()))?;
+
+        let mut responses = Vec::new();
+        let mut deadline = self.clock.now() + self.timeout;
+        loop {
+            let now = self.clock.now();
+            while let Some(event) = self.functional.poll(now) {
+                match event {
+                    Event::Transmit(frame) => self.write(&frame)?,
+                    Event::SendFailed(err) => return Err(Error::Transport(err.cast())),
+                    _ => {}
+                }
+            }
+            for ecu in 0..self.ecus.len() {
+                while let Some(event) = self.ecus[ecu].poll(now) {
+                    match event {
+                        Event::Transmit(frame) => self.write(&frame)?,
+                        Event::SendFailed(err) => return Err(Error::Transport(err.cast())),
+                        Event::Received(response) if target.is_none_or(|t| t == ecu) => {
+                            match classify_response(service, &response) {
+                                ResponseKind::Pending => {
+                                    deadline = deadline.max(now + self.pending_timeout);
+                                }
+                                ResponseKind::Positive => responses.push(Response {
+                                    ecu: ecu as u8,
+                                    data: response[1..].to_vec(),
+                                }),
+                                _ => {}
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            let busy = self.functional.is_sending()
+                || self
+                    .ecus
+                    .iter()
+                    .any(|link| link.is_sending() || link.is_receiving());
+            if !busy && now >= deadline {
+                break;
+            }
+
+            // Past the deadline only the link timers end a started transfer.
+            let next = self
+                .ecus
+                .iter()
+                .chain(Some(&self.functional))
+                .filter_map(|link| link.next_deadline())
+                .chain(Some(deadline).filter(|&deadline| now < deadline))
+                .min()
+                .unwrap_or(deadline);
+            if let Some(frame) = self
+                .can
+                .try_read_timeout(next.saturating_sub(now))
+                .map_err(|err| Error::Transport(isotp::Error::Can(err)))?
+            {
+                let now = self.clock.now();
+                for link in &mut self.ecus {
+                    link.handle_frame(now, &frame);
+                }
+            }
+        }
+
+        responses.sort_by_key(|r| r.ecu);
+        if responses.is_empty() && target.is_some() {
+            return Err(Error::NoResponse);
+        }
+        Ok(responses)
+    }
+
+    fn write(&mut self, frame: &C::Frame) -> Result<(), Error<C::Error>> {
+        self.can
+            .try_write(frame)
+            .map_err(|err| Error::Transport(isotp::Error::Can(err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{self, Bus, Context, Node},
+        uds::server::{Reply, Server},
+        Frame,
+    };
+    use embedded_can::Frame as _;
+
+    /// Third ECU that answers physical requests with a first frame only.
+    struct Stalled;
+
+    impl Node for Stalled {
+        fn on_frame(&mut self, frame: &Frame, cx: &mut Context<'_>) {
+            let request = StandardId::new(REQUEST_ID + 2).unwrap();
+            // Single frames only, not the flow control of the tester.
+            if frame.id() == request.into() && frame.data()[0] & 0xF0 == 0 {
+                let response = StandardId::new(RESPONSE_ID + 2).unwrap();
+                cx.send(
+                    Frame::new(response, &[0x10, 20, 0x49, 0x02, 0x01, 0x57, 0x56, 0x57]).unwrap(),
+                );
+            }
+        }
+    }
+
+    fn setup() -> Client<sim::Endpoint, sim::VirtualClock> {
+        let bus = Bus::new();
+        let ecu = |n: u16, configure: &dyn Fn(&mut Server)| {
+            let id = |raw| StandardId::new(raw).unwrap();
+            let mut config = Config::new(id(RESPONSE_ID + n), id(REQUEST_ID + n));
+            config.with_padding(0xAA);
+            let mut server = Server::new(config);
+            server.with_functional_id(id(FUNCTIONAL_ID));
+            configure(&mut server);
+            bus.add_node(server);
+        };
+
+        // Engine control module with a freeze frame and two DTCs.
+        ecu(0, &|server| {
+            server
+                .with_reply(
+                    &[0x01, 0x00],
+                    Reply::Message(vec![0x41, 0x00, 0x18, 0x18, 0x00, 0x01]),
+                )
+                .with_reply(
+                    &[0x01, 0x20],
+                    Reply::Message(vec![0x41, 0x20, 0x80, 0x00, 0x00, 0x00]),
+                )
+                .with_reply(&[0x01, 0x0C], Reply::Message(vec![0x41, 0x0C, 0x1A, 0xF8]))
+                .with_reply(&[0x01, 0x05], Reply::Message(vec![0x41, 0x05, 0x7B]))
+                .with_reply(
+                    &[0x02, 0x02, 0x00],
+                    Reply::Message(vec![0x42, 0x02, 0x00, 0x03, 0x01]),
+                )
+                .with_reply(
+                    &[0x03],
+                    Reply::Message(vec![0x43, 0x02, 0x03, 0x01, 0xC1, 0x23]),
+                )
+                .with_reply(&[0x07], Reply::Message(vec![0x47, 0x00]))
+                .with_reply(&[0x04], Reply::Message(vec![0x44]))
+                .with_reply(
+                    &[0x09, 0x02],
+                    Reply::Message(b"\x49\x02\x01WVWZZZ1JZXW000001".to_vec()),
+                );
+        });
+        // Transmission control module, slow to answer.
+        ecu(1, &|server| {
+            server
+                .with_reply(
+                    &[0x01, 0x00],
+                    Reply::Message(vec![0x41, 0x00, 0x08, 0x00, 0x00, 0x00]),
+                )
+                .with_reply(&[0x01, 0x05], Reply::Pending(Duration::from_millis(300)))
+                .with_reply(&[0x01, 0x05], Reply::Message(vec![0x41, 0x05, 0x50]));
+        });
+
+        bus.add_node(Stalled);
+
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        Client::new(endpoint, clock)
+    }
+
+    #[test]
+    fn live_data() {
+        let mut obd = setup();
+        assert_eq!(
+            obd.supported_pids().unwrap(),
+            [
+                (0, vec![0x04, 0x05, 0x0C, 0x0D, 0x20, 0x21]),
+                (1, vec![0x05])
+            ]
+        );
+
+        let rpm = obd.read_pid(0x0C).unwrap();
+        assert_eq!(rpm.len(), 1);
+        assert_eq!(
+            decode(0x0C, &rpm[0].data),
+            Some(Value {
+                value: 1726.0,
+                unit: "rpm"
+            })
+        );
+
+        // The second ECU asks for more time.
+        let temperatures: Vec<_> = obd
+            .read_pid(0x05)
+            .unwrap()
+            .iter()
+            .map(|r| (r.ecu, decode(0x05, &r.data).unwrap().value))
+            .collect();
+        assert_eq!(temperatures, [(0, 83.0), (1, 40.0)]);
+        assert_eq!(pid_name(0x05), Some("Engine coolant temperature"));
+
+        assert!(matches!(obd.read_pid(0x10), Err(Error::NoResponse)));
+    }
+
+    #[test]
+    fn dtcs_and_vehicle_info() {
+        let mut obd = setup();
+        assert_eq!(
+            obd.stored_dtcs().unwrap(),
+            [(0, vec![Dtc(0x0301), Dtc(0xC123)])]
+        );
+        assert_eq!(Dtc(0xC123).to_string(), "U0123");
+        assert_eq!(obd.pending_dtcs().unwrap(), [(0, vec![])]);
+        assert!(obd.permanent_dtcs().unwrap().is_empty());
+
+        let freeze = obd.read_freeze_frame(0x02, 0).unwrap();
+        assert_eq!(freeze[0].data, [0x03, 0x01]);
+
+        assert_eq!(obd.clear_dtcs().unwrap(), [0]);
+        assert_eq!(obd.vin().unwrap(), "WVWZZZ1JZXW000001");
+        assert!(matches!(
+            obd.request_physical(1, &[0x09, 0x02]),
+            Err(Error::NoResponse)
+        ));
+        assert!(matches!(obd.request(&[]), Err(Error::InvalidRequest)));
+        assert!(obd.request(&[0xC5]).unwrap().is_empty());
+
+        // A response that stops after the first frame ends with N_Cr.
+        let start = obd.clock.now();
+        assert!(matches!(
+            obd.request_physical(2, &[0x09, 0x02]),
+            Err(Error::NoResponse)
+        ));
+        assert_eq!(obd.clock.now() - start, Duration::from_secs(1));
+    }
+}
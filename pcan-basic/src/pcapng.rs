@@ -0,0 +1,351 @@
+//! pcapng capture files for Wireshark.
+//!
+//! Frames are stored with the `LINKTYPE_CAN_SOCKETCAN` link type, so the
+//! CANopen, J1939 and ISO-TP dissectors of Wireshark can be used on them.
+//! Every channel is written as its own interface.
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use pcan_basic::{pcapng, Interface};
+//!
+//! let mut can = Interface::init().unwrap();
+//! can.add_capture(1, pcapng::Writer::new(File::create("capture.pcapng").unwrap()));
+//! ```
+
+use std::{
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use embedded_can::Frame as _;
+
+use crate::{candump::socketcan_error_frame, Capture, Direction, Id, Message, Record};
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+const LINKTYPE_CAN_SOCKETCAN: u16 = 227;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_SHB_USERAPPL: u16 = 4;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_TSRESOL: u16 = 9;
+const OPT_EPB_FLAGS: u16 = 2;
+const EPB_FLAGS_INBOUND: u32 = 0x1;
+const EPB_FLAGS_OUTBOUND: u32 = 0x2;
+
+// SocketCAN `struct can_frame` and `struct canfd_frame`, the ID is big endian.
+const CAN_EFF_FLAG: u32 = 0x8000_0000;
+const CAN_RTR_FLAG: u32 = 0x4000_0000;
+const CAN_FRAME_SIZE: usize = 16;
+const CANFD_FRAME_SIZE: usize = 72;
+const CANFD_BRS: u8 = 0x01;
+const CANFD_ESI: u8 = 0x02;
+const CANFD_FDF: u8 = 0x04;
+
+/// Writes records as pcapng file with nanosecond timestamps.
+///
+/// An interface description block is written for each channel when its first
+/// record is written.
+pub struct Writer<W: Write> {
+    writer: W,
+    start_time: Option<SystemTime>,
+    interface_names: Vec<(u8, String)>,
+    // Channels in the order of their interface IDs.
+    interfaces: Vec<u8>,
+    header_written: bool,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            start_time: None,
+            interface_names: Vec::new(),
+            interfaces: Vec::new(),
+            header_written: false,
+        }
+    }
+
+    /// Absolute time that corresponds to a record timestamp of zero.
+    ///
+    /// If not set, the first record is assumed to be written right when it
+    /// was captured.
+    pub fn with_start_time(&mut self, start_time: SystemTime) -> &mut Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    /// Name of the interface shown in Wireshark, defaults to `can0` for channel 1.
+    pub fn with_interface(&mut self, channel: u8, name: impl Into<String>) -> &mut Self {
+        self.interface_names.retain(|(c, _)| *c != channel);
+        self.interface_names.push((channel, name.into()));
+        self
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        if !self.header_written {
+            self.write_section_header()?;
+        }
+        let start_time = *self.start_time.get_or_insert_with(|| {
+            SystemTime::now()
+                .checked_sub(record.timestamp)
+                .unwrap_or(UNIX_EPOCH)
+        });
+        let interface = match self.interfaces.iter().position(|c| *c == record.channel) {
+            Some(interface) => interface,
+            None => self.write_interface_description(record.channel)?,
+        };
+
+        let packet = socketcan_frame(&record.message);
+        let nanos = (start_time + record.timestamp)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        let flags = match record.direction {
+            Direction::Rx => EPB_FLAGS_INBOUND,
+            Direction::Tx => EPB_FLAGS_OUTBOUND,
+        };
+
+        let mut body = Vec::with_capacity(20 + packet.len() + 12);
+        body.extend_from_slice(&(interface as u32).to_le_bytes());
+        body.extend_from_slice(&((nanos >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(nanos as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&packet);
+        push_option(&mut body, OPT_EPB_FLAGS, &flags.to_le_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        self.write_block(ENHANCED_PACKET_BLOCK, &body)
+    }
+
+    /// Writes the section header if nothing was written and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.header_written {
+            self.write_section_header()?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_section_header(&mut self) -> io::Result<()> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        // Version 1.0 and unknown section length.
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        body.extend_from_slice(&(-1i64).to_le_bytes());
+        let application = concat!("pcan-basic-rs ", env!("CARGO_PKG_VERSION"));
+        push_option(&mut body, OPT_SHB_USERAPPL, application.as_bytes());
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        self.write_block(SECTION_HEADER_BLOCK, &body)?;
+        self.header_written = true;
+        Ok(())
+    }
+
+    fn write_interface_description(&mut self, channel: u8) -> io::Result<usize> {
+        let name = match self.interface_names.iter().find(|(c, _)| *c == channel) {
+            Some((_, name)) => name.clone(),
+            None => format!("can{}", channel.saturating_sub(1)),
+        };
+
+        let mut body = Vec::new();
+        body.extend_from_slice(&LINKTYPE_CAN_SOCKETCAN.to_le_bytes());
+        body.extend_from_slice(&0u16.to_le_bytes());
+        // Snapshot length, no limit.
+        body.extend_from_slice(&0u32.to_le_bytes());
+        push_option(&mut body, OPT_IF_NAME, name.as_bytes());
+        // Timestamps in units of 10^-9 seconds.
+        push_option(&mut body, OPT_IF_TSRESOL, &[9]);
+        push_option(&mut body, OPT_ENDOFOPT, &[]);
+        self.write_block(INTERFACE_DESCRIPTION_BLOCK, &body)?;
+
+        self.interfaces.push(channel);
+        Ok(self.interfaces.len() - 1)
+    }
+
+    fn write_block(&mut self, block_type: u32, body: &[u8]) -> io::Result<()> {
+        let length = (12 + body.len()) as u32;
+        self.writer.write_all(&block_type.to_le_bytes())?;
+        self.writer.write_all(&length.to_le_bytes())?;
+        self.writer.write_all(body)?;
+        self.writer.write_all(&length.to_le_bytes())
+    }
+}
+
+impl<W: Write> Capture for Writer<W> {
+    fn capture(&mut self, record: &Record) -> io::Result<()> {
+        self.write(record)
+    }
+}
+
+/// Appends an option padded to 32 bits.
+fn push_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len() + (4 - value.len() % 4) % 4, 0);
+}
+
+fn raw_id(id: Id) -> u32 {
+    match id {
+        Id::Standard(id) => id.as_raw() as u32,
+        Id::Extended(id) => id.as_raw() | CAN_EFF_FLAG,
+    }
+}
+
+fn socketcan_frame(message: &Message) -> Vec<u8> {
+    let (id, len, flags, data, size) = match message {
+        Message::Can(frame) => {
+            let mut id = raw_id(frame.id());
+            if frame.is_remote_frame() {
+                id |= CAN_RTR_FLAG;
+            }
+            (id, frame.dlc(), 0, frame.data(), CAN_FRAME_SIZE)
+        }
+        Message::Fd(frame) => {
+            let mut flags = CANFD_FDF;
+            if frame.bit_rate_switch() {
+                flags |= CANFD_BRS;
+            }
+            if frame.error_state_indicator() {
+                flags |= CANFD_ESI;
+            }
+            let data = frame.data();
+            (
+                raw_id(frame.id()),
+                data.len(),
+                flags,
+                data,
+                CANFD_FRAME_SIZE,
+            )
+        }
+        Message::Error(error) => {
+            let (id, data) = socketcan_error_frame(error);
+            let mut packet = frame_header(id, data.len(), 0);
+            packet.extend_from_slice(&data);
+            return packet;
+        }
+    };
+
+    let mut packet = frame_header(id, len, flags);
+    packet.extend_from_slice(data);
+    packet.resize(size, 0);
+    packet
+}
+
+fn frame_header(id: u32, len: usize, flags: u8) -> Vec<u8> {
+    let mut header = Vec::with_capacity(CANFD_FRAME_SIZE);
+    header.extend_from_slice(&id.to_be_bytes());
+    header.extend_from_slice(&[len as u8, flags, 0, 0]);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorFrame, ErrorKind, ExtendedId, FdFrame, Frame, StandardId};
+    use std::{convert::TryInto, time::Duration};
+
+    fn u32_at(b: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(b[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Splits a pcapng file into blocks of type and body.
+    fn blocks(mut file: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut blocks = Vec::new();
+        while !file.is_empty() {
+            let length = u32_at(file, 4) as usize;
+            assert_eq!(length % 4, 0);
+            assert_eq!(u32_at(file, length - 4) as usize, length);
+            blocks.push((u32_at(file, 0), &file[8..length - 4]));
+            file = &file[length..];
+        }
+        blocks
+    }
+
+    #[test]
+    fn blocks_per_channel() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let records = [
+            Record {
+                timestamp: Duration::from_micros(1500),
+                channel: 1,
+                direction: Direction::Rx,
+                message: Message::Can(
+                    Frame::new(ExtendedId::new(0x18FE_F100).unwrap(), &[1, 2, 3]).unwrap(),
+                ),
+            },
+            Record {
+                timestamp: Duration::from_micros(1600),
+                channel: 2,
+                direction: Direction::Tx,
+                message: Message::Fd(
+                    FdFrame::new(StandardId::new(0x123).unwrap(), &[0xAA; 12]).unwrap(),
+                ),
+            },
+            Record {
+                timestamp: Duration::from_micros(1700),
+                channel: 1,
+                direction: Direction::Rx,
+                message: Message::Error(ErrorFrame {
+                    kind: ErrorKind::Stuff,
+                    direction: Direction::Rx,
+                    ecc: 0,
+                    rx_error_counter: 9,
+                    tx_error_counter: 0,
+                }),
+            },
+        ];
+
+        let mut writer = Writer::new(Vec::new());
+        writer.with_start_time(start).with_interface(2, "pcan-usb");
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let file = writer.finish().unwrap();
+        let blocks = blocks(&file);
+
+        let types: Vec<_> = blocks.iter().map(|(t, _)| *t).collect();
+        assert_eq!(
+            types,
+            [
+                SECTION_HEADER_BLOCK,
+                INTERFACE_DESCRIPTION_BLOCK,
+                ENHANCED_PACKET_BLOCK,
+                INTERFACE_DESCRIPTION_BLOCK,
+                ENHANCED_PACKET_BLOCK,
+                ENHANCED_PACKET_BLOCK,
+            ]
+        );
+        assert_eq!(u32_at(blocks[0].1, 0), BYTE_ORDER_MAGIC);
+        let idb = blocks[3].1;
+        assert_eq!(&idb[..2], &LINKTYPE_CAN_SOCKETCAN.to_le_bytes());
+        assert_eq!(&idb[12..20], b"pcan-usb");
+
+        let epb = blocks[2].1;
+        assert_eq!(u32_at(epb, 0), 0);
+        let nanos = (u32_at(epb, 4) as u64) << 32 | u32_at(epb, 8) as u64;
+        assert_eq!(nanos, 1_600_000_000_001_500_000);
+        assert_eq!(u32_at(epb, 12), 16);
+        assert_eq!(&epb[20..28], &[0x98, 0xFE, 0xF1, 0x00, 3, 0, 0, 0]);
+        assert_eq!(&epb[28..31], &[1, 2, 3]);
+        assert_eq!(u32_at(epb, 40), EPB_FLAGS_INBOUND);
+
+        let epb = blocks[4].1;
+        assert_eq!(u32_at(epb, 0), 1);
+        assert_eq!(u32_at(epb, 12), 72);
+        assert_eq!(
+            &epb[20..28],
+            &[0, 0, 0x01, 0x23, 12, CANFD_FDF | CANFD_BRS, 0, 0]
+        );
+        assert_eq!(u32_at(epb, 20 + 72 + 4), EPB_FLAGS_OUTBOUND);
+
+        let epb = blocks[5].1;
+        assert_eq!(u32_at(epb, 0), 0);
+        assert_eq!(epb[20] & 0x20, 0x20);
+        assert_eq!(epb[28 + 7], 9);
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{FdFrame, Frame};
+
+/// Whether a message was received from or transmitted to the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Rx,
+    Tx,
+}
+
+/// Type of a bus error, as reported by the CAN controller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Bit,
+    Form,
+    Stuff,
+    Other,
+}
+
+/// An error frame seen on the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErrorFrame {
+    pub kind: ErrorKind,
+    /// Direction of the frame that was corrupted.
+    pub direction: Direction,
+    /// Error code capture register of the controller, if known.
+    pub ecc: u8,
+    pub rx_error_counter: u8,
+    pub tx_error_counter: u8,
+}
+
+impl ErrorFrame {
+    // PCAN encodes error frames with the error type bits 0x01 (bit),
+    // 0x02 (form), 0x04 (stuff) and 0x08 (other).
+    pub(crate) fn kind_from_bits(bits: u8) -> ErrorKind {
+        match bits {
+            0x01 => ErrorKind::Bit,
+            0x02 => ErrorKind::Form,
+            0x04 => ErrorKind::Stuff,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    pub(crate) fn kind_bits(&self) -> u8 {
+        match self.kind {
+            ErrorKind::Bit => 0x01,
+            ErrorKind::Form => 0x02,
+            ErrorKind::Stuff => 0x04,
+            ErrorKind::Other => 0x08,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Message {
+    Can(Frame),
+    Fd(FdFrame),
+    Error(ErrorFrame),
+}
+
+impl From<Frame> for Message {
+    fn from(frame: Frame) -> Self {
+        Message::Can(frame)
+    }
+}
+
+impl From<FdFrame> for Message {
+    fn from(frame: FdFrame) -> Self {
+        Message::Fd(frame)
+    }
+}
+
+impl From<ErrorFrame> for Message {
+    fn from(frame: ErrorFrame) -> Self {
+        Message::Error(frame)
+    }
+}
+
+/// A message as stored in a trace or log file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    /// Time since the start of the recording.
+    pub timestamp: Duration,
+    /// Bus number, starting at 1 like in PCAN-View.
+    pub channel: u8,
+    pub direction: Direction,
+    pub message: Message,
+}
+
+/// Destination for recorded traffic, e.g. a trace file writer.
+pub trait Capture {
+    fn capture(&mut self, record: &Record) -> io::Result<()>;
+}
+
+/// Allows multiple interfaces to record into the same file.
+impl<C: Capture> Capture for Arc<Mutex<C>> {
+    fn capture(&mut self, record: &Record) -> io::Result<()> {
+        self.lock()
+            .map_err(|_| io::Error::other("capture mutex poisoned"))?
+            .capture(record)
+    }
+}
@@ -0,0 +1,479 @@
+//! Replays recorded traffic with its original timing.
+//!
+//! ```no_run
+//! use std::{fs::File, io::BufReader};
+//! use pcan_basic::{replay::{Replay, Speed}, trc, Interface, SystemClock};
+//!
+//! let reader = trc::Reader::new(BufReader::new(File::open("field.trc").unwrap())).unwrap();
+//! let mut can = Interface::init().unwrap();
+//! let summary = Replay::new()
+//!     .with_speed(Speed::Scaled(2.0))
+//!     .run(&mut can, &mut SystemClock::new(), reader.map_while(Result::ok))
+//!     .unwrap();
+//! println!("{} frames sent, {} late", summary.sent, summary.late);
+//! ```
+
+use std::time::Duration;
+
+use embedded_can::{blocking::Can, Frame as _, Id};
+
+use crate::{Clock, Filter, Frame, Message, Record};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    /// Multiplies the original rate, `Scaled(2.0)` replays twice as fast.
+    Scaled(f64),
+    /// Sends frames back to back, ignoring their timestamps.
+    AsFastAsPossible,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Loops {
+    Count(u32),
+    Forever,
+}
+
+/// Outcome of a replay.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub sent: usize,
+    /// Records that were filtered out, are not classic CAN frames or are too
+    /// far in the future for the speed.
+    pub skipped: usize,
+    /// Frames sent later than the tolerance allows.
+    pub late: usize,
+    pub max_lateness: Duration,
+}
+
+/// Replay configuration.
+pub struct Replay {
+    speed: Speed,
+    loops: Loops,
+    filters: Vec<Filter>,
+    remap: Vec<(Id, Id)>,
+    tolerance: Duration,
+    on_late: Option<LateCallback>,
+}
+
+type LateCallback = Box<dyn FnMut(&Record, Duration)>;
+
+impl Default for Replay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        Self {
+            speed: Speed::Scaled(1.0),
+            loops: Loops::Count(1),
+            filters: Vec::new(),
+            remap: Vec::new(),
+            tolerance: Duration::from_millis(1),
+            on_late: None,
+        }
+    }
+
+    /// Scaled factors are clamped to positive numbers, an infinite factor
+    /// sends all frames at once and NaN keeps the original rate.
+    pub fn with_speed(&mut self, speed: Speed) -> &mut Self {
+        self.speed = match speed {
+            Speed::Scaled(factor) if factor.is_nan() => Speed::Scaled(1.0),
+            Speed::Scaled(factor) => Speed::Scaled(factor.max(f64::MIN_POSITIVE)),
+            Speed::AsFastAsPossible => Speed::AsFastAsPossible,
+        };
+        self
+    }
+
+    /// Replays the recording multiple times. Records are kept in memory for
+    /// all passes after the first one, `Count(0)` sends nothing.
+    ///
+    /// A pass starts one average frame interval of the recording after the
+    /// last frame of the previous pass.
+    pub fn with_loops(&mut self, loops: Loops) -> &mut Self {
+        self.loops = loops;
+        self
+    }
+
+    /// Only replays frames matching at least one of the filters.
+    /// Without filters all frames are replayed.
+    pub fn with_filter(&mut self, filter: Filter) -> &mut Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Sends frames recorded with the ID `from` with the ID `to` instead.
+    pub fn with_remap(&mut self, from: impl Into<Id>, to: impl Into<Id>) -> &mut Self {
+        self.remap.push((from.into(), to.into()));
+        self
+    }
+
+    /// How late a frame may be sent before it is reported. Defaults to 1ms.
+    pub fn with_tolerance(&mut self, tolerance: Duration) -> &mut Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Called for every late frame with the record and how late it was sent.
+    pub fn on_late(&mut self, f: impl FnMut(&Record, Duration) + 'static) -> &mut Self {
+        self.on_late = Some(Box::new(f));
+        self
+    }
+
+    /// Sends the classic CAN frames of `records` to `can`, paced by `clock`.
+    ///
+    /// Timing is relative to the first record, which is sent immediately.
+    pub fn run<C, K>(
+        &mut self,
+        can: &mut C,
+        clock: &mut K,
+        records: impl IntoIterator<Item = Record>,
+    ) -> Result<Summary, C::Error>
+    where
+        C: Can<Frame = Frame>,
+        K: Clock,
+    {
+        let mut summary = Summary::default();
+        if self.loops == Loops::Count(0) {
+            return Ok(summary);
+        }
+        let mut buffer = Vec::new();
+        let keep = self.loops != Loops::Count(1);
+
+        let mut pass = Pass::default();
+        for record in records {
+            self.replay(can, clock, &mut pass, &record, &mut summary)?;
+            if keep {
+                buffer.push(record);
+            }
+        }
+
+        let mut remaining = match self.loops {
+            Loops::Count(n) => n.saturating_sub(1),
+            Loops::Forever => u32::MAX,
+        };
+        // Nothing to send would loop forever without ever sleeping.
+        while remaining > 0 && summary.sent > 0 {
+            let mut pass = self.next_pass(&pass);
+            for record in &buffer {
+                self.replay(can, clock, &mut pass, record, &mut summary)?;
+            }
+            if self.loops != Loops::Forever {
+                remaining -= 1;
+            }
+        }
+
+        Ok(summary)
+    }
+
+    fn replay<C, K>(
+        &mut self,
+        can: &mut C,
+        clock: &mut K,
+        pass: &mut Pass,
+        record: &Record,
+        summary: &mut Summary,
+    ) -> Result<(), C::Error>
+    where
+        C: Can<Frame = Frame>,
+        K: Clock,
+    {
+        let frame = match self.prepare(record) {
+            Some(frame) => frame,
+            None => {
+                summary.skipped += 1;
+                return Ok(());
+            }
+        };
+
+        let (start, first) = *pass
+            .origin
+            .get_or_insert_with(|| (clock.now(), record.timestamp));
+        pass.last = record.timestamp;
+        pass.sent += 1;
+        if let Speed::Scaled(factor) = self.speed {
+            let offset = record.timestamp.saturating_sub(first).as_secs_f64() / factor;
+            let target = match Duration::try_from_secs_f64(offset)
+                .ok()
+                .and_then(|offset| start.checked_add(offset))
+            {
+                Some(target) => target,
+                None => {
+                    summary.skipped += 1;
+                    return Ok(());
+                }
+            };
+            clock.sleep_until(target);
+
+            let lateness = clock.now().saturating_sub(target);
+            if lateness > self.tolerance {
+                summary.late += 1;
+                summary.max_lateness = summary.max_lateness.max(lateness);
+                if let Some(on_late) = &mut self.on_late {
+                    on_late(record, lateness);
+                }
+            }
+        }
+
+        can.try_write(&frame)?;
+        summary.sent += 1;
+        Ok(())
+    }
+
+    /// Continues the timeline of `previous`, or starts at the current time if
+    /// the recording has no frame interval.
+    fn next_pass(&self, previous: &Pass) -> Pass {
+        let factor = match self.speed {
+            Speed::Scaled(factor) => factor,
+            Speed::AsFastAsPossible => return Pass::default(),
+        };
+        let origin = previous.origin.and_then(|(start, first)| {
+            let span = previous.last.saturating_sub(first).as_secs_f64();
+            let gap = span / (previous.sent.checked_sub(1)? as f64);
+            if gap == 0.0 {
+                return None;
+            }
+            let offset = Duration::try_from_secs_f64((span + gap) / factor).ok()?;
+            Some((start.checked_add(offset)?, first))
+        });
+        Pass {
+            origin,
+            ..Pass::default()
+        }
+    }
+
+    fn prepare(&self, record: &Record) -> Option<Frame> {
+        let frame = match record.message {
+            Message::Can(frame) => frame,
+            Message::Fd(_) | Message::Error(_) => return None,
+        };
+        if !self.filters.is_empty() && !self.filters.iter().any(|f| f.matches(frame.id())) {
+            return None;
+        }
+
+        let id = match self.remap.iter().find(|(from, _)| *from == frame.id()) {
+            Some((_, to)) => *to,
+            None => return Some(frame),
+        };
+        if frame.is_remote_frame() {
+            Frame::new_remote(id, frame.dlc()).ok()
+        } else {
+            Frame::new(id, frame.data()).ok()
+        }
+    }
+}
+
+/// Maps recording time to clock time for one pass over the records.
+#[derive(Default)]
+struct Pass {
+    origin: Option<(Duration, Duration)>,
+    /// Timestamp of the last frame sent.
+    last: Duration,
+    sent: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{Bus, Context, Node, VirtualClock},
+        Direction, ErrorFrame, ErrorKind, StandardId,
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    #[derive(Default)]
+    struct Recorder {
+        frames: Vec<(Duration, Frame)>,
+    }
+
+    impl Node for Recorder {
+        fn on_frame(&mut self, frame: &Frame, cx: &mut Context<'_>) {
+            self.frames.push((cx.now(), *frame));
+        }
+    }
+
+    fn frame(id: u16, data: &[u8]) -> Frame {
+        Frame::new(StandardId::new(id).unwrap(), data).unwrap()
+    }
+
+    fn record(millis: u64, message: impl Into<Message>) -> Record {
+        Record {
+            timestamp: Duration::from_millis(millis),
+            channel: 1,
+            direction: Direction::Rx,
+            message: message.into(),
+        }
+    }
+
+    fn recording() -> Vec<Record> {
+        vec![
+            record(100, frame(0x100, &[1])),
+            record(110, frame(0x200, &[2])),
+            record(
+                115,
+                ErrorFrame {
+                    kind: ErrorKind::Stuff,
+                    direction: Direction::Rx,
+                    ecc: 0,
+                    rx_error_counter: 8,
+                    tx_error_counter: 0,
+                },
+            ),
+            record(130, frame(0x100, &[3])),
+        ]
+    }
+
+    fn times(recorder: &Rc<RefCell<Recorder>>) -> Vec<u64> {
+        let recorder = recorder.borrow();
+        recorder
+            .frames
+            .iter()
+            .map(|(t, _)| t.as_millis() as u64)
+            .collect()
+    }
+
+    fn setup() -> (Rc<RefCell<Recorder>>, crate::sim::Endpoint, VirtualClock) {
+        let bus = Bus::new();
+        let recorder = bus.add_node(Recorder::default());
+        let endpoint = bus.endpoint();
+        (recorder, endpoint, bus.clock())
+    }
+
+    #[test]
+    fn keeps_timing() {
+        let (recorder, mut can, mut clock) = setup();
+        clock.sleep(Duration::from_millis(5));
+
+        let summary = Replay::new()
+            .run(&mut can, &mut clock, recording())
+            .unwrap();
+        clock.sleep(Duration::default());
+
+        assert_eq!(summary.sent, 3);
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.late, 0);
+        assert_eq!(times(&recorder), [5, 15, 35]);
+    }
+
+    #[test]
+    fn scaled_and_looped() {
+        let (recorder, mut can, mut clock) = setup();
+
+        let summary = Replay::new()
+            .with_speed(Speed::Scaled(2.0))
+            .with_loops(Loops::Count(2))
+            .run(&mut can, &mut clock, recording())
+            .unwrap();
+        clock.sleep(Duration::default());
+
+        assert_eq!(summary.sent, 6);
+        // The second pass starts one average interval, 7.5ms, later.
+        assert_eq!(times(&recorder), [0, 5, 15, 22, 27, 37]);
+
+        let summary = Replay::new()
+            .with_loops(Loops::Count(0))
+            .run(&mut can, &mut clock, recording())
+            .unwrap();
+        assert_eq!(summary, Summary::default());
+
+        // Only the first frame can be scheduled this slow.
+        let summary = Replay::new()
+            .with_speed(Speed::Scaled(1e-300))
+            .run(&mut can, &mut clock, recording())
+            .unwrap();
+        assert_eq!((summary.sent, summary.skipped), (1, 3));
+    }
+
+    #[test]
+    fn invalid_speed() {
+        let (recorder, mut can, mut clock) = setup();
+
+        let summary = Replay::new()
+            .with_speed(Speed::Scaled(0.0))
+            .run(&mut can, &mut clock, recording())
+            .unwrap();
+        assert_eq!((summary.sent, summary.skipped), (1, 3));
+
+        Replay::new()
+            .with_speed(Speed::Scaled(f64::NAN))
+            .run(&mut can, &mut clock, recording())
+            .unwrap();
+        Replay::new()
+            .with_speed(Speed::Scaled(f64::INFINITY))
+            .run(&mut can, &mut clock, recording())
+            .unwrap();
+        clock.sleep(Duration::default());
+
+        assert_eq!(times(&recorder), [0, 0, 10, 30, 30, 30, 30]);
+    }
+
+    #[test]
+    fn as_fast_as_possible() {
+        let (recorder, mut can, mut clock) = setup();
+
+        Replay::new()
+            .with_speed(Speed::AsFastAsPossible)
+            .run(&mut can, &mut clock, recording())
+            .unwrap();
+        clock.sleep(Duration::default());
+
+        assert_eq!(times(&recorder), [0, 0, 0]);
+    }
+
+    #[test]
+    fn filter_and_remap() {
+        let (recorder, mut can, mut clock) = setup();
+
+        let summary = Replay::new()
+            .with_filter(Filter::new(StandardId::new(0x100).unwrap().into()))
+            .with_remap(
+                StandardId::new(0x100).unwrap(),
+                StandardId::new(0x101).unwrap(),
+            )
+            .run(&mut can, &mut clock, recording())
+            .unwrap();
+        clock.sleep(Duration::default());
+
+        assert_eq!(summary.sent, 2);
+        assert_eq!(summary.skipped, 2);
+        let recorder = recorder.borrow();
+        let frames: Vec<_> = recorder.frames.iter().map(|(_, f)| *f).collect();
+        assert_eq!(frames, [frame(0x101, &[1]), frame(0x101, &[3])]);
+    }
+
+    /// Oversleeps like a loaded system.
+    struct Sluggish(VirtualClock);
+
+    impl Clock for Sluggish {
+        fn now(&self) -> Duration {
+            self.0.now()
+        }
+
+        fn sleep_until(&mut self, deadline: Duration) {
+            self.0.sleep_until(deadline + Duration::from_millis(3));
+        }
+    }
+
+    #[test]
+    fn reports_late_frames() {
+        let (_, mut can, clock) = setup();
+        let late = Rc::new(RefCell::new(Vec::new()));
+
+        let reported = late.clone();
+        let summary = Replay::new()
+            .with_tolerance(Duration::from_millis(2))
+            .on_late(move |record, lateness| {
+                reported.borrow_mut().push((record.timestamp, lateness))
+            })
+            .run(&mut can, &mut Sluggish(clock), recording())
+            .unwrap();
+
+        assert_eq!(summary.late, 3);
+        assert_eq!(summary.max_lateness, Duration::from_millis(3));
+        assert_eq!(
+            late.borrow()[1],
+            (Duration::from_millis(110), Duration::from_millis(3))
+        );
+    }
+}
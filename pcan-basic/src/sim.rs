@@ -0,0 +1,341 @@
+//! Simulated CAN bus with a virtual clock, for tests without hardware.
+//!
+//! Everything runs on the calling thread. Time only advances while an
+//! [`Endpoint`] waits for a frame or a [`VirtualClock`] sleeps, and all events
+//! scheduled up to that point are processed in order. Simulations are
+//! deterministic and take no wall clock time.
+//!
+//! Code under test talks to the bus through an [`Endpoint`], which implements
+//! the same traits as [`Interface`](crate::Interface). Simulated devices
+//! implement [`Node`] and react to frames and timers.
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    rc::Rc,
+    time::Duration,
+};
+
+use crate::{Clock, Frame, ReadTimeout};
+
+/// A simulated device on the bus.
+pub trait Node {
+    /// Called for every frame sent by another participant of the bus.
+    fn on_frame(&mut self, frame: &Frame, cx: &mut Context<'_>);
+
+    /// Called when a timer requested with [`Context::wake_after()`] expires.
+    fn on_timer(&mut self, _cx: &mut Context<'_>) {}
+}
+
+/// Lets a [`Node`] send frames and schedule timers.
+pub struct Context<'a> {
+    now: Duration,
+    node: usize,
+    events: &'a mut Events,
+}
+
+impl Context<'_> {
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    pub fn send(&mut self, frame: Frame) {
+        self.send_after(Duration::default(), frame);
+    }
+
+    pub fn send_after(&mut self, delay: Duration, frame: Frame) {
+        self.events.push(
+            self.now + delay,
+            Event::Frame {
+                sender: Participant::Node(self.node),
+                frame,
+            },
+        );
+    }
+
+    pub fn wake_after(&mut self, delay: Duration) {
+        self.events
+            .push(self.now + delay, Event::Timer { node: self.node });
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// A blocking read would never return: no frame is queued and no event is pending.
+    Idle,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Idle => write!(f, "Simulated bus is idle, no frame will arrive"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Participant {
+    Endpoint(usize),
+    Node(usize),
+}
+
+enum Event {
+    Frame { sender: Participant, frame: Frame },
+    Timer { node: usize },
+}
+
+#[derive(Default)]
+struct Events {
+    // Ordered by time, events at the same time in the order they were scheduled.
+    queue: BTreeMap<(Duration, u64), Event>,
+    sequence: u64,
+}
+
+impl Events {
+    fn push(&mut self, time: Duration, event: Event) {
+        self.queue.insert((time, self.sequence), event);
+        self.sequence += 1;
+    }
+}
+
+#[derive(Default)]
+struct State {
+    now: Duration,
+    events: Events,
+    endpoints: Vec<VecDeque<Frame>>,
+    nodes: Vec<Rc<RefCell<dyn Node>>>,
+}
+
+impl State {
+    /// Processes the next event if it is due no later than `limit`.
+    fn step(&mut self, limit: Option<Duration>) -> bool {
+        match self.events.queue.keys().next() {
+            Some((time, _)) if limit.is_none_or(|limit| *time <= limit) => {}
+            _ => return false,
+        }
+        let ((time, _), event) = self.events.queue.pop_first().unwrap();
+        self.now = self.now.max(time);
+
+        match event {
+            Event::Frame { sender, frame } => {
+                for (i, rx) in self.endpoints.iter_mut().enumerate() {
+                    if sender != Participant::Endpoint(i) {
+                        rx.push_back(frame);
+                    }
+                }
+                for i in 0..self.nodes.len() {
+                    if sender != Participant::Node(i) {
+                        let node = self.nodes[i].clone();
+                        let mut cx = Context {
+                            now: self.now,
+                            node: i,
+                            events: &mut self.events,
+                        };
+                        node.borrow_mut().on_frame(&frame, &mut cx);
+                    }
+                }
+            }
+            Event::Timer { node } => {
+                let n = self.nodes[node].clone();
+                let mut cx = Context {
+                    now: self.now,
+                    node,
+                    events: &mut self.events,
+                };
+                n.borrow_mut().on_timer(&mut cx);
+            }
+        }
+        true
+    }
+
+    fn run_until(&mut self, deadline: Duration) {
+        while self.step(Some(deadline)) {}
+        self.now = self.now.max(deadline);
+    }
+}
+
+/// A simulated CAN bus.
+///
+/// Cloning yields another handle to the same bus.
+#[derive(Clone, Default)]
+pub struct Bus {
+    state: Rc<RefCell<State>>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Connects a new endpoint that receives all frames sent by others.
+    pub fn endpoint(&self) -> Endpoint {
+        let mut state = self.state.borrow_mut();
+        state.endpoints.push(VecDeque::new());
+        Endpoint {
+            state: self.state.clone(),
+            index: state.endpoints.len() - 1,
+        }
+    }
+
+    /// Attaches a simulated device. The returned handle gives access to its
+    /// state between bus operations, e.g. for assertions.
+    pub fn add_node<N: Node + 'static>(&self, node: N) -> Rc<RefCell<N>> {
+        let node = Rc::new(RefCell::new(node));
+        self.state.borrow_mut().nodes.push(node.clone());
+        node
+    }
+
+    pub fn clock(&self) -> VirtualClock {
+        VirtualClock {
+            state: self.state.clone(),
+        }
+    }
+}
+
+/// Connection of the code under test to a simulated [`Bus`].
+pub struct Endpoint {
+    state: Rc<RefCell<State>>,
+    index: usize,
+}
+
+impl Endpoint {
+    pub fn clock(&self) -> VirtualClock {
+        VirtualClock {
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl embedded_can::Can for Endpoint {
+    type Frame = Frame;
+    type Error = Error;
+
+    fn try_transmit(&mut self, frame: &Frame) -> nb::Result<Option<Frame>, Error> {
+        let mut state = self.state.borrow_mut();
+        let now = state.now;
+        state.events.push(
+            now,
+            Event::Frame {
+                sender: Participant::Endpoint(self.index),
+                frame: *frame,
+            },
+        );
+        Ok(None)
+    }
+
+    fn try_receive(&mut self) -> nb::Result<Frame, Error> {
+        let mut state = self.state.borrow_mut();
+        let now = state.now;
+        while state.step(Some(now)) {}
+        state.endpoints[self.index]
+            .pop_front()
+            .ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl embedded_can::blocking::Can for Endpoint {
+    type Frame = Frame;
+    type Error = Error;
+
+    fn try_write(&mut self, frame: &Frame) -> Result<(), Error> {
+        nb::block!(embedded_can::Can::try_transmit(self, frame)).map(|_| ())
+    }
+
+    fn try_read(&mut self) -> Result<Frame, Error> {
+        let mut state = self.state.borrow_mut();
+        loop {
+            if let Some(frame) = state.endpoints[self.index].pop_front() {
+                return Ok(frame);
+            }
+            if !state.step(None) {
+                return Err(Error::Idle);
+            }
+        }
+    }
+}
+
+impl ReadTimeout for Endpoint {
+    fn try_read_timeout(&mut self, timeout: Duration) -> Result<Option<Frame>, Error> {
+        let mut state = self.state.borrow_mut();
+        let deadline = state.now + timeout;
+        loop {
+            if let Some(frame) = state.endpoints[self.index].pop_front() {
+                return Ok(Some(frame));
+            }
+            if !state.step(Some(deadline)) {
+                state.now = state.now.max(deadline);
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Virtual time of a simulated [`Bus`]. Sleeping runs the simulation.
+#[derive(Clone)]
+pub struct VirtualClock {
+    state: Rc<RefCell<State>>,
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        self.state.borrow().now
+    }
+
+    fn sleep_until(&mut self, deadline: Duration) {
+        self.state.borrow_mut().run_until(deadline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StandardId;
+    use embedded_can::{blocking::Can as _, Frame as _};
+
+    struct Echo {
+        delay: Duration,
+    }
+
+    impl Node for Echo {
+        fn on_frame(&mut self, frame: &Frame, cx: &mut Context<'_>) {
+            cx.send_after(self.delay, *frame);
+        }
+    }
+
+    #[test]
+    fn read_timeout_advances_virtual_time() {
+        let bus = Bus::new();
+        bus.add_node(Echo {
+            delay: Duration::from_millis(30),
+        });
+        let mut endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+
+        let frame = Frame::new(StandardId::new(0x123).unwrap(), &[1, 2]).unwrap();
+        endpoint.try_write(&frame).unwrap();
+
+        let timeout = Duration::from_millis(20);
+        assert_eq!(endpoint.try_read_timeout(timeout).unwrap(), None);
+        assert_eq!(clock.now(), timeout);
+
+        assert_eq!(endpoint.try_read_timeout(timeout).unwrap(), Some(frame));
+        assert_eq!(clock.now(), Duration::from_millis(30));
+
+        assert!(matches!(endpoint.try_read(), Err(Error::Idle)));
+    }
+
+    #[test]
+    fn endpoints_see_each_other() {
+        let bus = Bus::new();
+        let mut a = bus.endpoint();
+        let mut b = bus.endpoint();
+
+        let frame = Frame::new(StandardId::new(1).unwrap(), &[]).unwrap();
+        a.try_write(&frame).unwrap();
+        assert_eq!(b.try_read().unwrap(), frame);
+        assert!(matches!(a.try_read(), Err(Error::Idle)));
+    }
+}
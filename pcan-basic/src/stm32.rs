@@ -0,0 +1,405 @@
+//! Host side of the STM32 system memory bootloader CAN protocol (ST AN3154).
+//!
+//! Every command is a frame whose identifier is the command code. The
+//! bootloader answers on the same identifier with [`ACK`] or [`NACK`], data
+//! written to the device is sent with identifier `0x04`.
+//!
+//! The bootloader waits for responses with the blocking `try_read()`. Wrap
+//! the interface in a [`Timeout`](crate::Timeout) to stop waiting for a device
+//! that does not answer.
+
+pub mod device;
+pub mod flash;
+
+use std::fmt;
+
+use embedded_can::{Frame, StandardId};
+
+pub const ACK: u8 = 0x79;
+pub const NACK: u8 = 0x1F;
+
+/// Largest block of Read Memory and Write Memory.
+pub const MAX_BLOCK_LEN: usize = 256;
+
+/// Command codes, also used as frame identifiers.
+pub mod command {
+    pub const GET: u8 = 0x00;
+    pub const GET_VERSION: u8 = 0x01;
+    pub const GET_ID: u8 = 0x02;
+    pub const READ_MEMORY: u8 = 0x11;
+    pub const GO: u8 = 0x21;
+    pub const WRITE_MEMORY: u8 = 0x31;
+    pub const ERASE: u8 = 0x43;
+    pub const WRITE_PROTECT: u8 = 0x63;
+    pub const WRITE_UNPROTECT: u8 = 0x73;
+    pub const READOUT_PROTECT: u8 = 0x82;
+    pub const READOUT_UNPROTECT: u8 = 0x92;
+    /// Makes the bootloader select the CAN interface.
+    pub const SYNC: u8 = 0x79;
+}
+
+/// Identifier of frames with data for Write Memory.
+pub const DATA_ID: u16 = 0x04;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Can(E),
+    /// The bootloader rejected the command, for example because of readout
+    /// protection or an invalid address.
+    Nack(u8),
+    /// A frame other than the expected response was received.
+    UnexpectedResponse {
+        command: u8,
+        data: Vec<u8>,
+    },
+    /// The arguments cannot be encoded, like an empty or too long block.
+    InvalidArgument,
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Can(err) => write!(f, "CAN error: {}", err),
+            Error::Nack(command) => write!(f, "Command {:#04x} not acknowledged", command),
+            Error::UnexpectedResponse { command, data } => write!(
+                f,
+                "Unexpected response to command {:#04x}: {:02X?}",
+                command, data
+            ),
+            Error::InvalidArgument => write!(f, "Invalid argument"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Error<E> {}
+
+/// Answer to the Get command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Info {
+    /// Protocol version, 0x20 is version 2.0.
+    pub version: u8,
+    pub commands: Vec<u8>,
+}
+
+pub struct Bootloader<C> {
+    can: C,
+}
+
+impl<C> Bootloader<C>
+where
+    C: embedded_can::blocking::Can,
+{
+    pub fn new(can: C) -> Self {
+        Self { can }
+    }
+
+    pub fn can(&mut self) -> &mut C {
+        &mut self.can
+    }
+
+    pub fn into_inner(self) -> C {
+        self.can
+    }
+
+    /// The bootloader listens on multiple communication interfaces.
+    /// Sends a synchronization message so it locks on the CAN interface.
+    pub fn sync(&mut self) -> Result<(), Error<C::Error>> {
+        self.command(command::SYNC, &[])
+    }
+
+    /// Returns the protocol version and the supported commands.
+    pub fn get(&mut self) -> Result<Info, Error<C::Error>> {
+        self.send(command::GET, &[])?;
+        self.receive_ack(command::GET)?;
+        let count = self.receive_byte(command::GET)?;
+        let version = self.receive_byte(command::GET)?;
+        let commands = (0..count)
+            .map(|_| self.receive_byte(command::GET))
+            .collect::<Result<_, _>>()?;
+        self.receive_ack(command::GET)?;
+        Ok(Info { version, commands })
+    }
+
+    /// Returns the protocol version and the two option bytes.
+    pub fn get_version(&mut self) -> Result<(u8, [u8; 2]), Error<C::Error>> {
+        self.send(command::GET_VERSION, &[])?;
+        self.receive_ack(command::GET_VERSION)?;
+        let data = self.receive_data(command::GET_VERSION)?;
+        self.receive_ack(command::GET_VERSION)?;
+        match *data.as_slice() {
+            [version, option1, option2] => Ok((version, [option1, option2])),
+            _ => Err(Error::UnexpectedResponse {
+                command: command::GET_VERSION,
+                data,
+            }),
+        }
+    }
+
+    /// Returns the product ID of the device, for example 0x413 for STM32F405.
+    pub fn get_id(&mut self) -> Result<u16, Error<C::Error>> {
+        self.send(command::GET_ID, &[])?;
+        self.receive_ack(command::GET_ID)?;
+        let data = self.receive_data(command::GET_ID)?;
+        self.receive_ack(command::GET_ID)?;
+        match *data.as_slice() {
+            [msb, lsb] => Ok(u16::from_be_bytes([msb, lsb])),
+            _ => Err(Error::UnexpectedResponse {
+                command: command::GET_ID,
+                data,
+            }),
+        }
+    }
+
+    /// Fills `buf` with up to 256 bytes of memory from `address`.
+    pub fn read_memory(&mut self, address: u32, buf: &mut [u8]) -> Result<(), Error<C::Error>> {
+        if buf.is_empty() || buf.len() > MAX_BLOCK_LEN {
+            return Err(Error::InvalidArgument);
+        }
+        self.send(command::READ_MEMORY, &address_and_len(address, buf.len()))?;
+        self.receive_ack(command::READ_MEMORY)?;
+
+        let mut received = 0;
+        while received < buf.len() {
+            let data = self.receive_data(command::READ_MEMORY)?;
+            if received + data.len() > buf.len() {
+                return Err(Error::UnexpectedResponse {
+                    command: command::READ_MEMORY,
+                    data,
+                });
+            }
+            buf[received..received + data.len()].copy_from_slice(&data);
+            received += data.len();
+        }
+        self.receive_ack(command::READ_MEMORY)
+    }
+
+    /// Jumps to the application at `address`.
+    pub fn go(&mut self, address: u32) -> Result<(), Error<C::Error>> {
+        self.command(command::GO, &address.to_be_bytes())
+    }
+
+    /// Writes up to 256 bytes to RAM or flash at `address`. Flash has to be erased.
+    pub fn write_memory(&mut self, address: u32, data: &[u8]) -> Result<(), Error<C::Error>> {
+        if data.is_empty() || data.len() > MAX_BLOCK_LEN {
+            return Err(Error::InvalidArgument);
+        }
+        self.send(command::WRITE_MEMORY, &address_and_len(address, data.len()))?;
+        self.receive_ack(command::WRITE_MEMORY)?;
+
+        for chunk in data.chunks(8) {
+            let frame = frame(DATA_ID, chunk);
+            self.can.try_write(&frame).map_err(Error::Can)?;
+            self.receive_ack(command::WRITE_MEMORY)?;
+        }
+        self.receive_ack(command::WRITE_MEMORY)
+    }
+
+    /// Erases the whole flash.
+    pub fn mass_erase(&mut self) -> Result<(), Error<C::Error>> {
+        self.command_with_completion(command::ERASE, &[0xFF])
+    }
+
+    /// Erases flash pages or sectors by number.
+    pub fn erase_pages(&mut self, pages: &[u8]) -> Result<(), Error<C::Error>> {
+        // 0xFF alone is a mass erase.
+        if pages.contains(&0xFF) {
+            return Err(Error::InvalidArgument);
+        }
+        for chunk in pages.chunks(8) {
+            self.command_with_completion(command::ERASE, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Enables write protection of flash sectors. The device resets afterwards.
+    pub fn write_protect(&mut self, sectors: &[u8]) -> Result<(), Error<C::Error>> {
+        if sectors.is_empty() || sectors.len() > 8 {
+            return Err(Error::InvalidArgument);
+        }
+        self.command_with_completion(command::WRITE_PROTECT, sectors)
+    }
+
+    /// Disables write protection of all sectors. The device resets afterwards.
+    pub fn write_unprotect(&mut self) -> Result<(), Error<C::Error>> {
+        self.command_with_completion(command::WRITE_UNPROTECT, &[])
+    }
+
+    /// Enables readout protection. The device resets afterwards.
+    pub fn readout_protect(&mut self) -> Result<(), Error<C::Error>> {
+        self.command_with_completion(command::READOUT_PROTECT, &[])
+    }
+
+    /// Disables readout protection, which erases the whole flash. The device
+    /// resets afterwards.
+    pub fn readout_unprotect(&mut self) -> Result<(), Error<C::Error>> {
+        self.command_with_completion(command::READOUT_UNPROTECT, &[])
+    }
+
+    fn command(&mut self, command: u8, data: &[u8]) -> Result<(), Error<C::Error>> {
+        self.send(command, data)?;
+        self.receive_ack(command)
+    }
+
+    /// Sends a command that is acknowledged on reception and on completion.
+    fn command_with_completion(&mut self, command: u8, data: &[u8]) -> Result<(), Error<C::Error>> {
+        self.command(command, data)?;
+        self.receive_ack(command)
+    }
+
+    fn send(&mut self, command: u8, data: &[u8]) -> Result<(), Error<C::Error>> {
+        self.can
+            .try_write(&frame(command as u16, data))
+            .map_err(Error::Can)
+    }
+
+    fn receive_data(&mut self, command: u8) -> Result<Vec<u8>, Error<C::Error>> {
+        let frame = self.can.try_read().map_err(Error::Can)?;
+        if frame.id() != StandardId::new(command as u16).unwrap().into() {
+            return Err(Error::UnexpectedResponse {
+                command,
+                data: frame.data().to_vec(),
+            });
+        }
+        Ok(frame.data().to_vec())
+    }
+
+    fn receive_byte(&mut self, command: u8) -> Result<u8, Error<C::Error>> {
+        let data = self.receive_data(command)?;
+        match *data.as_slice() {
+            [byte] => Ok(byte),
+            _ => Err(Error::UnexpectedResponse { command, data }),
+        }
+    }
+
+    fn receive_ack(&mut self, command: u8) -> Result<(), Error<C::Error>> {
+        match self.receive_byte(command) {
+            Ok(ACK) => Ok(()),
+            Ok(NACK) => Err(Error::Nack(command)),
+            Ok(byte) => Err(Error::UnexpectedResponse {
+                command,
+                data: vec![byte],
+            }),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+fn frame<F: Frame>(id: u16, data: &[u8]) -> F {
+    // Identifiers are command codes and data never exceeds 8 bytes.
+    F::new(StandardId::new(id).unwrap(), data).ok().unwrap()
+}
+
+fn address_and_len(address: u32, len: usize) -> [u8; 5] {
+    let [a, b, c, d] = address.to_be_bytes();
+    [a, b, c, d, (len - 1) as u8]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Bus, Context, Node};
+
+    /// Answers frames with canned responses on the identifier of the frame.
+    struct Scripted {
+        script: Vec<(u16, Vec<u8>, Vec<Vec<u8>>)>,
+        received: Vec<(u16, Vec<u8>)>,
+    }
+
+    impl Node for Scripted {
+        fn on_frame(&mut self, frame: &crate::Frame, cx: &mut Context<'_>) {
+            let id = match frame.id() {
+                embedded_can::Id::Standard(id) => id.as_raw(),
+                embedded_can::Id::Extended(_) => return,
+            };
+            self.received.push((id, frame.data().to_vec()));
+            let position = self
+                .script
+                .iter()
+                .position(|(i, data, _)| *i == id && data == frame.data());
+            if let Some(position) = position {
+                let (_, _, responses) = self.script.remove(position);
+                let response_id = if id == DATA_ID {
+                    command::WRITE_MEMORY as u16
+                } else {
+                    id
+                };
+                for response in responses {
+                    cx.send(super::frame(response_id, &response));
+                }
+            }
+        }
+    }
+
+    fn setup(
+        script: Vec<(u16, Vec<u8>, Vec<Vec<u8>>)>,
+    ) -> (
+        Bootloader<sim::Endpoint>,
+        std::rc::Rc<std::cell::RefCell<Scripted>>,
+    ) {
+        let bus = Bus::new();
+        let device = bus.add_node(Scripted {
+            script,
+            received: Vec::new(),
+        });
+        (Bootloader::new(bus.endpoint()), device)
+    }
+
+    #[test]
+    fn get_and_read_memory() {
+        let (mut bl, _) = setup(vec![
+            (0x79, vec![], vec![vec![ACK]]),
+            (
+                0x00,
+                vec![],
+                vec![
+                    vec![ACK],
+                    vec![3],
+                    vec![0x20],
+                    vec![0x00],
+                    vec![0x11],
+                    vec![0x31],
+                    vec![ACK],
+                ],
+            ),
+            (0x02, vec![], vec![vec![ACK], vec![0x04, 0x13], vec![ACK]]),
+            (
+                0x11,
+                vec![0x08, 0x00, 0x00, 0x00, 9],
+                vec![vec![ACK], (0..8).collect(), vec![8, 9], vec![ACK]],
+            ),
+        ]);
+
+        bl.sync().unwrap();
+        let info = bl.get().unwrap();
+        assert_eq!(info.version, 0x20);
+        assert_eq!(info.commands, [0x00, 0x11, 0x31]);
+        assert_eq!(bl.get_id().unwrap(), 0x413);
+
+        let mut buf = [0; 10];
+        bl.read_memory(0x0800_0000, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn write_memory_and_nack() {
+        let (mut bl, device) = setup(vec![
+            (0x31, vec![0x20, 0x00, 0x00, 0x00, 9], vec![vec![ACK]]),
+            (0x04, (0..8).collect(), vec![vec![ACK]]),
+            (0x04, vec![8, 9], vec![vec![ACK], vec![ACK]]),
+            (0x43, vec![0x00, 0x01], vec![vec![NACK]]),
+        ]);
+
+        let data: Vec<u8> = (0..10).collect();
+        bl.write_memory(0x2000_0000, &data).unwrap();
+        assert_eq!(device.borrow().received.len(), 3);
+
+        assert!(matches!(
+            bl.erase_pages(&[0, 1]),
+            Err(Error::Nack(command::ERASE))
+        ));
+        assert!(matches!(
+            bl.write_memory(0x2000_0000, &[]),
+            Err(Error::InvalidArgument)
+        ));
+    }
+}
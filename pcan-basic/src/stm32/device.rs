@@ -0,0 +1,524 @@
+//! Simulated STM32 system bootloader to test flashing tools without a board.
+//!
+//! [`Device`] is a [`Node`] of a simulated [`Bus`](crate::sim::Bus) speaking
+//! the AN3154 CAN protocol. It keeps the flash contents in memory, tracks
+//! readout and write protection and can be told to reject or ignore commands.
+//! Like real flash, programming only clears bits, so data written without an
+//! erase reads back wrong.
+//!
+//! ```
+//! use pcan_basic::{sim::Bus, stm32::{device::Device, flash::Layout, Bootloader}};
+//!
+//! let bus = Bus::new();
+//! let device = bus.add_node(Device::new(Layout::uniform(0x0800_0000, 0x800, 64)));
+//!
+//! let mut bl = Bootloader::new(bus.endpoint());
+//! bl.sync().unwrap();
+//! bl.erase_pages(&[0]).unwrap();
+//! bl.write_memory(0x0800_0000, &[1, 2, 3, 4]).unwrap();
+//! assert_eq!(device.borrow().read(0x0800_0000, 4), Some(&[1, 2, 3, 4][..]));
+//! ```
+
+use std::convert::TryInto;
+
+use embedded_can::Frame as _;
+
+use super::{command, flash::Layout, frame, ACK, DATA_ID, NACK};
+use crate::{
+    sim::{Context, Node},
+    Frame, Id,
+};
+
+/// Commands announced by Get, in the order of the reference manual.
+const COMMANDS: [u8; 11] = [
+    command::GET,
+    command::GET_VERSION,
+    command::GET_ID,
+    command::READ_MEMORY,
+    command::GO,
+    command::WRITE_MEMORY,
+    command::ERASE,
+    command::WRITE_PROTECT,
+    command::WRITE_UNPROTECT,
+    command::READOUT_PROTECT,
+    command::READOUT_UNPROTECT,
+];
+
+/// Misbehavior injected into the handling of a command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Rejects the command.
+    Nack,
+    /// Ignores the command, so the host times out.
+    Drop,
+    /// Acknowledges Write Memory but programs the first byte inverted.
+    Corrupt,
+}
+
+struct Rule {
+    command: u8,
+    /// Matching commands to let through before the fault applies.
+    skip: usize,
+    fault: Fault,
+    once: bool,
+}
+
+struct Write {
+    address: u32,
+    len: usize,
+    data: Vec<u8>,
+    corrupt: bool,
+}
+
+pub struct Device {
+    layout: Layout,
+    flash: Vec<u8>,
+    id: u16,
+    version: u8,
+    synced: bool,
+    readout_protected: bool,
+    write_protected: Vec<u8>,
+    rules: Vec<Rule>,
+    write: Option<Write>,
+    commands: Vec<u8>,
+    resets: usize,
+    started: Option<u32>,
+}
+
+impl Device {
+    /// Erased flash with the given layout, without protection.
+    pub fn new(layout: Layout) -> Self {
+        let flash = vec![0xFF; layout.size() as usize];
+        Self {
+            layout,
+            flash,
+            id: 0x413,
+            version: 0x20,
+            synced: false,
+            readout_protected: false,
+            write_protected: Vec::new(),
+            rules: Vec::new(),
+            write: None,
+            commands: Vec::new(),
+            resets: 0,
+            started: None,
+        }
+    }
+
+    /// Product ID returned by Get ID, 0x413 (STM32F405) by default.
+    pub fn with_id(&mut self, id: u16) -> &mut Self {
+        self.id = id;
+        self
+    }
+
+    /// Protocol version returned by Get and Get Version, 0x20 by default.
+    pub fn with_version(&mut self, version: u8) -> &mut Self {
+        self.version = version;
+        self
+    }
+
+    pub fn with_readout_protection(&mut self, protected: bool) -> &mut Self {
+        self.readout_protected = protected;
+        self
+    }
+
+    /// Write protects the given pages.
+    pub fn with_write_protection(&mut self, pages: &[u8]) -> &mut Self {
+        self.write_protected = pages.to_vec();
+        self
+    }
+
+    /// Applies `fault` to every following `command`.
+    pub fn with_fault(&mut self, command: u8, fault: Fault) -> &mut Self {
+        self.rules.push(Rule {
+            command,
+            skip: 0,
+            fault,
+            once: false,
+        });
+        self
+    }
+
+    /// Applies `fault` once, to the `command` after `skip` others of its kind.
+    pub fn with_fault_once(&mut self, command: u8, skip: usize, fault: Fault) -> &mut Self {
+        self.rules.push(Rule {
+            command,
+            skip,
+            fault,
+            once: true,
+        });
+        self
+    }
+
+    /// Writes data directly to flash, as if programmed before.
+    pub fn with_flash(&mut self, address: u32, data: &[u8]) -> &mut Self {
+        let offset = self.offset(address, data.len()).expect("outside of flash");
+        self.flash[offset..offset + data.len()].copy_from_slice(data);
+        self
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// The whole flash memory.
+    pub fn flash(&self) -> &[u8] {
+        &self.flash
+    }
+
+    /// Returns `None` if the range is not completely in flash.
+    pub fn read(&self, address: u32, len: usize) -> Option<&[u8]> {
+        let offset = self.offset(address, len)?;
+        Some(&self.flash[offset..offset + len])
+    }
+
+    pub fn is_readout_protected(&self) -> bool {
+        self.readout_protected
+    }
+
+    pub fn write_protected(&self) -> &[u8] {
+        &self.write_protected
+    }
+
+    /// Commands received, excluding data frames of Write Memory.
+    pub fn commands(&self) -> &[u8] {
+        &self.commands
+    }
+
+    /// How often the device reset after changing protection.
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    /// Address passed to the last Go command.
+    pub fn started(&self) -> Option<u32> {
+        self.started
+    }
+
+    fn offset(&self, address: u32, len: usize) -> Option<usize> {
+        let offset = address.checked_sub(self.layout.base())? as usize;
+        if offset + len <= self.flash.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    fn is_writable(&self, address: u32, len: usize) -> bool {
+        let last = address + len as u32 - 1;
+        match (self.layout.page_of(address), self.layout.page_of(last)) {
+            (Some(first), Some(last)) => {
+                (first..=last).all(|page| !self.write_protected.iter().any(|&p| p as usize == page))
+            }
+            _ => false,
+        }
+    }
+
+    fn fault(&mut self, command: u8) -> Option<Fault> {
+        let index = self.rules.iter_mut().position(|rule| {
+            if rule.command != command {
+                return false;
+            }
+            if rule.skip > 0 {
+                rule.skip -= 1;
+                return false;
+            }
+            true
+        })?;
+        let fault = self.rules[index].fault;
+        if self.rules[index].once {
+            self.rules.remove(index);
+        }
+        Some(fault)
+    }
+
+    fn reset(&mut self) {
+        self.synced = false;
+        self.resets += 1;
+    }
+
+    fn erase_page(&mut self, page: usize) {
+        let (address, size) = self.layout.page(page).unwrap();
+        let offset = (address - self.layout.base()) as usize;
+        self.flash[offset..offset + size as usize].fill(0xFF);
+    }
+
+    fn handle_data(&mut self, data: &[u8], cx: &mut Context<'_>) {
+        let mut write = match self.write.take() {
+            Some(write) => write,
+            None => return,
+        };
+        if write.data.len() + data.len() > write.len {
+            cx.send(frame(command::WRITE_MEMORY as u16, &[NACK]));
+            return;
+        }
+        write.data.extend_from_slice(data);
+        cx.send(frame(command::WRITE_MEMORY as u16, &[ACK]));
+        if write.data.len() < write.len {
+            self.write = Some(write);
+            return;
+        }
+
+        if write.corrupt {
+            write.data[0] = !write.data[0];
+        }
+        let offset = self.offset(write.address, write.len).unwrap();
+        for (cell, byte) in self.flash[offset..].iter_mut().zip(write.data) {
+            *cell &= byte;
+        }
+        cx.send(frame(command::WRITE_MEMORY as u16, &[ACK]));
+    }
+
+    fn handle_command(&mut self, command: u8, data: &[u8], cx: &mut Context<'_>) {
+        let id = command as u16;
+        let ack = |cx: &mut Context<'_>| cx.send(frame(id, &[ACK]));
+        let nack = |cx: &mut Context<'_>| cx.send(frame(id, &[NACK]));
+
+        let corrupt = match self.fault(command) {
+            Some(Fault::Nack) => return nack(cx),
+            Some(Fault::Drop) => return,
+            Some(Fault::Corrupt) => true,
+            None => false,
+        };
+
+        match command {
+            command::SYNC => ack(cx),
+            command::GET => {
+                ack(cx);
+                cx.send(frame(id, &[COMMANDS.len() as u8]));
+                cx.send(frame(id, &[self.version]));
+                for &c in &COMMANDS {
+                    cx.send(frame(id, &[c]));
+                }
+                ack(cx);
+            }
+            command::GET_VERSION => {
+                ack(cx);
+                cx.send(frame(id, &[self.version, 0x00, 0x00]));
+                ack(cx);
+            }
+            command::GET_ID => {
+                ack(cx);
+                cx.send(frame(id, &self.id.to_be_bytes()));
+                ack(cx);
+            }
+            command::READ_MEMORY => {
+                let (address, len) = match address_and_len(data) {
+                    Some(request) if !self.readout_protected => request,
+                    _ => return nack(cx),
+                };
+                let data = match self.read(address, len) {
+                    Some(data) => data.to_vec(),
+                    None => return nack(cx),
+                };
+                ack(cx);
+                for chunk in data.chunks(8) {
+                    cx.send(frame(id, chunk));
+                }
+                ack(cx);
+            }
+            command::GO => {
+                let address = match data.try_into() {
+                    Ok(address) if !self.readout_protected => u32::from_be_bytes(address),
+                    _ => return nack(cx),
+                };
+                ack(cx);
+                self.started = Some(address);
+                self.synced = false;
+            }
+            command::WRITE_MEMORY => {
+                let (address, len) = match address_and_len(data) {
+                    Some(request) if !self.readout_protected => request,
+                    _ => return nack(cx),
+                };
+                if self.offset(address, len).is_none() || !self.is_writable(address, len) {
+                    return nack(cx);
+                }
+                ack(cx);
+                self.write = Some(Write {
+                    address,
+                    len,
+                    data: Vec::with_capacity(len),
+                    corrupt,
+                });
+            }
+            command::ERASE => {
+                if self.readout_protected || data.is_empty() {
+                    return nack(cx);
+                }
+                if data == [0xFF] {
+                    if !self.write_protected.is_empty() {
+                        return nack(cx);
+                    }
+                    ack(cx);
+                    self.flash.fill(0xFF);
+                    return ack(cx);
+                }
+                let valid = data.iter().all(|&page| {
+                    (page as usize) < self.layout.page_count()
+                        && !self.write_protected.contains(&page)
+                });
+                if !valid {
+                    return nack(cx);
+                }
+                ack(cx);
+                for &page in data {
+                    self.erase_page(page as usize);
+                }
+                ack(cx);
+            }
+            command::WRITE_PROTECT => {
+                if self.readout_protected
+                    || data.iter().any(|&p| p as usize >= self.layout.page_count())
+                {
+                    return nack(cx);
+                }
+                ack(cx);
+                for &page in data {
+                    if !self.write_protected.contains(&page) {
+                        self.write_protected.push(page);
+                    }
+                }
+                ack(cx);
+                self.reset();
+            }
+            command::WRITE_UNPROTECT => {
+                if self.readout_protected {
+                    return nack(cx);
+                }
+                ack(cx);
+                self.write_protected.clear();
+                ack(cx);
+                self.reset();
+            }
+            command::READOUT_PROTECT => {
+                ack(cx);
+                self.readout_protected = true;
+                ack(cx);
+                self.reset();
+            }
+            command::READOUT_UNPROTECT => {
+                ack(cx);
+                self.flash.fill(0xFF);
+                self.readout_protected = false;
+                ack(cx);
+                self.reset();
+            }
+            _ => nack(cx),
+        }
+    }
+}
+
+impl Node for Device {
+    fn on_frame(&mut self, frame: &Frame, cx: &mut Context<'_>) {
+        let id = match frame.id() {
+            Id::Standard(id) => id.as_raw(),
+            Id::Extended(_) => return,
+        };
+
+        // Until synchronized the bootloader listens on all of its interfaces.
+        if !self.synced {
+            if id == command::SYNC as u16 {
+                self.synced = true;
+                self.commands.push(command::SYNC);
+                cx.send(super::frame(id, &[ACK]));
+            }
+            return;
+        }
+
+        if id == DATA_ID {
+            return self.handle_data(frame.data(), cx);
+        }
+        // A new command aborts an incomplete write.
+        self.write = None;
+        if let Ok(command) = id.try_into() {
+            self.commands.push(command);
+            self.handle_command(command, frame.data(), cx);
+        }
+    }
+}
+
+fn address_and_len(data: &[u8]) -> Option<(u32, usize)> {
+    match *data {
+        [a, b, c, d, n] => Some((u32::from_be_bytes([a, b, c, d]), n as usize + 1)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        image::Image,
+        sim::Bus,
+        stm32::{flash::Flasher, Bootloader, Error},
+    };
+
+    #[test]
+    fn protection() {
+        let bus = Bus::new();
+        let mut device = Device::new(Layout::uniform(0x0800_0000, 0x400, 16));
+        device
+            .with_flash(0x0800_0000, &[0x12; 4])
+            .with_write_protection(&[1]);
+        let device = bus.add_node(device);
+        let mut bl = Bootloader::new(bus.endpoint());
+
+        bl.sync().unwrap();
+        assert_eq!(bl.get_id().unwrap(), 0x413);
+        assert_eq!(bl.get().unwrap().commands, COMMANDS);
+        assert!(matches!(
+            bl.erase_pages(&[0, 1]),
+            Err(Error::Nack(command::ERASE))
+        ));
+        assert!(matches!(bl.mass_erase(), Err(Error::Nack(command::ERASE))));
+
+        bl.write_unprotect().unwrap();
+        bl.sync().unwrap();
+        bl.readout_protect().unwrap();
+        assert_eq!(device.borrow().resets(), 2);
+        bl.sync().unwrap();
+        let mut buf = [0; 4];
+        assert!(matches!(
+            bl.read_memory(0x0800_0000, &mut buf),
+            Err(Error::Nack(command::READ_MEMORY))
+        ));
+
+        // Removing readout protection erases the flash.
+        bl.readout_unprotect().unwrap();
+        bl.sync().unwrap();
+        bl.read_memory(0x0800_0000, &mut buf).unwrap();
+        assert_eq!(buf, [0xFF; 4]);
+        assert!(!device.borrow().is_readout_protected());
+    }
+
+    /// The sequence of the former `stm32-fwupdate` example, on a simulated bus
+    /// instead of a PCAN channel.
+    #[test]
+    fn firmware_update() {
+        const FLASH_BASE: u32 = 0x0800_0000;
+        // Sectors of the STM32F4 series with 1 MiB of flash.
+        let layout = Layout::new(FLASH_BASE, &[(4, 0x4000), (1, 0x1_0000), (7, 0x2_0000)]);
+        let bus = Bus::new();
+        let mut device = Device::new(layout.clone());
+        device.with_fault_once(command::WRITE_MEMORY, 3, Fault::Drop);
+        let device = bus.add_node(device);
+        let image = Image::from_binary(FLASH_BASE, &(0..2000).map(|i| i as u8).collect::<Vec<_>>());
+
+        let mut bl = Bootloader::new(bus.endpoint());
+        bl.sync().unwrap();
+        let mut flasher = Flasher::new(layout);
+        // The dropped block fails the first attempt, which then resumes.
+        assert!(flasher.flash(&mut bl, &image).is_err());
+        bl.sync().ok();
+        flasher.flash(&mut bl, &image).unwrap();
+        bl.go(image.segments()[0].address).unwrap();
+
+        let device = device.borrow();
+        assert_eq!(
+            device.read(FLASH_BASE, 2000),
+            Some(&image.segments()[0].data[..])
+        );
+        assert_eq!(device.started(), Some(FLASH_BASE));
+    }
+}
@@ -0,0 +1,579 @@
+//! Verified and resumable flashing of an [`Image`] with the STM32 bootloader.
+//!
+//! Only the flash pages touched by the image are erased. Every block is
+//! written with Write Memory and then checked with Read Memory, either right
+//! away or as a CRC-32 over the whole segment.
+
+use std::{convert::TryFrom, fmt};
+
+use super::{Bootloader, Error as BootloaderError, MAX_BLOCK_LEN};
+use crate::image::{crc32, Image, Segment};
+
+/// Flash memory organization of a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    base: u32,
+    pages: Vec<u32>,
+}
+
+impl Layout {
+    /// Pages of the given sizes starting at `base`, given as `(count, size)` pairs.
+    ///
+    /// The first four sectors of an STM32F405 are `(4, 0x4000)`, followed by
+    /// `(1, 0x1_0000)` and `(7, 0x2_0000)`.
+    pub fn new(base: u32, pages: &[(usize, u32)]) -> Self {
+        let pages = pages
+            .iter()
+            .flat_map(|&(count, size)| std::iter::repeat_n(size, count))
+            .collect();
+        Self { base, pages }
+    }
+
+    /// `count` pages of `size` bytes starting at `base`.
+    pub fn uniform(base: u32, size: u32, count: usize) -> Self {
+        Self::new(base, &[(count, size)])
+    }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    /// Total flash size in bytes.
+    pub fn size(&self) -> u64 {
+        self.pages.iter().map(|&s| s as u64).sum()
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Start address and size of a page.
+    pub fn page(&self, index: usize) -> Option<(u32, u32)> {
+        let size = *self.pages.get(index)?;
+        let offset: u64 = self.pages[..index].iter().map(|&s| s as u64).sum();
+        Some((self.base + offset as u32, size))
+    }
+
+    /// Index of the page containing `address`.
+    pub fn page_of(&self, address: u32) -> Option<usize> {
+        let mut offset = address.checked_sub(self.base)? as u64;
+        for (index, &size) in self.pages.iter().enumerate() {
+            if offset < size as u64 {
+                return Some(index);
+            }
+            offset -= size as u64;
+        }
+        None
+    }
+
+    /// Sorted indices of all pages touched by `segments`.
+    ///
+    /// Fails with the first address outside of flash.
+    pub fn pages_touched<'a>(
+        &self,
+        segments: impl IntoIterator<Item = &'a Segment>,
+    ) -> Result<Vec<usize>, u32> {
+        let mut pages = Vec::new();
+        for segment in segments {
+            if segment.data.is_empty() {
+                continue;
+            }
+            let last = (segment.end() - 1) as u32;
+            let first = self.page_of(segment.address).ok_or(segment.address)?;
+            let last = self.page_of(last).ok_or(last)?;
+            pages.extend(first..=last);
+        }
+        pages.sort_unstable();
+        pages.dedup();
+        Ok(pages)
+    }
+}
+
+/// What is erased before writing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Erase {
+    /// The pages touched by the image.
+    Pages,
+    /// The whole flash.
+    Mass,
+    /// Nothing, the flash has been erased before.
+    None,
+}
+
+/// How written data is checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verify {
+    None,
+    /// Reads back every block right after writing it.
+    ReadBack,
+    /// Reads back a segment after writing it and compares the CRC-32.
+    Crc,
+}
+
+/// Position of the next block to write.
+///
+/// Only meaningful for the image it was taken from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Index of the segment.
+    pub segment: usize,
+    /// Bytes of the segment written already.
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub segment: usize,
+    pub segments: usize,
+    /// Bytes written, from all segments.
+    pub written: usize,
+    pub total: usize,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Bootloader(BootloaderError<E>),
+    /// The image has data at an address outside of flash.
+    OutsideFlash(u32),
+    /// The bootloader addresses pages with a single byte, 0xFF is reserved.
+    PageNumber(usize),
+    /// Read back data differs from the image, starting at this address.
+    Mismatch(u32),
+    /// The CRC-32 of a segment read back differs from the image.
+    Crc {
+        address: u32,
+        expected: u32,
+        actual: u32,
+    },
+    /// The checkpoint to resume from is not within the image.
+    Checkpoint(Checkpoint),
+}
+
+impl<E> From<BootloaderError<E>> for Error<E> {
+    fn from(err: BootloaderError<E>) -> Self {
+        Error::Bootloader(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bootloader(err) => err.fmt(f),
+            Error::OutsideFlash(address) => {
+                write!(f, "Address {:#010x} is outside of flash", address)
+            }
+            Error::PageNumber(page) => write!(f, "Page {} cannot be erased", page),
+            Error::Mismatch(address) => write!(f, "Verification failed at {:#010x}", address),
+            Error::Crc {
+                address,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "CRC of segment at {:#010x} is {:#010x}, expected {:#010x}",
+                address, actual, expected
+            ),
+            Error::Checkpoint(checkpoint) => write!(
+                f,
+                "Segment {} offset {:#x} is not within the image",
+                checkpoint.segment, checkpoint.offset
+            ),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Error<E> {}
+
+type ProgressCallback = Box<dyn FnMut(&Progress)>;
+
+pub struct Flasher {
+    layout: Layout,
+    erase: Erase,
+    verify: Verify,
+    checkpoint: Checkpoint,
+    /// Pages to erase again before writing continues at the checkpoint.
+    damaged: Vec<usize>,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl Flasher {
+    pub fn new(layout: Layout) -> Self {
+        Self {
+            layout,
+            erase: Erase::Pages,
+            verify: Verify::ReadBack,
+            checkpoint: Checkpoint::default(),
+            damaged: Vec::new(),
+            on_progress: None,
+        }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// What is erased before writing, [`Erase::Pages`] by default.
+    pub fn with_erase(&mut self, erase: Erase) -> &mut Self {
+        self.erase = erase;
+        self
+    }
+
+    /// How written data is checked, [`Verify::ReadBack`] by default.
+    pub fn with_verify(&mut self, verify: Verify) -> &mut Self {
+        self.verify = verify;
+        self
+    }
+
+    pub fn on_progress(&mut self, f: impl FnMut(&Progress) + 'static) -> &mut Self {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Continues writing at `checkpoint` with the next call to [`Flasher::flash()`].
+    pub fn resume_from(&mut self, checkpoint: Checkpoint) -> &mut Self {
+        self.checkpoint = checkpoint;
+        self.damaged.clear();
+        self
+    }
+
+    /// Where an interrupted write continues.
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.checkpoint
+    }
+
+    /// Erases the flash as configured and writes `image`.
+    ///
+    /// If writing fails, calling this again with the same image erases the
+    /// pages of the failed block or segment again and continues at the start
+    /// of the first of them. Flash cannot be programmed twice without an erase.
+    /// The bootloader might need a [`Bootloader::sync()`] after a device reset.
+    pub fn flash<C>(
+        &mut self,
+        bootloader: &mut Bootloader<C>,
+        image: &Image,
+    ) -> Result<(), Error<C::Error>>
+    where
+        C: embedded_can::blocking::Can,
+    {
+        let segments = image.segments();
+        let total = image.len();
+
+        let pages = self
+            .layout
+            .pages_touched(segments)
+            .map_err(Error::OutsideFlash)?;
+        let Checkpoint { segment, offset } = self.checkpoint;
+        let within = match segments.get(segment) {
+            Some(s) => offset as usize <= s.data.len(),
+            None => segment == segments.len() && offset == 0,
+        };
+        if !within {
+            return Err(Error::Checkpoint(self.checkpoint));
+        }
+
+        if !self.damaged.is_empty() {
+            bootloader.erase_pages(&page_numbers(&self.damaged)?)?;
+            self.damaged.clear();
+        } else if self.checkpoint == Checkpoint::default() {
+            match self.erase {
+                Erase::Pages => bootloader.erase_pages(&page_numbers(&pages)?)?,
+                Erase::Mass => bootloader.mass_erase()?,
+                Erase::None => {}
+            }
+        }
+
+        let mut written: usize = segments[..self.checkpoint.segment.min(segments.len())]
+            .iter()
+            .map(|s| s.data.len())
+            .sum::<usize>()
+            + self.checkpoint.offset as usize;
+
+        for (index, segment) in segments.iter().enumerate().skip(self.checkpoint.segment) {
+            let mut offset = self.checkpoint.offset as usize;
+            for block in segment.data[offset..].chunks(MAX_BLOCK_LEN) {
+                let address = segment.address + offset as u32;
+                let result = match bootloader.write_memory(address, block) {
+                    Ok(()) if self.verify == Verify::ReadBack => {
+                        verify_block(bootloader, address, block)
+                    }
+                    result => result.map_err(Error::from),
+                };
+                if let Err(err) = result {
+                    // The block may be programmed partially or wrong.
+                    self.rewind(segments, address, block.len());
+                    return Err(err);
+                }
+
+                offset += block.len();
+                written += block.len();
+                self.checkpoint = Checkpoint {
+                    segment: index,
+                    offset: offset as u32,
+                };
+                if let Some(on_progress) = &mut self.on_progress {
+                    on_progress(&Progress {
+                        segment: index,
+                        segments: segments.len(),
+                        written,
+                        total,
+                    });
+                }
+            }
+
+            if self.verify == Verify::Crc {
+                if let Err(err) = verify_crc(bootloader, segment) {
+                    self.rewind(segments, segment.address, segment.data.len());
+                    return Err(err);
+                }
+            }
+            self.checkpoint = Checkpoint {
+                segment: index + 1,
+                offset: 0,
+            };
+        }
+
+        self.checkpoint = Checkpoint::default();
+        Ok(())
+    }
+
+    /// Marks the pages of `len` bytes at `address` for erasing and moves the
+    /// checkpoint back to the first data in them.
+    fn rewind(&mut self, segments: &[Segment], address: u32, len: usize) {
+        // The image was checked to be inside the flash.
+        let first = self.layout.page_of(address).unwrap();
+        let last = self.layout.page_of(address + len as u32 - 1).unwrap();
+        let (start, _) = self.layout.page(first).unwrap();
+        self.damaged = (first..=last).collect();
+        self.checkpoint = segments
+            .iter()
+            .position(|s| s.end() > start as u64)
+            .map(|index| Checkpoint {
+                segment: index,
+                offset: start.saturating_sub(segments[index].address),
+            })
+            .unwrap();
+    }
+}
+
+/// Page numbers for the bootloader, which reserves 0xFF.
+fn page_numbers<E>(pages: &[usize]) -> Result<Vec<u8>, Error<E>> {
+    pages
+        .iter()
+        .map(|&page| match u8::try_from(page) {
+            Ok(page) if page != 0xFF => Ok(page),
+            _ => Err(Error::PageNumber(page)),
+        })
+        .collect()
+}
+
+fn verify_block<C>(
+    bootloader: &mut Bootloader<C>,
+    address: u32,
+    expected: &[u8],
+) -> Result<(), Error<C::Error>>
+where
+    C: embedded_can::blocking::Can,
+{
+    let mut buf = [0; MAX_BLOCK_LEN];
+    let actual = &mut buf[..expected.len()];
+    bootloader.read_memory(address, actual)?;
+    match actual.iter().zip(expected).position(|(a, e)| a != e) {
+        Some(i) => Err(Error::Mismatch(address + i as u32)),
+        None => Ok(()),
+    }
+}
+
+fn verify_crc<C>(bootloader: &mut Bootloader<C>, segment: &Segment) -> Result<(), Error<C::Error>>
+where
+    C: embedded_can::blocking::Can,
+{
+    let mut data = vec![0; segment.data.len()];
+    for (i, block) in data.chunks_mut(MAX_BLOCK_LEN).enumerate() {
+        bootloader.read_memory(segment.address + (i * MAX_BLOCK_LEN) as u32, block)?;
+    }
+    let expected = crc32(&segment.data);
+    let actual = crc32(&data);
+    if actual != expected {
+        return Err(Error::Crc {
+            address: segment.address,
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sim::{self, Bus},
+        stm32::{
+            command,
+            device::{Device, Fault},
+        },
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    fn setup(
+        configure: impl FnOnce(&mut Device),
+    ) -> (Bootloader<sim::Endpoint>, Rc<RefCell<Device>>) {
+        let bus = Bus::new();
+        let mut device = Device::new(layout());
+        configure(&mut device);
+        let device = bus.add_node(device);
+        let mut bootloader = Bootloader::new(bus.endpoint());
+        bootloader.sync().unwrap();
+        (bootloader, device)
+    }
+
+    fn layout() -> Layout {
+        Layout::uniform(0x0800_0000, 0x800, 64)
+    }
+
+    fn image() -> Image {
+        let mut image = Image::from_binary(0x0800_0000, &[0x5A; 1000]);
+        image.write(0x0800_1000, &(0..=255).collect::<Vec<u8>>());
+        image
+    }
+
+    #[test]
+    fn flash_touched_pages() {
+        let (mut bl, device) = setup(|device| {
+            device.with_flash(0x0800_0800, &[0; 16]);
+        });
+
+        let progress = Rc::new(RefCell::new(Vec::new()));
+        let reported = progress.clone();
+        let mut flasher = Flasher::new(layout());
+        flasher.on_progress(move |p| reported.borrow_mut().push(*p));
+        flasher.flash(&mut bl, &image()).unwrap();
+
+        let device = device.borrow();
+        assert_eq!(device.read(0x0800_0000, 1000), Some(&[0x5A; 1000][..]));
+        assert_eq!(device.read(0x0800_1000, 256).unwrap()[255], 255);
+        // Page 1 is not part of the image and keeps its contents.
+        assert_eq!(device.read(0x0800_0800, 16), Some(&[0; 16][..]));
+
+        let progress = progress.borrow();
+        assert_eq!(progress.len(), 5);
+        assert_eq!(progress[4].written, 1256);
+        assert_eq!(progress[4].total, 1256);
+    }
+
+    #[test]
+    fn resume_after_failed_block() {
+        let (mut bl, device) = setup(|device| {
+            device.with_fault_once(command::WRITE_MEMORY, 2, Fault::Drop);
+        });
+
+        let mut flasher = Flasher::new(layout());
+        assert!(matches!(
+            flasher.flash(&mut bl, &image()),
+            Err(Error::Bootloader(BootloaderError::Can(sim::Error::Idle)))
+        ));
+        // The dropped block might have been programmed partially, the
+        // page is erased again and written from its start.
+        assert_eq!(flasher.checkpoint(), Checkpoint::default());
+
+        flasher.flash(&mut bl, &image()).unwrap();
+        assert_eq!(flasher.checkpoint(), Checkpoint::default());
+
+        let device = device.borrow();
+        assert_eq!(device.read(0x0800_0000, 1000), Some(&[0x5A; 1000][..]));
+        let erases = device
+            .commands()
+            .iter()
+            .filter(|&&c| c == command::ERASE)
+            .count();
+        // Pages 0 and 2 are erased with one command, page 0 again when resuming.
+        assert_eq!(erases, 2);
+    }
+
+    #[test]
+    fn resume_after_corruption() {
+        let (mut bl, device) = setup(|device| {
+            device.with_fault_once(command::WRITE_MEMORY, 4, Fault::Corrupt);
+        });
+        let mut flasher = Flasher::new(layout());
+        assert!(matches!(
+            flasher.flash(&mut bl, &image()),
+            Err(Error::Mismatch(0x0800_1000))
+        ));
+        assert_eq!(
+            flasher.checkpoint(),
+            Checkpoint {
+                segment: 1,
+                offset: 0
+            }
+        );
+        flasher.flash(&mut bl, &image()).unwrap();
+        assert_eq!(
+            device.borrow().read(0x0800_1000, 256),
+            Some(&(0..=255).collect::<Vec<u8>>()[..])
+        );
+
+        let (mut bl, device) = setup(|device| {
+            device.with_fault_once(command::WRITE_MEMORY, 4, Fault::Corrupt);
+        });
+        let mut flasher = Flasher::new(layout());
+        flasher.with_verify(Verify::Crc);
+        assert!(flasher.flash(&mut bl, &image()).is_err());
+        flasher.flash(&mut bl, &image()).unwrap();
+        assert_eq!(
+            device.borrow().read(0x0800_1000, 256),
+            Some(&(0..=255).collect::<Vec<u8>>()[..])
+        );
+
+        let mut flasher = Flasher::new(layout());
+        flasher.resume_from(Checkpoint {
+            segment: 0,
+            offset: 1001,
+        });
+        assert!(matches!(
+            flasher.flash(&mut bl, &image()),
+            Err(Error::Checkpoint(_))
+        ));
+    }
+
+    #[test]
+    fn detect_corruption() {
+        let (mut bl, _) = setup(|device| {
+            device.with_fault_once(command::WRITE_MEMORY, 1, Fault::Corrupt);
+        });
+        let mut flasher = Flasher::new(layout());
+        assert!(matches!(
+            flasher.flash(&mut bl, &image()),
+            Err(Error::Mismatch(0x0800_0100))
+        ));
+
+        let (mut bl, _) = setup(|device| {
+            device.with_fault_once(command::WRITE_MEMORY, 4, Fault::Corrupt);
+        });
+        let mut flasher = Flasher::new(layout());
+        flasher.with_verify(Verify::Crc);
+        assert!(matches!(
+            flasher.flash(&mut bl, &image()),
+            Err(Error::Crc {
+                address: 0x0800_1000,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn pages_touched() {
+        let layout = Layout::new(0x0800_0000, &[(4, 0x4000), (1, 0x1_0000), (7, 0x2_0000)]);
+        assert_eq!(layout.size(), 0x10_0000);
+        assert_eq!(layout.page(5), Some((0x0802_0000, 0x2_0000)));
+        assert_eq!(layout.page_of(0x0801_FFFF), Some(4));
+        assert_eq!(layout.page_of(0x0810_0000), None);
+
+        let mut image = Image::from_binary(0x0800_3FF0, &[0; 0x20]);
+        image.write(0x0802_0000, &[0; 4]);
+        assert_eq!(layout.pages_touched(image.segments()), Ok(vec![0, 1, 5]));
+
+        image.write(0x2000_0000, &[0; 4]);
+        assert_eq!(layout.pages_touched(image.segments()), Err(0x2000_0000));
+    }
+}
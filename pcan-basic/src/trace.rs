@@ -0,0 +1,237 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use pcan_basic_sys::*;
+
+use crate::{get_path, get_value, set_path, set_value, Error, Interface};
+
+/// Configuration of the trace files written by the PCAN driver.
+///
+/// The driver records all traffic of a channel in the PCAN-View `.trc` format.
+/// Apply the configuration with [`Interface::start_trace()`].
+#[derive(Debug, Clone, Default)]
+pub struct TraceConfig {
+    location: Option<PathBuf>,
+    max_size: u32,
+    segmented: bool,
+    date: bool,
+    time: bool,
+    overwrite: bool,
+}
+
+impl TraceConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Directory for the trace files. Defaults to the directory of the calling process.
+    pub fn with_location(&mut self, location: impl Into<PathBuf>) -> &mut Self {
+        self.location = Some(location.into());
+        self
+    }
+
+    /// Maximum size of a trace file in megabytes, clamped to 1 to 100.
+    /// When reached, tracing stops unless the trace is segmented.
+    pub fn with_max_size(&mut self, megabytes: u32) -> &mut Self {
+        self.max_size = megabytes.clamp(1, 100);
+        self
+    }
+
+    /// Continue in a new file when the maximum size is reached.
+    pub fn segmented(&mut self, segmented: bool) -> &mut Self {
+        self.segmented = segmented;
+        self
+    }
+
+    /// Include the date in the file name.
+    pub fn with_date(&mut self, date: bool) -> &mut Self {
+        self.date = date;
+        self
+    }
+
+    /// Include the start time in the file name.
+    pub fn with_time(&mut self, time: bool) -> &mut Self {
+        self.time = time;
+        self
+    }
+
+    /// Overwrite an existing trace with the same file name.
+    pub fn overwrite(&mut self, overwrite: bool) -> &mut Self {
+        self.overwrite = overwrite;
+        self
+    }
+
+    fn flags(&self) -> u32 {
+        let mut flags = TRACE_FILE_SINGLE;
+        if self.segmented {
+            flags |= TRACE_FILE_SEGMENTED;
+        }
+        if self.date {
+            flags |= TRACE_FILE_DATE;
+        }
+        if self.time {
+            flags |= TRACE_FILE_TIME;
+        }
+        if self.overwrite {
+            flags |= TRACE_FILE_OVERWRITE;
+        }
+        flags
+    }
+}
+
+impl Interface {
+    /// Starts writing a driver trace of this channel and returns the path of the trace file.
+    ///
+    /// A trace that is already running is stopped first because the driver only
+    /// accepts configuration changes while tracing is off.
+    pub fn start_trace(&mut self, config: &TraceConfig) -> Result<PathBuf, Error> {
+        self.stop_trace()?;
+
+        if let Some(location) = &config.location {
+            set_path(self.channel, PCAN_TRACE_LOCATION, location)?;
+        }
+        let mut max_size = config.max_size;
+        set_value(self.channel, PCAN_TRACE_SIZE, &mut max_size)?;
+        let mut flags = config.flags();
+        set_value(self.channel, PCAN_TRACE_CONFIGURE, &mut flags)?;
+
+        let mut parameter_on = PCAN_PARAMETER_ON;
+        set_value(self.channel, PCAN_TRACE_STATUS, &mut parameter_on)?;
+
+        let location = get_path(self.channel, PCAN_TRACE_LOCATION)?;
+        let channel = channel_name(self.channel)
+            .ok_or_else(|| Error(format!("Unknown channel {:#X}", self.channel)))?;
+        trace_file(&location, &channel, config).ok_or_else(|| {
+            Error(format!(
+                "No trace file of {} was created in {}",
+                channel,
+                location.display()
+            ))
+        })
+    }
+
+    pub fn stop_trace(&mut self) -> Result<(), Error> {
+        let mut parameter_off = PCAN_PARAMETER_OFF;
+        set_value(self.channel, PCAN_TRACE_STATUS, &mut parameter_off)
+    }
+
+    pub fn is_tracing(&self) -> Result<bool, Error> {
+        let mut status = PCAN_PARAMETER_OFF;
+        get_value(self.channel, PCAN_TRACE_STATUS, &mut status)?;
+        Ok(status == PCAN_PARAMETER_ON)
+    }
+
+    /// Directory the driver writes the trace files to.
+    pub fn trace_location(&self) -> Result<PathBuf, Error> {
+        get_path(self.channel, PCAN_TRACE_LOCATION)
+    }
+}
+
+/// Name of a channel handle as used in trace file names, e.g. `PCAN_USBBUS1`.
+fn channel_name(channel: u16) -> Option<String> {
+    let channel = channel as u32;
+    let (bus, number) = match channel {
+        PCAN_ISABUS1..=PCAN_ISABUS8 => ("ISA", channel - PCAN_ISABUS1 + 1),
+        PCAN_DNGBUS1 => ("DNG", 1),
+        PCAN_PCIBUS1..=PCAN_PCIBUS8 => ("PCI", channel - PCAN_PCIBUS1 + 1),
+        PCAN_PCIBUS9..=PCAN_PCIBUS16 => ("PCI", channel - PCAN_PCIBUS9 + 9),
+        PCAN_USBBUS1..=PCAN_USBBUS8 => ("USB", channel - PCAN_USBBUS1 + 1),
+        PCAN_USBBUS9..=PCAN_USBBUS16 => ("USB", channel - PCAN_USBBUS9 + 9),
+        PCAN_PCCBUS1..=PCAN_PCCBUS2 => ("PCC", channel - PCAN_PCCBUS1 + 1),
+        PCAN_LANBUS1..=PCAN_LANBUS16 => ("LAN", channel - PCAN_LANBUS1 + 1),
+        _ => return None,
+    };
+    Some(format!("PCAN_{}BUS{}", bus, number))
+}
+
+/// Finds the file the driver writes the trace of `channel` to.
+///
+/// The driver names trace files `[yyyyMMdd_][HHmmss_]<channel>[_<segment>].trc`,
+/// with the date, time and segment number depending on the configuration.
+/// Dates and times sort by name, so the newest file is the greatest one.
+fn trace_file(location: &Path, channel: &str, config: &TraceConfig) -> Option<PathBuf> {
+    fs::read_dir(location)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let (prefix, segment) = trace_file_key(&name, channel, config)?;
+            Some(((prefix.to_owned(), segment), entry.path()))
+        })
+        .max_by(|(a, _), (b, _)| a.cmp(b))
+        .map(|(_, path)| path)
+}
+
+/// Date and time prefix and segment number of a trace file name, if it is a
+/// trace of `channel` written with `config`.
+fn trace_file_key<'a>(
+    name: &'a str,
+    channel: &str,
+    config: &TraceConfig,
+) -> Option<(&'a str, u32)> {
+    // Skips `digits` digits followed by an underscore.
+    let number = |text: &'a str, digits: usize| {
+        let (number, rest) = (text.get(..digits)?, text.get(digits..)?);
+        if !number.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        rest.strip_prefix('_')
+    };
+
+    let stem = name.strip_suffix(".trc")?;
+    let mut rest = stem;
+    if config.date {
+        rest = number(rest, 8)?;
+    }
+    if config.time {
+        rest = number(rest, 6)?;
+    }
+    let prefix = &stem[..stem.len() - rest.len()];
+
+    let rest = rest.strip_prefix(channel)?;
+    let segment = match rest.strip_prefix('_') {
+        Some(segment) if config.segmented => segment.parse().ok()?,
+        None if rest.is_empty() => 0,
+        _ => return None,
+    };
+    Some((prefix, segment))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_file_names() {
+        assert_eq!(
+            channel_name(PCAN_USBBUS1 as u16).as_deref(),
+            Some("PCAN_USBBUS1")
+        );
+        assert_eq!(
+            channel_name(PCAN_PCIBUS10 as u16).as_deref(),
+            Some("PCAN_PCIBUS10")
+        );
+        assert_eq!(channel_name(PCAN_NONEBUS as u16), None);
+
+        let mut config = TraceConfig::new();
+        let key = |name, config: &TraceConfig| trace_file_key(name, "PCAN_USBBUS1", config);
+        assert_eq!(key("PCAN_USBBUS1.trc", &config), Some(("", 0)));
+        assert_eq!(key("PCAN_USBBUS10.trc", &config), None);
+        assert_eq!(key("PCAN_USBBUS1_2.trc", &config), None);
+        assert_eq!(key("PCAN_USBBUS1.asc", &config), None);
+
+        config.with_date(true).with_time(true).segmented(true);
+        assert_eq!(
+            key("20261018_154210_PCAN_USBBUS1_2.trc", &config),
+            Some(("20261018_154210_", 2))
+        );
+        assert_eq!(key("20261018_PCAN_USBBUS1_2.trc", &config), None);
+        assert_eq!(key("2026101x_154210_PCAN_USBBUS1_2.trc", &config), None);
+        assert_eq!(key("20261018_154210_PCAN_USBBUS1_x.trc", &config), None);
+
+        assert_eq!(config.with_max_size(0).max_size, 1);
+        assert_eq!(config.with_max_size(500).max_size, 100);
+    }
+}
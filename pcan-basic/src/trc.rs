@@ -0,0 +1,108 @@
+//! PEAK `.trc` trace files as written by PCAN-View and the PCAN driver.
+//!
+//! Files of version 1.0 up to 2.1 can be read. Status, error counter and
+//! event lines are skipped, everything else is mapped to a [`Record`](crate::Record).
+//! Versions 1.1, 2.0 and 2.1 can be written.
+
+mod reader;
+mod writer;
+
+pub use reader::Reader;
+pub use writer::{RotatingWriter, Timestamps, Writer};
+
+use std::{
+    fmt, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Version {
+    V1_0,
+    V1_1,
+    V1_2,
+    V1_3,
+    V2_0,
+    V2_1,
+}
+
+impl Version {
+    fn parse(s: &str) -> Option<Version> {
+        match s.trim() {
+            "1.0" => Some(Version::V1_0),
+            "1.1" => Some(Version::V1_1),
+            "1.2" => Some(Version::V1_2),
+            "1.3" => Some(Version::V1_3),
+            "2.0" => Some(Version::V2_0),
+            "2.1" => Some(Version::V2_1),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Version::V1_0 => "1.0",
+            Version::V1_1 => "1.1",
+            Version::V1_2 => "1.2",
+            Version::V1_3 => "1.3",
+            Version::V2_0 => "2.0",
+            Version::V2_1 => "2.1",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Information from the header of a trace file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub version: Version,
+    /// Absolute time of the first record, all record timestamps are relative to it.
+    pub start_time: Option<SystemTime>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// A line of the file could not be parsed.
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Parse { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+// `$STARTTIME` is an OLE automation date: fractional days since 1899-12-30.
+const OLE_DAYS_TO_UNIX_EPOCH: f64 = 25569.0;
+const SECONDS_PER_DAY: f64 = 86400.0;
+
+fn ole_date_to_system_time(days: f64) -> Option<SystemTime> {
+    let secs = (days - OLE_DAYS_TO_UNIX_EPOCH) * SECONDS_PER_DAY;
+    if !secs.is_finite() || secs < 0.0 || secs > u32::MAX as f64 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs_f64(secs))
+}
@@ -0,0 +1,657 @@
+use std::{io::BufRead, str::SplitWhitespace, time::Duration};
+
+use embedded_can::Frame as _;
+
+use super::{ole_date_to_system_time, Error, Metadata, Version};
+use crate::{
+    dlc_to_len, len_to_dlc, Direction, ErrorFrame, ExtendedId, FdFrame, Frame, Id, Message, Record,
+    StandardId,
+};
+
+/// Columns of a record line, named after the letters used by `$COLUMNS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Number,
+    Offset,
+    Type,
+    Bus,
+    Id,
+    Direction,
+    Reserved,
+    Dlc,
+    Length,
+    Data,
+}
+
+impl Column {
+    fn parse(s: &str) -> Option<Column> {
+        match s.trim() {
+            "N" => Some(Column::Number),
+            "O" => Some(Column::Offset),
+            "T" => Some(Column::Type),
+            "B" => Some(Column::Bus),
+            "I" => Some(Column::Id),
+            "d" => Some(Column::Direction),
+            "R" => Some(Column::Reserved),
+            "L" => Some(Column::Dlc),
+            "l" => Some(Column::Length),
+            "D" => Some(Column::Data),
+            _ => None,
+        }
+    }
+
+    /// Column layout of files without a `$COLUMNS` header.
+    fn defaults(version: Version) -> Vec<Column> {
+        use Column::*;
+
+        match version {
+            Version::V1_0 => vec![Number, Offset, Id, Dlc, Data],
+            Version::V1_1 => vec![Number, Offset, Type, Id, Dlc, Data],
+            Version::V1_2 => vec![Number, Offset, Bus, Type, Id, Dlc, Data],
+            Version::V1_3 => vec![Number, Offset, Bus, Type, Id, Reserved, Dlc, Data],
+            Version::V2_0 => vec![Number, Offset, Type, Id, Direction, Length, Data],
+            Version::V2_1 => vec![
+                Number, Offset, Type, Bus, Id, Direction, Reserved, Dlc, Data,
+            ],
+        }
+    }
+}
+
+/// Kind of a record line, from the type column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Data,
+    Remote,
+    Fd {
+        brs: bool,
+        esi: bool,
+    },
+    Error,
+    /// Status, error counter and event lines carry no frame.
+    Ignored,
+}
+
+/// Streaming reader for `.trc` files.
+///
+/// The header is parsed by [`Reader::new()`], records are read line by line
+/// while iterating. A malformed line yields an [`Error::Parse`] with its line
+/// number, iteration continues with the next line.
+pub struct Reader<R> {
+    reader: R,
+    metadata: Metadata,
+    columns: Vec<Column>,
+    line: String,
+    line_number: usize,
+    // The first record line is read together with the header.
+    pending: bool,
+    done: bool,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(reader: R) -> Result<Self, Error> {
+        let mut this = Self {
+            reader,
+            metadata: Metadata {
+                version: Version::V1_0,
+                start_time: None,
+            },
+            columns: Vec::new(),
+            line: String::new(),
+            line_number: 0,
+            pending: false,
+            done: false,
+        };
+
+        let mut columns = None;
+        while this.read_line()? {
+            let line = this.line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if !line.starts_with(';') {
+                this.pending = true;
+                break;
+            }
+
+            let directive = line.trim_start_matches(';').trim();
+            if let Some(value) = directive.strip_prefix("$FILEVERSION=") {
+                this.metadata.version = Version::parse(value).ok_or_else(|| Error::Parse {
+                    line: this.line_number,
+                    message: format!("unsupported file version {}", value),
+                })?;
+            } else if let Some(value) = directive.strip_prefix("$STARTTIME=") {
+                let days = value.trim().parse().map_err(|_| Error::Parse {
+                    line: this.line_number,
+                    message: format!("invalid start time {}", value),
+                })?;
+                this.metadata.start_time = ole_date_to_system_time(days);
+            } else if let Some(value) = directive.strip_prefix("$COLUMNS=") {
+                let parsed: Option<Vec<_>> = value.split(',').map(Column::parse).collect();
+                columns = Some(parsed.ok_or_else(|| Error::Parse {
+                    line: this.line_number,
+                    message: format!("invalid column list {}", value),
+                })?);
+            }
+        }
+
+        this.columns = columns.unwrap_or_else(|| Column::defaults(this.metadata.version));
+        Ok(this)
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    fn read_line(&mut self) -> Result<bool, Error> {
+        self.line.clear();
+        let n = self.reader.read_line(&mut self.line)?;
+        self.line_number += 1;
+        Ok(n > 0)
+    }
+
+    fn parse_record(&self) -> Result<Option<Record>, String> {
+        let version = self.metadata.version;
+        let mut tokens = self.line.split_whitespace();
+        let mut timestamp = Duration::default();
+        let mut channel = 1;
+        let mut direction = Direction::Rx;
+        let mut kind = Kind::Data;
+        let mut id = None;
+        let mut len = None;
+
+        for (i, column) in self.columns.iter().enumerate() {
+            if *column == Column::Data {
+                break;
+            }
+            if kind == Kind::Error && version >= Version::V2_0 && *column != Column::Bus {
+                // Error lines leave the identifier and length columns empty.
+                let rest = &self.columns[i..];
+                return parse_error_frame(tokens, rest, direction).map(|(direction, error)| {
+                    Some(Record {
+                        timestamp,
+                        channel,
+                        direction,
+                        message: Message::Error(error),
+                    })
+                });
+            }
+
+            let token = tokens
+                .next()
+                .ok_or_else(|| format!("missing {:?} column", column))?;
+            match column {
+                Column::Number => {
+                    let number = token.strip_suffix(')').unwrap_or(token);
+                    number
+                        .parse::<u64>()
+                        .map_err(|_| format!("invalid message number {}", token))?;
+                }
+                Column::Offset => {
+                    timestamp = parse_offset(token)
+                        .ok_or_else(|| format!("invalid time offset {}", token))?;
+                }
+                Column::Type => {
+                    let (k, d) = parse_type(token, version)
+                        .ok_or_else(|| format!("unknown message type {}", token))?;
+                    kind = k;
+                    direction = d.unwrap_or(direction);
+                    if kind == Kind::Ignored {
+                        return Ok(None);
+                    }
+                }
+                Column::Bus => {
+                    channel = token
+                        .parse()
+                        .map_err(|_| format!("invalid bus number {}", token))?;
+                }
+                // Version 1.x error lines have no meaningful identifier.
+                Column::Id if kind == Kind::Error => {}
+                Column::Id => id = Some(parse_id(token)?),
+                Column::Direction => {
+                    direction = match token {
+                        "Rx" => Direction::Rx,
+                        "Tx" => Direction::Tx,
+                        _ => return Err(format!("invalid direction {}", token)),
+                    };
+                }
+                Column::Reserved => {}
+                Column::Dlc => {
+                    let dlc: u8 = token
+                        .parse()
+                        .ok()
+                        .filter(|dlc| *dlc <= 15)
+                        .ok_or_else(|| format!("invalid data length code {}", token))?;
+                    len = Some(match kind {
+                        Kind::Fd { .. } => dlc_to_len(dlc),
+                        _ => dlc_to_len(dlc).min(8),
+                    });
+                }
+                Column::Length => {
+                    len = Some(
+                        token
+                            .parse()
+                            .ok()
+                            .filter(|len| len_to_dlc(*len).is_some())
+                            .ok_or_else(|| format!("invalid data length {}", token))?,
+                    );
+                }
+                Column::Data => unreachable!(),
+            }
+        }
+
+        let len = len.ok_or("missing data length")?;
+        let mut data = [0u8; 64];
+        let mut count = 0;
+        for token in tokens {
+            if token == "RTR" && version < Version::V2_0 {
+                kind = Kind::Remote;
+                break;
+            }
+            if count == len {
+                return Err(format!("more than {} data bytes", len));
+            }
+            data[count] = parse_byte(token)?;
+            count += 1;
+        }
+        if kind != Kind::Remote && count != len {
+            return Err(format!("expected {} data bytes, found {}", len, count));
+        }
+
+        let id = || id.ok_or_else(|| "missing identifier".to_string());
+        let too_long = || format!("data length {} too long for the frame type", len);
+        let message = match kind {
+            Kind::Data => Message::Can(Frame::new(id()?, &data[..len]).ok().ok_or_else(too_long)?),
+            Kind::Remote => Message::Can(Frame::new_remote(id()?, len).ok().ok_or_else(too_long)?),
+            Kind::Fd { brs, esi } => {
+                let mut frame = FdFrame::new(id()?, &data[..len]).ok_or_else(too_long)?;
+                frame.set_bit_rate_switch(brs);
+                frame.set_error_state_indicator(esi);
+                Message::Fd(frame)
+            }
+            Kind::Error => Message::Error(error_frame_from_bytes(&data[..len])),
+            Kind::Ignored => unreachable!(),
+        };
+
+        Ok(Some(Record {
+            timestamp,
+            channel,
+            direction,
+            message,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if !self.pending {
+                match self.read_line() {
+                    Ok(true) => {}
+                    Ok(false) => self.done = true,
+                    Err(err) => {
+                        self.done = true;
+                        return Some(Err(err));
+                    }
+                }
+                if self.done {
+                    break;
+                }
+            }
+            self.pending = false;
+
+            let line = self.line.trim();
+            if line.is_empty() || line.starts_with(';') {
+                continue;
+            }
+            match self.parse_record() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) => continue,
+                Err(message) => {
+                    return Some(Err(Error::Parse {
+                        line: self.line_number,
+                        message,
+                    }))
+                }
+            }
+        }
+        None
+    }
+}
+
+fn parse_type(token: &str, version: Version) -> Option<(Kind, Option<Direction>)> {
+    if version < Version::V2_0 {
+        return match token {
+            "Rx" => Some((Kind::Data, Some(Direction::Rx))),
+            "Tx" => Some((Kind::Data, Some(Direction::Tx))),
+            "Error" => Some((Kind::Error, None)),
+            "Warng" => Some((Kind::Ignored, None)),
+            _ => None,
+        };
+    }
+
+    let kind = match token {
+        "DT" => Kind::Data,
+        "RR" => Kind::Remote,
+        "FD" => Kind::Fd {
+            brs: false,
+            esi: false,
+        },
+        "FB" => Kind::Fd {
+            brs: true,
+            esi: false,
+        },
+        "FE" => Kind::Fd {
+            brs: false,
+            esi: true,
+        },
+        "BI" => Kind::Fd {
+            brs: true,
+            esi: true,
+        },
+        "ER" => Kind::Error,
+        "ST" | "EC" | "EV" => Kind::Ignored,
+        _ => return None,
+    };
+    Some((kind, None))
+}
+
+/// Parses a time offset in milliseconds with up to six decimal places.
+fn parse_offset(token: &str) -> Option<Duration> {
+    let (millis, fraction) = match token.find('.') {
+        Some(i) => (&token[..i], &token[i + 1..]),
+        None => (token, ""),
+    };
+    if millis.is_empty()
+        || fraction.len() > 6
+        || !millis.bytes().all(|b| b.is_ascii_digit())
+        || !fraction.bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let millis: u64 = millis.parse().ok()?;
+    let mut nanos = 0u32;
+    for (i, digit) in fraction.bytes().enumerate() {
+        nanos += (digit - b'0') as u32 * 10u32.pow(5 - i as u32);
+    }
+    Duration::from_millis(millis).checked_add(Duration::from_nanos(nanos as u64))
+}
+
+/// Standard identifiers are written with up to 4 digits, extended ones with 8.
+fn parse_id(token: &str) -> Result<Id, String> {
+    let invalid = || format!("invalid identifier {}", token);
+    let raw = u32::from_str_radix(token, 16).map_err(|_| invalid())?;
+    if token.len() <= 4 {
+        StandardId::new(raw as u16)
+            .filter(|_| raw <= 0x7FF)
+            .map(Id::Standard)
+            .ok_or_else(invalid)
+    } else {
+        ExtendedId::new(raw).map(Id::Extended).ok_or_else(invalid)
+    }
+}
+
+fn parse_byte(token: &str) -> Result<u8, String> {
+    if token.len() != 2 {
+        return Err(format!("invalid data byte {}", token));
+    }
+    u8::from_str_radix(token, 16).map_err(|_| format!("invalid data byte {}", token))
+}
+
+// Error frames carry five bytes: error type, direction (0 = Tx, 1 = Rx),
+// error code capture, Rx error counter and Tx error counter.
+fn error_frame_from_bytes(data: &[u8]) -> ErrorFrame {
+    let byte = |i: usize| data.get(i).copied().unwrap_or(0);
+    ErrorFrame {
+        kind: ErrorFrame::kind_from_bits(byte(0)),
+        direction: if byte(1) == 0 {
+            Direction::Tx
+        } else {
+            Direction::Rx
+        },
+        ecc: byte(2),
+        rx_error_counter: byte(3),
+        tx_error_counter: byte(4),
+    }
+}
+
+/// Parses the remainder of a version 2.x `ER` line.
+fn parse_error_frame(
+    tokens: SplitWhitespace<'_>,
+    columns: &[Column],
+    mut direction: Direction,
+) -> Result<(Direction, ErrorFrame), String> {
+    let mut tokens: Vec<&str> = tokens.filter(|token| *token != "-").collect();
+    if columns.contains(&Column::Direction) {
+        if let Some(i) = tokens.iter().position(|t| *t == "Rx" || *t == "Tx") {
+            direction = if tokens[i] == "Rx" {
+                Direction::Rx
+            } else {
+                Direction::Tx
+            };
+            tokens.drain(..=i);
+        }
+    }
+    // Drop the length column if one was written. Data bytes always have two digits,
+    // error frames never have more than 8 bytes.
+    if let Some(len) = tokens
+        .first()
+        .filter(|t| t.len() == 1)
+        .and_then(|t| t.parse::<usize>().ok())
+    {
+        if tokens.len() == len + 1 {
+            tokens.remove(0);
+        }
+    }
+
+    let data = tokens
+        .iter()
+        .map(|token| parse_byte(token))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((direction, error_frame_from_bytes(&data)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(trc: &str) -> (Metadata, Vec<Result<Record, Error>>) {
+        let reader = Reader::new(trc.as_bytes()).unwrap();
+        let metadata = reader.metadata().clone();
+        (metadata, reader.collect())
+    }
+
+    fn std_id(id: u16) -> Id {
+        StandardId::new(id).unwrap().into()
+    }
+
+    #[test]
+    fn version_1_1() {
+        let (metadata, records) = read(
+            ";$FILEVERSION=1.1
+;$STARTTIME=41766.4648963079
+;
+;   Message Number
+;   |         Time Offset (ms)
+;   |         |        Type
+;   |         |        |        ID (hex)
+;   |         |        |        |     Data Length Code
+;   |         |        |        |     |   Data Bytes (hex) ...
+;   |         |        |        |     |   |
+;---+--   ----+----  --+--  ----+---  +  -+ -- -- -- -- -- -- --
+     1)      1059.9  Rx         0300  8  00 01 02 03 04 05 06 07
+     2)      1283.2  Tx     18EFFF00  2  AA BB
+     3)      1298.9  Warng  FFFFFFFF  4  00 00 00 08  BUSHEAVY
+     4)      1300.0  Rx         0123  4  RTR
+",
+        );
+
+        assert_eq!(metadata.version, Version::V1_1);
+        assert!(metadata.start_time.is_some());
+        let records: Vec<_> = records.into_iter().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 3);
+
+        assert_eq!(records[0].timestamp, Duration::from_micros(1_059_900));
+        assert_eq!(records[0].direction, Direction::Rx);
+        assert_eq!(
+            records[0].message,
+            Message::Can(Frame::new(std_id(0x300), &[0, 1, 2, 3, 4, 5, 6, 7]).unwrap())
+        );
+
+        let id = ExtendedId::new(0x18EF_FF00).unwrap();
+        assert_eq!(records[1].direction, Direction::Tx);
+        assert_eq!(
+            records[1].message,
+            Message::Can(Frame::new(id, &[0xAA, 0xBB]).unwrap())
+        );
+
+        assert_eq!(
+            records[2].message,
+            Message::Can(Frame::new_remote(std_id(0x123), 4).unwrap())
+        );
+    }
+
+    #[test]
+    fn version_1_3() {
+        let (_, records) = read(
+            ";$FILEVERSION=1.3
+     1)      1059.900 2  Rx        0300 -  3    01 02 03
+",
+        );
+        let record = records[0].as_ref().unwrap();
+        assert_eq!(record.channel, 2);
+        assert_eq!(
+            record.message,
+            Message::Can(Frame::new(std_id(0x300), &[1, 2, 3]).unwrap())
+        );
+    }
+
+    #[test]
+    fn version_2_0() {
+        let (metadata, records) = read(
+            ";$FILEVERSION=2.0
+;$STARTTIME=42209.4075997106
+;$COLUMNS=N,O,T,I,d,l,D
+;
+;   Message   Time    Type ID     Rx/Tx
+;   Number    Offset  |    [hex]  |  Data Length
+;   |         [ms]    |    |      |  |  Data [hex] ...
+;   |         |       |    |      |  |  |
+;---+-- ------+------ +- --+----- +- +- +- -- -- -- -- -- -- --
+      1      1059.900 DT     0300 Rx 8  00 00 00 00 04 00 00 00
+      2      1283.231 FB 00000400 Tx 12 00 01 02 03 04 05 06 07 08 09 0A 0B
+      3      1290.000 ST          Rx    00 00 00 08
+      4      1300.500 RR     0301 Rx 2
+      5      1310.000 ER          Rx    04 01 02 03 00
+",
+        );
+
+        assert_eq!(metadata.version, Version::V2_0);
+        let records: Vec<_> = records.into_iter().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 4);
+
+        let data: Vec<u8> = (0..12).collect();
+        let frame = FdFrame::new(ExtendedId::new(0x400).unwrap(), &data).unwrap();
+        assert_eq!(records[1].direction, Direction::Tx);
+        assert_eq!(records[1].message, Message::Fd(frame));
+
+        assert_eq!(
+            records[2].message,
+            Message::Can(Frame::new_remote(std_id(0x301), 2).unwrap())
+        );
+
+        assert_eq!(
+            records[3].message,
+            Message::Error(ErrorFrame {
+                kind: crate::ErrorKind::Stuff,
+                direction: Direction::Rx,
+                ecc: 2,
+                rx_error_counter: 3,
+                tx_error_counter: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn version_2_1() {
+        let (_, records) = read(
+            ";$FILEVERSION=2.1
+;$COLUMNS=N,O,T,B,I,d,R,L,D
+      1      1059.900 DT 1      0300 Rx - 8    00 00 00 00 04 00 00 00
+      2      1060.000 FD 2      0123 Rx - 9    00 01 02 03 04 05 06 07 08 09 0A 0B
+",
+        );
+        let records: Vec<_> = records.into_iter().map(Result::unwrap).collect();
+        assert_eq!(records[1].channel, 2);
+        match records[1].message {
+            Message::Fd(frame) => {
+                assert_eq!(frame.data().len(), 12);
+                assert!(!frame.bit_rate_switch());
+            }
+            _ => panic!("expected FD frame"),
+        }
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let (_, records) = read(
+            ";$FILEVERSION=1.1
+     1)      1059.9  Rx         0300  8  00 01 02
+     2)      1060.0  Rx         0800  0
+     3)      1061.0  Rx         0300  1  GG
+     4)      1062.0  Rx         0300  1  01
+",
+        );
+
+        let lines: Vec<_> = records
+            .iter()
+            .map(|record| match record {
+                Err(Error::Parse { line, .. }) => Some(*line),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lines, [Some(2), Some(3), Some(4), None]);
+    }
+
+    #[test]
+    fn classic_frame_too_long() {
+        let (_, records) = read(
+            ";$FILEVERSION=2.0
+;$COLUMNS=N,O,T,I,d,l,D
+      1      1059.900 DT     0300 Rx 9  00 01 02 03 04 05 06 07 08
+      2      1060.000 DT     0300 Rx 12 00 01 02 03 04 05 06 07 08 09 0A 0B
+      3      1061.000 RR     0300 Rx 12
+      4      1062.000 DT     0300 Rx 1  01
+",
+        );
+        let lines: Vec<_> = records
+            .iter()
+            .map(|record| match record {
+                Err(Error::Parse { line, .. }) => Some(*line),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lines, [Some(3), Some(4), Some(5), None]);
+    }
+
+    #[test]
+    fn arbitrary_input_does_not_panic() {
+        // Cheap stand-in for the fuzz target in `fuzz/`, runs with every `cargo test`.
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let alphabet = b" \n;$=.,)-0123456789ABCDEFxRTDdLlNOBI";
+        for _ in 0..500 {
+            let mut input = b";$FILEVERSION=2.1\n".to_vec();
+            for _ in 0..200 {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                input.push(alphabet[(state % alphabet.len() as u64) as usize]);
+            }
+            if let Ok(reader) = Reader::new(&input[..]) {
+                reader.for_each(drop);
+            }
+        }
+    }
+}
@@ -0,0 +1,583 @@
+use std::{
+    fmt::Write as _,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use embedded_can::Frame as _;
+
+use super::{Version, OLE_DAYS_TO_UNIX_EPOCH, SECONDS_PER_DAY};
+use crate::{Capture, Direction, Id, Message, Record};
+
+/// How record timestamps are mapped to the time offsets in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamps {
+    /// Offsets are the record timestamps as they are, e.g. the time since system start
+    /// for frames captured from an [`Interface`](crate::Interface).
+    Absolute,
+    /// Offsets start at zero with the first record of the file.
+    Relative,
+}
+
+/// Writes records as `.trc` file of version 1.1, 2.0 or 2.1.
+///
+/// The header is written together with the first record, so the configuration
+/// can be changed until then. Version 1.1 cannot store CAN FD frames.
+pub struct Writer<W: Write> {
+    writer: W,
+    version: Version,
+    timestamps: Timestamps,
+    start_time: Option<SystemTime>,
+    // Timestamp of the first record, subtracted for relative offsets.
+    base: Option<Duration>,
+    number: u64,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(writer: W, version: Version) -> io::Result<Self> {
+        if ![Version::V1_1, Version::V2_0, Version::V2_1].contains(&version) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("writing version {} is not supported", version),
+            ));
+        }
+
+        Ok(Self {
+            writer,
+            version,
+            timestamps: Timestamps::Relative,
+            start_time: None,
+            base: None,
+            number: 0,
+        })
+    }
+
+    pub fn with_timestamps(&mut self, timestamps: Timestamps) -> &mut Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// Absolute time that corresponds to a record timestamp of zero.
+    ///
+    /// Used for the start time in the header. If not set, the first record is
+    /// assumed to be written right when it was captured.
+    pub fn with_start_time(&mut self, start_time: SystemTime) -> &mut Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        if self.version < Version::V2_0 {
+            if let Message::Fd(_) = record.message {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "CAN FD frames require file version 2.0 or later",
+                ));
+            }
+        }
+
+        let base = match self.base {
+            Some(base) => base,
+            None => {
+                let base = match self.timestamps {
+                    Timestamps::Absolute => Duration::default(),
+                    Timestamps::Relative => record.timestamp,
+                };
+                let start_time = self
+                    .start_time
+                    .or_else(|| SystemTime::now().checked_sub(record.timestamp))
+                    .unwrap_or(UNIX_EPOCH);
+                self.write_header(start_time + base)?;
+                self.base = Some(base);
+                base
+            }
+        };
+
+        self.number += 1;
+        let offset = record.timestamp.checked_sub(base).unwrap_or_default();
+        let line = match self.version {
+            Version::V1_1 => format_v1(self.number, offset, record),
+            _ => format_v2(self.version, self.number, offset, record),
+        };
+        writeln!(self.writer, "{}", line)
+    }
+
+    /// Writes the header if no record was written and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.base.is_none() {
+            self.write_header(self.start_time.unwrap_or_else(SystemTime::now))?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write_header(&mut self, start_time: SystemTime) -> io::Result<()> {
+        let secs = start_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let days = secs / SECONDS_PER_DAY + OLE_DAYS_TO_UNIX_EPOCH;
+
+        writeln!(self.writer, ";$FILEVERSION={}", self.version)?;
+        writeln!(self.writer, ";$STARTTIME={:.10}", days)?;
+        let header = match self.version {
+            Version::V1_1 => HEADER_V1_1,
+            Version::V2_0 => HEADER_V2_0,
+            _ => HEADER_V2_1,
+        };
+        self.writer.write_all(header.as_bytes())
+    }
+}
+
+impl<W: Write> Capture for Writer<W> {
+    fn capture(&mut self, record: &Record) -> io::Result<()> {
+        self.write(record)
+    }
+}
+
+const HEADER_V1_1: &str = ";
+;   Message Number
+;   |         Time Offset (ms)
+;   |         |        Type
+;   |         |        |        ID (hex)
+;   |         |        |        |     Data Length Code
+;   |         |        |        |     |   Data Bytes (hex) ...
+;   |         |        |        |     |   |
+;---+--   ----+----  --+--  ----+---  +  -+ -- -- -- -- -- -- --
+";
+
+const HEADER_V2_0: &str = ";$COLUMNS=N,O,T,I,d,l,D
+;
+;   Message   Time    Type ID     Rx/Tx
+;   Number    Offset  |    [hex]  |  Data Length
+;   |         [ms]    |    |      |  |  Data [hex] ...
+;   |         |       |    |      |  |  |
+;---+-- ------+------ +- --+----- +- +- +- -- -- -- -- -- -- --
+";
+
+const HEADER_V2_1: &str = ";$COLUMNS=N,O,T,B,I,d,R,L,D
+;
+;   Message   Time    Type    ID     Rx/Tx
+;   Number    Offset  |  Bus  [hex]  |  Reserved
+;   |         [ms]    |  |    |      |  |  Data Length Code
+;   |         |       |  |    |      |  |  |    Data [hex] ...
+;   |         |       |  |    |      |  |  |    |
+;---+-- ------+------ +- +- --+----- +- +- +--- +- -- -- -- -- -- -- --
+";
+
+fn format_v1(number: u64, offset: Duration, record: &Record) -> String {
+    let micros = offset.as_micros();
+    let offset = format!("{}.{}", micros / 1000, micros % 1000 / 100);
+    let direction = match record.direction {
+        Direction::Rx => "Rx",
+        Direction::Tx => "Tx",
+    };
+
+    let (kind, id, dlc, data) = match &record.message {
+        Message::Can(frame) if frame.is_remote_frame() => (
+            direction,
+            format_id(frame.id()),
+            frame.dlc(),
+            "RTR".to_string(),
+        ),
+        Message::Can(frame) => (
+            direction,
+            format_id(frame.id()),
+            frame.dlc(),
+            format_data(frame.data()),
+        ),
+        Message::Error(error) => {
+            let data = error_frame_bytes(error);
+            ("Error", "-".to_string(), data.len(), format_data(&data))
+        }
+        Message::Fd(_) => unreachable!(),
+    };
+    format!(
+        "{:>6}) {:>11}  {:<5}  {:>8}  {}  {}",
+        number, offset, kind, id, dlc, data
+    )
+}
+
+fn format_v2(version: Version, number: u64, offset: Duration, record: &Record) -> String {
+    let micros = offset.as_micros();
+    let offset = format!("{}.{:03}", micros / 1000, micros % 1000);
+    let direction = match record.direction {
+        Direction::Rx => "Rx",
+        Direction::Tx => "Tx",
+    };
+
+    // Version 2.0 stores the data length, version 2.1 the DLC.
+    let (kind, id, dlc, len, data) = match &record.message {
+        Message::Can(frame) if frame.is_remote_frame() => (
+            "RR",
+            format_id(frame.id()),
+            frame.dlc(),
+            frame.dlc(),
+            String::new(),
+        ),
+        Message::Can(frame) => (
+            "DT",
+            format_id(frame.id()),
+            frame.dlc(),
+            frame.data().len(),
+            format_data(frame.data()),
+        ),
+        Message::Fd(frame) => {
+            let kind = match (frame.bit_rate_switch(), frame.error_state_indicator()) {
+                (false, false) => "FD",
+                (true, false) => "FB",
+                (false, true) => "FE",
+                (true, true) => "BI",
+            };
+            (
+                kind,
+                format_id(frame.id()),
+                frame.dlc(),
+                frame.data().len(),
+                format_data(frame.data()),
+            )
+        }
+        Message::Error(error) => {
+            let data = error_frame_bytes(error);
+            (
+                "ER",
+                "-".to_string(),
+                data.len(),
+                data.len(),
+                format_data(&data),
+            )
+        }
+    };
+
+    if version == Version::V2_0 {
+        format!(
+            "{:>7} {:>13} {} {:>8} {} {:<2} {}",
+            number, offset, kind, id, direction, len, data
+        )
+    } else {
+        format!(
+            "{:>7} {:>13} {} {:<2} {:>8} {} - {:<4} {}",
+            number, offset, kind, record.channel, id, direction, dlc, data
+        )
+    }
+}
+
+fn format_id(id: Id) -> String {
+    match id {
+        Id::Standard(id) => format!("{:04X}", id.as_raw()),
+        Id::Extended(id) => format!("{:08X}", id.as_raw()),
+    }
+}
+
+fn format_data(data: &[u8]) -> String {
+    let mut s = String::with_capacity(data.len() * 3);
+    for (i, byte) in data.iter().enumerate() {
+        if i > 0 {
+            s.push(' ');
+        }
+        write!(s, "{:02X}", byte).unwrap();
+    }
+    s
+}
+
+fn error_frame_bytes(error: &crate::ErrorFrame) -> [u8; 5] {
+    let direction = match error.direction {
+        Direction::Tx => 0,
+        Direction::Rx => 1,
+    };
+    [
+        error.kind_bits(),
+        direction,
+        error.ecc,
+        error.rx_error_counter,
+        error.tx_error_counter,
+    ]
+}
+
+/// Writes records to a series of `.trc` files, starting a new file when the
+/// current one exceeds a size or covers more than a time span.
+///
+/// The files are named after the given path with a running number appended,
+/// e.g. `capture_001.trc`, `capture_002.trc`. Each file is self-contained,
+/// relative time offsets restart at zero in every file.
+pub struct RotatingWriter {
+    path: PathBuf,
+    version: Version,
+    timestamps: Timestamps,
+    start_time: Option<SystemTime>,
+    max_size: Option<u64>,
+    max_duration: Option<Duration>,
+    current: Option<CurrentFile>,
+    paths: Vec<PathBuf>,
+}
+
+struct CurrentFile {
+    writer: Writer<CountingWriter<BufWriter<File>>>,
+    first_timestamp: Duration,
+}
+
+impl RotatingWriter {
+    pub fn new(path: impl Into<PathBuf>, version: Version) -> io::Result<Self> {
+        // Fail early for unsupported versions instead of with the first record.
+        Writer::new(io::sink(), version)?;
+
+        Ok(Self {
+            path: path.into(),
+            version,
+            timestamps: Timestamps::Relative,
+            start_time: None,
+            max_size: None,
+            max_duration: None,
+            current: None,
+            paths: Vec::new(),
+        })
+    }
+
+    pub fn with_timestamps(&mut self, timestamps: Timestamps) -> &mut Self {
+        self.timestamps = timestamps;
+        self
+    }
+
+    /// See [`Writer::with_start_time()`].
+    pub fn with_start_time(&mut self, start_time: SystemTime) -> &mut Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    /// Maximum file size in bytes.
+    pub fn with_max_size(&mut self, bytes: u64) -> &mut Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Maximum time span of the records in a file.
+    pub fn with_max_duration(&mut self, duration: Duration) -> &mut Self {
+        self.max_duration = Some(duration);
+        self
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        if let Some(current) = &self.current {
+            let size_exceeded = self
+                .max_size
+                .is_some_and(|max| current.writer.writer.count >= max);
+            let duration_exceeded = self
+                .max_duration
+                .is_some_and(|max| record.timestamp.saturating_sub(current.first_timestamp) >= max);
+            if size_exceeded || duration_exceeded {
+                self.current.take().unwrap().writer.finish()?;
+            }
+        }
+
+        if self.current.is_none() {
+            let path = self.segment_path(self.paths.len() + 1);
+            let file = CountingWriter {
+                inner: BufWriter::new(File::create(&path)?),
+                count: 0,
+            };
+            let mut writer = Writer::new(file, self.version)?;
+            writer.with_timestamps(self.timestamps);
+            if let Some(start_time) = self.start_time {
+                writer.with_start_time(start_time);
+            }
+            self.paths.push(path);
+            self.current = Some(CurrentFile {
+                writer,
+                first_timestamp: record.timestamp,
+            });
+        }
+
+        self.current.as_mut().unwrap().writer.write(record)
+    }
+
+    /// Paths of all files written so far, including the current one.
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    /// Closes the current file and returns the paths of all written files.
+    pub fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        if let Some(current) = self.current.take() {
+            current.writer.finish()?;
+        }
+        Ok(self.paths)
+    }
+
+    fn segment_path(&self, index: usize) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = format!("{}_{:03}.trc", stem, index);
+        match self.path.parent() {
+            Some(parent) => parent.join(name),
+            None => Path::new(&name).to_path_buf(),
+        }
+    }
+}
+
+impl Capture for RotatingWriter {
+    fn capture(&mut self, record: &Record) -> io::Result<()> {
+        self.write(record)
+    }
+}
+
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{trc::Reader, ErrorFrame, ErrorKind, ExtendedId, FdFrame, Frame, StandardId};
+
+    fn records() -> Vec<Record> {
+        let std_id = StandardId::new(0x123).unwrap();
+        let ext_id = ExtendedId::new(0x18EF_FF00).unwrap();
+        let record = |millis, direction, message| Record {
+            timestamp: Duration::from_micros(millis),
+            channel: 1,
+            direction,
+            message,
+        };
+        vec![
+            record(
+                5_000_000,
+                Direction::Rx,
+                Message::Can(Frame::new(std_id, &[1, 2, 3]).unwrap()),
+            ),
+            record(
+                5_001_300,
+                Direction::Tx,
+                Message::Can(Frame::new(ext_id, &[0xFF; 8]).unwrap()),
+            ),
+            record(
+                5_002_000,
+                Direction::Rx,
+                Message::Can(Frame::new_remote(std_id, 8).unwrap()),
+            ),
+            record(
+                5_003_000,
+                Direction::Rx,
+                Message::Error(ErrorFrame {
+                    kind: ErrorKind::Form,
+                    direction: Direction::Tx,
+                    ecc: 0x12,
+                    rx_error_counter: 0,
+                    tx_error_counter: 8,
+                }),
+            ),
+        ]
+    }
+
+    fn round_trip(version: Version, timestamps: Timestamps, records: &[Record]) -> Vec<Record> {
+        let mut writer = Writer::new(Vec::new(), version).unwrap();
+        writer.with_timestamps(timestamps);
+        for record in records {
+            writer.write(record).unwrap();
+        }
+        let trc = writer.finish().unwrap();
+
+        let reader = Reader::new(&trc[..]).unwrap();
+        assert_eq!(reader.metadata().version, version);
+        reader.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn round_trip_v1_1() {
+        let records = records();
+        let read = round_trip(Version::V1_1, Timestamps::Relative, &records);
+        assert_eq!(read.len(), records.len());
+        for (read, written) in read.iter().zip(&records) {
+            assert_eq!(read.message, written.message);
+            assert_eq!(read.direction, written.direction);
+        }
+        assert_eq!(read[1].timestamp, Duration::from_micros(1300));
+    }
+
+    #[test]
+    fn round_trip_v2() {
+        let mut records = records();
+        let mut fd = FdFrame::new(ExtendedId::new(0x400).unwrap(), &[0xAB; 48]).unwrap();
+        fd.set_error_state_indicator(true);
+        records.push(Record {
+            timestamp: Duration::from_secs(6),
+            channel: 2,
+            direction: Direction::Tx,
+            message: Message::Fd(fd),
+        });
+
+        for version in [Version::V2_0, Version::V2_1] {
+            let read = round_trip(version, Timestamps::Absolute, &records);
+            if version == Version::V2_1 {
+                assert_eq!(read, records);
+            } else {
+                // Version 2.0 has no bus column.
+                assert!(read.iter().all(|record| record.channel == 1));
+            }
+        }
+    }
+
+    #[test]
+    fn fd_frames_need_v2() {
+        let mut writer = Writer::new(Vec::new(), Version::V1_1).unwrap();
+        let fd = FdFrame::new(StandardId::new(1).unwrap(), &[0; 12]).unwrap();
+        let err = writer
+            .write(&Record {
+                timestamp: Duration::default(),
+                channel: 1,
+                direction: Direction::Rx,
+                message: Message::Fd(fd),
+            })
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn rotation() {
+        let dir = std::env::temp_dir().join(format!("pcan-basic-trc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut writer = RotatingWriter::new(dir.join("capture.trc"), Version::V2_1).unwrap();
+        writer.with_max_duration(Duration::from_secs(1));
+        let frame = Frame::new(StandardId::new(1).unwrap(), &[]).unwrap();
+        for millis in (0..2500).step_by(100) {
+            writer
+                .write(&Record {
+                    timestamp: Duration::from_millis(millis),
+                    channel: 1,
+                    direction: Direction::Rx,
+                    message: Message::Can(frame),
+                })
+                .unwrap();
+        }
+        let paths = writer.finish().unwrap();
+        assert_eq!(paths.len(), 3);
+        assert!(paths[0].ends_with("capture_001.trc"));
+
+        let records: Vec<_> = Reader::new(io::BufReader::new(File::open(&paths[1]).unwrap()))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records.len(), 10);
+        assert_eq!(records[0].timestamp, Duration::default());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
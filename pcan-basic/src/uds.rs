@@ -0,0 +1,666 @@
+//! Unified diagnostic services (ISO 14229) client.
+//!
+//! ```no_run
+//! use pcan_basic::{isotp::{Config, IsoTp}, uds::{self, Client}, Interface, StandardId, SystemClock};
+//!
+//! let can = Interface::init().unwrap();
+//! let config = Config::new(StandardId::new(0x7E0).unwrap(), StandardId::new(0x7E8).unwrap());
+//! let mut client = Client::new(IsoTp::new(can, SystemClock::new(), config));
+//! client.diagnostic_session_control(uds::session::EXTENDED).unwrap();
+//! let vin = client.read_data_by_identifier(0xF190).unwrap();
+//! ```
+
+pub mod flash;
+pub mod server;
+
+use std::{convert::TryInto, fmt, time::Duration};
+
+use crate::{
+    isotp::{self, IsoTp},
+    Clock, ReadTimeout,
+};
+
+/// Service identifiers.
+pub mod sid {
+    pub const DIAGNOSTIC_SESSION_CONTROL: u8 = 0x10;
+    pub const ECU_RESET: u8 = 0x11;
+    pub const CLEAR_DIAGNOSTIC_INFORMATION: u8 = 0x14;
+    pub const READ_DTC_INFORMATION: u8 = 0x19;
+    pub const READ_DATA_BY_IDENTIFIER: u8 = 0x22;
+    pub const SECURITY_ACCESS: u8 = 0x27;
+    pub const WRITE_DATA_BY_IDENTIFIER: u8 = 0x2E;
+    pub const ROUTINE_CONTROL: u8 = 0x31;
+    pub const REQUEST_DOWNLOAD: u8 = 0x34;
+    pub const TRANSFER_DATA: u8 = 0x36;
+    pub const REQUEST_TRANSFER_EXIT: u8 = 0x37;
+    pub const TESTER_PRESENT: u8 = 0x3E;
+    pub const NEGATIVE_RESPONSE: u8 = 0x7F;
+}
+
+/// Diagnostic sessions.
+pub mod session {
+    pub const DEFAULT: u8 = 0x01;
+    pub const PROGRAMMING: u8 = 0x02;
+    pub const EXTENDED: u8 = 0x03;
+}
+
+/// Reset types of the ECU reset service.
+pub mod reset {
+    pub const HARD: u8 = 0x01;
+    pub const KEY_OFF_ON: u8 = 0x02;
+    pub const SOFT: u8 = 0x03;
+}
+
+/// Sub-functions of the routine control service.
+pub mod routine {
+    pub const START: u8 = 0x01;
+    pub const STOP: u8 = 0x02;
+    pub const REQUEST_RESULTS: u8 = 0x03;
+}
+
+/// Negative response codes.
+pub mod nrc {
+    pub const GENERAL_REJECT: u8 = 0x10;
+    pub const SERVICE_NOT_SUPPORTED: u8 = 0x11;
+    pub const SUB_FUNCTION_NOT_SUPPORTED: u8 = 0x12;
+    pub const INCORRECT_MESSAGE_LENGTH: u8 = 0x13;
+    pub const RESPONSE_TOO_LONG: u8 = 0x14;
+    pub const BUSY_REPEAT_REQUEST: u8 = 0x21;
+    pub const CONDITIONS_NOT_CORRECT: u8 = 0x22;
+    pub const REQUEST_SEQUENCE_ERROR: u8 = 0x24;
+    pub const REQUEST_OUT_OF_RANGE: u8 = 0x31;
+    pub const SECURITY_ACCESS_DENIED: u8 = 0x33;
+    pub const INVALID_KEY: u8 = 0x35;
+    pub const EXCEEDED_NUMBER_OF_ATTEMPTS: u8 = 0x36;
+    pub const REQUIRED_TIME_DELAY_NOT_EXPIRED: u8 = 0x37;
+    pub const UPLOAD_DOWNLOAD_NOT_ACCEPTED: u8 = 0x70;
+    pub const TRANSFER_DATA_SUSPENDED: u8 = 0x71;
+    pub const GENERAL_PROGRAMMING_FAILURE: u8 = 0x72;
+    pub const WRONG_BLOCK_SEQUENCE_COUNTER: u8 = 0x73;
+    pub const RESPONSE_PENDING: u8 = 0x78;
+    pub const SUB_FUNCTION_NOT_SUPPORTED_IN_ACTIVE_SESSION: u8 = 0x7E;
+    pub const SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION: u8 = 0x7F;
+
+    pub fn name(code: u8) -> Option<&'static str> {
+        Some(match code {
+            GENERAL_REJECT => "generalReject",
+            SERVICE_NOT_SUPPORTED => "serviceNotSupported",
+            SUB_FUNCTION_NOT_SUPPORTED => "subFunctionNotSupported",
+            INCORRECT_MESSAGE_LENGTH => "incorrectMessageLengthOrInvalidFormat",
+            RESPONSE_TOO_LONG => "responseTooLong",
+            BUSY_REPEAT_REQUEST => "busyRepeatRequest",
+            CONDITIONS_NOT_CORRECT => "conditionsNotCorrect",
+            REQUEST_SEQUENCE_ERROR => "requestSequenceError",
+            REQUEST_OUT_OF_RANGE => "requestOutOfRange",
+            SECURITY_ACCESS_DENIED => "securityAccessDenied",
+            INVALID_KEY => "invalidKey",
+            EXCEEDED_NUMBER_OF_ATTEMPTS => "exceededNumberOfAttempts",
+            REQUIRED_TIME_DELAY_NOT_EXPIRED => "requiredTimeDelayNotExpired",
+            UPLOAD_DOWNLOAD_NOT_ACCEPTED => "uploadDownloadNotAccepted",
+            TRANSFER_DATA_SUSPENDED => "transferDataSuspended",
+            GENERAL_PROGRAMMING_FAILURE => "generalProgrammingFailure",
+            WRONG_BLOCK_SEQUENCE_COUNTER => "wrongBlockSequenceCounter",
+            RESPONSE_PENDING => "requestCorrectlyReceivedResponsePending",
+            SUB_FUNCTION_NOT_SUPPORTED_IN_ACTIVE_SESSION => {
+                "subFunctionNotSupportedInActiveSession"
+            }
+            SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION => "serviceNotSupportedInActiveSession",
+            _ => return None,
+        })
+    }
+}
+
+/// Bit in the sub-function byte that suppresses the positive response.
+pub const SUPPRESS_POSITIVE_RESPONSE: u8 = 0x80;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Transport(isotp::Error<E>),
+    /// No response within P2 or P2*.
+    Timeout,
+    /// The ECU answered with a negative response code, see [`nrc`].
+    Negative {
+        service: u8,
+        code: u8,
+    },
+    InvalidResponse(Vec<u8>),
+    /// The request is empty or a sub-function is out of range.
+    InvalidRequest,
+}
+
+impl<E> From<isotp::Error<E>> for Error<E> {
+    fn from(err: isotp::Error<E>) -> Self {
+        Error::Transport(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Transport(err) => err.fmt(f),
+            Error::Timeout => write!(f, "No response from ECU"),
+            Error::Negative { service, code } => {
+                write!(
+                    f,
+                    "Service {:#04x} rejected with NRC {:#04x}",
+                    service, code
+                )?;
+                if let Some(name) = nrc::name(*code) {
+                    write!(f, " ({})", name)?;
+                }
+                Ok(())
+            }
+            Error::InvalidResponse(response) => write!(f, "Invalid response {:02X?}", response),
+            Error::InvalidRequest => write!(f, "Invalid request"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Error<E> {}
+
+/// What a response means for a request of `service`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ResponseKind {
+    Positive,
+    /// Negative response code 0x78, the final response follows.
+    Pending,
+    Negative(u8),
+    /// Not a response to the request.
+    Unexpected,
+}
+
+pub(crate) fn classify_response(service: u8, response: &[u8]) -> ResponseKind {
+    match *response {
+        [sid::NEGATIVE_RESPONSE, rejected, nrc::RESPONSE_PENDING, ..] if rejected == service => {
+            ResponseKind::Pending
+        }
+        [sid::NEGATIVE_RESPONSE, rejected, code, ..] if rejected == service => {
+            ResponseKind::Negative(code)
+        }
+        [sid, ..] if sid == service.wrapping_add(0x40) => ResponseKind::Positive,
+        _ => ResponseKind::Unexpected,
+    }
+}
+
+/// Diagnostic trouble code with its status byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dtc {
+    /// 24 bit code.
+    pub code: u32,
+    pub status: u8,
+}
+
+impl Dtc {
+    /// Parses the records of a reportDTCByStatusMask response.
+    pub fn parse_records(data: &[u8]) -> Vec<Dtc> {
+        data.chunks_exact(4)
+            .map(|record| Dtc {
+                code: u32::from_be_bytes([0, record[0], record[1], record[2]]),
+                status: record[3],
+            })
+            .collect()
+    }
+}
+
+pub struct Client<C: ReadTimeout, K> {
+    isotp: IsoTp<C, K>,
+    p2: Duration,
+    p2_star: Duration,
+    keep_alive: Duration,
+    last_request: Duration,
+}
+
+impl<C, K> Client<C, K>
+where
+    C: ReadTimeout,
+    C::Frame: embedded_can::Frame,
+    K: Clock,
+{
+    pub fn new(isotp: IsoTp<C, K>) -> Self {
+        Self {
+            isotp,
+            p2: Duration::from_millis(50),
+            p2_star: Duration::from_millis(5000),
+            keep_alive: Duration::from_secs(2),
+            last_request: Duration::default(),
+        }
+    }
+
+    /// Response timeouts, updated by the ECU's answer to session changes.
+    /// Default to 50 ms and 5 s.
+    pub fn with_timing(&mut self, p2: Duration, p2_star: Duration) -> &mut Self {
+        self.p2 = p2;
+        self.p2_star = p2_star;
+        self
+    }
+
+    /// Interval of tester present requests sent by [`Client::idle()`], defaults to 2 s.
+    pub fn with_keep_alive(&mut self, interval: Duration) -> &mut Self {
+        self.keep_alive = interval;
+        self
+    }
+
+    pub fn timing(&self) -> (Duration, Duration) {
+        (self.p2, self.p2_star)
+    }
+
+    pub fn isotp(&mut self) -> &mut IsoTp<C, K> {
+        &mut self.isotp
+    }
+
+    pub fn into_inner(self) -> IsoTp<C, K> {
+        self.isotp
+    }
+
+    /// Sends a raw request and returns the positive response, including the
+    /// response service identifier.
+    ///
+    /// Pending responses (NRC 0x78) extend the timeout to P2*. Returns an empty
+    /// response when the positive response is suppressed.
+    pub fn request(&mut self, request: &[u8]) -> Result<Vec<u8>, Error<C::Error>> {
+        let service = *request.first().ok_or(Error::InvalidRequest)?;
+        let suppressed = has_sub_function(service)
+            && request
+                .get(1)
+                .is_some_and(|sub| sub & SUPPRESS_POSITIVE_RESPONSE != 0);
+
+        self.isotp.send(request)?;
+        self.last_request = self.isotp.clock().now();
+
+        let mut timeout = self.p2;
+        loop {
+            let response = match self.isotp.receive(timeout)? {
+                Some(response) => response,
+                // Negative responses are sent even if the positive one is suppressed.
+                None if suppressed => return Ok(Vec::new()),
+                None => return Err(Error::Timeout),
+            };
+
+            match classify_response(service, &response) {
+                ResponseKind::Pending => timeout = self.p2_star,
+                ResponseKind::Negative(code) => return Err(Error::Negative { service, code }),
+                ResponseKind::Positive => return Ok(response),
+                ResponseKind::Unexpected => return Err(Error::InvalidResponse(response)),
+            }
+        }
+    }
+
+    /// Changes the session and adopts the P2 and P2* timing of the response.
+    pub fn diagnostic_session_control(&mut self, session: u8) -> Result<(), Error<C::Error>> {
+        let response = self.request(&[sid::DIAGNOSTIC_SESSION_CONTROL, session])?;
+        if let [_, _, p2_hi, p2_lo, p2_star_hi, p2_star_lo] = *response.as_slice() {
+            self.p2 = Duration::from_millis(u16::from_be_bytes([p2_hi, p2_lo]) as u64);
+            self.p2_star =
+                Duration::from_millis(u16::from_be_bytes([p2_star_hi, p2_star_lo]) as u64 * 10);
+        }
+        Ok(())
+    }
+
+    pub fn ecu_reset(&mut self, reset_type: u8) -> Result<(), Error<C::Error>> {
+        self.request(&[sid::ECU_RESET, reset_type])?;
+        Ok(())
+    }
+
+    /// Unlocks the security `level` (odd request seed sub-function) with the
+    /// key computed by `key` from the level and the seed.
+    ///
+    /// An all zero seed means the level is unlocked already and no key is sent.
+    pub fn security_access(
+        &mut self,
+        level: u8,
+        key: impl FnOnce(u8, &[u8]) -> Vec<u8>,
+    ) -> Result<(), Error<C::Error>> {
+        let send_key = level.checked_add(1).ok_or(Error::InvalidRequest)?;
+        let response = self.request(&[sid::SECURITY_ACCESS, level])?;
+        let seed = response.get(2..).unwrap_or_default();
+        if seed.iter().all(|&b| b == 0) {
+            return Ok(());
+        }
+
+        let mut request = vec![sid::SECURITY_ACCESS, send_key];
+        request.extend(key(level, seed));
+        self.request(&request)?;
+        Ok(())
+    }
+
+    pub fn read_data_by_identifier(&mut self, did: u16) -> Result<Vec<u8>, Error<C::Error>> {
+        let [hi, lo] = did.to_be_bytes();
+        let response = self.request(&[sid::READ_DATA_BY_IDENTIFIER, hi, lo])?;
+        match response.get(1..3) {
+            Some(echo) if echo == [hi, lo] => Ok(response[3..].to_vec()),
+            _ => Err(Error::InvalidResponse(response)),
+        }
+    }
+
+    pub fn write_data_by_identifier(
+        &mut self,
+        did: u16,
+        data: &[u8],
+    ) -> Result<(), Error<C::Error>> {
+        let mut request = vec![sid::WRITE_DATA_BY_IDENTIFIER];
+        request.extend_from_slice(&did.to_be_bytes());
+        request.extend_from_slice(data);
+        self.request(&request)?;
+        Ok(())
+    }
+
+    /// Returns the response after the sub-function byte.
+    pub fn read_dtc_information(
+        &mut self,
+        sub_function: u8,
+        parameters: &[u8],
+    ) -> Result<Vec<u8>, Error<C::Error>> {
+        let mut request = vec![sid::READ_DTC_INFORMATION, sub_function];
+        request.extend_from_slice(parameters);
+        let response = self.request(&request)?;
+        Ok(response.get(2..).unwrap_or_default().to_vec())
+    }
+
+    /// Reads all DTCs whose status matches `mask` (reportDTCByStatusMask).
+    pub fn read_dtcs_by_status_mask(&mut self, mask: u8) -> Result<Vec<Dtc>, Error<C::Error>> {
+        let response = self.read_dtc_information(0x02, &[mask])?;
+        // Skip the status availability mask.
+        Ok(Dtc::parse_records(response.get(1..).unwrap_or_default()))
+    }
+
+    /// Clears the DTCs of a group, `0xFFFFFF` for all.
+    pub fn clear_diagnostic_information(&mut self, group: u32) -> Result<(), Error<C::Error>> {
+        let [_, a, b, c] = group.to_be_bytes();
+        self.request(&[sid::CLEAR_DIAGNOSTIC_INFORMATION, a, b, c])?;
+        Ok(())
+    }
+
+    /// Returns the routine status record.
+    pub fn routine_control(
+        &mut self,
+        control: u8,
+        routine: u16,
+        options: &[u8],
+    ) -> Result<Vec<u8>, Error<C::Error>> {
+        let mut request = vec![sid::ROUTINE_CONTROL, control];
+        request.extend_from_slice(&routine.to_be_bytes());
+        request.extend_from_slice(options);
+        let response = self.request(&request)?;
+        Ok(response.get(4..).unwrap_or_default().to_vec())
+    }
+
+    /// Sends tester present without a positive response.
+    pub fn tester_present(&mut self) -> Result<(), Error<C::Error>> {
+        self.request(&[sid::TESTER_PRESENT, SUPPRESS_POSITIVE_RESPONSE])?;
+        Ok(())
+    }
+
+    /// Waits for `duration` while keeping a non-default session alive with
+    /// tester present requests.
+    pub fn idle(&mut self, duration: Duration) -> Result<(), Error<C::Error>> {
+        let end = self.isotp.clock().now() + duration;
+        loop {
+            let next = self.last_request + self.keep_alive;
+            if next >= end {
+                self.isotp.clock().sleep_until(end);
+                return Ok(());
+            }
+            self.isotp.clock().sleep_until(next);
+            self.tester_present()?;
+        }
+    }
+
+    /// Returns the maximum length of transfer data requests, including the
+    /// service identifier and block sequence counter.
+    pub fn request_download(
+        &mut self,
+        address: u32,
+        size: u32,
+        data_format: u8,
+    ) -> Result<usize, Error<C::Error>> {
+        let mut request = vec![sid::REQUEST_DOWNLOAD, data_format, 0x44];
+        request.extend_from_slice(&address.to_be_bytes());
+        request.extend_from_slice(&size.to_be_bytes());
+        let response = self.request(&request)?;
+
+        let len = response.get(1).map_or(0, |format| (format >> 4) as usize);
+        match response.get(2..2 + len) {
+            Some(bytes) if (1..=8).contains(&len) => Ok(bytes
+                .iter()
+                .fold(0u64, |acc, &b| acc << 8 | b as u64)
+                .try_into()
+                .unwrap_or(usize::MAX)),
+            _ => Err(Error::InvalidResponse(response)),
+        }
+    }
+
+    /// Returns the transfer response parameters.
+    pub fn transfer_data(&mut self, sequence: u8, data: &[u8]) -> Result<Vec<u8>, Error<C::Error>> {
+        let mut request = Vec::with_capacity(data.len() + 2);
+        request.extend_from_slice(&[sid::TRANSFER_DATA, sequence]);
+        request.extend_from_slice(data);
+        let response = self.request(&request)?;
+        match response.get(1) {
+            Some(&echo) if echo == sequence => Ok(response[2..].to_vec()),
+            _ => Err(Error::InvalidResponse(response)),
+        }
+    }
+
+    pub fn request_transfer_exit(&mut self, parameters: &[u8]) -> Result<Vec<u8>, Error<C::Error>> {
+        let mut request = vec![sid::REQUEST_TRANSFER_EXIT];
+        request.extend_from_slice(parameters);
+        let response = self.request(&request)?;
+        Ok(response[1..].to_vec())
+    }
+}
+
+/// Services whose second byte is a sub-function with the suppress bit.
+pub(crate) fn has_sub_function(service: u8) -> bool {
+    matches!(
+        service,
+        sid::DIAGNOSTIC_SESSION_CONTROL
+            | sid::ECU_RESET
+            | sid::READ_DTC_INFORMATION
+            | sid::SECURITY_ACCESS
+            | sid::ROUTINE_CONTROL
+            | sid::TESTER_PRESENT
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        isotp::{Config, Event, Link},
+        sim::{self, Bus, Context, Node},
+        Frame, StandardId,
+    };
+
+    /// Requests with their responses, each sent after a delay.
+    type Script = Vec<(Vec<u8>, Vec<(Duration, Vec<u8>)>)>;
+
+    /// Answers requests with canned responses.
+    struct Scripted {
+        link: Link<Frame>,
+        script: Script,
+        pending: Vec<(Duration, Vec<u8>)>,
+        requests: Vec<Vec<u8>>,
+    }
+
+    impl Scripted {
+        fn drive(&mut self, cx: &mut Context<'_>) {
+            while let Some(event) = self.link.poll(cx.now()) {
+                match event {
+                    Event::Transmit(frame) => cx.send(frame),
+                    Event::Received(request) => {
+                        if let Some((_, responses)) =
+                            self.script.iter().find(|(r, _)| *r == request)
+                        {
+                            let mut at = cx.now();
+                            for (delay, response) in responses {
+                                at += *delay;
+                                self.pending.push((at, response.clone()));
+                            }
+                        }
+                        self.requests.push(request);
+                    }
+                    _ => {}
+                }
+            }
+            if !self.pending.is_empty() && !self.link.is_sending() && self.pending[0].0 <= cx.now()
+            {
+                let (_, response) = self.pending.remove(0);
+                self.link.send(&response).unwrap();
+                return self.drive(cx);
+            }
+            if let Some(&(at, _)) = self.pending.first() {
+                cx.wake_after(at.saturating_sub(cx.now()));
+            }
+            if let Some(deadline) = self.link.next_deadline() {
+                cx.wake_after(deadline.saturating_sub(cx.now()));
+            }
+        }
+    }
+
+    impl Node for Scripted {
+        fn on_frame(&mut self, frame: &Frame, cx: &mut Context<'_>) {
+            self.link.handle_frame(cx.now(), frame);
+            self.drive(cx);
+        }
+
+        fn on_timer(&mut self, cx: &mut Context<'_>) {
+            self.drive(cx);
+        }
+    }
+
+    type TestClient = Client<sim::Endpoint, sim::VirtualClock>;
+
+    fn setup(script: Script) -> (TestClient, std::rc::Rc<std::cell::RefCell<Scripted>>) {
+        let tester = StandardId::new(0x7E0).unwrap();
+        let ecu = StandardId::new(0x7E8).unwrap();
+        let bus = Bus::new();
+        let server = bus.add_node(Scripted {
+            link: Link::new(Config::new(ecu, tester)),
+            script,
+            pending: Vec::new(),
+            requests: Vec::new(),
+        });
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        let isotp = IsoTp::new(endpoint, clock, Config::new(tester, ecu));
+        (Client::new(isotp), server)
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn session_timing_and_response_pending() {
+        let (mut client, _) = setup(vec![
+            (
+                vec![0x10, 0x03],
+                vec![(ms(5), vec![0x50, 0x03, 0x00, 0x19, 0x01, 0xF4])],
+            ),
+            (
+                vec![0x31, 0x01, 0xFF, 0x00],
+                vec![
+                    (ms(20), vec![0x7F, 0x31, 0x78]),
+                    (ms(3000), vec![0x7F, 0x31, 0x78]),
+                    (ms(3000), vec![0x71, 0x01, 0xFF, 0x00, 0x00]),
+                ],
+            ),
+            (vec![0x11, 0x01], vec![(ms(10), vec![0x7F, 0x11, 0x22])]),
+        ]);
+
+        client
+            .diagnostic_session_control(session::EXTENDED)
+            .unwrap();
+        assert_eq!(client.timing(), (ms(25), ms(5000)));
+
+        let status = client.routine_control(routine::START, 0xFF00, &[]).unwrap();
+        assert_eq!(status, [0x00]);
+
+        assert!(matches!(
+            client.ecu_reset(reset::HARD),
+            Err(Error::Negative {
+                service: 0x11,
+                code: nrc::CONDITIONS_NOT_CORRECT
+            })
+        ));
+
+        // The response to the DID read never comes.
+        assert!(matches!(
+            client.read_data_by_identifier(0xF190),
+            Err(Error::Timeout)
+        ));
+
+        assert!(matches!(client.request(&[]), Err(Error::InvalidRequest)));
+        assert!(matches!(client.request(&[0xC5]), Err(Error::Timeout)));
+        assert!(matches!(
+            client.security_access(0xFF, |_, seed| seed.to_vec()),
+            Err(Error::InvalidRequest)
+        ));
+    }
+
+    #[test]
+    fn security_access_and_data() {
+        let (mut client, server) = setup(vec![
+            (
+                vec![0x27, 0x01],
+                vec![(ms(1), vec![0x67, 0x01, 0x12, 0x34])],
+            ),
+            (
+                vec![0x27, 0x02, 0xED, 0xCB],
+                vec![(ms(1), vec![0x67, 0x02])],
+            ),
+            (
+                vec![0x22, 0xF1, 0x90],
+                vec![(ms(1), b"\x62\xF1\x90WVWZZZ1JZXW000001".to_vec())],
+            ),
+            (
+                vec![0x2E, 0xF1, 0x98, 1, 2],
+                vec![(ms(1), vec![0x6E, 0xF1, 0x98])],
+            ),
+            (
+                vec![0x19, 0x02, 0x08],
+                vec![(
+                    ms(1),
+                    vec![
+                        0x59, 0x02, 0xFF, 0x12, 0x34, 0x56, 0x08, 0xC0, 0x01, 0x00, 0x09,
+                    ],
+                )],
+            ),
+        ]);
+
+        client
+            .security_access(1, |level, seed| {
+                assert_eq!(level, 1);
+                seed.iter().map(|b| !b).collect()
+            })
+            .unwrap();
+        assert_eq!(
+            client.read_data_by_identifier(0xF190).unwrap(),
+            b"WVWZZZ1JZXW000001"
+        );
+        client.write_data_by_identifier(0xF198, &[1, 2]).unwrap();
+        assert_eq!(
+            client.read_dtcs_by_status_mask(0x08).unwrap(),
+            [
+                Dtc {
+                    code: 0x123456,
+                    status: 0x08
+                },
+                Dtc {
+                    code: 0xC00100,
+                    status: 0x09
+                },
+            ]
+        );
+
+        // Tester present every 2 s, without response.
+        client.idle(Duration::from_secs(5)).unwrap();
+        let server = server.borrow();
+        let tester_present = server
+            .requests
+            .iter()
+            .filter(|r| **r == [0x3E, 0x80])
+            .count();
+        assert_eq!(tester_present, 2);
+    }
+}
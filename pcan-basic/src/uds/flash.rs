@@ -0,0 +1,433 @@
+//! Firmware download to an ECU with the UDS transfer services.
+//!
+//! Every segment of an [`Image`] is erased with a routine, downloaded with
+//! RequestDownload, TransferData and RequestTransferExit, then verified with a
+//! checksum routine. Before flashing, the client has to enter the programming
+//! session and unlock the security level required by the ECU.
+//!
+//! Both routines take the address and length format identifier `0x44`, the
+//! address and the size of the segment as options. The checksum routine
+//! additionally takes the CRC-32 of the segment. A routine status of 0 means
+//! success.
+
+use std::fmt;
+
+use super::{routine, Client, Error as UdsError};
+use crate::{
+    image::{crc32, Image, Segment},
+    Clock, ReadTimeout,
+};
+
+/// Position of the next block to transfer.
+///
+/// Only meaningful for the image it was taken from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Index of the segment.
+    pub segment: usize,
+    /// Bytes of the segment transferred already.
+    pub offset: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub segment: usize,
+    pub segments: usize,
+    /// Bytes transferred, from all segments.
+    pub transferred: usize,
+    pub total: usize,
+}
+
+#[derive(Debug)]
+pub enum Error<E> {
+    Uds(UdsError<E>),
+    /// A routine reported a status other than 0.
+    RoutineFailed {
+        routine: u16,
+        status: Vec<u8>,
+    },
+    /// The ECU accepts no data in transfer data requests.
+    BlockLength(usize),
+}
+
+impl<E> From<UdsError<E>> for Error<E> {
+    fn from(err: UdsError<E>) -> Self {
+        Error::Uds(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Uds(err) => err.fmt(f),
+            Error::RoutineFailed { routine, status } => {
+                write!(
+                    f,
+                    "Routine {:#06x} failed with status {:02X?}",
+                    routine, status
+                )
+            }
+            Error::BlockLength(len) => write!(f, "Invalid maximum block length {}", len),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for Error<E> {}
+
+type ProgressCallback = Box<dyn FnMut(&Progress)>;
+
+pub struct Flasher {
+    erase_routine: Option<u16>,
+    check_routine: Option<u16>,
+    data_format: u8,
+    retries: u32,
+    checkpoint: Checkpoint,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl Default for Flasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Flasher {
+    pub fn new() -> Self {
+        Self {
+            erase_routine: Some(0xFF00),
+            check_routine: Some(0x0202),
+            data_format: 0x00,
+            retries: 2,
+            checkpoint: Checkpoint::default(),
+            on_progress: None,
+        }
+    }
+
+    /// Routine that erases a segment, `0xFF00` by default. `None` skips erasing.
+    pub fn with_erase_routine(&mut self, routine: Option<u16>) -> &mut Self {
+        self.erase_routine = routine;
+        self
+    }
+
+    /// Routine that verifies a segment, `0x0202` by default. `None` skips verification.
+    pub fn with_check_routine(&mut self, routine: Option<u16>) -> &mut Self {
+        self.check_routine = routine;
+        self
+    }
+
+    /// Data format identifier of RequestDownload for compression and encryption,
+    /// 0 by default.
+    pub fn with_data_format(&mut self, data_format: u8) -> &mut Self {
+        self.data_format = data_format;
+        self
+    }
+
+    /// How often a block is sent again after a timeout, defaults to 2.
+    pub fn with_retries(&mut self, retries: u32) -> &mut Self {
+        self.retries = retries;
+        self
+    }
+
+    pub fn on_progress(&mut self, f: impl FnMut(&Progress) + 'static) -> &mut Self {
+        self.on_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Continues a transfer at `checkpoint` with the next call to [`Flasher::flash()`].
+    pub fn resume_from(&mut self, checkpoint: Checkpoint) -> &mut Self {
+        self.checkpoint = checkpoint;
+        self
+    }
+
+    /// Where an interrupted transfer continues.
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.checkpoint
+    }
+
+    /// Downloads `image` to the ECU.
+    ///
+    /// If the transfer fails, calling this again with the same image continues
+    /// with the segment and block that failed, without erasing it again.
+    pub fn flash<C, K>(
+        &mut self,
+        client: &mut Client<C, K>,
+        image: &Image,
+    ) -> Result<(), Error<C::Error>>
+    where
+        C: ReadTimeout,
+        C::Frame: embedded_can::Frame,
+        K: Clock,
+    {
+        let segments = image.segments();
+        let total = image.len();
+        let mut transferred: usize = segments[..self.checkpoint.segment.min(segments.len())]
+            .iter()
+            .map(|s| s.data.len())
+            .sum::<usize>()
+            + self.checkpoint.offset as usize;
+
+        for (index, segment) in segments.iter().enumerate().skip(self.checkpoint.segment) {
+            let size = segment.data.len() as u32;
+            let mut offset = self.checkpoint.offset;
+
+            if offset == 0 {
+                if let Some(routine) = self.erase_routine {
+                    let options = memory_options(segment);
+                    self.run_routine(client, routine, &options)?;
+                }
+            }
+
+            if offset < size {
+                let block_len = client.request_download(
+                    segment.address + offset,
+                    size - offset,
+                    self.data_format,
+                )?;
+                if block_len <= 2 {
+                    return Err(Error::BlockLength(block_len));
+                }
+
+                let mut sequence = 1u8;
+                for block in segment.data[offset as usize..].chunks(block_len - 2) {
+                    self.transfer(client, sequence, block)?;
+                    sequence = sequence.wrapping_add(1);
+                    offset += block.len() as u32;
+                    transferred += block.len();
+                    self.checkpoint = Checkpoint {
+                        segment: index,
+                        offset,
+                    };
+                    if let Some(on_progress) = &mut self.on_progress {
+                        on_progress(&Progress {
+                            segment: index,
+                            segments: segments.len(),
+                            transferred,
+                            total,
+                        });
+                    }
+                }
+                client.request_transfer_exit(&[])?;
+            }
+
+            if let Some(routine) = self.check_routine {
+                let mut options = memory_options(segment);
+                options.extend_from_slice(&crc32(&segment.data).to_be_bytes());
+                self.run_routine(client, routine, &options)?;
+            }
+            self.checkpoint = Checkpoint {
+                segment: index + 1,
+                offset: 0,
+            };
+        }
+
+        self.checkpoint = Checkpoint::default();
+        Ok(())
+    }
+
+    fn transfer<C, K>(
+        &self,
+        client: &mut Client<C, K>,
+        sequence: u8,
+        block: &[u8],
+    ) -> Result<(), Error<C::Error>>
+    where
+        C: ReadTimeout,
+        C::Frame: embedded_can::Frame,
+        K: Clock,
+    {
+        let mut attempts = 0;
+        loop {
+            match client.transfer_data(sequence, block) {
+                // The ECU acknowledges a repeated block without writing it twice.
+                Err(UdsError::Timeout) if attempts < self.retries => attempts += 1,
+                result => return result.map(drop).map_err(Error::Uds),
+            }
+        }
+    }
+
+    fn run_routine<C, K>(
+        &self,
+        client: &mut Client<C, K>,
+        routine: u16,
+        options: &[u8],
+    ) -> Result<(), Error<C::Error>>
+    where
+        C: ReadTimeout,
+        C::Frame: embedded_can::Frame,
+        K: Clock,
+    {
+        let status = client.routine_control(routine::START, routine, options)?;
+        match status.first() {
+            Some(0) | None => Ok(()),
+            Some(_) => Err(Error::RoutineFailed { routine, status }),
+        }
+    }
+}
+
+fn memory_options(segment: &Segment) -> Vec<u8> {
+    let mut options = vec![0x44];
+    options.extend_from_slice(&segment.address.to_be_bytes());
+    options.extend_from_slice(&(segment.data.len() as u32).to_be_bytes());
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        isotp::{Config, IsoTp},
+        sim::{self, Bus},
+        uds::{
+            server::{Reply, Server},
+            session, sid,
+        },
+        StandardId,
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    type TestClient = Client<sim::Endpoint, sim::VirtualClock>;
+
+    fn be_u32(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    /// ECU with erase and checksum routines as expected by the flasher.
+    fn setup(configure: impl FnOnce(&mut Server)) -> (TestClient, Rc<RefCell<Server>>) {
+        let tester = StandardId::new(0x7E0).unwrap();
+        let ecu = StandardId::new(0x7E8).unwrap();
+        let bus = Bus::new();
+        let mut server = Server::new(Config::new(ecu, tester));
+        server
+            .with_max_block_len(0x102)
+            .with_routine(0xFF00, |_, options, memory| {
+                memory.erase(be_u32(&options[1..]), be_u32(&options[5..]) as usize);
+                Ok(vec![0])
+            })
+            .with_routine(0x0202, |_, options, memory| {
+                let data = memory.read(be_u32(&options[1..]), be_u32(&options[5..]) as usize);
+                let valid = data.is_some_and(|data| crc32(data) == be_u32(&options[9..]));
+                Ok(vec![if valid { 0 } else { 1 }])
+            });
+        configure(&mut server);
+        let server = bus.add_node(server);
+
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        let client = Client::new(IsoTp::new(endpoint, clock, Config::new(tester, ecu)));
+        (client, server)
+    }
+
+    fn unlock(client: &mut TestClient) {
+        client
+            .diagnostic_session_control(session::PROGRAMMING)
+            .unwrap();
+        client
+            .security_access(1, |_, seed| seed.iter().map(|b| !b).collect())
+            .unwrap();
+    }
+
+    fn image() -> Image {
+        let mut image = Image::from_binary(0x0800_0000, &[0x5A; 1000]);
+        image.write(0x0800_4000, &(0..=255).collect::<Vec<u8>>());
+        image
+    }
+
+    #[test]
+    fn flash_image() {
+        let (mut client, server) = setup(|_| {});
+        unlock(&mut client);
+
+        let progress = Rc::new(RefCell::new(Vec::new()));
+        let reported = progress.clone();
+        let mut flasher = Flasher::new();
+        flasher.on_progress(move |p| reported.borrow_mut().push(*p));
+        flasher.flash(&mut client, &image()).unwrap();
+
+        let server = server.borrow();
+        assert_eq!(
+            server.memory().read(0x0800_0000, 1000),
+            Some(&[0x5A; 1000][..])
+        );
+        assert_eq!(server.memory().read(0x0800_4000, 256).unwrap()[255], 255);
+
+        // 4 blocks of 256 bytes for the first segment, one for the second.
+        let progress = progress.borrow();
+        assert_eq!(progress.len(), 5);
+        assert_eq!(
+            progress[4],
+            Progress {
+                segment: 1,
+                segments: 2,
+                transferred: 1256,
+                total: 1256
+            }
+        );
+    }
+
+    #[test]
+    fn retry_and_resume() {
+        let (mut client, server) = setup(|server| {
+            // A lost block is sent again, a lost ECU interrupts the transfer.
+            server
+                .with_reply_once(&[sid::TRANSFER_DATA, 2], Reply::Drop)
+                .with_reply_once(&[sid::TRANSFER_DATA, 3], Reply::Drop)
+                .with_reply_once(&[sid::TRANSFER_DATA, 3], Reply::Drop);
+        });
+        unlock(&mut client);
+
+        let mut flasher = Flasher::new();
+        flasher.with_retries(1);
+        assert!(matches!(
+            flasher.flash(&mut client, &image()),
+            Err(Error::Uds(UdsError::Timeout))
+        ));
+        assert_eq!(
+            flasher.checkpoint(),
+            Checkpoint {
+                segment: 0,
+                offset: 512
+            }
+        );
+
+        unlock(&mut client);
+        flasher.flash(&mut client, &image()).unwrap();
+        assert_eq!(flasher.checkpoint(), Checkpoint::default());
+
+        let server = server.borrow();
+        assert_eq!(
+            server.memory().read(0x0800_0000, 1000),
+            Some(&[0x5A; 1000][..])
+        );
+        let downloads: Vec<_> = server
+            .requests()
+            .iter()
+            .filter(|r| r[0] == sid::REQUEST_DOWNLOAD)
+            .map(|r| be_u32(&r[3..]))
+            .collect();
+        assert_eq!(downloads, [0x0800_0000, 0x0800_0200, 0x0800_4000]);
+        let erases = server
+            .requests()
+            .iter()
+            .filter(|r| r.starts_with(&[sid::ROUTINE_CONTROL, 0x01, 0xFF, 0x00]))
+            .count();
+        assert_eq!(erases, 2);
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let (mut client, server) = setup(|_| {});
+        unlock(&mut client);
+        server
+            .borrow_mut()
+            .with_routine(0x0202, |_, _, _| Ok(vec![1]));
+
+        let mut flasher = Flasher::new();
+        assert!(matches!(
+            flasher.flash(&mut client, &image()),
+            Err(Error::RoutineFailed {
+                routine: 0x0202,
+                ..
+            })
+        ));
+    }
+}
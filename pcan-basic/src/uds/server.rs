@@ -0,0 +1,965 @@
+//! Simulated UDS server (ECU) to test clients and flashing tools without hardware.
+//!
+//! [`Server`] is a [`Node`] of a simulated [`Bus`](crate::sim::Bus). It keeps a
+//! table of data identifiers, a DTC store, the active session and security
+//! level, and the memory written by downloads. Requests can be answered with
+//! scripted replies to test delays, pending responses and negative responses.
+//!
+//! ```
+//! use pcan_basic::{isotp::{Config, IsoTp}, sim::Bus, uds::{server::Server, Client}, StandardId};
+//!
+//! let tester = StandardId::new(0x7E0).unwrap();
+//! let ecu = StandardId::new(0x7E8).unwrap();
+//!
+//! let bus = Bus::new();
+//! let mut server = Server::new(Config::new(ecu, tester));
+//! server.with_did(0xF190, b"WVWZZZ1JZXW000001");
+//! bus.add_node(server);
+//!
+//! let endpoint = bus.endpoint();
+//! let clock = endpoint.clock();
+//! let mut client = Client::new(IsoTp::new(endpoint, clock, Config::new(tester, ecu)));
+//! assert_eq!(client.read_data_by_identifier(0xF190).unwrap(), b"WVWZZZ1JZXW000001");
+//! ```
+
+use std::{collections::BTreeMap, time::Duration};
+
+use super::{has_sub_function, nrc, session, sid, Dtc, SUPPRESS_POSITIVE_RESPONSE};
+use crate::{
+    isotp::{Config, Event, Link},
+    sim::{Context, Node},
+    Frame, Id,
+};
+
+/// Interval of repeated response pending messages.
+const PENDING_INTERVAL: Duration = Duration::from_secs(2);
+/// Longest response with a 12 bit ISO-TP length.
+const MAX_RESPONSE_LEN: usize = 4095;
+
+/// Scripted answer to requests, replacing or delaying the normal processing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Sends this response without processing the request.
+    Message(Vec<u8>),
+    /// Rejects the request with a negative response code.
+    Negative(u8),
+    /// Sends response pending messages for the given time, then the response.
+    ///
+    /// Like [`Reply::Delay`], the response comes from the next matching reply
+    /// or from the normal processing.
+    Pending(Duration),
+    /// Sends the response after the given time instead of the response delay.
+    Delay(Duration),
+    /// Ignores the request.
+    Drop,
+}
+
+struct Rule {
+    prefix: Vec<u8>,
+    reply: Reply,
+    once: bool,
+}
+
+struct Did {
+    data: Vec<u8>,
+    writable: bool,
+    security_level: Option<u8>,
+}
+
+struct Download {
+    address: u32,
+    size: u32,
+    offset: u32,
+    sequence: u8,
+}
+
+/// Sparse memory written by downloads.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Memory {
+    segments: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Memory {
+    /// Returns `None` unless the whole range has been written.
+    pub fn read(&self, address: u32, len: usize) -> Option<&[u8]> {
+        let address = address as u64;
+        let (&start, data) = self.segments.range(..=address).next_back()?;
+        let offset = (address - start) as usize;
+        data.get(offset..offset + len)
+    }
+
+    pub fn write(&mut self, address: u32, data: &[u8]) {
+        let start = address as u64;
+        let end = start + data.len() as u64;
+        let touching: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|(&s, d)| s <= end && s + d.len() as u64 >= start)
+            .map(|(&s, _)| s)
+            .collect();
+
+        // Fast path for sequential writes to the end of a segment.
+        if let [s] = *touching.as_slice() {
+            if s <= start {
+                let segment = self.segments.get_mut(&s).unwrap();
+                let offset = (start - s) as usize;
+                if segment.len() < offset + data.len() {
+                    segment.resize(offset + data.len(), 0xFF);
+                }
+                segment[offset..offset + data.len()].copy_from_slice(data);
+                return;
+            }
+        }
+
+        let merged_start = touching.first().map_or(start, |&s| s.min(start));
+        let merged_end = touching
+            .iter()
+            .map(|s| s + self.segments[s].len() as u64)
+            .fold(end, u64::max);
+        let mut merged = vec![0xFF; (merged_end - merged_start) as usize];
+        for s in touching {
+            let segment = self.segments.remove(&s).unwrap();
+            let offset = (s - merged_start) as usize;
+            merged[offset..offset + segment.len()].copy_from_slice(&segment);
+        }
+        let offset = (start - merged_start) as usize;
+        merged[offset..offset + data.len()].copy_from_slice(data);
+        self.segments.insert(merged_start, merged);
+    }
+
+    pub fn erase(&mut self, address: u32, len: usize) {
+        let start = address as u64;
+        let end = start + len as u64;
+        let overlapping: Vec<u64> = self
+            .segments
+            .iter()
+            .filter(|(&s, d)| s < end && s + d.len() as u64 > start)
+            .map(|(&s, _)| s)
+            .collect();
+        for s in overlapping {
+            let segment = self.segments.remove(&s).unwrap();
+            let segment_end = s + segment.len() as u64;
+            if s < start {
+                self.segments
+                    .insert(s, segment[..(start - s) as usize].to_vec());
+            }
+            if segment_end > end {
+                self.segments
+                    .insert(end, segment[(end - s) as usize..].to_vec());
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.segments.clear();
+    }
+
+    /// Contiguous written ranges in ascending order.
+    pub fn segments(&self) -> impl Iterator<Item = (u32, &[u8])> + '_ {
+        self.segments
+            .iter()
+            .map(|(&address, data)| (address as u32, data.as_slice()))
+    }
+}
+
+/// Handles routine control requests with the sub-function, the option record and
+/// the memory. Returns the status record or a negative response code.
+pub type RoutineHandler = Box<dyn FnMut(u8, &[u8], &mut Memory) -> Result<Vec<u8>, u8>>;
+
+/// Computes the security key for a level from the seed.
+pub type KeyFunction = Box<dyn Fn(u8, &[u8]) -> Vec<u8>>;
+
+/// Simulated ECU answering UDS requests.
+pub struct Server {
+    link: Link<Frame>,
+    functional: Option<Link<Frame>>,
+    rules: Vec<Rule>,
+    dids: BTreeMap<u16, Did>,
+    dtcs: Vec<Dtc>,
+    routines: BTreeMap<u16, RoutineHandler>,
+    key: KeyFunction,
+    max_attempts: u32,
+    max_block_len: u16,
+    response_delay: Duration,
+    s3: Duration,
+
+    session: u8,
+    security_level: u8,
+    seed: Option<(u8, Vec<u8>)>,
+    seed_counter: u32,
+    failed_attempts: u32,
+    download: Option<Download>,
+    memory: Memory,
+    last_request: Duration,
+    outgoing: Vec<(Duration, Vec<u8>)>,
+    requests: Vec<Vec<u8>>,
+    resets: u32,
+}
+
+impl Server {
+    /// Creates a server in the default session that receives requests and sends
+    /// responses as configured by `config`.
+    pub fn new(config: Config) -> Self {
+        Self {
+            link: Link::new(config),
+            functional: None,
+            rules: Vec::new(),
+            dids: BTreeMap::new(),
+            dtcs: Vec::new(),
+            routines: BTreeMap::new(),
+            key: Box::new(|_, seed| seed.iter().map(|b| !b).collect()),
+            max_attempts: 3,
+            max_block_len: 0x402,
+            response_delay: Duration::from_millis(1),
+            s3: Duration::from_secs(5),
+
+            session: session::DEFAULT,
+            security_level: 0,
+            seed: None,
+            seed_counter: 0,
+            failed_attempts: 0,
+            download: None,
+            memory: Memory::default(),
+            last_request: Duration::default(),
+            outgoing: Vec::new(),
+            requests: Vec::new(),
+            resets: 0,
+        }
+    }
+
+    /// Also accepts single frame requests sent to the functional address `id`,
+    /// such as `0x7DF`.
+    ///
+    /// Negative responses that a service or parameter is not supported are
+    /// suppressed for functional requests.
+    pub fn with_functional_id(&mut self, id: impl Into<Id>) -> &mut Self {
+        let mut config = self.link.config().clone();
+        config.with_rx_id(id);
+        self.functional = Some(Link::new(config));
+        self
+    }
+
+    /// Adds a read-only data identifier.
+    pub fn with_did(&mut self, did: u16, data: &[u8]) -> &mut Self {
+        self.dids.insert(
+            did,
+            Did {
+                data: data.to_vec(),
+                writable: false,
+                security_level: None,
+            },
+        );
+        self
+    }
+
+    /// Adds a data identifier that can be written in a non-default session,
+    /// after unlocking `security_level` if set.
+    pub fn with_writable_did(
+        &mut self,
+        did: u16,
+        data: &[u8],
+        security_level: Option<u8>,
+    ) -> &mut Self {
+        self.dids.insert(
+            did,
+            Did {
+                data: data.to_vec(),
+                writable: true,
+                security_level,
+            },
+        );
+        self
+    }
+
+    pub fn with_dtc(&mut self, code: u32, status: u8) -> &mut Self {
+        self.dtcs.push(Dtc { code, status });
+        self
+    }
+
+    pub fn with_routine(
+        &mut self,
+        routine: u16,
+        handler: impl FnMut(u8, &[u8], &mut Memory) -> Result<Vec<u8>, u8> + 'static,
+    ) -> &mut Self {
+        self.routines.insert(routine, Box::new(handler));
+        self
+    }
+
+    /// Key expected for a seed. Defaults to the seed with all bits inverted.
+    pub fn with_security_key(&mut self, key: impl Fn(u8, &[u8]) -> Vec<u8> + 'static) -> &mut Self {
+        self.key = Box::new(key);
+        self
+    }
+
+    /// Invalid keys accepted before security access is blocked until reset, defaults to 3.
+    pub fn with_max_attempts(&mut self, attempts: u32) -> &mut Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Maximum length of transfer data requests, defaults to 1026 bytes.
+    pub fn with_max_block_len(&mut self, len: u16) -> &mut Self {
+        self.max_block_len = len;
+        self
+    }
+
+    /// Time between a request and its response, defaults to 1 ms.
+    pub fn with_response_delay(&mut self, delay: Duration) -> &mut Self {
+        self.response_delay = delay;
+        self
+    }
+
+    /// Answers every request starting with `prefix` with `reply`.
+    /// Scripted replies are matched in the order they were added.
+    pub fn with_reply(&mut self, prefix: &[u8], reply: Reply) -> &mut Self {
+        self.rules.push(Rule {
+            prefix: prefix.to_vec(),
+            reply,
+            once: false,
+        });
+        self
+    }
+
+    /// Answers the next request starting with `prefix` with `reply`.
+    pub fn with_reply_once(&mut self, prefix: &[u8], reply: Reply) -> &mut Self {
+        self.rules.push(Rule {
+            prefix: prefix.to_vec(),
+            reply,
+            once: true,
+        });
+        self
+    }
+
+    pub fn session(&self) -> u8 {
+        self.session
+    }
+
+    /// Unlocked security level, 0 when locked.
+    pub fn security_level(&self) -> u8 {
+        self.security_level
+    }
+
+    pub fn did(&self, did: u16) -> Option<&[u8]> {
+        self.dids.get(&did).map(|did| did.data.as_slice())
+    }
+
+    pub fn dtcs(&self) -> &[Dtc] {
+        &self.dtcs
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut Memory {
+        &mut self.memory
+    }
+
+    /// All requests received so far.
+    pub fn requests(&self) -> &[Vec<u8>] {
+        &self.requests
+    }
+
+    /// Number of ECU resets performed.
+    pub fn resets(&self) -> u32 {
+        self.resets
+    }
+
+    fn handle_request(&mut self, now: Duration, request: Vec<u8>, functional: bool) {
+        self.requests.push(request.clone());
+        if request.is_empty() {
+            return;
+        }
+
+        // S3 timeout: without requests the ECU falls back to the default session.
+        if self.session != session::DEFAULT && now - self.last_request > self.s3 {
+            self.enter_session(session::DEFAULT);
+        }
+        self.last_request = now;
+
+        let service = request[0];
+        let mut delay = self.response_delay;
+        let mut start = 0;
+        while let Some(i) = self.rules[start..]
+            .iter()
+            .position(|rule| request.starts_with(&rule.prefix))
+        {
+            let i = start + i;
+            let reply = if self.rules[i].once {
+                start = i;
+                self.rules.remove(i).reply
+            } else {
+                start = i + 1;
+                self.rules[i].reply.clone()
+            };
+
+            match reply {
+                Reply::Message(response) => {
+                    self.queue(now + delay, response);
+                    return;
+                }
+                Reply::Negative(code) => {
+                    self.queue(now + delay, vec![sid::NEGATIVE_RESPONSE, service, code]);
+                    return;
+                }
+                Reply::Pending(duration) => {
+                    let mut at = now + self.response_delay;
+                    while at < now + duration {
+                        self.queue(
+                            at,
+                            vec![sid::NEGATIVE_RESPONSE, service, nrc::RESPONSE_PENDING],
+                        );
+                        at += PENDING_INTERVAL;
+                    }
+                    delay = duration;
+                }
+                Reply::Delay(duration) => delay = duration,
+                Reply::Drop => return,
+            }
+        }
+
+        let suppressed = has_sub_function(service)
+            && request
+                .get(1)
+                .is_some_and(|sub| sub & SUPPRESS_POSITIVE_RESPONSE != 0);
+        match self.process(&request) {
+            Ok(_) if suppressed => {}
+            Ok(response) => self.queue(now + delay, response),
+            Err(nrc::SERVICE_NOT_SUPPORTED)
+            | Err(nrc::SUB_FUNCTION_NOT_SUPPORTED)
+            | Err(nrc::REQUEST_OUT_OF_RANGE)
+                if functional => {}
+            Err(code) => self.queue(now + delay, vec![sid::NEGATIVE_RESPONSE, service, code]),
+        }
+    }
+
+    fn queue(&mut self, at: Duration, mut response: Vec<u8>) {
+        if response.len() > MAX_RESPONSE_LEN {
+            // Positive responses have the service ID plus 0x40.
+            let service = response[0].wrapping_sub(0x40);
+            response = vec![sid::NEGATIVE_RESPONSE, service, nrc::RESPONSE_TOO_LONG];
+        }
+        let index = self.outgoing.partition_point(|(t, _)| *t <= at);
+        self.outgoing.insert(index, (at, response));
+    }
+
+    fn enter_session(&mut self, session: u8) {
+        self.session = session;
+        self.security_level = 0;
+        self.seed = None;
+        self.download = None;
+    }
+
+    /// Returns the positive response or a negative response code.
+    fn process(&mut self, request: &[u8]) -> Result<Vec<u8>, u8> {
+        let service = request[0];
+        let sub_function = request.get(1).map(|sub| sub & !SUPPRESS_POSITIVE_RESPONSE);
+        let default_session = self.session == session::DEFAULT;
+
+        match (service, sub_function) {
+            (sid::DIAGNOSTIC_SESSION_CONTROL, Some(session)) => {
+                check_len(request, 2)?;
+                if !(session::DEFAULT..=session::EXTENDED).contains(&session) {
+                    return Err(nrc::SUB_FUNCTION_NOT_SUPPORTED);
+                }
+                self.enter_session(session);
+                // P2 of 50 ms and P2* of 5 s.
+                Ok(vec![0x50, session, 0x00, 0x32, 0x01, 0xF4])
+            }
+            (sid::ECU_RESET, Some(reset_type)) => {
+                check_len(request, 2)?;
+                if !(1..=3).contains(&reset_type) {
+                    return Err(nrc::SUB_FUNCTION_NOT_SUPPORTED);
+                }
+                self.enter_session(session::DEFAULT);
+                self.failed_attempts = 0;
+                self.resets += 1;
+                Ok(vec![0x51, reset_type])
+            }
+            (sid::CLEAR_DIAGNOSTIC_INFORMATION, _) => {
+                check_len(request, 4)?;
+                let group = u32::from_be_bytes([0, request[1], request[2], request[3]]);
+                self.dtcs
+                    .retain(|dtc| group != 0xFFFFFF && dtc.code != group);
+                Ok(vec![0x54])
+            }
+            (sid::READ_DTC_INFORMATION, Some(report)) => {
+                let matching =
+                    |mask: u8| self.dtcs.iter().filter(move |dtc| dtc.status & mask != 0);
+                let mut response = vec![0x59, report, 0xFF];
+                match report {
+                    0x01 => {
+                        check_len(request, 3)?;
+                        let count = matching(request[2]).count() as u16;
+                        response.push(0x01);
+                        response.extend_from_slice(&count.to_be_bytes());
+                    }
+                    0x02 | 0x0A => {
+                        let mask = if report == 0x02 {
+                            check_len(request, 3)?;
+                            request[2]
+                        } else {
+                            check_len(request, 2)?;
+                            0xFF
+                        };
+                        for dtc in matching(mask) {
+                            response.extend_from_slice(&dtc.code.to_be_bytes()[1..]);
+                            response.push(dtc.status);
+                        }
+                    }
+                    _ => return Err(nrc::SUB_FUNCTION_NOT_SUPPORTED),
+                }
+                Ok(response)
+            }
+            (sid::READ_DATA_BY_IDENTIFIER, _) => {
+                if request.len() < 3 || request.len().is_multiple_of(2) {
+                    return Err(nrc::INCORRECT_MESSAGE_LENGTH);
+                }
+                let mut response = vec![0x62];
+                for did in request[1..].chunks_exact(2) {
+                    let entry = self
+                        .dids
+                        .get(&u16::from_be_bytes([did[0], did[1]]))
+                        .ok_or(nrc::REQUEST_OUT_OF_RANGE)?;
+                    response.extend_from_slice(did);
+                    response.extend_from_slice(&entry.data);
+                }
+                Ok(response)
+            }
+            (sid::WRITE_DATA_BY_IDENTIFIER, _) => {
+                if request.len() < 4 {
+                    return Err(nrc::INCORRECT_MESSAGE_LENGTH);
+                }
+                if default_session {
+                    return Err(nrc::SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION);
+                }
+                let security_level = self.security_level;
+                let entry = match self
+                    .dids
+                    .get_mut(&u16::from_be_bytes([request[1], request[2]]))
+                {
+                    Some(entry) if entry.writable => entry,
+                    _ => return Err(nrc::REQUEST_OUT_OF_RANGE),
+                };
+                if entry
+                    .security_level
+                    .is_some_and(|level| level != security_level)
+                {
+                    return Err(nrc::SECURITY_ACCESS_DENIED);
+                }
+                entry.data = request[3..].to_vec();
+                Ok(vec![0x6E, request[1], request[2]])
+            }
+            (sid::SECURITY_ACCESS, Some(level)) => {
+                if default_session {
+                    return Err(nrc::SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION);
+                }
+                if level == 0 || level > 0x7E {
+                    return Err(nrc::SUB_FUNCTION_NOT_SUPPORTED);
+                }
+                if !level.is_multiple_of(2) {
+                    check_len(request, 2)?;
+                    if self.failed_attempts >= self.max_attempts {
+                        return Err(nrc::EXCEEDED_NUMBER_OF_ATTEMPTS);
+                    }
+                    let seed = if self.security_level == level {
+                        vec![0; 4]
+                    } else {
+                        self.seed_counter += 1;
+                        let seed = self.seed_counter.wrapping_mul(0x9E37_79B9).to_be_bytes();
+                        self.seed = Some((level, seed.to_vec()));
+                        seed.to_vec()
+                    };
+                    let mut response = vec![0x67, level];
+                    response.extend(seed);
+                    Ok(response)
+                } else {
+                    let seed = match self.seed.take() {
+                        Some((requested, seed)) if requested + 1 == level => seed,
+                        _ => return Err(nrc::REQUEST_SEQUENCE_ERROR),
+                    };
+                    if request[2..] == (self.key)(level - 1, &seed)[..] {
+                        self.security_level = level - 1;
+                        self.failed_attempts = 0;
+                        Ok(vec![0x67, level])
+                    } else {
+                        self.failed_attempts += 1;
+                        if self.failed_attempts >= self.max_attempts {
+                            Err(nrc::EXCEEDED_NUMBER_OF_ATTEMPTS)
+                        } else {
+                            Err(nrc::INVALID_KEY)
+                        }
+                    }
+                }
+            }
+            (sid::ROUTINE_CONTROL, Some(control)) => {
+                if request.len() < 4 {
+                    return Err(nrc::INCORRECT_MESSAGE_LENGTH);
+                }
+                if default_session {
+                    return Err(nrc::SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION);
+                }
+                if !(1..=3).contains(&control) {
+                    return Err(nrc::SUB_FUNCTION_NOT_SUPPORTED);
+                }
+                let routine = u16::from_be_bytes([request[2], request[3]]);
+                let handler = self
+                    .routines
+                    .get_mut(&routine)
+                    .ok_or(nrc::REQUEST_OUT_OF_RANGE)?;
+                let status = handler(control, &request[4..], &mut self.memory)?;
+                let mut response = vec![0x71, control, request[2], request[3]];
+                response.extend(status);
+                Ok(response)
+            }
+            (sid::REQUEST_DOWNLOAD, _) => {
+                if request.len() < 3 {
+                    return Err(nrc::INCORRECT_MESSAGE_LENGTH);
+                }
+                let address_len = (request[2] & 0x0F) as usize;
+                let size_len = (request[2] >> 4) as usize;
+                if !(1..=4).contains(&address_len) || !(1..=4).contains(&size_len) {
+                    return Err(nrc::REQUEST_OUT_OF_RANGE);
+                }
+                check_len(request, 3 + address_len + size_len)?;
+                if self.session != session::PROGRAMMING {
+                    return Err(nrc::SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION);
+                }
+                if self.security_level == 0 {
+                    return Err(nrc::SECURITY_ACCESS_DENIED);
+                }
+                if self.download.is_some() {
+                    return Err(nrc::CONDITIONS_NOT_CORRECT);
+                }
+                let address = be_value(&request[3..3 + address_len]);
+                let size = be_value(&request[3 + address_len..]);
+                if address.checked_add(size).is_none() {
+                    return Err(nrc::REQUEST_OUT_OF_RANGE);
+                }
+                self.download = Some(Download {
+                    address,
+                    size,
+                    offset: 0,
+                    sequence: 1,
+                });
+                let mut response = vec![0x74, 0x20];
+                response.extend_from_slice(&self.max_block_len.to_be_bytes());
+                Ok(response)
+            }
+            (sid::TRANSFER_DATA, Some(_)) => {
+                let download = self.download.as_mut().ok_or(nrc::REQUEST_SEQUENCE_ERROR)?;
+                // Not a sub-function, the block sequence counter uses all bits.
+                let sequence = request[1];
+                let data = &request[2..];
+                if request.len() > self.max_block_len as usize {
+                    return Err(nrc::INCORRECT_MESSAGE_LENGTH);
+                }
+                if sequence == download.sequence {
+                    if download.offset as usize + data.len() > download.size as usize {
+                        return Err(nrc::TRANSFER_DATA_SUSPENDED);
+                    }
+                    let address = download
+                        .address
+                        .checked_add(download.offset)
+                        .ok_or(nrc::TRANSFER_DATA_SUSPENDED)?;
+                    self.memory.write(address, data);
+                    download.offset += data.len() as u32;
+                    download.sequence = download.sequence.wrapping_add(1);
+                } else if sequence != download.sequence.wrapping_sub(1) {
+                    // A repeated block is acknowledged without writing it again.
+                    return Err(nrc::WRONG_BLOCK_SEQUENCE_COUNTER);
+                }
+                Ok(vec![0x76, sequence])
+            }
+            (sid::REQUEST_TRANSFER_EXIT, _) => {
+                match &self.download {
+                    Some(download) if download.offset == download.size => {}
+                    _ => return Err(nrc::REQUEST_SEQUENCE_ERROR),
+                }
+                self.download = None;
+                Ok(vec![0x77])
+            }
+            (sid::TESTER_PRESENT, Some(0)) => {
+                check_len(request, 2)?;
+                Ok(vec![0x7E, 0x00])
+            }
+            (sid::TESTER_PRESENT, Some(_)) => Err(nrc::SUB_FUNCTION_NOT_SUPPORTED),
+            (service, None) if has_sub_function(service) => Err(nrc::INCORRECT_MESSAGE_LENGTH),
+            _ => Err(nrc::SERVICE_NOT_SUPPORTED),
+        }
+    }
+
+    fn drive(&mut self, cx: &mut Context<'_>) {
+        loop {
+            while let Some(event) = self.link.poll(cx.now()) {
+                match event {
+                    Event::Transmit(frame) => cx.send(frame),
+                    Event::Received(request) => self.handle_request(cx.now(), request, false),
+                    _ => {}
+                }
+            }
+            let functional = self
+                .functional
+                .as_mut()
+                .and_then(|link| link.poll(cx.now()));
+            if let Some(Event::Received(request)) = functional {
+                self.handle_request(cx.now(), request, true);
+                continue;
+            }
+            match self.outgoing.first() {
+                Some((at, _)) if *at <= cx.now() && !self.link.is_sending() => {
+                    let (_, response) = self.outgoing.remove(0);
+                    // Responses were limited to MAX_RESPONSE_LEN when queued.
+                    self.link.send(&response).unwrap();
+                }
+                _ => break,
+            }
+        }
+
+        if let Some((at, _)) = self.outgoing.first() {
+            cx.wake_after(at.saturating_sub(cx.now()));
+        }
+        if let Some(deadline) = self.link.next_deadline() {
+            cx.wake_after(deadline.saturating_sub(cx.now()));
+        }
+    }
+}
+
+impl Node for Server {
+    fn on_frame(&mut self, frame: &Frame, cx: &mut Context<'_>) {
+        self.link.handle_frame(cx.now(), frame);
+        if let Some(functional) = &mut self.functional {
+            functional.handle_frame(cx.now(), frame);
+        }
+        self.drive(cx);
+    }
+
+    fn on_timer(&mut self, cx: &mut Context<'_>) {
+        self.drive(cx);
+    }
+}
+
+fn check_len(request: &[u8], len: usize) -> Result<(), u8> {
+    if request.len() == len {
+        Ok(())
+    } else {
+        Err(nrc::INCORRECT_MESSAGE_LENGTH)
+    }
+}
+
+fn be_value(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    buf[4 - bytes.len()..].copy_from_slice(bytes);
+    u32::from_be_bytes(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        isotp::IsoTp,
+        sim::{self, Bus},
+        uds::{reset, routine, Client, Error},
+        Clock, StandardId,
+    };
+    use std::{cell::RefCell, rc::Rc};
+
+    type TestClient = Client<sim::Endpoint, sim::VirtualClock>;
+
+    fn setup(configure: impl FnOnce(&mut Server)) -> (TestClient, Rc<RefCell<Server>>) {
+        let tester = StandardId::new(0x7E0).unwrap();
+        let ecu = StandardId::new(0x7E8).unwrap();
+        let bus = Bus::new();
+        let mut server = Server::new(Config::new(ecu, tester));
+        configure(&mut server);
+        let server = bus.add_node(server);
+        let endpoint = bus.endpoint();
+        let clock = endpoint.clock();
+        let client = Client::new(IsoTp::new(endpoint, clock, Config::new(tester, ecu)));
+        (client, server)
+    }
+
+    fn negative<T, E>(result: Result<T, Error<E>>) -> Option<u8> {
+        match result {
+            Err(Error::Negative { code, .. }) => Some(code),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn sessions_security_and_dids() {
+        let (mut client, server) = setup(|server| {
+            server
+                .with_writable_did(0xF198, b"old", Some(1))
+                .with_dtc(0x123456, 0x09)
+                .with_dtc(0xC00100, 0x40);
+        });
+
+        assert_eq!(
+            negative(client.write_data_by_identifier(0xF198, b"new")),
+            Some(nrc::SERVICE_NOT_SUPPORTED_IN_ACTIVE_SESSION)
+        );
+        client
+            .diagnostic_session_control(session::EXTENDED)
+            .unwrap();
+        assert_eq!(
+            negative(client.write_data_by_identifier(0xF198, b"new")),
+            Some(nrc::SECURITY_ACCESS_DENIED)
+        );
+
+        assert_eq!(
+            negative(client.security_access(1, |_, _| vec![0; 4])),
+            Some(nrc::INVALID_KEY)
+        );
+        client
+            .security_access(1, |_, seed| seed.iter().map(|b| !b).collect())
+            .unwrap();
+        client.write_data_by_identifier(0xF198, b"new").unwrap();
+        assert_eq!(client.read_data_by_identifier(0xF198).unwrap(), b"new");
+        assert_eq!(
+            negative(client.read_data_by_identifier(0x1234)),
+            Some(nrc::REQUEST_OUT_OF_RANGE)
+        );
+
+        let dtcs = client.read_dtcs_by_status_mask(0x01).unwrap();
+        assert_eq!(
+            dtcs,
+            [Dtc {
+                code: 0x123456,
+                status: 0x09
+            }]
+        );
+        client.clear_diagnostic_information(0xFFFFFF).unwrap();
+        assert!(server.borrow().dtcs().is_empty());
+
+        // The session times out without tester present.
+        client.idle(Duration::from_secs(6)).unwrap();
+        client.tester_present().unwrap();
+        assert_eq!(server.borrow().session(), session::EXTENDED);
+        client.isotp().clock().sleep(Duration::from_secs(6));
+        client.tester_present().unwrap();
+        assert_eq!(server.borrow().session(), session::DEFAULT);
+        assert_eq!(server.borrow().security_level(), 0);
+
+        client.ecu_reset(reset::SOFT).unwrap();
+        assert_eq!(server.borrow().resets(), 1);
+    }
+
+    #[test]
+    fn scripted_replies() {
+        let (mut client, server) = setup(|server| {
+            server
+                .with_routine(0xFF00, |_, _, memory| {
+                    memory.clear();
+                    Ok(vec![0x00])
+                })
+                .with_reply_once(
+                    &[0x31, 0x01, 0xFF, 0x00],
+                    Reply::Pending(Duration::from_secs(7)),
+                )
+                .with_reply_once(&[0x11], Reply::Negative(nrc::CONDITIONS_NOT_CORRECT))
+                .with_reply_once(
+                    &[0x22, 0xF1, 0x90],
+                    Reply::Message(vec![0x62, 0xF1, 0x90, 0x42]),
+                )
+                .with_reply(&[0x22, 0xF1, 0x91], Reply::Drop);
+        });
+
+        client
+            .diagnostic_session_control(session::EXTENDED)
+            .unwrap();
+        let start = client.isotp().clock().now();
+        let status = client.routine_control(routine::START, 0xFF00, &[]).unwrap();
+        assert_eq!(status, [0x00]);
+        assert!(client.isotp().clock().now() - start >= Duration::from_secs(7));
+
+        assert_eq!(
+            negative(client.ecu_reset(reset::HARD)),
+            Some(nrc::CONDITIONS_NOT_CORRECT)
+        );
+        client.ecu_reset(reset::HARD).unwrap();
+
+        assert_eq!(client.read_data_by_identifier(0xF190).unwrap(), [0x42]);
+        assert!(matches!(
+            client.read_data_by_identifier(0xF191),
+            Err(Error::Timeout)
+        ));
+        assert_eq!(server.borrow().requests().len(), 6);
+    }
+
+    #[test]
+    fn download() {
+        let (mut client, server) = setup(|server| {
+            server.with_max_block_len(0x82);
+        });
+        client
+            .diagnostic_session_control(session::PROGRAMMING)
+            .unwrap();
+        client
+            .security_access(1, |_, seed| seed.iter().map(|b| !b).collect())
+            .unwrap();
+
+        let image: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+        let block_len = client.request_download(0x0800_4000, 1000, 0x00).unwrap();
+        assert_eq!(block_len, 0x82);
+        for (i, block) in image.chunks(block_len - 2).enumerate() {
+            client.transfer_data(i as u8 + 1, block).unwrap();
+        }
+        // A repeated block is accepted, a skipped one is not.
+        client.transfer_data(8, &image[896..]).unwrap();
+        assert_eq!(
+            negative(client.transfer_data(10, &[])),
+            Some(nrc::WRONG_BLOCK_SEQUENCE_COUNTER)
+        );
+        client.request_transfer_exit(&[]).unwrap();
+
+        assert_eq!(
+            server.borrow().memory().read(0x0800_4000, 1000),
+            Some(&image[..])
+        );
+
+        assert_eq!(
+            negative(client.request_download(0xFFFF_FF00, 0x200, 0x00)),
+            Some(nrc::REQUEST_OUT_OF_RANGE)
+        );
+    }
+
+    #[test]
+    fn response_too_long() {
+        let (mut client, _) = setup(|server| {
+            server
+                .with_did(0xF190, &[0x55; 4093])
+                .with_did(0xF191, &[0x55; 4092]);
+        });
+        assert_eq!(
+            negative(client.read_data_by_identifier(0xF190)),
+            Some(nrc::RESPONSE_TOO_LONG)
+        );
+        assert_eq!(client.read_data_by_identifier(0xF191).unwrap().len(), 4092);
+    }
+
+    #[test]
+    fn memory_segments() {
+        let mut memory = Memory::default();
+        memory.write(0x100, &[1, 2, 3, 4]);
+        memory.write(0x104, &[5, 6]);
+        memory.write(0x0FE, &[0xA, 0xB]);
+        memory.write(0x200, &[9]);
+        assert_eq!(
+            memory.read(0x0FE, 8),
+            Some(&[0xA, 0xB, 1, 2, 3, 4, 5, 6][..])
+        );
+        assert_eq!(memory.read(0x0FE, 9), None);
+
+        memory.erase(0x101, 2);
+        let segments: Vec<_> = memory.segments().collect();
+        assert_eq!(
+            segments,
+            [
+                (0x0FE, &[0xA, 0xB, 1][..]),
+                (0x103, &[4, 5, 6][..]),
+                (0x200, &[9][..]),
+            ]
+        );
+    }
+}
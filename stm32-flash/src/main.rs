@@ -0,0 +1,540 @@
+//! Flashes STM32 microcontrollers through their CAN bootloader (ST AN3154)
+//! with a PCAN interface.
+
+use std::{
+    cell::Cell,
+    convert::TryFrom,
+    fmt, fs,
+    path::{Path, PathBuf},
+    process,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use clap::{Parser, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+use pcan_basic::{
+    image::{self, crc32, Image},
+    stm32::{
+        self,
+        flash::{self, Erase, Flasher, Layout, Verify},
+        Bootloader,
+    },
+    Interface, ReadTimeout, Timeout, TimeoutError,
+};
+use pcan_basic_sys::*;
+use serde_json::json;
+
+const EXIT_CODES: &str = "\
+Exit codes:
+  0  Success
+  2  Invalid arguments
+  3  Image cannot be loaded or does not fit into flash
+  4  CAN interface error
+  5  No response from the bootloader
+  6  Command rejected by the bootloader, for example due to protection
+  7  Verification failed
+  8  Unexpected response from the bootloader";
+
+#[derive(Parser)]
+#[command(version, about, after_help = EXIT_CODES)]
+struct Args {
+    /// Firmware image
+    image: PathBuf,
+
+    /// PCAN-USB channel, usb1 to usb16
+    #[arg(long, default_value = "usb1", value_parser = parse_channel)]
+    channel: u16,
+
+    /// Bit rate, 10k to 1M. 125k uses a late sample point that the bootloader requires
+    #[arg(long, default_value = "125k", value_parser = parse_bitrate)]
+    bitrate: u16,
+
+    /// Bit timing as SJA1000 BTR0/BTR1 register value, overrides --bitrate
+    #[arg(long, value_parser = parse_u16)]
+    btr0btr1: Option<u16>,
+
+    #[arg(long, value_enum, default_value_t = Format::Auto)]
+    format: Format,
+
+    /// Load address of binary images
+    #[arg(long, default_value = "0x08000000", value_parser = parse_u32)]
+    address: u32,
+
+    /// Start of flash
+    #[arg(long, default_value = "0x08000000", value_parser = parse_u32)]
+    flash_base: u32,
+
+    /// Flash pages as COUNTxSIZE list, STM32F4 sectors by default
+    #[arg(long, default_value = "4x16k,1x64k,7x128k", value_parser = parse_pages)]
+    pages: PageList,
+
+    #[arg(long, value_enum, default_value_t = EraseMode::Pages)]
+    erase: EraseMode,
+
+    #[arg(long, value_enum, default_value_t = VerifyMode::ReadBack)]
+    verify: VerifyMode,
+
+    /// Removes readout and write protection before flashing.
+    /// Removing readout protection erases the whole flash.
+    #[arg(long)]
+    unprotect: bool,
+
+    /// Enables readout protection after flashing
+    #[arg(long, conflicts_with = "go")]
+    protect: bool,
+
+    /// Starts the application after flashing, at the given address or the start of the image
+    #[arg(long, num_args = 0..=1, value_parser = parse_u32)]
+    go: Option<Option<u32>>,
+
+    /// Response timeout in milliseconds, long enough to erase 8 pages
+    #[arg(long, default_value_t = 20_000)]
+    timeout: u64,
+
+    /// How long to wait for the bootloader to answer, in milliseconds
+    #[arg(long, default_value_t = 5_000)]
+    connect_timeout: u64,
+
+    /// How often flashing resumes after a failed block
+    #[arg(long, default_value_t = 2)]
+    retries: u32,
+
+    /// Prints a JSON report instead of progress messages
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// From the file contents and extension
+    Auto,
+    Hex,
+    Srec,
+    Elf,
+    Bin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum EraseMode {
+    /// Pages touched by the image
+    Pages,
+    /// The whole flash
+    Mass,
+    /// Nothing
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum VerifyMode {
+    /// Reads back every block after writing it
+    ReadBack,
+    /// Reads back every segment and compares its CRC-32
+    Crc,
+    None,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PageList(Vec<(usize, u32)>);
+
+/// Cause of a failure, reported as exit code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    Image = 3,
+    Interface = 4,
+    NoResponse = 5,
+    Rejected = 6,
+    Verify = 7,
+    Protocol = 8,
+}
+
+#[derive(Debug)]
+struct Failed {
+    failure: Failure,
+    message: String,
+}
+
+impl Failed {
+    fn new(failure: Failure, message: impl ToString) -> Self {
+        Self {
+            failure,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<image::Error> for Failed {
+    fn from(err: image::Error) -> Self {
+        Failed::new(Failure::Image, err)
+    }
+}
+
+impl<E: fmt::Display> From<stm32::Error<TimeoutError<E>>> for Failed {
+    fn from(err: stm32::Error<TimeoutError<E>>) -> Self {
+        let failure = match &err {
+            stm32::Error::Can(TimeoutError::Timeout) => Failure::NoResponse,
+            stm32::Error::Can(TimeoutError::Can(_)) => Failure::Interface,
+            stm32::Error::Nack(_) => Failure::Rejected,
+            stm32::Error::UnexpectedResponse { .. } => Failure::Protocol,
+            stm32::Error::InvalidArgument => Failure::Image,
+        };
+        Failed::new(failure, err)
+    }
+}
+
+impl<E: fmt::Display> From<flash::Error<TimeoutError<E>>> for Failed {
+    fn from(err: flash::Error<TimeoutError<E>>) -> Self {
+        match err {
+            flash::Error::Bootloader(err) => err.into(),
+            flash::Error::OutsideFlash(_)
+            | flash::Error::PageNumber(_)
+            | flash::Error::Checkpoint(_) => Failed::new(Failure::Image, err),
+            flash::Error::Mismatch(_) | flash::Error::Crc { .. } => {
+                Failed::new(Failure::Verify, err)
+            }
+        }
+    }
+}
+
+/// What happened, for the JSON report.
+#[derive(Default)]
+struct Report {
+    segments: Vec<serde_json::Value>,
+    device_id: Option<u16>,
+    bootloader_version: Option<u8>,
+    written: Rc<Cell<usize>>,
+    resumed: u32,
+    started: Option<u32>,
+}
+
+fn main() {
+    let args = Args::parse();
+    let start = Instant::now();
+    let mut report = Report::default();
+    let result = run(&args, &mut report);
+
+    let exit_code = match &result {
+        Ok(()) => 0,
+        Err(failed) => failed.failure as i32,
+    };
+    if args.json {
+        let report = json!({
+            "result": if result.is_ok() { "ok" } else { "error" },
+            "exit_code": exit_code,
+            "error": result.as_ref().err().map(|f| f.message.clone()),
+            "image": args.image,
+            "segments": report.segments,
+            "device_id": report.device_id.map(|id| format!("{:#05x}", id)),
+            "bootloader_version": report.bootloader_version.map(|v| format!("{}.{}", v >> 4, v & 0xF)),
+            "written": report.written.get(),
+            "resumed": report.resumed,
+            "started": report.started.map(|a| format!("{:#010x}", a)),
+            "duration_ms": start.elapsed().as_millis() as u64,
+        });
+        println!("{}", report);
+    } else if let Err(failed) = &result {
+        eprintln!("error: {}", failed.message);
+    }
+    process::exit(exit_code);
+}
+
+fn run(args: &Args, report: &mut Report) -> Result<(), Failed> {
+    let image = load_image(&args.image, args.format, args.address)?;
+    if image.is_empty() {
+        return Err(Failed::new(Failure::Image, "Image contains no data"));
+    }
+    let layout = Layout::new(args.flash_base, &args.pages.0);
+    if let Err(address) = layout.pages_touched(image.segments()) {
+        return Err(Failed::new(
+            Failure::Image,
+            format!("Address {:#010x} is outside of flash", address),
+        ));
+    }
+    report.segments = image
+        .segments()
+        .iter()
+        .map(|s| {
+            json!({
+                "address": format!("{:#010x}", s.address),
+                "size": s.data.len(),
+                "crc32": format!("{:#010x}", crc32(&s.data)),
+            })
+        })
+        .collect();
+
+    let btr0btr1 = args.btr0btr1.unwrap_or(args.bitrate);
+    let can =
+        Interface::open(args.channel, btr0btr1).map_err(|e| Failed::new(Failure::Interface, e))?;
+    program(can, &image, layout, args, report)
+}
+
+/// Flashes the image through any CAN interface, a simulated one in the tests.
+fn program<C>(
+    can: C,
+    image: &Image,
+    layout: Layout,
+    args: &Args,
+    report: &mut Report,
+) -> Result<(), Failed>
+where
+    C: ReadTimeout<Frame = pcan_basic::Frame>,
+    C::Error: fmt::Display,
+{
+    let mut bl = Bootloader::new(Timeout::new(can, Duration::from_millis(args.timeout)));
+    let log = |message: &str| {
+        if !args.json {
+            println!("{}", message);
+        }
+    };
+
+    connect(&mut bl, args)?;
+    let id = bl.get_id()?;
+    let version = bl.get()?.version;
+    report.device_id = Some(id);
+    report.bootloader_version = Some(version);
+    log(&format!(
+        "Connected to device {:#05x}, bootloader version {}.{}",
+        id,
+        version >> 4,
+        version & 0xF
+    ));
+
+    if args.unprotect {
+        let mut probe = [0];
+        if let Err(stm32::Error::Nack(_)) = bl.read_memory(args.flash_base, &mut probe) {
+            log("Removing readout protection, this erases the flash");
+            bl.readout_unprotect()?;
+            connect(&mut bl, args)?;
+        }
+        bl.write_unprotect()?;
+        connect(&mut bl, args)?;
+    }
+
+    let progress = if args.json {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(image.len() as u64)
+    };
+    progress
+        .set_style(ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} {msg}").unwrap());
+    let mut flasher = Flasher::new(layout);
+    flasher
+        .with_erase(match args.erase {
+            EraseMode::Pages => Erase::Pages,
+            EraseMode::Mass => Erase::Mass,
+            EraseMode::None => Erase::None,
+        })
+        .with_verify(match args.verify {
+            VerifyMode::ReadBack => Verify::ReadBack,
+            VerifyMode::Crc => Verify::Crc,
+            VerifyMode::None => Verify::None,
+        });
+    let (bar, written) = (progress.clone(), report.written.clone());
+    flasher.on_progress(move |p| {
+        bar.set_position(p.written as u64);
+        written.set(p.written);
+    });
+
+    progress.set_message("erasing");
+    loop {
+        match flasher.flash(&mut bl, image).map_err(Failed::from) {
+            Ok(()) => break,
+            Err(failed)
+                if report.resumed < args.retries
+                    && matches!(failed.failure, Failure::NoResponse | Failure::Protocol) =>
+            {
+                report.resumed += 1;
+                progress.set_message(format!("resuming: {}", failed.message));
+                connect(&mut bl, args)?;
+            }
+            Err(failed) => {
+                progress.abandon();
+                return Err(failed);
+            }
+        }
+    }
+    progress.finish_with_message("written and verified");
+
+    if args.protect {
+        log("Enabling readout protection");
+        bl.readout_protect()?;
+    }
+    if let Some(address) = args.go {
+        let address = address.unwrap_or(image.segments()[0].address);
+        log(&format!("Starting application at {:#010x}", address));
+        bl.go(address)?;
+        report.started = Some(address);
+    }
+    Ok(())
+}
+
+fn load_image(path: &Path, format: Format, address: u32) -> Result<Image, Failed> {
+    let is_bin = path.extension().is_some_and(|e| e == "bin");
+    let text = || fs::read_to_string(path).map_err(image::Error::Io);
+    let image = match format {
+        Format::Auto if is_bin => {
+            Image::from_binary(address, &fs::read(path).map_err(image::Error::Io)?)
+        }
+        Format::Auto => Image::load(path)?,
+        Format::Hex => Image::from_ihex(&text()?)?,
+        Format::Srec => Image::from_srec(&text()?)?,
+        Format::Elf => Image::from_elf(&fs::read(path).map_err(image::Error::Io)?)?,
+        Format::Bin => Image::from_binary(address, &fs::read(path).map_err(image::Error::Io)?),
+    };
+    Ok(image)
+}
+
+/// Synchronizes with the bootloader, after startup or a reset.
+fn connect<C>(bl: &mut Bootloader<Timeout<C>>, args: &Args) -> Result<(), Failed>
+where
+    C: ReadTimeout<Frame = pcan_basic::Frame>,
+    C::Error: fmt::Display,
+{
+    let deadline = Instant::now() + Duration::from_millis(args.connect_timeout);
+    bl.can().set_timeout(Duration::from_millis(100));
+    let result = loop {
+        match bl.sync() {
+            // A bootloader that is synchronized already rejects the sync command.
+            Ok(()) | Err(stm32::Error::Nack(_)) => break Ok(()),
+            Err(stm32::Error::Can(TimeoutError::Timeout)) if Instant::now() < deadline => {}
+            Err(err) => break Err(Failed::from(err)),
+        }
+    };
+    bl.can().set_timeout(Duration::from_millis(args.timeout));
+    result
+}
+
+fn parse_u32(s: &str) -> Result<u32, String> {
+    let s = s.replace('_', "");
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+fn parse_u16(s: &str) -> Result<u16, String> {
+    let value = parse_u32(s)?;
+    u16::try_from(value).map_err(|e| e.to_string())
+}
+
+fn parse_size(s: &str) -> Result<u32, String> {
+    let s = s.to_ascii_lowercase();
+    match s.strip_suffix('k') {
+        Some(kib) => parse_u32(kib)?
+            .checked_mul(1024)
+            .ok_or_else(|| format!("size {} too large", s)),
+        None => parse_u32(&s),
+    }
+}
+
+fn parse_pages(s: &str) -> Result<PageList, String> {
+    s.split(',')
+        .map(|group| {
+            let (count, size) = group
+                .split_once('x')
+                .ok_or_else(|| format!("expected COUNTxSIZE, got {}", group))?;
+            let count = count
+                .trim()
+                .parse()
+                .map_err(|_| format!("invalid count {}", count))?;
+            Ok((count, parse_size(size.trim())?))
+        })
+        .collect::<Result<_, _>>()
+        .map(PageList)
+}
+
+fn parse_channel(s: &str) -> Result<u16, String> {
+    const USB: [u32; 16] = [
+        PCAN_USBBUS1,
+        PCAN_USBBUS2,
+        PCAN_USBBUS3,
+        PCAN_USBBUS4,
+        PCAN_USBBUS5,
+        PCAN_USBBUS6,
+        PCAN_USBBUS7,
+        PCAN_USBBUS8,
+        PCAN_USBBUS9,
+        PCAN_USBBUS10,
+        PCAN_USBBUS11,
+        PCAN_USBBUS12,
+        PCAN_USBBUS13,
+        PCAN_USBBUS14,
+        PCAN_USBBUS15,
+        PCAN_USBBUS16,
+    ];
+    s.to_ascii_lowercase()
+        .strip_prefix("usb")
+        .and_then(|n| n.parse::<usize>().ok())
+        .and_then(|n| USB.get(n.checked_sub(1)?))
+        .map(|&channel| channel as u16)
+        .ok_or_else(|| format!("unknown channel {}, expected usb1 to usb16", s))
+}
+
+fn parse_bitrate(s: &str) -> Result<u16, String> {
+    let btr0btr1 = match s.to_ascii_lowercase().as_str() {
+        "1m" => PCAN_BAUD_1M,
+        "800k" => PCAN_BAUD_800K,
+        "500k" => PCAN_BAUD_500K,
+        "250k" => PCAN_BAUD_250K,
+        "125k" => pcan_basic::BTR0BTR1_125K_STM32 as u32,
+        "100k" => PCAN_BAUD_100K,
+        "50k" => PCAN_BAUD_50K,
+        "20k" => PCAN_BAUD_20K,
+        "10k" => PCAN_BAUD_10K,
+        _ => return Err(format!("unsupported bit rate {}", s)),
+    };
+    Ok(btr0btr1 as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use pcan_basic::{sim::Bus, stm32::device::Device};
+
+    use super::*;
+
+    #[test]
+    fn parse_arguments() {
+        assert_eq!(parse_u32("0x0800_0000"), Ok(0x0800_0000));
+        assert_eq!(parse_u32("1024"), Ok(1024));
+        assert_eq!(
+            parse_pages("4x16k, 1x0x10000"),
+            Ok(PageList(vec![(4, 0x4000), (1, 0x1_0000)]))
+        );
+        assert!(parse_pages("16k").is_err());
+        assert_eq!(parse_size("4194303k"), Ok(0xFFFF_FC00));
+        assert!(parse_size("4194304k").is_err());
+        assert_eq!(parse_channel("USB2"), Ok(PCAN_USBBUS2 as u16));
+        assert!(parse_channel("usb17").is_err());
+        assert_eq!(parse_bitrate("125k"), Ok(0x033A));
+    }
+
+    #[test]
+    fn command_line() {
+        let args = Args::try_parse_from(["stm32-flash", "fw.hex", "--go", "--json"]).unwrap();
+        assert_eq!(args.go, Some(None));
+        assert_eq!(args.pages.0.len(), 3);
+        let args = Args::try_parse_from(["stm32-flash", "fw.bin", "--go", "0x08004000"]).unwrap();
+        assert_eq!(args.go, Some(Some(0x0800_4000)));
+        assert!(Args::try_parse_from(["stm32-flash", "fw.hex", "--go", "--protect"]).is_err());
+    }
+
+    #[test]
+    fn simulated_device() {
+        let args = Args::try_parse_from(["stm32-flash", "fw.bin", "--go", "--json"]).unwrap();
+        let layout = Layout::new(args.flash_base, &args.pages.0);
+        let bus = Bus::new();
+        let device = bus.add_node(Device::new(layout.clone()));
+        let image = Image::from_binary(0x0800_4000, &[0x5A; 0x300]);
+
+        let mut report = Report::default();
+        program(bus.endpoint(), &image, layout, &args, &mut report).unwrap();
+        assert_eq!(report.written.get(), 0x300);
+        assert_eq!(report.started, Some(0x0800_4000));
+        assert_eq!(device.borrow().started(), Some(0x0800_4000));
+        assert_eq!(
+            device.borrow().read(0x0800_4000, 0x300),
+            Some(&[0x5A; 0x300][..])
+        );
+    }
+}